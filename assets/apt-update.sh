@@ -0,0 +1,5 @@
+printf '\033[1m\033[94mChecking the APT sources ...\033[0m\n'
+if ! apt-get update; then
+printf '\033[1m\033[91mUnable to update the APT sources, please check the mirror in /etc/apt/sources.list (or use --no-apt-update)!\033[0m\n' >&2
+exit 1
+fi
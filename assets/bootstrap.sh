@@ -1,28 +1,73 @@
-#!/bin/bash
+#!/bin/sh
 # === bootstrap.sh
-set -eo pipefail
 count=0
-PACKAGES=(
-{}
-)
-length=${#PACKAGES[@]}
-for p in "${PACKAGES[@]}"; do
+length={}
+unpack() {
+for p in "$@"; do
 count=$((count+1))
-echo -e "\e[1m[$count/$length] Installing ${p}...\e[0m"
+printf '\033[1m[%s/%s] Installing %s...\033[0m\n' "$count" "$length" "$p"
 dpkg --force-depends --force-unsafe-io --unpack "/var/cache/apt/archives/${p}"
 done
 sync
-count_c=1;length_c=$(dpkg -l | grep -c 'iU')
-function dpkg_progress () {
-    while read action step package; do
-if [ "$action" = 'processing:' ] && [ "$step" = 'configure:' ]; then
-echo -e "\e[1m\e[96m[$count_c/$length_c] Configuring $package...\e[0m";count_c=$(( count_c + 1 ))
+}
+count_c=0
+length_c={}
+# the markers time the configuration of the packages (--capture-guest-log)
+mark() {
+if [ -n "${AOSCBOOTSTRAP_PROFILE:-}" ]; then
+printf '@@aoscbootstrap %s %s %s\n' "$1" "$(date +%s.%N)" "$2"
+fi
+}
+# the triggers of these packages are only processed at the end (defer-triggers)
+deferred='{}'
+no_triggers=''
+if [ -n "$deferred" ]; then
+no_triggers='--no-triggers'
+fi
+# process the pending triggers right away, but those of the deferred packages
+process_triggers() {
+[ -n "$no_triggers" ] || return 0
+pending=''
+for s in $(dpkg-query -W -f='${db:Status-Status}:${Package}\n'); do
+case "$s" in
+triggers-pending:*) p="${s#triggers-pending:}" ;;
+*) continue ;;
+esac
+case " $deferred " in
+*" $p "*) ;;
+*) pending="$pending $p" ;;
+esac
+done
+if [ -n "$pending" ]; then
+# shellcheck disable=SC2086
+DEBIAN_FRONTEND=noninteractive dpkg --triggers-only --force-depends $pending
 fi
-    done
 }
-{ DEBIAN_FRONTEND=noninteractive dpkg --status-fd=7 --configure --pending --force-configure-any --force-depends 7>&1 >&8 | dpkg_progress; } 8>&1 \
-|| { echo 'Configuring missed packages ...'; dpkg --configure -a; }
-echo -e '\e[1m\e[94mCopying skeleton files ...\e[0m'
+# packages in the same group form a dependency cycle and are configured together
+configure() {
+for g in "$@"; do
+count_c=$((count_c+1))
+printf '\033[1m\033[96m[%s/%s] Configuring %s...\033[0m\n' "$count_c" "$length_c" "$g"
+mark configure-start "$g"
+# shellcheck disable=SC2086
+if ! DEBIAN_FRONTEND=noninteractive dpkg --configure $no_triggers --force-configure-any --force-depends $g; then
+printf '\033[1m\033[91mFailed to configure %s!\033[0m\n' "$g" >&2
+exit 1
+fi
+process_triggers
+mark configure-end "$g"
+done
+}
+# the pre-dependencies are configured before the packages depending on them are unpacked
+{}
+# process the remaining triggers
+mark configure-start '(pending triggers)'
+if [ -n "$no_triggers" ]; then
+DEBIAN_FRONTEND=noninteractive dpkg --triggers-only --pending
+fi
+DEBIAN_FRONTEND=noninteractive dpkg --configure --pending
+mark configure-end '(pending triggers)'
+printf '\033[1m\033[94mCopying skeleton files ...\033[0m\n'
 cp -rvT /etc/skel /root
-echo -e '\e[1m\e[94mEnabling systemd vendor presets ...\e[0m'
+printf '\033[1m\033[94mEnabling systemd vendor presets ...\033[0m\n'
 systemctl preset-all
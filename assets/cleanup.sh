@@ -1,4 +1,3 @@
-#!/bin/bash -e
 # === cleanup.sh
 remove_files () {
 WHITELIST="^/efi
@@ -23,7 +22,7 @@ WHITELIST="^/efi
     ALL_FILES="$(mktemp)"
     RM_FILES="$(mktemp)"
     PATTERN_FILES="$(mktemp)"
-    echo -e '\e[1mCollecting files from dpkg ...\e[0m'
+    printf '\033[1mCollecting files from dpkg ...\033[0m\n'
     # Accessing /proc causes race conditions. Better to avoid accessing pseudo filesystems.
     # Note: using `-not -regex' does not prevent find from accessing them.
     # `-prune` is more flexible, but `-mount` would be better.
@@ -35,15 +34,14 @@ WHITELIST="^/efi
     grep -vEf "$PATTERN_FILES" < "$ALL_FILES" > "${ALL_FILES}.new"
     mv "${ALL_FILES}.new" "$ALL_FILES"
     grep -vxFf "$DPKG_FILES" < "$ALL_FILES" > "$RM_FILES"
-    echo -e '\e[1mRemoving files ...\e[0m'
+    printf '\033[1mRemoving files ...\033[0m\n'
     xargs -a "$RM_FILES" rm -rfv
     rm -fv "$ALL_FILES" "$DPKG_FILES" "$RM_FILES"
     # Remove some extra files that absolutely should not be in the release files.
-    echo -e '\e[1mRemoving sensitive files ...\e[0m'
+    printf '\033[1mRemoving sensitive files ...\033[0m\n'
     rm -fv /etc/machine-id
     rm -fv /etc/ssh/ssh_host_*_key*
 }
 
-set -eo pipefail
-echo -e '\e[1mCleaning up the installation ...\e[0m'
+printf '\033[1mCleaning up the installation ...\033[0m\n'
 remove_files
@@ -0,0 +1,14 @@
+#!/bin/sh
+# === smoke-test.sh
+set -- \
+{}
+failed=0
+for c in "$@"; do
+printf '\033[1m\033[94mChecking: %s\033[0m\n' "$c"
+if ! output="$(sh -c "$c" 2>&1)"; then
+printf '%s\n' "$output" >&2
+printf '\033[1m\033[91mSmoke test failed: %s\033[0m\n' "$c" >&2
+failed=1
+fi
+done
+exit $failed
@@ -0,0 +1,5 @@
+# === sources.sh
+printf '\033[1m\033[94mWriting the APT sources ...\033[0m\n'
+cat > /etc/apt/sources.list << 'EOF_SOURCES'
+{}EOF_SOURCES
+chmod 0644 /etc/apt/sources.list
@@ -0,0 +1,3890 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, IsTerminal, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytesize::ByteSize;
+use nix::unistd::Uid;
+use owo_colors::colored::*;
+use rayon::prelude::*;
+use serde_json::json;
+
+use crate::{
+    cancel,
+    failure::Stage,
+    fs, guest, install,
+    lockfile::{self, Lockfile},
+    logging, network,
+    progress::Progress,
+    sbom,
+    solv::{self, PackageMeta, PackageRequest, RedundantRequest},
+    timings::Timings,
+    topics::{self, fetch_topics, filter_topics, Topic},
+    update, verify,
+};
+
+pub const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
+const DEFAULT_DOWNLOAD_JOBS: usize = 4;
+const DEFAULT_SPACE_HEADROOM: u64 = 512 * 1024 * 1024;
+/// Estimated average size of the installed files, to count the inodes they need (the
+/// manifests do not list the files; the average of a base system is larger than this)
+const BYTES_PER_FILE: u64 = 16 * 1024;
+/// Configures the packages unpacked by `--update`
+const CONFIGURE_PENDING: &str = "DEBIAN_FRONTEND=noninteractive dpkg --configure --pending";
+/// Where the packages are downloaded by default, and where stage 2 finds them in the guest
+const ARCHIVES: &str = "var/cache/apt/archives";
+/// Where stage 1 records the resolved packages (for --stage2-only and --update)
+const STAGE1_LOCKFILE: &str = "var/lib/aoscbootstrap/packages.lock";
+/// Name of the source of the topics in the pins
+const TOPICS_SOURCE: &str = "topics";
+/// Name of the source of the `--local-deb` packages in the pins
+const LOCAL_SOURCE: &str = "local";
+/// Priority of the `--local-deb` packages, above any repository
+const LOCAL_PIN_PRIORITY: i32 = 1001;
+/// The package list read from stdin (`--include-files -`)
+const STDIN_LIST: &str = "-";
+/// How many of the slowest package configurations are reported (`--capture-guest-log`)
+const SLOWEST_CONFIGURES: usize = 10;
+/// The packages larger than this once installed are decompressed while they are extracted,
+/// instead of ahead in memory
+const MAX_DECODED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Options of the exported archives and images
+#[derive(Clone, Debug, Default)]
+pub struct ExportOptions {
+    /// Path of the xz compressed tar archive
+    pub tar_xz: Option<String>,
+    /// Path of the gz compressed tar archive
+    pub tar_gz: Option<String>,
+    /// Path of the squashfs archive
+    pub squashfs: Option<String>,
+    pub squashfs_comp: fs::SquashfsCompression,
+    /// Path of the OCI image layout tarball
+    pub oci_output: Option<String>,
+    pub compress: install::LayerCompression,
+    /// Compression level of the OCI image layer (default: the algorithm's default)
+    pub compress_level: Option<i32>,
+    /// Timestamp of all the entries in the archives (in seconds since the Unix epoch)
+    pub source_date_epoch: u64,
+}
+
+impl ExportOptions {
+    /// Whether any archive or image is exported
+    fn has_output(&self) -> bool {
+        self.tar_xz.is_some()
+            || self.tar_gz.is_some()
+            || self.squashfs.is_some()
+            || self.oci_output.is_some()
+    }
+
+    /// How many archives are written to stdout ([fs::STDOUT_PATH])
+    fn stdout_outputs(&self) -> usize {
+        [&self.tar_xz, &self.tar_gz, &self.oci_output]
+            .into_iter()
+            .filter(|o| o.as_deref() == Some(fs::STDOUT_PATH))
+            .count()
+    }
+}
+
+/// Rewrites or filters the resolved packages before they are downloaded, e.g. to swap a
+/// package for a fork or to add the packages of a vendor (see [Bootstrap::post_resolve])
+pub trait PostResolve: Send + Sync {
+    fn post_resolve(&self, packages: &mut Vec<PackageMeta>) -> Result<()>;
+}
+
+impl<F> PostResolve for F
+where
+    F: Fn(&mut Vec<PackageMeta>) -> Result<()> + Send + Sync,
+{
+    fn post_resolve(&self, packages: &mut Vec<PackageMeta>) -> Result<()> {
+        self(packages)
+    }
+}
+
+/// A [PostResolve] hook, shared by the clones of the [Bootstrap]
+#[derive(Clone)]
+struct Hook(Arc<dyn PostResolve>);
+
+impl std::fmt::Debug for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PostResolve")
+    }
+}
+
+/// Bootstraps an AOSC OS system into a target directory.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// aoscbootstrap::Bootstrap::new("config/aosc-mainline.toml", "stable", "/root/aosc")
+///     .arches(vec!["amd64".to_string()])
+///     .include(vec!["network-base".to_string()])
+///     .run()
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Bootstrap {
+    config: String,
+    branches: Vec<String>,
+    target: String,
+    mirrors: Vec<String>,
+    final_mirror: Option<String>,
+    apt_update: bool,
+    arches: Vec<String>,
+    include: Vec<String>,
+    include_files: Vec<String>,
+    exclude: Vec<String>,
+    scripts: Vec<String>,
+    host_hooks: Vec<String>,
+    topics: Vec<String>,
+    comps: Vec<String>,
+    clean: bool,
+    download_only: bool,
+    dry_run: bool,
+    why: Option<String>,
+    install_recommends: bool,
+    allow_missing_essential: bool,
+    write_lock: Option<PathBuf>,
+    sbom: Option<PathBuf>,
+    sbom_format: sbom::SbomFormat,
+    lock: Option<PathBuf>,
+    post_resolve: Option<Hook>,
+    report: Option<PathBuf>,
+    bootstrap_pack: Option<PathBuf>,
+    local_debs: Vec<PathBuf>,
+    graph: Option<PathBuf>,
+    stage1_only: bool,
+    stage2_only: bool,
+    update: bool,
+    smoke_test: bool,
+    capture_guest_log: Option<PathBuf>,
+    verify_tree: bool,
+    verify_tree_warn: bool,
+    max_rate: Option<u64>,
+    downloader: network::Downloader,
+    mirror_fail_threshold: Option<usize>,
+    skip_missing: bool,
+    snapshot: Option<String>,
+    snapshot_template: String,
+    attempts: usize,
+    jobs: Option<usize>,
+    force: bool,
+    export: ExportOptions,
+    strict_overwrites: bool,
+    verify: bool,
+    cache: bool,
+    keyring: Option<PathBuf>,
+    keyring_dirs: Vec<PathBuf>,
+    proxy: Option<String>,
+    timeouts: network::Timeouts,
+    deadline: Option<Duration>,
+    space_check: bool,
+    package_cache: Option<PathBuf>,
+    archive_dir: Option<PathBuf>,
+    exclude_paths: Vec<String>,
+    keep_locales: Vec<String>,
+    space_headroom: u64,
+    decoder_memory_limit: Option<u64>,
+    check_signatures: bool,
+    manifest_compression: Vec<network::ManifestCompression>,
+    qemu_path: Option<String>,
+    dev_mode: fs::DevMode,
+    unprivileged: bool,
+    guest_backend: Option<guest::GuestBackend>,
+    guest_shell: String,
+    guest_shell_flags: Option<Vec<String>>,
+    guest_env: Vec<(String, String)>,
+    guest_network: bool,
+    resolv_conf: Option<String>,
+    keep_resolv_conf: bool,
+    quiet: bool,
+    verbose: bool,
+    confirm: bool,
+    timings: bool,
+}
+
+impl Bootstrap {
+    /// Bootstrap the `branch` into `target`, using the packages listed in the `config` file
+    pub fn new(config: &str, branch: &str, target: &str) -> Self {
+        Bootstrap {
+            config: config.to_string(),
+            branches: vec![branch.to_string()],
+            target: target.to_string(),
+            mirrors: vec![DEFAULT_MIRROR.to_string()],
+            final_mirror: None,
+            apt_update: true,
+            arches: Vec::new(),
+            include: Vec::new(),
+            include_files: Vec::new(),
+            exclude: Vec::new(),
+            scripts: Vec::new(),
+            host_hooks: Vec::new(),
+            topics: Vec::new(),
+            comps: Vec::new(),
+            clean: false,
+            download_only: false,
+            dry_run: false,
+            why: None,
+            install_recommends: false,
+            allow_missing_essential: false,
+            write_lock: None,
+            sbom: None,
+            sbom_format: sbom::SbomFormat::default(),
+            lock: None,
+            post_resolve: None,
+            report: None,
+            bootstrap_pack: None,
+            local_debs: Vec::new(),
+            graph: None,
+            stage1_only: false,
+            stage2_only: false,
+            update: false,
+            smoke_test: false,
+            capture_guest_log: None,
+            verify_tree: false,
+            verify_tree_warn: false,
+            max_rate: None,
+            downloader: network::Downloader::Builtin,
+            mirror_fail_threshold: None,
+            skip_missing: false,
+            snapshot: None,
+            snapshot_template: network::DEFAULT_SNAPSHOT_TEMPLATE.to_string(),
+            attempts: 3,
+            jobs: None,
+            force: false,
+            export: ExportOptions::default(),
+            strict_overwrites: false,
+            verify: true,
+            cache: true,
+            keyring: None,
+            keyring_dirs: Vec::new(),
+            proxy: None,
+            timeouts: network::Timeouts::default(),
+            deadline: None,
+            space_check: true,
+            package_cache: None,
+            archive_dir: None,
+            exclude_paths: Vec::new(),
+            keep_locales: Vec::new(),
+            space_headroom: DEFAULT_SPACE_HEADROOM,
+            decoder_memory_limit: None,
+            check_signatures: true,
+            manifest_compression: vec![
+                network::ManifestCompression::Zstd,
+                network::ManifestCompression::Xz,
+                network::ManifestCompression::Gzip,
+                network::ManifestCompression::Plain,
+            ],
+            qemu_path: None,
+            dev_mode: fs::DevMode::Nodes,
+            unprivileged: false,
+            guest_backend: None,
+            guest_shell: guest::DEFAULT_SHELL.to_string(),
+            guest_env: Vec::new(),
+            guest_network: false,
+            resolv_conf: None,
+            keep_resolv_conf: false,
+            guest_shell_flags: None,
+            quiet: false,
+            verbose: false,
+            confirm: false,
+            timings: false,
+        }
+    }
+
+    /// Branches to merge (e.g. `stable` and an overlay), later ones take precedence over the
+    /// earlier ones when they provide the same version of a package
+    pub fn branches(mut self, branches: Vec<String>) -> Self {
+        self.branches = branches;
+        self
+    }
+
+    /// Mirrors to be used, later ones are used as fallbacks
+    pub fn mirrors(mut self, mirrors: Vec<String>) -> Self {
+        self.mirrors = mirrors;
+        self
+    }
+
+    /// Mirror written to the sources.list of the bootstrapped system (default: the primary
+    /// mirror, or [DEFAULT_MIRROR] if the primary mirror is a local directory)
+    pub fn final_mirror(mut self, mirror: Option<String>) -> Self {
+        self.final_mirror = mirror;
+        self
+    }
+
+    /// Check that apt can update from the final mirror during stage 2 (default)
+    pub fn apt_update(mut self, apt_update: bool) -> Self {
+        self.apt_update = apt_update;
+        self
+    }
+
+    /// CPU architectures to consider (default: the host's architecture)
+    pub fn arches(mut self, arches: Vec<String>) -> Self {
+        self.arches = arches;
+        self
+    }
+
+    /// Extra packages to include
+    pub fn include(mut self, packages: Vec<String>) -> Self {
+        self.include = packages;
+        self
+    }
+
+    /// Extra packages to include, read from the lists (`-` reads a list from stdin)
+    pub fn include_files(mut self, paths: Vec<String>) -> Self {
+        self.include_files = paths;
+        self
+    }
+
+    /// Packages never to install
+    pub fn exclude(mut self, packages: Vec<String>) -> Self {
+        self.exclude = packages;
+        self
+    }
+
+    /// Scripts to run during stage 2 (after the clean up, if any)
+    pub fn scripts(mut self, paths: Vec<String>) -> Self {
+        self.scripts = paths;
+        self
+    }
+
+    /// Executables to run on the host with the target as `$1` before stage 2 enters the guest
+    pub fn host_hooks(mut self, paths: Vec<String>) -> Self {
+        self.host_hooks = paths;
+        self
+    }
+
+    pub fn topics(mut self, topics: Vec<String>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Additional components (`main` is always used)
+    pub fn comps(mut self, comps: Vec<String>) -> Self {
+        self.comps = comps;
+        self
+    }
+
+    /// Clean up (factory-reset) the bootstrapped environment
+    pub fn clean(mut self, clean: bool) -> Self {
+        self.clean = clean;
+        self
+    }
+
+    /// Only download the packages
+    pub fn download_only(mut self, download_only: bool) -> Self {
+        self.download_only = download_only;
+        self
+    }
+
+    /// Only resolve the dependencies and print the package list
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Explain why a package is pulled in (with [Bootstrap::dry_run])
+    pub fn why(mut self, package: Option<String>) -> Self {
+        self.why = package;
+        self
+    }
+
+    pub fn install_recommends(mut self, install_recommends: bool) -> Self {
+        self.install_recommends = install_recommends;
+        self
+    }
+
+    /// Only warn if essential packages (`Essential: yes` or `Priority: required`) of the
+    /// repository are not installed, instead of failing
+    pub fn allow_missing_essential(mut self, allow: bool) -> Self {
+        self.allow_missing_essential = allow;
+        self
+    }
+
+    /// Write the resolved packages to a lockfile
+    pub fn write_lock(mut self, path: Option<PathBuf>) -> Self {
+        self.write_lock = path;
+        self
+    }
+
+    /// Write the software bill of materials of the resolved packages (also in dry-run mode)
+    pub fn sbom(mut self, path: Option<PathBuf>) -> Self {
+        self.sbom = path;
+        self
+    }
+
+    pub fn sbom_format(mut self, format: sbom::SbomFormat) -> Self {
+        self.sbom_format = format;
+        self
+    }
+
+    /// Rewrite or filter the resolved packages before they are downloaded. The packages keep
+    /// the position of the resolved package of the same name: the added ones are configured
+    /// last, and are not extracted during stage 1. The lockfiles replayed with [Bootstrap::lock]
+    /// are not rewritten.
+    pub fn post_resolve<H: PostResolve + 'static>(mut self, hook: H) -> Self {
+        self.post_resolve = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Install exactly the packages from a lockfile (skips the dependency resolution, and the
+    /// manifests and the topics are not fetched)
+    pub fn lock(mut self, path: Option<PathBuf>) -> Self {
+        self.lock = path;
+        self
+    }
+
+    /// Write a report of the bootstrap (in JSON), after stage 2 or the download
+    pub fn report(mut self, path: Option<PathBuf>) -> Self {
+        self.report = path;
+        self
+    }
+
+    /// Extract this tarball as the base files of the system, instead of the embedded one
+    pub fn bootstrap_pack(mut self, path: Option<PathBuf>) -> Self {
+        self.bootstrap_pack = path;
+        self
+    }
+
+    /// Locally built packages, preferred over the packages of the same name in the repositories
+    pub fn local_debs(mut self, paths: Vec<PathBuf>) -> Self {
+        self.local_debs = paths;
+        self
+    }
+
+    /// Write the resolved dependency graph (in Graphviz DOT format)
+    pub fn graph(mut self, path: Option<PathBuf>) -> Self {
+        self.graph = path;
+        self
+    }
+
+    pub fn stage1_only(mut self, stage1_only: bool) -> Self {
+        self.stage1_only = stage1_only;
+        self
+    }
+
+    /// Only run stage 2 in a target where stage 1 has been run
+    pub fn stage2_only(mut self, stage2_only: bool) -> Self {
+        self.stage2_only = stage2_only;
+        self
+    }
+
+    /// Update an installed target in place: only the packages which have changed since
+    /// the last run are downloaded and extracted
+    pub fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// Run some basic commands in the bootstrapped system after stage 2
+    pub fn smoke_test(mut self, smoke_test: bool) -> Self {
+        self.smoke_test = smoke_test;
+        self
+    }
+
+    /// Write the output of stage 2 (or of the configuration of the updated packages) to a
+    /// file, and report the packages which took the longest to configure
+    pub fn capture_guest_log(mut self, path: Option<PathBuf>) -> Self {
+        self.capture_guest_log = path;
+        self
+    }
+
+    /// Check the finished tree against the files of the packages (their sizes, and the
+    /// checksums recorded by dpkg), failing on the missing and mismatched files
+    pub fn verify_tree(mut self, verify: bool) -> Self {
+        self.verify_tree = verify;
+        self
+    }
+
+    /// Only warn about the files which do not match in [Bootstrap::verify_tree]
+    pub fn verify_tree_warn(mut self, warn: bool) -> Self {
+        self.verify_tree_warn = warn;
+        self
+    }
+
+    /// Limit the aggregate download rate (in bytes per second)
+    pub fn max_rate(mut self, max_rate: Option<u64>) -> Self {
+        self.max_rate = max_rate;
+        self
+    }
+
+    /// Program downloading the packages (default: the built-in HTTP client)
+    pub fn downloader(mut self, downloader: network::Downloader) -> Self {
+        self.downloader = downloader;
+        self
+    }
+
+    /// Stop using a mirror once this many packages have failed to download from it
+    /// (after their retries), instead of trying it for every package
+    pub fn mirror_fail_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.mirror_fail_threshold = threshold;
+        self
+    }
+
+    /// Leave out the optional packages missing from the mirrors (e.g. while they are being
+    /// synchronized) if the others can do without them, instead of failing
+    pub fn skip_missing(mut self, skip: bool) -> Self {
+        self.skip_missing = skip;
+        self
+    }
+
+    /// Fetch the manifests and the packages from the snapshot of the mirrors at this
+    /// timestamp (`YYYYMMDDTHHMMSSZ`), the final sources.list still uses the live mirror
+    pub fn snapshot(mut self, timestamp: Option<String>) -> Self {
+        self.snapshot = timestamp;
+        self
+    }
+
+    /// Where the snapshots are, see [network::DEFAULT_SNAPSHOT_TEMPLATE]
+    pub fn snapshot_template(mut self, template: String) -> Self {
+        self.snapshot_template = template;
+        self
+    }
+
+    /// Maximum number of attempts for each download
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Limit the number of parallel jobs
+    pub fn jobs(mut self, jobs: Option<usize>) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Allow a target directory which is not empty
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn export(mut self, export: ExportOptions) -> Self {
+        self.export = export;
+        self
+    }
+
+    /// Fail when a file is shipped by more than one package during stage 1
+    pub fn strict_overwrites(mut self, strict: bool) -> Self {
+        self.strict_overwrites = strict;
+        self
+    }
+
+    /// Verify the checksums of the downloaded packages (default)
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Reuse the result of a previous resolution with the same manifests and packages (default)
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn keyring(mut self, path: Option<PathBuf>) -> Self {
+        self.keyring = path;
+        self
+    }
+
+    /// Trust all the keyrings (`*.gpg` and `*.asc`) in these directories, in addition to
+    /// the [Bootstrap::keyring]
+    pub fn keyring_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.keyring_dirs = dirs;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Option<String>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Timeouts of the connections (the read timeout is also the window of the stall detection)
+    pub fn timeouts(mut self, timeouts: network::Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Abort the download of the packages if it takes longer than this
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Check the available disk space before downloading the packages (default)
+    pub fn space_check(mut self, check: bool) -> Self {
+        self.space_check = check;
+        self
+    }
+
+    /// Paths not to extract during stage 1 nor to install during stage 2
+    /// (glob patterns, e.g. `/usr/share/doc`)
+    pub fn exclude_paths(mut self, patterns: Vec<String>) -> Self {
+        self.exclude_paths = patterns;
+        self
+    }
+
+    /// Only keep these locales in `/usr/share/locale` (e.g. `en_US`)
+    pub fn keep_locales(mut self, locales: Vec<String>) -> Self {
+        self.keep_locales = locales;
+        self
+    }
+
+    /// Host-wide cache of the downloaded packages, shared between the bootstraps
+    pub fn package_cache(mut self, dir: Option<PathBuf>) -> Self {
+        self.package_cache = dir;
+        self
+    }
+
+    /// Download the packages into `dir` instead of the target's `/var/cache/apt/archives`
+    /// (bind-mounted there during stage 2)
+    pub fn archive_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.archive_dir = dir;
+        self
+    }
+
+    /// Extra space required on top of the packages and the installed system, in bytes
+    pub fn space_headroom(mut self, bytes: u64) -> Self {
+        self.space_headroom = bytes;
+        self
+    }
+
+    /// Memory limit of each package decompressor during stage 1, in bytes (e.g. on
+    /// memory-constrained builders, with as many decompressors as extraction jobs)
+    pub fn decoder_memory_limit(mut self, bytes: Option<u64>) -> Self {
+        self.decoder_memory_limit = bytes;
+        self
+    }
+
+    /// Verify the signature of the repository (default)
+    pub fn check_signatures(mut self, check: bool) -> Self {
+        self.check_signatures = check;
+        self
+    }
+
+    /// Compression formats of the manifests to try, in the order of preference
+    pub fn manifest_compression(mut self, formats: Vec<network::ManifestCompression>) -> Self {
+        self.manifest_compression = formats;
+        self
+    }
+
+    pub fn qemu_path(mut self, path: Option<String>) -> Self {
+        self.qemu_path = path;
+        self
+    }
+
+    /// How `/dev` is populated, [fs::DevMode::Skip] and [fs::DevMode::Symlinks] do not
+    /// require root (stage 2 then needs the proot backend)
+    pub fn dev_mode(mut self, mode: fs::DevMode) -> Self {
+        self.dev_mode = mode;
+        self
+    }
+
+    /// Bootstrap without privileges (e.g. in a user namespace): the owners of the files are
+    /// recorded in [install::OWNERSHIP] instead of being applied, and restored in the
+    /// exported archives. Stage 2 runs with proot
+    pub fn unprivileged(mut self, unprivileged: bool) -> Self {
+        self.unprivileged = unprivileged;
+        self
+    }
+
+    pub fn guest_backend(mut self, backend: Option<guest::GuestBackend>) -> Self {
+        self.guest_backend = backend;
+        self
+    }
+
+    /// The mechanism used for entering the guest, proot when unprivileged unless specified
+    fn effective_guest_backend(&self) -> Option<guest::GuestBackend> {
+        match self.guest_backend {
+            None if self.unprivileged => Some(guest::GuestBackend::Proot),
+            backend => backend,
+        }
+    }
+
+    /// Interpreter of the stage 2 scripts in the guest (default: bash)
+    pub fn guest_shell(mut self, shell: String) -> Self {
+        self.guest_shell = shell;
+        self
+    }
+
+    /// Options passed to the guest shell (default: [guest::default_shell_flags])
+    pub fn guest_shell_flags(mut self, flags: Option<Vec<String>>) -> Self {
+        self.guest_shell_flags = flags;
+        self
+    }
+
+    /// Environment variables of the commands run in the guest, overriding the
+    /// `[guest-env]` section of the configuration file and [guest::DEFAULT_GUEST_ENV]
+    pub fn guest_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.guest_env = env;
+        self
+    }
+
+    /// Give the commands run in the guest the DNS configuration of the host and a hostname,
+    /// for the scripts downloading files
+    pub fn guest_network(mut self, guest_network: bool) -> Self {
+        self.guest_network = guest_network;
+        self
+    }
+
+    /// The DNS configuration given to the guest (default: [guest::DEFAULT_RESOLV_CONF])
+    pub fn resolv_conf(mut self, path: Option<String>) -> Self {
+        self.resolv_conf = path;
+        self
+    }
+
+    /// Leave the DNS configuration in the target after running the guest commands
+    pub fn keep_resolv_conf(mut self, keep: bool) -> Self {
+        self.keep_resolv_conf = keep;
+        self
+    }
+
+    fn setup_guest_network(&self, target_path: &Path, guard: &mut guest::GuestGuard) -> Result<()> {
+        if !self.guest_network {
+            return Ok(());
+        }
+        let resolv_conf = self
+            .resolv_conf
+            .as_deref()
+            .unwrap_or(guest::DEFAULT_RESOLV_CONF);
+
+        guest::setup_network(
+            target_path,
+            Path::new(resolv_conf),
+            self.keep_resolv_conf,
+            guard,
+        )
+    }
+
+    /// The environment of the commands run in the guest, the later sources win
+    fn effective_guest_env(&self, config: &install::Config) -> Vec<(String, String)> {
+        let mut env = guest::DEFAULT_GUEST_ENV
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        env.extend(config.guest_env.clone());
+        env.extend(self.guest_env.iter().cloned());
+
+        env.into_iter().collect()
+    }
+
+    /// Do not show the download and extraction progress
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// List the packages to be installed in the summary
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Ask for confirmation before downloading the packages
+    pub fn confirm(mut self, confirm: bool) -> Self {
+        self.confirm = confirm;
+        self
+    }
+
+    /// Print how long each phase took at the end (and include it in the report)
+    pub fn timings(mut self, timings: bool) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    /// Fail (or warn with `--allow-missing-essential`) if essential packages are missing
+    fn check_essential_packages(
+        &self,
+        missing: &[String],
+        excludes: &[String],
+        pins: &[solv::PinDecision],
+    ) -> Result<()> {
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let describe = missing
+            .iter()
+            .map(|name| {
+                if excludes.contains(name) {
+                    format!("{} (excluded)", name)
+                } else if pins.iter().any(|p| &p.package == name && p.kept.is_empty()) {
+                    format!("{} (kept out by a pin)", name)
+                } else {
+                    name.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        if !self.allow_missing_essential {
+            bail!(
+                "Essential packages would not be installed: {}\nInclude them, or use --allow-missing-essential if this is intended.",
+                describe.join(", ")
+            );
+        }
+        logging::event(
+            "essential_missing",
+            json!({ "packages": missing }),
+            format!(
+                "Warning: essential packages are not installed: {}",
+                describe.join(", ")
+            )
+            .yellow(),
+        );
+
+        Ok(())
+    }
+
+    /// The mirrors the manifests and the packages are downloaded from (the snapshots of
+    /// the mirrors with `--snapshot`)
+    fn source_mirrors(&self) -> Result<Vec<String>> {
+        let mirrors = self
+            .mirrors
+            .iter()
+            .map(|m| network::normalize_mirror(m))
+            .collect::<Vec<_>>();
+        if mirrors.is_empty() {
+            bail!("No mirror specified");
+        }
+        if self.branches.is_empty() {
+            bail!("No branch specified");
+        }
+        let Some(ref timestamp) = self.snapshot else {
+            return Ok(mirrors);
+        };
+        network::check_snapshot_timestamp(timestamp)?;
+        if !self.topics.is_empty() {
+            bail!("--topics cannot be used with --snapshot: the topics are not snapshotted");
+        }
+
+        mirrors
+            .iter()
+            .map(|m| network::snapshot_mirror(&self.snapshot_template, m, timestamp))
+            .collect()
+    }
+
+    fn effective_arches(&self) -> Vec<String> {
+        let mut arches = if self.arches.is_empty() {
+            get_default_arch()
+        } else {
+            self.arches.clone()
+        };
+        // append the `noarch` architecture if it does not exist.
+        // this is to avoid confusing issues with dependency resolving.
+        if !arches.contains(&"all".to_string()) {
+            arches.push("all".to_string());
+        }
+
+        arches
+    }
+
+    /// Print the packages offered by the branches (and the topics) whose names match
+    /// `search` (a glob pattern, or a substring), without resolving or downloading anything.
+    /// Only the names are printed with `names_only`.
+    pub fn list_packages(&self, search: Option<&str>, names_only: bool) -> Result<()> {
+        let search = PackageSearch::new(search)?;
+        let mirrors = self.source_mirrors()?;
+        let arches = self.effective_arches();
+        let arches = arches.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+        let mut comps = self.comps.clone();
+        comps.push("main".to_string());
+        let comps = comps.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let client = network::make_new_client(self.proxy.as_deref(), &self.timeouts)?;
+        if !self.topics.is_empty() {
+            // rejects the unknown topics
+            filter_topics(self.topics.clone(), fetch_topics(&client)?)?;
+        }
+        let lists_root = tempfile::tempdir()?;
+        let lists = lists_root.path().join("var/lib/apt/lists");
+        fs::make_dirs(&lists, fs::DIR_MODE)?;
+        let manifests = network::fetch_manifests(
+            &client,
+            &mirrors,
+            &self.branches,
+            &self.topics,
+            &arches,
+            &comps,
+            lists_root.path(),
+            &network::ManifestOptions {
+                check_signatures: self.check_signatures,
+                keyring: self.keyring.clone(),
+                keyring_dirs: self.keyring_dirs.clone(),
+                compressions: self.manifest_compression.clone(),
+                attempts: self.attempts,
+                jobs: self.jobs.unwrap_or(DEFAULT_DOWNLOAD_JOBS),
+            },
+        )?;
+        let groups = self
+            .branches
+            .iter()
+            .cloned()
+            .chain(std::iter::once(TOPICS_SOURCE.to_string()))
+            .zip(manifests)
+            .map(|(source, group)| (source, group.iter().map(|p| lists.join(p)).collect()))
+            .collect::<Vec<_>>();
+        let mut pool = solv::Pool::new();
+        solv::populate_pool(&mut pool, &groups)?;
+
+        let mut candidates = pool
+            .candidates()
+            .into_iter()
+            .filter(|c| search.matches(&c.name))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.repo.cmp(&b.repo)));
+        let mut out = std::io::stdout().lock();
+        if names_only {
+            candidates.dedup_by(|a, b| a.name == b.name);
+            for c in &candidates {
+                writeln!(out, "{}", c.name)?;
+            }
+        } else if logging::is_json() {
+            for c in &candidates {
+                let line = json!({
+                    "name": c.name,
+                    "version": c.version,
+                    "arch": c.arch,
+                    "source": c.repo,
+                    "download_size": c.download_size,
+                    "install_size": c.install_size,
+                });
+                writeln!(out, "{}", line)?;
+            }
+        } else {
+            for c in &candidates {
+                writeln!(
+                    out,
+                    "{} {} [{}, {}] {} download, {} installed",
+                    c.name.bold(),
+                    c.version.green(),
+                    c.arch,
+                    c.repo,
+                    ByteSize::b(c.download_size),
+                    ByteSize::b(c.install_size)
+                )?;
+            }
+        }
+        logging::event(
+            "packages_listed",
+            json!({ "packages": candidates.len() }),
+            format!("{} package(s) found.", candidates.len()),
+        );
+
+        Ok(())
+    }
+
+    /// Run the bootstrap, stopping at the first error (which has the failed [Stage] attached)
+    pub fn run(&self) -> Result<()> {
+        let start = Instant::now();
+        let mut timings = Timings::default();
+        let mut stage = Stage::Setup;
+        let result = self.bootstrap(&mut timings, &mut stage);
+        if self.timings {
+            timings.print(start.elapsed());
+        }
+
+        result.map_err(|e| e.context(stage))
+    }
+
+    fn bootstrap(&self, timings: &mut Timings, stage: &mut Stage) -> Result<()> {
+        check_input_files(&self.config, &self.include_files, &self.scripts)?;
+        check_host_hooks(&self.host_hooks)?;
+        if self.guest_network {
+            let resolv_conf = self
+                .resolv_conf
+                .as_deref()
+                .unwrap_or(guest::DEFAULT_RESOLV_CONF);
+            File::open(resolv_conf).with_context(|| format!("Unable to read {}", resolv_conf))?;
+        }
+        self.path_filter()?;
+        if let Some(ref pack) = self.bootstrap_pack {
+            install::check_bootstrap_pack(pack)?;
+        }
+        let local_debs = self
+            .local_debs
+            .iter()
+            .map(|p| install::read_local_deb(p))
+            .collect::<Result<Vec<_>>>()?;
+        if self.lock.is_some() && !local_debs.is_empty() {
+            bail!("--local-deb cannot be used with --lock");
+        }
+        if self.update && (self.stage1_only || self.stage2_only) {
+            bail!("--update cannot be used with --stage1-only or --stage2-only");
+        }
+        if self.unprivileged {
+            if self.dev_mode == fs::DevMode::Nodes {
+                bail!("--unprivileged requires --no-device-nodes or --minimal-dev");
+            }
+            if self.effective_guest_backend() != Some(guest::GuestBackend::Proot) {
+                bail!("--unprivileged requires the proot guest backend");
+            }
+        }
+        // without device nodes, nothing needs privileges but entering the guest
+        let rootless = self.dev_mode != fs::DevMode::Nodes
+            && (self.download_only
+                || self.stage1_only
+                || self.effective_guest_backend() == Some(guest::GuestBackend::Proot));
+        if !self.dry_run && !rootless && !Uid::current().is_root() {
+            bail!("aoscbootstrap must be run as root (or with --no-device-nodes or --minimal-dev, and the proot backend for stage 2).");
+        }
+
+        let target = self.target.as_str();
+        let mirrors = &self.source_mirrors()?;
+        if !self.force && !self.dry_run && !self.stage2_only && !self.update {
+            check_target(Path::new(target))?;
+        }
+        // rather than after downloading the packages
+        self.final_sources_list()?;
+        if self.export.squashfs.as_deref() == Some(fs::STDOUT_PATH) {
+            bail!("The squashfs archive cannot be written to stdout");
+        }
+        if self.export.stdout_outputs() > 1 {
+            bail!("Only one archive can be written to stdout");
+        }
+        if self.export.stdout_outputs() == 1 && !self.dry_run {
+            fs::reserve_stdout()?;
+        }
+        if self.export.squashfs.is_some() && which::which("mksquashfs").is_err() {
+            bail!(
+                "Cannot find mksquashfs binary! Please install squashfs-tools (e.g. `apt install squashfs-tools`)."
+            );
+        }
+        let downloads = !self.dry_run && !self.stage2_only;
+        if downloads
+            && self.downloader == network::Downloader::Aria2c
+            && which::which("aria2c").is_err()
+        {
+            bail!("Cannot find aria2c binary! Please install aria2 (e.g. `apt install aria2`).");
+        }
+        if self.skip_missing && self.downloader == network::Downloader::Aria2c {
+            bail!("--skip-missing requires the built-in downloader");
+        }
+        if self.skip_missing && self.lock.is_some() {
+            bail!("--skip-missing cannot be used with --lock");
+        }
+        // the sources of the topics would need their metadata, which is not locked
+        if !self.topics.is_empty() && self.lock.is_some() {
+            bail!("--topics cannot be used with --lock");
+        }
+        // the debs of the packages kept by an update may be gone
+        if self.verify_tree && self.update {
+            bail!("--verify-tree cannot be used with --update");
+        }
+        if let Some(level) = self.export.compress_level {
+            self.export.compress.check_level(level)?;
+        }
+        let arches = self.effective_arches();
+        let main_arch = arches
+            .iter()
+            .find(|a| *a != "all")
+            .ok_or_else(|| anyhow!("Did not find the main architecture"))?
+            .clone();
+        let needs_guest = !self.download_only && !self.stage1_only && !self.dry_run;
+        let host_arch = get_arch_name();
+        let emulator = if needs_guest && host_arch.is_none() {
+            // nothing tells whether the host can run the guest, assume that it can
+            logging::event(
+                "unknown_host_arch",
+                json!({ "arch": main_arch }),
+                format!(
+                    "Warning: unable to detect the architecture of the host, running the {} guest natively.",
+                    main_arch
+                )
+                .yellow(),
+            );
+            None
+        } else if needs_guest && host_arch != Some(main_arch.as_str()) {
+            logging::event(
+                "emulation",
+                json!({ "arch": main_arch }),
+                format!(
+                    "Bootstrapping a foreign architecture ({}), using QEMU user-mode emulation.",
+                    main_arch.cyan()
+                ),
+            );
+            Some(guest::Emulator::find(
+                &main_arch,
+                self.qemu_path.as_deref(),
+            )?)
+        } else {
+            None
+        };
+        let config_path = self.config.as_str();
+        let config = install::read_config(config_path)
+            .context(format!("when reading configuration file '{}'", config_path))?;
+        let client = network::make_new_client(self.proxy.as_deref(), &self.timeouts)?;
+        if self.snapshot.is_some() && !self.stage2_only {
+            for branch in &self.branches {
+                network::check_snapshot(&client, mirrors, branch, self.attempts)?;
+            }
+        }
+        let target_path = Path::new(target);
+        let archive_path = self
+            .archive_dir
+            .clone()
+            .unwrap_or_else(|| target_path.join(ARCHIVES));
+        let threads = self.jobs.unwrap_or_else(num_cpus::get);
+        if let Some(jobs) = self.jobs {
+            std::env::set_var("RAYON_NUM_THREADS", jobs.to_string());
+        }
+        let previous = if self.update {
+            Some(read_installed_lockfile(target_path)?)
+        } else {
+            None
+        };
+        if self.stage2_only {
+            let resolved = check_stage1_tree(target_path, &archive_path)?;
+            self.write_sbom(&resolved.packages(), &mirrors[0])?;
+            // the stage 1 packages are already installed, the debs are already downloaded
+            self.check_disk_space(
+                target_path,
+                &[(
+                    "Installed system (stage 2)",
+                    (resolved.install_size() - resolved.stub_install_size()) * 1024,
+                )],
+            )?;
+            self.check_free_inodes(
+                target_path,
+                (resolved.install_size() - resolved.stub_install_size()) * 1024,
+            )?;
+            *stage = Stage::Stage2;
+            let script =
+                self.write_stage2_script(&resolved.install_order(), target_path, &config)?;
+            let stage2 = Instant::now();
+            self.do_stage2(
+                target_path,
+                &archive_path,
+                &resolved.packages(),
+                script,
+                emulator.as_ref(),
+                &config,
+            )?;
+            timings.record("Stage 2", stage2, None);
+            let arches = arches.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+            self.write_report(&resolved, mirrors, &arches, &[], timings)?;
+            *stage = Stage::Export;
+            let export = Instant::now();
+            self.do_export(target_path, threads, &main_arch)?;
+            timings.record("Export", export, None);
+            return Ok(());
+        }
+        let arches = arches.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+        let mut extra_packages = self
+            .include
+            .iter()
+            .map(|p| -> Result<_> {
+                let (package, applies) = split_arch_qualifier(p, &arches)
+                    .with_context(|| format!("--include: invalid package `{}`", p))?;
+                Ok(applies.then(|| (package.to_string(), "--include".to_string())))
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>>>()?;
+        if !self.include_files.is_empty() {
+            let extras = collect_packages_from_lists(&self.include_files, &arches)?;
+            logging::event(
+                "include_files",
+                json!({ "packages": extras.len() }),
+                format!(
+                    "Read {} extra packages from the lists.",
+                    extras.len().cyan().bold()
+                ),
+            );
+            extra_packages.extend(extras);
+        }
+        let mut comps = self.comps.clone();
+        comps.push("main".to_string());
+        let comps_str = comps.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+
+        if !self.dry_run {
+            fs::make_dirs(target_path, fs::DIR_MODE)?;
+            fs::make_dirs(&archive_path, fs::DIR_MODE)?;
+        }
+
+        let topics = Cow::Borrowed(&self.topics);
+        let filtered = if !topics.is_empty() {
+            filter_topics(topics.to_vec(), fetch_topics(&client)?)?
+        } else {
+            Vec::new()
+        };
+
+        let mut requests = config
+            .stub_packages
+            .iter()
+            .chain(config.base_packages.iter())
+            .map(|p| (p.clone(), config_path.to_string()))
+            .collect::<Vec<_>>();
+        let mut extra_requests = Vec::new();
+        for (package, source) in extra_packages {
+            let request: PackageRequest = package
+                .parse()
+                .with_context(|| format!("when parsing the included package from {}", source))?;
+            extra_requests.push(request.clone());
+            requests.push((request, source));
+        }
+        let (all_stages, warnings) = dedup_requests(requests);
+        for warning in warnings {
+            logging::event(
+                "duplicate_package",
+                json!({ "message": warning }),
+                format!("Warning: {}", warning).yellow(),
+            );
+        }
+
+        let mut redundant = Vec::new();
+        // kept to resolve the dependencies again without the missing packages (--skip-missing)
+        let mut resolver = None;
+        let mut resolved = if let Some(ref lock) = self.lock {
+            logging::event(
+                "lockfile_read",
+                json!({ "path": lock }),
+                format!("Using the packages locked in {} ...", lock.display().cyan()),
+            );
+            Lockfile::read(lock)?
+        } else {
+            // do not touch the target in dry-run mode, the manifests are stored in a temporary directory
+            let dry_run_root = if self.dry_run {
+                Some(tempfile::tempdir()?)
+            } else {
+                None
+            };
+            let lists_root = dry_run_root
+                .as_ref()
+                .map(|d| d.path())
+                .unwrap_or(target_path);
+            fs::make_dirs(&lists_root.join("var/lib/apt/lists"), fs::DIR_MODE)?;
+            *stage = Stage::Manifests;
+            logging::event(
+                "manifests_download",
+                json!({ "mirrors": mirrors, "branches": self.branches }),
+                "Downloading manifests ...",
+            );
+            let fetch = Instant::now();
+            let manifests = network::fetch_manifests(
+                &client,
+                mirrors,
+                &self.branches,
+                &topics,
+                &arches,
+                &comps_str,
+                lists_root,
+                &network::ManifestOptions {
+                    check_signatures: self.check_signatures,
+                    keyring: self.keyring.clone(),
+                    keyring_dirs: self.keyring_dirs.clone(),
+                    compressions: self.manifest_compression.clone(),
+                    attempts: self.attempts,
+                    jobs: self.jobs.unwrap_or(DEFAULT_DOWNLOAD_JOBS),
+                },
+            )?;
+            timings.record("Manifests", fetch, None);
+
+            // the topics come last, after all the branches (and before the local packages)
+            let sources = self
+                .branches
+                .iter()
+                .cloned()
+                .chain(std::iter::once(TOPICS_SOURCE.to_string()))
+                .chain((!local_debs.is_empty()).then(|| LOCAL_SOURCE.to_string()))
+                .collect::<Vec<_>>();
+            let mut groups = sources
+                .iter()
+                .zip(&manifests)
+                .map(|(source, group)| {
+                    let paths = group
+                        .iter()
+                        .map(|p| lists_root.join("var/lib/apt/lists").join(p))
+                        .collect::<Vec<_>>();
+                    (source.clone(), paths)
+                })
+                .collect::<Vec<_>>();
+            if !local_debs.is_empty() {
+                let path = lists_root.join("var/lib/apt/lists/aoscbootstrap_local_Packages");
+                let manifest = local_debs.iter().map(|d| d.stanza.as_str());
+                fs::write_file(
+                    &path,
+                    manifest.collect::<Vec<_>>().join("\n"),
+                    fs::FILE_MODE,
+                )?;
+                groups.push((LOCAL_SOURCE.to_string(), vec![path]));
+            }
+            // the local packages win over any other source
+            let pins = local_pins(&local_debs)
+                .into_iter()
+                .chain(config.pin.iter().cloned())
+                .collect::<Vec<_>>();
+            let paths = groups
+                .iter()
+                .flat_map(|(_, paths)| paths.iter().cloned())
+                .collect::<Vec<_>>();
+            for pin in pins.iter().filter(|p| !sources.contains(&p.source)) {
+                logging::event(
+                    "pin_unknown_source",
+                    json!({ "package": pin.package, "source": pin.source }),
+                    format!(
+                        "Warning: the pin of {} refers to an unknown source {}",
+                        pin.package, pin.source
+                    )
+                    .yellow(),
+                );
+            }
+
+            let mut excludes = config.exclude.clone();
+            excludes.extend(self.exclude.iter().cloned());
+            // the graph, the explanations, the pin decisions (shown in dry-run mode) and the
+            // redundant includes (also in the report) need the pool, which is not cached
+            let cache_path = if !self.cache
+                || self.graph.is_some()
+                || self.why.is_some()
+                || (self.dry_run && !pins.is_empty())
+                || (!extra_requests.is_empty() && (self.dry_run || self.report.is_some()))
+                || self.allow_missing_essential
+                || self.skip_missing
+            {
+                None
+            } else {
+                resolution_cache_path(
+                    &paths,
+                    &all_stages,
+                    &config.stub_packages,
+                    &excludes,
+                    &pins,
+                    self.install_recommends,
+                )
+            };
+            if let Some((path, cached)) = cache_path
+                .as_ref()
+                .and_then(|p| Some((p, Lockfile::read(p).ok()?)))
+            {
+                logging::event(
+                    "resolution_cached",
+                    json!({ "path": path }),
+                    format!("Using the cached resolution {} ...", path.display().cyan()),
+                );
+                cached
+            } else {
+                *stage = Stage::Resolution;
+                logging::event(
+                    "resolution_start",
+                    json!({ "manifests": paths }),
+                    "Resolving dependencies ...",
+                );
+                let load = Instant::now();
+                let mut pool = solv::Pool::new();
+                solv::populate_pool(&mut pool, &groups)?;
+                timings.record("Loading manifests", load, None);
+                let resolution = Instant::now();
+                let pins = solv::pin_decisions(&pool, &pins)?;
+                print_pin_decisions(&pins);
+                let t = pool.resolve(&all_stages, &excludes, &pins, self.install_recommends)?;
+                if let Some(ref graph) = self.graph {
+                    let f = File::create(graph)?;
+                    t.write_graph(std::io::BufWriter::new(f))
+                        .context("when writing the dependency graph")?;
+                    logging::event(
+                        "graph_written",
+                        json!({ "path": graph }),
+                        format!("Dependency graph written to {}", graph.display().cyan()),
+                    );
+                }
+                if let Some(ref name) = self.why {
+                    let chains = t.explain(&all_stages, name)?;
+                    if chains.is_empty() {
+                        logging::event(
+                            "why",
+                            json!({ "package": name, "chains": 0 }),
+                            format!("{} is not required by any of the requested packages.", name),
+                        );
+                    }
+                    for chain in chains {
+                        println!("{}", chain);
+                    }
+                    return Ok(());
+                }
+                // stage 1 extracts the stub packages of the same resolution
+                let stub_packages = t.closure(&config.stub_packages)?;
+                redundant = t.redundant_requests(&all_stages, &extra_requests)?;
+                print_redundant_requests(&redundant);
+                let install_order = t.install_order()?;
+                self.check_essential_packages(
+                    &solv::missing_essential(&pool, &install_order.concat()),
+                    &excludes,
+                    &pins,
+                )?;
+                timings.record("Resolution", resolution, None);
+
+                let resolved = Lockfile::new(&install_order, &stub_packages);
+                if self.skip_missing {
+                    resolver = Some(Resolver {
+                        pool,
+                        excludes,
+                        pins,
+                    });
+                }
+                if let Some(ref path) = cache_path {
+                    let result = std::fs::create_dir_all(path.parent().unwrap())
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| resolved.write(path));
+                    if let Err(e) = result {
+                        logging::event(
+                            "cache_failed",
+                            json!({ "error": format!("{:#}", e) }),
+                            format!("Unable to save the resolution cache: {:#}", e).yellow(),
+                        );
+                    }
+                }
+
+                resolved
+            }
+        };
+        if self.lock.is_none() {
+            self.post_process(&mut resolved)?;
+        }
+        self.write_lockfile(&resolved)?;
+        let mut all_packages = resolved.packages();
+        self.write_sbom(&all_packages, &mirrors[0])?;
+        print_plan(&resolved, self.verbose);
+        let mut diff = previous.as_ref().map(|p| p.diff(&resolved));
+        if let Some(ref diff) = diff {
+            print_update_plan(diff);
+        }
+        if self.dry_run {
+            if self.lock.is_none() {
+                logging::event(
+                    "recommends",
+                    json!({ "install_recommends": self.install_recommends }),
+                    format!(
+                        "Recommended packages: {}",
+                        if self.install_recommends {
+                            "installed when possible"
+                        } else {
+                            "not installed"
+                        }
+                        .bold()
+                    ),
+                );
+            }
+            print_package_list(&all_packages);
+            return Ok(());
+        }
+        if self.confirm && !confirm("Do you want to continue?")? {
+            logging::event("aborted", json!({}), "Aborted.".red());
+            bail!("Aborted by the user");
+        }
+        *stage = Stage::Download;
+        // only the packages which have changed are downloaded for an update
+        let mut packages = match diff {
+            Some(ref diff) => diff.changed(),
+            None => all_packages.clone(),
+        };
+        let downloads = packages
+            .iter()
+            .filter(|p| !archive_path.join(p.file_name()).is_file())
+            .map(|p| p.download_size)
+            .sum();
+        // the downloaded packages and the installed system coexist until the end of stage 2
+        let mut space = vec![("Downloaded packages", downloads)];
+        if diff.is_none() {
+            space.push(("Installed system", resolved.install_size() * 1024));
+        }
+        if archive_path.metadata()?.dev() == target_path.metadata()?.dev() {
+            self.check_disk_space(target_path, &space)?;
+        } else {
+            // e.g. --archive-dir on a scratch storage
+            self.check_disk_space(&archive_path, &space[..1])?;
+            self.check_disk_space(target_path, &space[1..])?;
+        }
+        if diff.is_none() {
+            self.check_free_inodes(target_path, resolved.install_size() * 1024)?;
+        }
+        copy_local_debs(&local_debs, &packages, &archive_path)?;
+        logging::event(
+            "download_start",
+            json!({ "packages": packages.len() }),
+            "Downloading packages ...",
+        );
+        let download = Instant::now();
+        let options = network::DownloadOptions {
+            verify: self.verify,
+            jobs: self.jobs.unwrap_or(DEFAULT_DOWNLOAD_JOBS),
+            quiet: self.quiet,
+            max_rate: self.max_rate,
+            attempts: self.attempts,
+            stall_window: self.timeouts.read,
+            deadline: self.deadline,
+            cache_dir: self.package_cache.clone(),
+            downloader: self.downloader,
+            mirror_fail_threshold: self.mirror_fail_threshold,
+            skip_missing: self.skip_missing,
+        };
+        let mut stats =
+            network::batch_download(&client, &packages, mirrors, &archive_path, &options)?;
+        // the other packages may have been resolved to alternatives, which are downloaded too
+        while !stats.missing.is_empty() {
+            let resolver = resolver.as_mut().ok_or_else(|| {
+                anyhow!("Missing packages without a resolution to drop them from")
+            })?;
+            resolved = self.resolve_without_missing(
+                resolver,
+                &stats.missing,
+                &all_packages,
+                &all_stages,
+                &config,
+            )?;
+            self.post_process(&mut resolved)?;
+            self.write_lockfile(&resolved)?;
+            all_packages = resolved.packages();
+            self.write_sbom(&all_packages, &mirrors[0])?;
+            diff = previous.as_ref().map(|p| p.diff(&resolved));
+            packages = match diff {
+                Some(ref diff) => diff.changed(),
+                None => all_packages.clone(),
+            };
+            let more =
+                network::batch_download(&client, &packages, mirrors, &archive_path, &options)?;
+            stats.verified_bytes += more.verified_bytes;
+            stats.verify_time += more.verify_time;
+            stats.missing = more.missing;
+        }
+        nix::unistd::sync();
+        timings.record_duration(
+            "Verification",
+            stats.verify_time,
+            Some(stats.verified_bytes),
+        );
+        // the packages already in the archive are not downloaded again
+        timings.record_duration(
+            "Download",
+            download.elapsed().saturating_sub(stats.verify_time),
+            Some(downloads),
+        );
+        if self.download_only {
+            logging::event(
+                "download_finish",
+                json!({ "packages": packages.len() }),
+                "Download finished.".green().bold(),
+            );
+            return self.write_report(&resolved, mirrors, &arches, &redundant, timings);
+        }
+
+        *stage = if diff.is_some() {
+            Stage::Update
+        } else {
+            Stage::Stage1
+        };
+        let manual_packages = all_stages
+            .iter()
+            .map(|p| p.name.clone())
+            .collect::<Vec<_>>();
+        install::generate_apt_extended_state(
+            target_path,
+            &manual_packages,
+            &all_packages,
+            &main_arch,
+        )
+        .context("Unable to generate APT extended state")?;
+        if let Some(ref diff) = diff {
+            let update = Instant::now();
+            self.do_update(
+                diff,
+                &resolved,
+                target_path,
+                &archive_path,
+                emulator.as_ref(),
+                &config,
+            )?;
+            timings.record("Update", update, Some(downloads));
+        } else {
+            let stage1 = Instant::now();
+            let script = match self.do_stage1(
+                &resolved,
+                target_path,
+                // the primary mirror is used in the generated sources.list
+                &mirrors[0],
+                &archive_path,
+                filtered,
+                &config,
+            )? {
+                Some(value) => value,
+                None => return Ok(()),
+            };
+            timings.record("Stage 1", stage1, Some(resolved.stub_install_size() * 1024));
+
+            *stage = Stage::Stage2;
+            let stage2 = Instant::now();
+            self.do_stage2(
+                target_path,
+                &archive_path,
+                &all_packages,
+                script,
+                emulator.as_ref(),
+                &config,
+            )?;
+            timings.record("Stage 2", stage2, None);
+        }
+        self.write_report(&resolved, mirrors, &arches, &redundant, timings)?;
+        *stage = Stage::Export;
+        let export = Instant::now();
+        self.do_export(target_path, threads, &main_arch)?;
+        timings.record("Export", export, None);
+
+        Ok(())
+    }
+
+    /// Create the device nodes, the apt files and the base files
+    fn make_skeleton(
+        &self,
+        target_path: &Path,
+        mirror: &str,
+        topics: Vec<Topic>,
+        config: &install::Config,
+    ) -> Result<()> {
+        fs::make_dirs(&target_path.join("dev"), fs::DIR_MODE)?;
+        match self.dev_mode {
+            fs::DevMode::Nodes => fs::make_device_nodes(target_path, &config.device_nodes)
+                .context("when creating device nodes")?,
+            fs::DevMode::Skip => (),
+            fs::DevMode::Symlinks => {
+                fs::make_dev_symlinks(target_path).context("when creating the /dev symlinks")?
+            }
+        }
+        fs::bootstrap_apt(target_path, mirror, &self.branches)
+            .context("when preparing apt files")?;
+        topics::save_topics(target_path, topics)?;
+        match self.bootstrap_pack {
+            Some(ref pack) => {
+                let extracted = install::extract_custom_bootstrap_pack(pack, target_path)
+                    .context("when extracting base files")?;
+                if !extracted.pending_xattrs.is_empty() {
+                    logging::event(
+                        "xattrs_pending",
+                        json!({ "xattrs": extracted.pending_xattrs.len(), "path": pack }),
+                        format!(
+                            "Warning: unable to restore {} extended attribute(s) from {}",
+                            extracted.pending_xattrs.len(),
+                            pack.display()
+                        )
+                        .yellow(),
+                    );
+                }
+            }
+            None => install::extract_bootstrap_pack(target_path)
+                .context("when extracting base files")?,
+        }
+
+        Ok(())
+    }
+
+    fn write_report(
+        &self,
+        resolved: &Lockfile,
+        mirrors: &[String],
+        arches: &[&str],
+        redundant_includes: &[RedundantRequest],
+        timings: &Timings,
+    ) -> Result<()> {
+        let Some(ref path) = self.report else {
+            return Ok(());
+        };
+        let mut components = vec!["main"];
+        components.extend(self.comps.iter().map(|c| c.as_str()));
+        lockfile::Report {
+            mirrors,
+            branches: &self.branches,
+            arches,
+            components: &components,
+            topics: &self.topics,
+            redundant_includes,
+            timings: if self.timings { timings.phases() } else { &[] },
+            lockfile: resolved,
+        }
+        .write(path)?;
+        logging::event(
+            "report_written",
+            json!({ "path": path }),
+            format!("Build report written to {}", path.display().cyan()),
+        );
+
+        Ok(())
+    }
+
+    /// Run the [PostResolve] hook on the resolved packages
+    fn post_process(&self, resolved: &mut Lockfile) -> Result<()> {
+        let Some(Hook(ref hook)) = self.post_resolve else {
+            return Ok(());
+        };
+
+        resolved
+            .rewrite(|packages| hook.post_resolve(packages))
+            .context("when post-processing the resolved packages")
+    }
+
+    fn write_lockfile(&self, resolved: &Lockfile) -> Result<()> {
+        let Some(ref path) = self.write_lock else {
+            return Ok(());
+        };
+        resolved.write(path)?;
+        logging::event(
+            "lockfile_written",
+            json!({ "path": path }),
+            format!("Resolved packages written to {}", path.display().cyan()),
+        );
+
+        Ok(())
+    }
+
+    /// Drop the packages missing from the mirrors from the resolution, unless they are
+    /// essential or requested, and resolve the dependencies of the others again without them
+    fn resolve_without_missing(
+        &self,
+        resolver: &mut Resolver,
+        missing: &[String],
+        packages: &[PackageMeta],
+        requests: &[PackageRequest],
+        config: &install::Config,
+    ) -> Result<Lockfile> {
+        let required = packages
+            .iter()
+            .filter(|p| missing.contains(&p.name))
+            .filter(|p| p.is_essential() || requests.iter().any(|r| r.name == p.name))
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        if !required.is_empty() {
+            bail!(
+                "Essential or requested packages are missing from the mirrors: {}",
+                required.join(", ")
+            );
+        }
+        logging::event(
+            "packages_missing",
+            json!({ "packages": missing }),
+            format!(
+                "Warning: packages missing from the mirrors, resolving the dependencies again without them: {}",
+                missing.join(", ")
+            )
+            .yellow(),
+        );
+        resolver.excludes.extend(missing.iter().cloned());
+        let t = resolver
+            .pool
+            .resolve(
+                requests,
+                &resolver.excludes,
+                &resolver.pins,
+                self.install_recommends,
+            )
+            .context(
+                "The other packages cannot do without the packages missing from the mirrors",
+            )?;
+        let stub_packages = t.closure(&config.stub_packages)?;
+        let install_order = t.install_order()?;
+        self.check_essential_packages(
+            &solv::missing_essential(&resolver.pool, &install_order.concat()),
+            &resolver.excludes,
+            &resolver.pins,
+        )?;
+
+        Ok(Lockfile::new(&install_order, &stub_packages))
+    }
+
+    fn write_sbom(&self, packages: &[PackageMeta], mirror: &str) -> Result<()> {
+        let Some(ref path) = self.sbom else {
+            return Ok(());
+        };
+        let created = match self.export.source_date_epoch {
+            0 => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            epoch => epoch,
+        };
+        sbom::Sbom {
+            packages,
+            mirror,
+            branches: &self.branches,
+            created,
+        }
+        .write(path, self.sbom_format)?;
+        logging::event(
+            "sbom_written",
+            json!({ "path": path, "format": self.sbom_format, "packages": packages.len() }),
+            format!(
+                "Software bill of materials written to {}",
+                path.display().cyan()
+            ),
+        );
+
+        Ok(())
+    }
+
+    fn path_filter(&self) -> Result<install::PathFilter> {
+        install::PathFilter::new(&self.exclude_paths, &self.keep_locales)
+    }
+
+    fn extract_options<'a>(
+        &self,
+        filter: &'a install::PathFilter,
+        resume: bool,
+    ) -> ExtractOptions<'a> {
+        ExtractOptions {
+            quiet: self.quiet,
+            jobs: self.jobs.unwrap_or_else(num_cpus::get),
+            strict: self.strict_overwrites,
+            filter,
+            resume,
+            memory_limit: self.decoder_memory_limit,
+        }
+    }
+
+    /// Make sure the filesystem of the target can hold the `components` (description, bytes)
+    /// and the headroom at the same time
+    fn check_disk_space(&self, target: &Path, components: &[(&str, u64)]) -> Result<()> {
+        if !self.space_check {
+            return Ok(());
+        }
+        let mut components = components.to_vec();
+        components.push(("Headroom", self.space_headroom));
+
+        check_disk_usage(&components, fs3::available_space(target)?)
+    }
+
+    /// Make sure the filesystem of the target has enough free inodes for the files of
+    /// `install_size` bytes of packages, which is only a warning with `--skip-space-check`
+    fn check_free_inodes(&self, target: &Path, install_size: u64) -> Result<()> {
+        let stat = nix::sys::statvfs::statvfs(target)
+            .with_context(|| format!("when querying the filesystem of {}", target.display()))?;
+        let result = check_inode_usage(
+            install_size / BYTES_PER_FILE,
+            stat.files() as u64,
+            stat.files_available() as u64,
+        );
+        match result {
+            Err(e) if !self.space_check => {
+                logging::event(
+                    "inodes_low",
+                    json!({ "error": e.to_string() }),
+                    format!("Warning: {}", e).yellow(),
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn do_stage1(
+        &self,
+        resolved: &Lockfile,
+        target_path: &Path,
+        mirror: &str,
+        archive_path: &Path,
+        topics: Vec<Topic>,
+        config: &install::Config,
+    ) -> Result<Option<tempfile::NamedTempFile>> {
+        let stub_install = resolved.stub_packages();
+        let install_order = resolved.install_order();
+        logging::event(
+            "stage_start",
+            json!({ "stage": 1 }),
+            "Stage 1: Creating filesystem skeleton ...",
+        );
+        // the packages extracted by the interrupted run have overwritten the skeleton
+        if !is_stage1_interrupted(target_path) {
+            self.make_skeleton(target_path, mirror, topics, config)?;
+        }
+        logging::event(
+            "extract_start",
+            json!({ "packages": stub_install.len() }),
+            "Stage 1: Extracting packages ...",
+        );
+        let filter = self.path_filter()?;
+        extract_packages(
+            &stub_install,
+            target_path,
+            archive_path,
+            &self.extract_options(&filter, true),
+        )
+        .context("when extracting the packages")?;
+        if !filter.is_empty() {
+            // the packages are unpacked again by dpkg during stage 2
+            let path = target_path.join(install::DPKG_EXCLUDES);
+            fs::make_dirs(path.parent().unwrap(), fs::DIR_MODE)?;
+            fs::write_file(&path, filter.dpkg_config(), fs::FILE_MODE)?;
+        }
+        install::write_dpkg_database(target_path, &stub_install, archive_path)
+            .context("when generating the dpkg database")?;
+        if self.unprivileged {
+            // stage 2 unpacks the other packages without privileges either
+            install::record_ownership(target_path, &resolved.packages(), archive_path)
+                .context("when recording the owners of the files")?;
+        }
+        // written before entering the guest, so that the maintainer scripts see the configured locale
+        fs::write_system_config(
+            target_path,
+            config.hostname.as_deref(),
+            config.locale.as_deref(),
+            &config.fstab,
+        )
+        .context("when writing the system configuration")?;
+        let lockfile_path = target_path.join(STAGE1_LOCKFILE);
+        fs::make_dirs(lockfile_path.parent().unwrap(), fs::DIR_MODE)?;
+        resolved.write(&lockfile_path)?;
+        // stage 1 is complete, it is not resumed anymore
+        std::fs::remove_file(target_path.join(install::EXTRACTED))
+            .context("when removing the record of the extracted packages")?;
+        let script = self.write_stage2_script(&install_order, target_path, config)?;
+        nix::unistd::sync();
+        if self.stage1_only {
+            self.do_verify_tree(target_path, archive_path, &stub_install)?;
+            let (_, path) = script.keep().context("when persisting the script file")?;
+            logging::event(
+                "stage_finish",
+                json!({ "stage": 1, "script": path }),
+                format!(
+                    "Stage 1 finished.\nIf you want to continue stage 2, you can run `{} {:?}` inside the container.",
+                    self.shell_command().join(" "),
+                    path.file_name().unwrap().underline()
+                ),
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(script))
+    }
+
+    /// Report the slowest package configurations of the stage 2 output captured by
+    /// `--capture-guest-log`
+    fn report_slowest_configures(&self) -> Result<()> {
+        let Some(ref path) = self.capture_guest_log else {
+            return Ok(());
+        };
+        let output = std::fs::read(path)
+            .with_context(|| format!("when reading the guest log {}", path.display()))?;
+        let slowest =
+            install::slowest_configures(&String::from_utf8_lossy(&output), SLOWEST_CONFIGURES);
+        if slowest.is_empty() {
+            return Ok(());
+        }
+        let lines = slowest
+            .iter()
+            .map(|(packages, duration)| format!("{:>9.1}s  {}", duration.as_secs_f64(), packages))
+            .collect::<Vec<_>>();
+        let fields = slowest
+            .iter()
+            .map(|(packages, duration)| {
+                json!({ "packages": packages, "duration_ms": duration.as_millis() as u64 })
+            })
+            .collect::<Vec<_>>();
+        logging::event(
+            "slowest_configures",
+            json!({ "log": path, "slowest": fields }),
+            format!(
+                "Slowest package configurations (the output of stage 2 is in {}):\n{}",
+                path.display().cyan(),
+                lines.join("\n")
+            ),
+        );
+
+        Ok(())
+    }
+
+    /// Write the stage 2 script, including the extra scripts
+    fn write_stage2_script(
+        &self,
+        install_order: &[Vec<PackageMeta>],
+        target_path: &Path,
+        config: &install::Config,
+    ) -> Result<tempfile::NamedTempFile> {
+        // the names end up in the stage 2 script
+        solv::check_package_paths(&install_order.concat())?;
+        let mut script = install::write_install_script(
+            install_order,
+            &config.defer_triggers,
+            &self.final_sources_list()?,
+            self.apt_update,
+            self.clean,
+            &self.guest_shell,
+            target_path,
+        )?;
+        include_extra_scripts(&self.scripts, &mut script)
+            .context("when including extra scripts")?;
+
+        Ok(script)
+    }
+
+    /// The guest shell and its options, followed by the script when running it
+    fn shell_command(&self) -> Vec<&str> {
+        let mut command = vec![self.guest_shell.as_str()];
+        match &self.guest_shell_flags {
+            Some(flags) => command.extend(flags.iter().map(|f| f.as_str())),
+            None => command.extend(guest::default_shell_flags(&self.guest_shell)),
+        }
+
+        command
+    }
+
+    /// The sources.list of the bootstrapped system
+    fn final_sources_list(&self) -> Result<String> {
+        let mirror = match self.final_mirror {
+            Some(ref mirror) => network::normalize_mirror(mirror),
+            None => {
+                let primary = self
+                    .mirrors
+                    .first()
+                    .map(|m| network::normalize_mirror(m))
+                    .unwrap_or_default();
+                // a local mirror is unlikely to be available in the bootstrapped system
+                if primary.is_empty() || primary.starts_with("file:") {
+                    DEFAULT_MIRROR.to_string()
+                } else {
+                    primary
+                }
+            }
+        };
+        let mut comps = vec!["main"];
+        comps.extend(self.comps.iter().map(|c| c.as_str()));
+
+        fs::sources_list(&mirror, &self.branches, &comps)
+            .context("when generating the final sources.list")
+    }
+
+    fn do_stage2(
+        &self,
+        target_path: &Path,
+        archive_path: &Path,
+        packages: &[PackageMeta],
+        script: tempfile::NamedTempFile,
+        emulator: Option<&guest::Emulator>,
+        config: &install::Config,
+    ) -> Result<()> {
+        cancel::check()?;
+        install::create_directories(target_path, &config.directories)?;
+        run_host_hooks(&self.host_hooks, target_path)?;
+        logging::event(
+            "stage_start",
+            json!({ "stage": 2 }),
+            "Stage 2: Installing packages ...",
+        );
+        // removes the script, the emulator and the mounts even if stage 2 fails
+        let mut guard = guest::GuestGuard::new();
+        let script = script
+            .into_temp_path()
+            .keep()
+            .context("when persisting the script file")?;
+        guard.remove_on_exit(script.clone());
+        let script_file = script.file_name().unwrap().to_string_lossy();
+        if let Some(emulator) = emulator {
+            emulator.install(target_path, &mut guard)?;
+        }
+        self.setup_guest_network(target_path, &mut guard)?;
+        let mut binds = Vec::new();
+        if archive_path != target_path.join(ARCHIVES) {
+            binds.push((archive_path, ARCHIVES));
+        }
+        let mut env = self.effective_guest_env(config);
+        if self.capture_guest_log.is_some() {
+            env.push((install::PROFILE_ENV.to_string(), "1".to_string()));
+        }
+        guest::run_in_guest(
+            &self.target,
+            &[self.shell_command(), vec![&script_file]].concat(),
+            self.effective_guest_backend(),
+            &binds,
+            &env,
+            self.capture_guest_log.as_deref(),
+            &mut guard,
+        )
+        .context("when running install scripts in the container")?;
+        self.report_slowest_configures()?;
+        if self.smoke_test {
+            // reuses the emulator and the mounts of stage 2
+            self.do_smoke_test(target_path, &config.smoke_test, &env, &mut guard)?;
+        }
+        guard.cleanup()?;
+        // before the packages are removed, and without the guest mounts
+        self.do_verify_tree(target_path, archive_path, packages)?;
+        if self.clean
+            && archive_path
+                .canonicalize()?
+                .starts_with(target_path.canonicalize()?)
+        {
+            // the clean up script keeps the whitelisted directories (e.g. /var/lib/dpkg)
+            remove_debs(archive_path).context("when removing the downloaded packages")?;
+        }
+        nix::unistd::sync();
+        logging::event(
+            "stage_finish",
+            json!({ "stage": 2 }),
+            "Stage 2 finished.\nBase system ready!".green().bold(),
+        );
+
+        Ok(())
+    }
+
+    /// Check the tree against the files of the `packages` (`--verify-tree`)
+    fn do_verify_tree(
+        &self,
+        target_path: &Path,
+        archive_path: &Path,
+        packages: &[PackageMeta],
+    ) -> Result<()> {
+        if !self.verify_tree {
+            return Ok(());
+        }
+        cancel::check()?;
+        logging::event(
+            "tree_verify_start",
+            json!({ "packages": packages.len() }),
+            "Verifying the files of the packages in the tree ...",
+        );
+        let report = verify::verify_tree(target_path, packages, archive_path, &self.path_filter()?)
+            .context("when verifying the tree")?;
+        if !report.extra.is_empty() {
+            let message = format!(
+                "{} file(s) are not shipped by any package (e.g. created by the maintainer scripts).",
+                report.extra.len().bold()
+            );
+            logging::event(
+                "tree_extra_files",
+                json!({ "files": report.extra }),
+                if self.verbose {
+                    format!("{}\n{}", message, report.extra.join("\n"))
+                } else {
+                    message
+                },
+            );
+        }
+        if report.mismatches.is_empty() {
+            logging::event(
+                "tree_verify_finish",
+                json!({ "files": report.checked }),
+                format!("Verified {} files.", report.checked).green().bold(),
+            );
+            return Ok(());
+        }
+        let mismatches = report
+            .mismatches
+            .iter()
+            .map(|m| format!("{} ({}): {}", m.path, m.package, m.problem))
+            .collect::<Vec<_>>();
+        if !self.verify_tree_warn {
+            bail!(
+                "Files of the packages not matching the tree:\n{}",
+                mismatches.join("\n")
+            );
+        }
+        logging::event(
+            "tree_mismatches",
+            json!({ "mismatches": report.mismatches }),
+            format!(
+                "Warning: files of the packages not matching the tree:\n{}",
+                mismatches.join("\n")
+            )
+            .yellow(),
+        );
+
+        Ok(())
+    }
+
+    /// Apply the changes since the last run to the installed target: remove the files
+    /// which are no longer shipped, extract the new packages in place and configure them
+    fn do_update(
+        &self,
+        diff: &lockfile::PackageDiff,
+        resolved: &Lockfile,
+        target_path: &Path,
+        archive_path: &Path,
+        emulator: Option<&guest::Emulator>,
+        config: &install::Config,
+    ) -> Result<()> {
+        cancel::check()?;
+        if diff.is_empty() {
+            logging::event(
+                "update_finish",
+                json!({ "packages": 0 }),
+                "The target is already up to date.".green().bold(),
+            );
+            return Ok(());
+        }
+        logging::event(
+            "update_start",
+            json!({}),
+            "Updating the installed system ...",
+        );
+        let changed = diff.changed();
+        let kept = resolved
+            .packages()
+            .into_iter()
+            .filter(|p| !changed.iter().any(|c| c.name == p.name))
+            .collect::<Vec<_>>();
+        let previous = diff
+            .removed
+            .iter()
+            .chain(&diff.replaced)
+            .cloned()
+            .collect::<Vec<_>>();
+        let stale = update::stale_files(target_path, &previous, &kept, &changed, archive_path)
+            .context("when collecting the files of the previous packages")?;
+        let removed = update::remove_files(target_path, &stale)?;
+        if !removed.skipped.is_empty() {
+            logging::event(
+                "update_skipped_files",
+                json!({ "files": removed.skipped }),
+                format!(
+                    "Warning: {} file(s) outside of the target were not removed",
+                    removed.skipped.len()
+                )
+                .yellow(),
+            );
+        }
+        extract_packages(
+            &changed,
+            target_path,
+            archive_path,
+            &self.extract_options(&self.path_filter()?, false),
+        )
+        .context("when extracting the packages")?;
+        install::update_dpkg_database(target_path, &diff.removed, &changed, archive_path)
+            .context("when updating the dpkg database")?;
+        if self.unprivileged {
+            install::record_ownership(target_path, &changed, archive_path)
+                .context("when recording the owners of the files")?;
+        }
+
+        // the maintainer scripts of the new packages run in the guest
+        install::create_directories(target_path, &config.directories)?;
+        let mut guard = guest::GuestGuard::new();
+        if let Some(emulator) = emulator {
+            emulator.install(target_path, &mut guard)?;
+        }
+        self.setup_guest_network(target_path, &mut guard)?;
+        guest::run_in_guest(
+            &self.target,
+            &[self.shell_command(), vec!["-c", CONFIGURE_PENDING]].concat(),
+            self.effective_guest_backend(),
+            &[],
+            &self.effective_guest_env(config),
+            self.capture_guest_log.as_deref(),
+            &mut guard,
+        )
+        .context("when configuring the updated packages in the container")?;
+        guard.cleanup()?;
+        resolved.write(&target_path.join(STAGE1_LOCKFILE))?;
+        nix::unistd::sync();
+        logging::event(
+            "update_finish",
+            json!({ "packages": changed.len(), "removed_files": removed.removed }),
+            format!(
+                "Update finished: {} package(s) installed, {} file(s) removed.",
+                changed.len(),
+                removed.removed
+            )
+            .green()
+            .bold(),
+        );
+
+        Ok(())
+    }
+
+    fn do_smoke_test(
+        &self,
+        target_path: &Path,
+        extra_commands: &[String],
+        env: &[(String, String)],
+        guard: &mut guest::GuestGuard,
+    ) -> Result<()> {
+        cancel::check()?;
+        let commands = install::SMOKE_TEST_COMMANDS
+            .iter()
+            .map(|c| c.to_string())
+            .chain(extra_commands.iter().cloned())
+            .collect::<Vec<_>>();
+        logging::event(
+            "smoke_test_start",
+            json!({ "commands": commands }),
+            "Checking the bootstrapped system ...",
+        );
+        let script = install::write_smoke_test_script(&commands, &self.guest_shell, target_path)?
+            .into_temp_path()
+            .keep()
+            .context("when persisting the smoke test script")?;
+        guard.remove_on_exit(script.clone());
+        let script_file = script.file_name().unwrap().to_string_lossy();
+        guest::run_in_guest(
+            &self.target,
+            &[self.shell_command(), vec![&script_file]].concat(),
+            self.effective_guest_backend(),
+            &[],
+            env,
+            None,
+            guard,
+        )
+        .context("Smoke test failed, the bootstrapped system may be broken")?;
+        logging::event(
+            "smoke_test_finish",
+            json!({ "commands": commands.len() }),
+            "Smoke test passed.".green(),
+        );
+
+        Ok(())
+    }
+
+    fn do_export(&self, target_path: &Path, threads: usize, arch: &str) -> Result<()> {
+        // the guest may have exited normally
+        cancel::check()?;
+        let owners = if self.unprivileged && self.export.has_output() {
+            Some(install::read_ownership(target_path)?)
+        } else {
+            None
+        };
+        let owners = owners.as_ref();
+        if let Some(ref xz) = self.export.tar_xz {
+            let path = Path::new(&xz);
+            logging::event(
+                "export_start",
+                json!({ "format": "tar-xz", "path": path }),
+                "Compressing the xz tarball, please wait patiently ...",
+            );
+            fs::archive_xz_tarball(
+                target_path,
+                path,
+                threads as u32,
+                self.export.source_date_epoch,
+                owners,
+            )?;
+            if !fs::is_stdout(path) {
+                network::sha256sum_file_tag(path)?;
+            }
+            logging::event(
+                "export_finish",
+                json!({ "format": "tar-xz", "path": path }),
+                format!("Tarball available at {}", export_location(path).cyan()),
+            );
+        }
+        if let Some(ref gz) = self.export.tar_gz {
+            let path = Path::new(&gz);
+            logging::event(
+                "export_start",
+                json!({ "format": "tar-gz", "path": path }),
+                "Compressing the gz tarball, please wait patiently ...",
+            );
+            fs::archive_gz_tarball(target_path, path, self.export.source_date_epoch, owners)?;
+            if !fs::is_stdout(path) {
+                network::sha256sum_file_tag(path)?;
+            }
+            logging::event(
+                "export_finish",
+                json!({ "format": "tar-gz", "path": path }),
+                format!("Tarball available at {}", export_location(path).cyan()),
+            );
+        }
+        if let Some(ref squashfs) = self.export.squashfs {
+            let path = Path::new(&squashfs);
+            logging::event(
+                "export_start",
+                json!({ "format": "squashfs", "path": path }),
+                "Compressing the squashfs, please wait patiently ...",
+            );
+            fs::archive_squashfs(
+                target_path,
+                path,
+                threads as u32,
+                self.export.squashfs_comp,
+                owners,
+            )?;
+            if !fs::is_stdout(path) {
+                network::sha256sum_file_tag(path)?;
+            }
+            logging::event(
+                "export_finish",
+                json!({ "format": "squashfs", "path": path }),
+                format!("SquashFS available at {}", export_location(path).cyan()),
+            );
+        }
+        if let Some(ref oci) = self.export.oci_output {
+            let path = Path::new(&oci);
+            logging::event(
+                "export_start",
+                json!({ "format": "oci", "path": path }),
+                "Creating the OCI image, please wait patiently ...",
+            );
+            install::write_oci_image(
+                target_path,
+                path,
+                arch,
+                self.export.compress,
+                self.export.compress_level,
+                self.export.source_date_epoch,
+                owners,
+            )?;
+            if !fs::is_stdout(path) {
+                network::sha256sum_file_tag(path)?;
+            }
+            logging::event(
+                "export_finish",
+                json!({ "format": "oci", "path": path }),
+                format!("OCI image available at {}", export_location(path).cyan()),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Where an archive is exported, for the messages
+fn export_location(path: &Path) -> String {
+    if fs::is_stdout(path) {
+        return "stdout".to_string();
+    }
+
+    path.display().to_string()
+}
+
+/// AOSC OS specific architecture mapping for ppc64
+#[cfg(target_arch = "powerpc64")]
+#[inline]
+fn get_arch_name() -> Option<&'static str> {
+    let mut endian: libc::c_int = -1;
+    let result;
+    unsafe {
+        result = libc::prctl(libc::PR_GET_ENDIAN, &mut endian as *mut libc::c_int);
+    }
+    if result < 0 {
+        return None;
+    }
+    match endian {
+        libc::PR_ENDIAN_LITTLE | libc::PR_ENDIAN_PPC_LITTLE => Some("ppc64el"),
+        libc::PR_ENDIAN_BIG => Some("ppc64"),
+        _ => None,
+    }
+}
+
+/// AOSC OS specific architecture mapping table
+#[cfg(not(target_arch = "powerpc64"))]
+#[inline]
+fn get_arch_name() -> Option<&'static str> {
+    use std::env::consts::ARCH;
+
+    match ARCH {
+        "x86_64" => Some("amd64"),
+        "x86" => Some("i486"),
+        "powerpc" => Some("powerpc"),
+        "aarch64" => Some("arm64"),
+        "mips64" => Some("loongson3"),
+        "riscv64" => Some("riscv64"),
+        "loongarch64" => Some("loongarch64"),
+        _ => None,
+    }
+}
+
+fn get_default_arch() -> Vec<String> {
+    let mut arches = vec!["all".to_string()];
+    if let Some(arch) = get_arch_name() {
+        arches.push(arch.to_string());
+    }
+
+    arches
+}
+
+/// What resolving the dependencies again needs (`--skip-missing`)
+struct Resolver {
+    pool: solv::Pool,
+    excludes: Vec<String>,
+    pins: Vec<solv::PinDecision>,
+}
+
+/// How [extract_packages] extracts the packages
+struct ExtractOptions<'a> {
+    quiet: bool,
+    /// Number of packages extracted at once
+    jobs: usize,
+    /// Reject the files shipped by more than one package, instead of reporting them
+    strict: bool,
+    filter: &'a install::PathFilter,
+    /// Record the extracted packages in [install::EXTRACTED], and skip the ones already
+    /// recorded by an interrupted run
+    resume: bool,
+    /// Memory limit of the decoders, see [install::Decoders]
+    memory_limit: Option<u64>,
+}
+
+/// Extract the packages into `target`. Files shipped by more than one package are
+/// reported (or rejected), unless one of the packages replaces the other.
+fn extract_packages(
+    packages: &[PackageMeta],
+    target: &Path,
+    archive_path: &Path,
+    options: &ExtractOptions,
+) -> Result<()> {
+    solv::check_package_paths(packages)?;
+    let filter = options.filter;
+    let workers = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()
+        .context("Failed to create the extraction worker pool")?;
+    let completed = if options.resume {
+        install::read_extracted(target)?
+    } else {
+        HashSet::new()
+    };
+    let resumed = packages
+        .iter()
+        .filter(|p| completed.contains(&install::extracted_entry(p)))
+        .count();
+    if resumed > 0 {
+        logging::event(
+            "extract_resume",
+            json!({ "packages": resumed }),
+            format!(
+                "Resuming the extraction: {} package(s) were already extracted.",
+                resumed.cyan().bold()
+            ),
+        );
+    }
+    let mut manifest = if options.resume {
+        let path = target.join(install::EXTRACTED);
+        fs::make_dirs(path.parent().unwrap(), fs::DIR_MODE)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("when opening {}", path.display()))?;
+        // terminate the line cut short by the interruption
+        if std::fs::read(&path)?.last().is_some_and(|c| *c != b'\n') {
+            writeln!(file)?;
+        }
+        Some(file)
+    } else {
+        None
+    };
+    let progress = Progress::new("Extracting", packages.len(), options.quiet);
+    // path -> index of the package which shipped it
+    let mut owners = HashMap::new();
+    let mut conflicts = Vec::new();
+    let mut pending_xattrs = Vec::new();
+    let (mut skipped_files, mut skipped_bytes) = (0, 0);
+    // the data archives are decompressed ahead by the workers, but the packages are
+    // extracted one after another in the install order: the files shipped by several
+    // packages (e.g. with Replaces, or the usrmerge symbolic links and directories) end up
+    // like dpkg leaves them, whatever the scheduling
+    let decode = |chunk: &[PackageMeta]| -> Vec<Result<Option<Vec<u8>>>> {
+        chunk
+            .par_iter()
+            .map_init(
+                || install::Decoders::new(options.memory_limit),
+                |decoders, package| {
+                    if package.install_size > MAX_DECODED_SIZE {
+                        return Ok(None);
+                    }
+                    let filename = package.file_name();
+                    let f = File::open(archive_path.join(&filename))
+                        .with_context(|| format!("when opening {}", filename))?;
+                    install::decode_deb(BufReader::new(f), decoders)
+                        .map(Some)
+                        .with_context(|| format!("when decompressing {}", filename))
+                },
+            )
+            .collect()
+    };
+    let mut decoders = install::Decoders::new(options.memory_limit);
+    let mut commit = |index: usize, decoded: Result<Option<Vec<u8>>>| -> Result<()> {
+        cancel::check()?;
+        let package = &packages[index];
+        let filename = package.file_name();
+        progress.start_item(&package.name);
+        let entry = install::extracted_entry(package);
+        // the files of the packages already extracted are still checked for overwrites
+        let already_extracted = completed.contains(&entry);
+        let extracted = match decoded? {
+            Some(data) => install::extract_decoded_deb(&data, target, filter, !already_extracted),
+            None => {
+                let f = BufReader::new(
+                    File::open(archive_path.join(&filename))
+                        .with_context(|| format!("when opening {}", filename))?,
+                );
+                if already_extracted {
+                    install::scan_extracted_deb(f, target, filter, &mut decoders)
+                } else {
+                    install::extract_deb(f, target, filter, &mut decoders)
+                }
+            }
+        }
+        .with_context(|| {
+            if already_extracted {
+                format!("when reading {}", filename)
+            } else {
+                format!("when extracting {}", filename)
+            }
+        })?;
+        if let (Some(manifest), false) = (&mut manifest, already_extracted) {
+            writeln!(manifest, "{}", entry).context("when recording the extracted package")?;
+        }
+        skipped_files += extracted.skipped_files;
+        skipped_bytes += extracted.skipped_bytes;
+        pending_xattrs.extend(extracted.pending_xattrs);
+        for file in extracted.files {
+            let Some(other) = owners.insert(file.clone(), index) else {
+                continue;
+            };
+            let other: &PackageMeta = &packages[other];
+            if other.name == package.name
+                || package.replaces.contains(&other.name)
+                || other.replaces.contains(&package.name)
+            {
+                continue;
+            }
+            conflicts.push(format!("{}: {} and {}", file, other.name, package.name));
+        }
+        logging::json_event(
+            "extract",
+            json!({ "package": package.name, "version": package.version }),
+        );
+
+        Ok(())
+    };
+    let result = workers.install(|| -> Result<()> {
+        // the next `jobs` packages are decompressed while the current ones are extracted
+        let mut chunks = packages.chunks(options.jobs.max(1));
+        let mut decoded = chunks.next().map(decode).unwrap_or_default();
+        let mut index = 0;
+        loop {
+            let chunk = chunks.next();
+            let (next, committed) = rayon::join(
+                || chunk.map(decode).unwrap_or_default(),
+                || -> Result<()> {
+                    for result in decoded {
+                        commit(index, result)?;
+                        index += 1;
+                    }
+                    Ok(())
+                },
+            );
+            committed?;
+            if chunk.is_none() {
+                return Ok(());
+            }
+            decoded = next;
+        }
+    });
+    progress.finish();
+    result?;
+
+    if !filter.is_empty() {
+        logging::event(
+            "paths_excluded",
+            json!({ "files": skipped_files, "bytes": skipped_bytes }),
+            format!(
+                "Skipped {} excluded files, saving {}.",
+                skipped_files.bold(),
+                ByteSize::b(skipped_bytes).cyan().bold()
+            ),
+        );
+    }
+
+    if !pending_xattrs.is_empty() {
+        pending_xattrs.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        install::write_pending_xattrs(target, &pending_xattrs)
+            .context("when recording the pending extended attributes")?;
+        let names = pending_xattrs
+            .iter()
+            .map(|x| format!("{}: {}", x.path, x.name))
+            .collect::<Vec<_>>();
+        logging::event(
+            "xattrs_pending",
+            json!({ "xattrs": names, "path": install::PENDING_XATTRS }),
+            format!(
+                "Warning: unable to restore the extended attributes (CAP_SETFCAP is required for the file capabilities):\n{}\n\
+                 They are recorded in /{}, apply them with `setfattr --restore={}` in the target as a privileged user.",
+                names.join("\n"),
+                install::PENDING_XATTRS,
+                install::PENDING_XATTRS
+            )
+            .yellow(),
+        );
+    }
+
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    conflicts.sort();
+    if options.strict {
+        bail!(
+            "Files shipped by more than one package:\n{}",
+            conflicts.join("\n")
+        );
+    }
+    logging::event(
+        "file_conflicts",
+        json!({ "conflicts": conflicts }),
+        format!(
+            "Warning: files shipped by more than one package (overwritten):\n{}",
+            conflicts.join("\n")
+        )
+        .yellow(),
+    );
+
+    Ok(())
+}
+
+/// Collect the packages from the lists, along with where they are listed (`path:line`).
+///
+/// The entries qualified with architectures none of which are in `arches` (or, for the
+/// negated qualifiers, one of which is) are left out.
+fn collect_packages_from_lists(paths: &[String], arches: &[&str]) -> Result<Vec<(String, String)>> {
+    let mut packages = Vec::with_capacity(1024);
+
+    for path in paths {
+        if path == STDIN_LIST {
+            // the lists included from stdin are relative to the current directory
+            let stdin = std::io::stdin().lock();
+            collect_packages_from_reader(
+                stdin,
+                Path::new("<stdin>"),
+                Path::new("."),
+                arches,
+                &mut packages,
+                &mut Vec::new(),
+            )?;
+            continue;
+        }
+        collect_packages_from_list(Path::new(path), arches, &mut packages, &mut Vec::new())?;
+    }
+
+    Ok(packages)
+}
+
+/// Read a package list, following the `@include` (or `%include`) directives
+/// relative to the directory of the including file
+fn collect_packages_from_list(
+    path: &Path,
+    arches: &[&str],
+    packages: &mut Vec<(String, String)>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let real_path = path
+        .canonicalize()
+        .context(format!("Failed to open file: {}", path.display()))?;
+    if stack.contains(&real_path) {
+        let chain = stack
+            .iter()
+            .chain(std::iter::once(&real_path))
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        return Err(anyhow!("Include loop detected: {}", chain.join(" -> ")));
+    }
+    let f = File::open(&real_path).context(format!("Failed to open file: {}", path.display()))?;
+    let parent = real_path.parent().ok_or_else(|| anyhow!("Invalid path"))?;
+    stack.push(real_path.clone());
+    collect_packages_from_reader(BufReader::new(f), path, parent, arches, packages, stack)?;
+    stack.pop();
+
+    Ok(())
+}
+
+/// Read the package list `path` from `reader`, the includes are relative to `dir`
+fn collect_packages_from_reader<R: BufRead>(
+    reader: R,
+    path: &Path,
+    dir: &Path,
+    arches: &[&str],
+    packages: &mut Vec<(String, String)>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for (i, line) in reader.lines().enumerate() {
+        let location = || format!("{}:{}", path.display(), i + 1);
+        let line = line.with_context(location)?;
+        // package names can not contain `#`, the rest of the line is a comment
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some(("@include" | "%include", inc)) => {
+                collect_packages_from_list(&dir.join(inc.trim()), arches, packages, stack)
+                    .with_context(|| format!("when including from {}", location()))?;
+            }
+            _ => {
+                let (package, applies) = split_arch_qualifier(line, arches)
+                    .with_context(|| format!("{}: invalid package `{}`", location(), line))?;
+                if applies {
+                    packages.push((package.to_owned(), location()));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Split the architecture qualifier off a package entry, e.g. `grub [amd64 arm64]` or
+/// `u-boot [!amd64]`, and tell whether the entry applies to a build for `arches`
+fn split_arch_qualifier<'a>(entry: &'a str, arches: &[&str]) -> Result<(&'a str, bool)> {
+    let entry = entry.trim();
+    let (package, applies) = match entry.strip_suffix(']').and_then(|e| e.rsplit_once('[')) {
+        Some((package, qualifier)) => {
+            let qualifier = qualifier.split_whitespace().collect::<Vec<_>>();
+            let negated = qualifier.iter().filter(|a| a.starts_with('!')).count();
+            if qualifier.is_empty() || (negated != 0 && negated != qualifier.len()) {
+                bail!("Invalid architecture qualifier: [{}]", qualifier.join(" "));
+            }
+            let listed = qualifier
+                .iter()
+                .any(|a| arches.contains(&a.trim_start_matches('!')));
+            (package.trim_end(), listed == (negated == 0))
+        }
+        None => (entry, true),
+    };
+    package.parse::<PackageRequest>()?;
+
+    Ok((package, applies))
+}
+
+/// Remove the duplicated requests (keeping the first one), the requests come with their sources.
+///
+/// Returns the unique requests and the warnings about the packages requested more than once.
+fn dedup_requests(requests: Vec<(PackageRequest, String)>) -> (Vec<PackageRequest>, Vec<String>) {
+    let mut unique: Vec<(PackageRequest, String)> = Vec::with_capacity(requests.len());
+    let mut warnings = Vec::new();
+    for (request, source) in requests {
+        let Some((first, first_source)) = unique.iter().find(|(r, _)| r.name == request.name)
+        else {
+            unique.push((request, source));
+            continue;
+        };
+        if first.constraint != request.constraint {
+            warnings.push(format!(
+                "{} is requested as `{}` in {} and as `{}` in {}, using the former",
+                request.name, first, first_source, request, source
+            ));
+        } else if *first_source != source {
+            warnings.push(format!(
+                "{} is listed in both {} and {}",
+                request.name, first_source, source
+            ));
+        }
+    }
+
+    (unique.into_iter().map(|(r, _)| r).collect(), warnings)
+}
+
+/// Warn about the included packages which are required anyway
+fn print_redundant_requests(redundant: &[RedundantRequest]) {
+    for request in redundant {
+        logging::event(
+            "redundant_include",
+            json!({ "package": request.name, "required_by": request.required_by }),
+            format!(
+                "Warning: {} is included but already required by {}",
+                request.name, request.required_by
+            )
+            .yellow(),
+        );
+    }
+}
+
+#[inline]
+/// Print the resolved packages (sorted, one per line) so that the output can be diffed
+fn print_package_list(packages: &[PackageMeta]) {
+    let mut packages = packages.iter().collect::<Vec<_>>();
+    packages.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    for package in packages {
+        println!("{} {} {}", package.name, package.version, package.arch);
+    }
+}
+
+/// Path of the cached resolution, None if the cache can not be used
+fn resolution_cache_path(
+    manifests: &[PathBuf],
+    requests: &[PackageRequest],
+    stub_packages: &[PackageRequest],
+    excludes: &[String],
+    pins: &[solv::Pin],
+    install_recommends: bool,
+) -> Option<PathBuf> {
+    let to_strings = |r: &[PackageRequest]| r.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+    let parameters = json!({
+        "requests": to_strings(requests),
+        "stub_packages": to_strings(stub_packages),
+        "excludes": excludes,
+        "pins": pins,
+        "install_recommends": install_recommends,
+    });
+    let dir = lockfile::cache_dir()?;
+    match lockfile::cache_path(&dir, manifests, &parameters) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            logging::event(
+                "cache_failed",
+                json!({ "error": format!("{:#}", e) }),
+                format!("Unable to use the resolution cache: {:#}", e).yellow(),
+            );
+            None
+        }
+    }
+}
+
+/// The package names listed by `--list-packages --search`
+enum PackageSearch {
+    All,
+    Glob(glob::Pattern),
+    Substring(String),
+}
+
+impl PackageSearch {
+    /// A glob pattern if `search` has wildcards, otherwise a substring
+    fn new(search: Option<&str>) -> Result<Self> {
+        let Some(search) = search else {
+            return Ok(PackageSearch::All);
+        };
+        if !search.contains(['*', '?', '[']) {
+            return Ok(PackageSearch::Substring(search.to_string()));
+        }
+        let pattern = glob::Pattern::new(search)
+            .with_context(|| format!("Invalid package pattern `{}`", search))?;
+
+        Ok(PackageSearch::Glob(pattern))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            PackageSearch::All => true,
+            PackageSearch::Glob(pattern) => pattern.matches(name),
+            PackageSearch::Substring(text) => name.contains(text.as_str()),
+        }
+    }
+}
+
+/// Print the candidates kept out by the pins
+fn print_pin_decisions(decisions: &[solv::PinDecision]) {
+    let describe = |candidates: &[(String, String)]| {
+        candidates
+            .iter()
+            .map(|(version, source)| format!("{} ({})", version, source))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    for decision in decisions {
+        let message = if decision.kept.is_empty() {
+            format!(
+                "Pin: {} is never installed (priority {})",
+                decision.package.bold(),
+                decision.priority
+            )
+        } else if decision.last_resort && decision.rejected.is_empty() {
+            format!(
+                "Pin: {} is only installed as a last resort (priority {})",
+                decision.package.bold(),
+                decision.priority
+            )
+        } else {
+            format!(
+                "Pin: {} {} (priority {}), instead of {}",
+                decision.package.bold(),
+                describe(&decision.kept).green(),
+                decision.priority,
+                describe(&decision.rejected)
+            )
+        };
+        logging::event(
+            "pin",
+            json!({
+                "package": decision.package,
+                "priority": decision.priority,
+                "kept": decision.kept,
+                "rejected": decision.rejected,
+                "last_resort": decision.last_resort,
+            }),
+            message,
+        );
+    }
+}
+
+/// Print a summary of what is going to be installed
+fn print_plan(resolved: &Lockfile, verbose: bool) {
+    let packages = resolved.packages();
+    if verbose {
+        let mut packages = packages.iter().collect::<Vec<_>>();
+        packages.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        for package in packages {
+            logging::event(
+                "plan_package",
+                json!({
+                    "name": package.name,
+                    "version": package.version,
+                    "arch": package.arch,
+                    "download_size": package.download_size,
+                    "install_size": package.install_size,
+                }),
+                format!(
+                    "  {} {} {} ({})",
+                    package.name.cyan(),
+                    package.version,
+                    package.arch,
+                    ByteSize::b(package.install_size)
+                ),
+            );
+        }
+    }
+    let stage1_packages = resolved.stub_packages().len();
+    logging::event(
+        "resolution",
+        json!({
+            "packages": packages.len(),
+            "stage1_packages": stage1_packages,
+            "download_size": resolved.download_size(),
+            "install_size": resolved.install_size() * 1024,
+            "stage1_install_size": resolved.stub_install_size() * 1024,
+        }),
+        format!(
+            "{} new packages will be installed ({} during stage 1)\n\
+             Total download size: {}\n\
+             Total installed size: {} ({} during stage 1)",
+            packages.len().bold(),
+            stage1_packages,
+            ByteSize::b(resolved.download_size()).cyan().bold(),
+            ByteSize::kb(resolved.install_size()).cyan().bold(),
+            ByteSize::kb(resolved.stub_install_size()),
+        ),
+    );
+}
+
+/// Ask the user whether to continue, assuming yes when not attached to a terminal
+fn confirm(prompt: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    eprint!("{} [Y/n] ", prompt);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "" | "y" | "Y" | "yes"))
+}
+
+fn include_extra_scripts<W: Write>(scripts: &[String], output: &mut W) -> Result<()> {
+    if !scripts.is_empty() {
+        logging::event(
+            "include_scripts",
+            json!({ "scripts": scripts }),
+            format!(
+                "Including {} extra scripts (run in the guest during stage 2) ...",
+                scripts.len().bold()
+            ),
+        );
+        output.write_all(b"\necho '[guest] Running additional scripts ...';")?;
+        for s in scripts {
+            let mut f = File::open(s).with_context(|| format!("when opening {}", s))?;
+            output.write_all(format!("\n# === {}\n", &s).as_bytes())?;
+            std::io::copy(&mut f, output)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure the host hooks can be executed before starting
+fn check_host_hooks(hooks: &[String]) -> Result<()> {
+    for hook in hooks {
+        let metadata = std::fs::metadata(hook)
+            .with_context(|| format!("Unable to read the host hook {}", hook))?;
+        if !metadata.is_file() || metadata.mode() & 0o111 == 0 {
+            bail!("The host hook {} is not an executable file", hook);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the host hooks in order, with the target as their only argument
+fn run_host_hooks(hooks: &[String], target_path: &Path) -> Result<()> {
+    let target = target_path.canonicalize()?;
+    for hook in hooks {
+        cancel::check()?;
+        logging::event(
+            "host_hook_start",
+            json!({ "hook": hook, "target": target }),
+            format!("[host] Running the host hook {} ...", hook.cyan()),
+        );
+        let start = Instant::now();
+        let status = std::process::Command::new(hook)
+            .arg(&target)
+            .stdin(std::process::Stdio::null())
+            .status()
+            .with_context(|| format!("when running the host hook {}", hook))?;
+        if !status.success() {
+            logging::event(
+                "host_hook_failed",
+                json!({ "hook": hook, "status": status.code() }),
+                format!("[host] The host hook {} failed: {}", hook, status).red(),
+            );
+            bail!("The host hook {} failed: {}", hook, status);
+        }
+        logging::event(
+            "host_hook_finish",
+            json!({ "hook": hook, "seconds": start.elapsed().as_secs_f64() }),
+            format!("[host] The host hook {} finished.", hook),
+        );
+    }
+
+    Ok(())
+}
+
+/// Make sure the input files are readable before starting, and that the scripts
+/// are free of syntax errors (when bash is available on the host)
+fn check_input_files(config: &str, include_files: &[String], scripts: &[String]) -> Result<()> {
+    if include_files.iter().filter(|p| *p == STDIN_LIST).count() > 1 {
+        bail!("Only one package list can be read from stdin (`-`)");
+    }
+    let include_files = include_files.iter().filter(|p| *p != STDIN_LIST);
+    for path in std::iter::once(config).chain(include_files.chain(scripts).map(|p| p.as_str())) {
+        if Path::new(path).is_dir() {
+            bail!("{} is a directory", path);
+        }
+        File::open(path).with_context(|| format!("Unable to read {}", path))?;
+    }
+    if scripts.is_empty() || which::which("bash").is_err() {
+        return Ok(());
+    }
+    for script in scripts {
+        let output = std::process::Command::new("bash")
+            .args(["-n", script])
+            .output()
+            .context("when running bash")?;
+        if !output.status.success() {
+            bail!(
+                "Syntax error in {}:\n{}",
+                script,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the estimated number of files to install against the free inodes, the filesystems
+/// allocating the inodes dynamically (e.g. btrfs) report no total
+fn check_inode_usage(required: u64, total: u64, available: u64) -> Result<()> {
+    if total == 0 || available >= required {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "Not enough free inodes on the filesystem of the target: about {} files are going to be installed, but only {} inodes are free (out of {}). Recreate the filesystem with more inodes (e.g. `mkfs.ext4 -i 8192`).\nUse --skip-space-check to continue anyway.",
+        required,
+        available,
+        total
+    ))
+}
+
+/// Check the estimated peak disk usage (the sum of the `components`) against the available space
+fn check_disk_usage(components: &[(&str, u64)], available: u64) -> Result<()> {
+    let required = components.iter().map(|(_, bytes)| bytes).sum::<u64>();
+    if available >= required {
+        return Ok(());
+    }
+    let breakdown = components
+        .iter()
+        .map(|(name, bytes)| format!("  {}: {}", name, ByteSize::b(*bytes)))
+        .collect::<Vec<_>>();
+
+    Err(anyhow!(
+        "It's not possible to continue, disk space not enough: {} required, but only {} is available. You need at least {} more.\n{}\nUse --skip-space-check if the storage needs less (e.g. deduplicating filesystems).",
+        ByteSize::b(required),
+        ByteSize::b(available),
+        ByteSize::b(required - available),
+        breakdown.join("\n")
+    ))
+}
+
+/// Pin each of the local packages to the local source
+fn local_pins(local_debs: &[install::LocalDeb]) -> Vec<solv::Pin> {
+    local_debs
+        .iter()
+        .map(|deb| solv::Pin {
+            package: glob::Pattern::escape(&deb.name),
+            source: LOCAL_SOURCE.to_string(),
+            priority: LOCAL_PIN_PRIORITY,
+        })
+        .collect()
+}
+
+/// Remove the downloaded packages (`*.deb`) from the archive
+fn remove_debs(archive_path: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(archive_path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "deb") && path.is_file() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("when removing {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the resolved local packages into the archive, where they are picked up
+/// like the downloaded ones
+fn copy_local_debs(
+    local_debs: &[install::LocalDeb],
+    packages: &[PackageMeta],
+    archive_path: &Path,
+) -> Result<()> {
+    for deb in local_debs {
+        let Some(package) = packages
+            .iter()
+            .find(|p| p.name == deb.name && p.version == deb.version && p.arch == deb.arch)
+        else {
+            continue;
+        };
+        std::fs::copy(&deb.path, archive_path.join(package.file_name()))
+            .with_context(|| format!("when copying {}", deb.path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The packages recorded by the previous run in an installed target (for --update)
+fn read_installed_lockfile(target_path: &Path) -> Result<Lockfile> {
+    let path = target_path.join(STAGE1_LOCKFILE);
+    if !path.is_file() {
+        bail!(
+            "{} has not been bootstrapped by aoscbootstrap (/{} is missing), --update needs an installed target",
+            target_path.display(),
+            STAGE1_LOCKFILE
+        );
+    }
+
+    Lockfile::read(&path)
+}
+
+fn print_update_plan(diff: &lockfile::PackageDiff) {
+    for package in &diff.added {
+        logging::event(
+            "update_package",
+            json!({ "name": package.name, "version": package.version, "change": "added" }),
+            format!(
+                "  {} {} {}",
+                "+".green(),
+                package.name.cyan(),
+                package.version
+            ),
+        );
+    }
+    for (old, new) in diff.replaced.iter().zip(&diff.upgraded) {
+        logging::event(
+            "update_package",
+            json!({ "name": new.name, "version": new.version, "previous_version": old.version, "change": "upgraded" }),
+            format!(
+                "  {} {} {} -> {}",
+                "~".yellow(),
+                new.name.cyan(),
+                old.version,
+                new.version
+            ),
+        );
+    }
+    for package in &diff.removed {
+        logging::event(
+            "update_package",
+            json!({ "name": package.name, "version": package.version, "change": "removed" }),
+            format!(
+                "  {} {} {}",
+                "-".red(),
+                package.name.cyan(),
+                package.version
+            ),
+        );
+    }
+    logging::event(
+        "update_plan",
+        json!({
+            "added": diff.added.len(),
+            "upgraded": diff.upgraded.len(),
+            "removed": diff.removed.len(),
+        }),
+        format!(
+            "Update: {} added, {} upgraded, {} removed",
+            diff.added.len().bold(),
+            diff.upgraded.len().bold(),
+            diff.removed.len().bold()
+        ),
+    );
+}
+
+/// Directories which an interrupted run leaves in the target, resumed by the next run
+const RESUMABLE_DIRS: &[&str] = &["var/cache/apt/archives", "var/lib/apt/lists"];
+
+/// Whether the stage 1 extracting the packages into the target was interrupted
+fn is_stage1_interrupted(target_path: &Path) -> bool {
+    target_path.join(install::EXTRACTED).is_file() && !target_path.join(STAGE1_LOCKFILE).exists()
+}
+
+/// Refuse a target which is not an empty directory, unless it only contains what
+/// an interrupted run has already downloaded or extracted
+fn check_target(target_path: &Path) -> Result<()> {
+    if !target_path.exists() {
+        return Ok(());
+    }
+    if !target_path.is_dir() {
+        bail!("Target {} is not a directory.", target_path.display());
+    }
+    if is_stage1_interrupted(target_path) {
+        logging::event(
+            "stage1_resume",
+            json!({ "target": target_path }),
+            "Resuming the interrupted stage 1, the packages which were not completely extracted are extracted again.",
+        );
+        return Ok(());
+    }
+    let mut unexpected = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(target_path.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let name = path.to_string_lossy();
+            if RESUMABLE_DIRS.contains(&name.as_ref()) && entry.file_type()?.is_dir() {
+                continue;
+            }
+            let is_parent = RESUMABLE_DIRS
+                .iter()
+                .any(|d| d.starts_with(&format!("{}/", name)));
+            if is_parent && entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else {
+                unexpected.push(format!("/{}", name));
+            }
+        }
+    }
+    if unexpected.is_empty() {
+        return Ok(());
+    }
+    unexpected.sort();
+    let remaining = unexpected.len().saturating_sub(10);
+    unexpected.truncate(10);
+    if remaining > 0 {
+        unexpected.push(format!("... and {} more", remaining));
+    }
+    bail!(
+        "Target {} is not empty, found:\n{}\nPlease remove it first, or use --force to bootstrap over it.",
+        target_path.display(),
+        unexpected.join("\n")
+    );
+}
+
+/// Check that stage 1 has been run in the target, returns the packages it resolved
+fn check_stage1_tree(target_path: &Path, archive_path: &Path) -> Result<Lockfile> {
+    let lockfile_path = target_path.join(STAGE1_LOCKFILE);
+    if is_stage1_interrupted(target_path) {
+        bail!(
+            "The stage 1 in {} was interrupted, please run it again (without --stage2-only) to resume it",
+            target_path.display()
+        );
+    }
+    if !lockfile_path.is_file() {
+        bail!(
+            "{} does not contain a stage 1 tree (/{} is missing), please run stage 1 first (e.g. with --stage1-only)",
+            target_path.display(),
+            STAGE1_LOCKFILE
+        );
+    }
+    for path in ["usr/bin/bash", "var/lib/dpkg/status"] {
+        if !target_path.join(path).exists() {
+            bail!("The stage 1 tree is incomplete: /{} is missing", path);
+        }
+    }
+    let resolved = Lockfile::read(&lockfile_path)?;
+    let missing = resolved
+        .packages()
+        .iter()
+        .map(|p| p.file_name())
+        .filter(|name| !archive_path.join(name).is_file())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "{} package(s) are missing from {}:\n{}",
+            missing.len(),
+            archive_path.display(),
+            missing.join("\n")
+        );
+    }
+
+    Ok(resolved)
+}
+
+#[test]
+fn test_collect_packages_from_lists() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::create_dir_all(dir.path().join("common"))?;
+    std::fs::write(
+        dir.path().join("base.lst"),
+        "# base system\n@include common/core.lst\nbash  # the shell\n\nsystemd >= 250\n",
+    )?;
+    std::fs::write(
+        dir.path().join("common/core.lst"),
+        "glibc\n%include ../extra.lst\n",
+    )?;
+    std::fs::write(dir.path().join("extra.lst"), "coreutils\n")?;
+    let packages = collect_packages_from_lists(
+        &[dir.path().join("base.lst").display().to_string()],
+        &["amd64", "all"],
+    )?;
+    let names = packages.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["glibc", "coreutils", "bash", "systemd >= 250"]);
+    assert!(packages[2].1.ends_with("base.lst:3"));
+
+    std::fs::write(
+        dir.path().join("boot.lst"),
+        "grub [amd64 arm64]\nu-boot [arm64 riscv64]\nlinux-kernel [!loongarch64]\n",
+    )?;
+    let lists = [dir.path().join("boot.lst").display().to_string()];
+    let packages = collect_packages_from_lists(&lists, &["amd64", "all"])?;
+    let names = packages.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["grub", "linux-kernel"]);
+    assert!(packages[1].1.ends_with("boot.lst:3"));
+    let packages = collect_packages_from_lists(&lists, &["riscv64", "all"])?;
+    let names = packages.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["u-boot", "linux-kernel"]);
+    assert!(split_arch_qualifier("grub [amd64 !arm64]", &["amd64"]).is_err());
+    assert!(split_arch_qualifier("grub []", &["amd64"]).is_err());
+
+    let mut packages = Vec::new();
+    let stdin = std::io::Cursor::new("# from stdin\nvim\n\n@include extra.lst\n");
+    collect_packages_from_reader(
+        stdin,
+        Path::new("<stdin>"),
+        dir.path(),
+        &["amd64"],
+        &mut packages,
+        &mut Vec::new(),
+    )?;
+    let names = packages.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["vim", "coreutils"]);
+    assert_eq!(packages[0].1, "<stdin>:2");
+
+    std::fs::write(
+        dir.path().join("extra.lst"),
+        "coreutils\n@include base.lst\n",
+    )?;
+    let err = collect_packages_from_lists(
+        &[dir.path().join("base.lst").display().to_string()],
+        &["amd64"],
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("Include loop detected"));
+
+    std::fs::write(dir.path().join("extra.lst"), "coreutils\nfoo bar\n")?;
+    let err = collect_packages_from_lists(
+        &[dir.path().join("extra.lst").display().to_string()],
+        &["amd64"],
+    )
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("extra.lst:2: invalid package `foo bar`"));
+
+    Ok(())
+}
+
+#[test]
+fn test_dedup_requests() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("a.lst"), "bash\nglibc\nsystemd\n")?;
+    std::fs::write(dir.path().join("b.lst"), "systemd\ncoreutils\nbash\n")?;
+    let lists = ["a.lst", "b.lst"].map(|l| dir.path().join(l).display().to_string());
+    let mut requests = vec![("glibc".parse()?, "config".to_string())];
+    for (package, source) in collect_packages_from_lists(&lists, &["amd64"])? {
+        requests.push((package.parse()?, source));
+    }
+    let (requests, warnings) = dedup_requests(requests);
+    let names = requests.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["glibc", "bash", "systemd", "coreutils"]);
+    assert_eq!(warnings.len(), 3);
+    assert!(warnings[0].starts_with("glibc is listed in both config and "));
+
+    Ok(())
+}
+
+#[test]
+fn test_extract_packages() -> Result<()> {
+    let archives = tempfile::tempdir()?;
+    let package = |name: &str, files: &[(&str, &[u8])]| -> Result<PackageMeta> {
+        let package = PackageMeta {
+            sha256: String::new(),
+            path: format!("pool/main/{}.deb", name),
+            ..PackageMeta::for_test(name)
+        };
+        let deb = install::build_test_deb_with(".gz", files)?;
+        std::fs::write(archives.path().join(package.file_name()), deb)?;
+
+        Ok(package)
+    };
+    let filter = install::PathFilter::default();
+    let options = ExtractOptions {
+        quiet: true,
+        jobs: 1,
+        strict: false,
+        filter: &filter,
+        resume: false,
+        memory_limit: None,
+    };
+    let mut packages = Vec::new();
+    for i in 0..16 {
+        let name = format!("test{}", i);
+        let doc = format!("./usr/share/doc/{}/README", name);
+        // all the packages share (and create) the same parent directories
+        packages.push(package(&name, &[(&doc, name.as_bytes())])?);
+    }
+    let target = tempfile::tempdir()?;
+    extract_packages(
+        &packages,
+        target.path(),
+        archives.path(),
+        &ExtractOptions {
+            jobs: 4,
+            strict: true,
+            ..options
+        },
+    )?;
+    for package in &packages {
+        let doc = target
+            .path()
+            .join("usr/share/doc")
+            .join(&package.name)
+            .join("README");
+        assert_eq!(std::fs::read_to_string(doc)?, package.name);
+    }
+
+    let a = package("a", &[("./etc/a.conf", b"a")])?;
+    let mut b = package("b", &[("./etc/a.conf", b"b")])?;
+    let conflicting = [a.clone(), b.clone()];
+    let target = tempfile::tempdir()?;
+    extract_packages(
+        &conflicting,
+        target.path(),
+        archives.path(),
+        &ExtractOptions {
+            jobs: 1,
+            strict: false,
+            ..options
+        },
+    )?;
+    let err = extract_packages(
+        &conflicting,
+        target.path(),
+        archives.path(),
+        &ExtractOptions {
+            jobs: 1,
+            strict: true,
+            ..options
+        },
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("/etc/a.conf: a and b"), "{}", err);
+    b.replaces.push("a".to_string());
+    extract_packages(
+        &[a, b.clone()],
+        target.path(),
+        archives.path(),
+        &ExtractOptions {
+            jobs: 1,
+            strict: true,
+            ..options
+        },
+    )?;
+
+    // the last package in the install order wins, whatever the number of workers
+    let mut replacing = Vec::new();
+    for i in 0..8 {
+        let name = format!("replacing{}", i);
+        let mut package = package(&name, &[("./etc/a.conf", name.as_bytes())])?;
+        package.replaces.push("b".to_string());
+        replacing.push(package);
+    }
+    for _ in 0..4 {
+        let target = tempfile::tempdir()?;
+        extract_packages(
+            &[&[b.clone()], &replacing[..]].concat(),
+            target.path(),
+            archives.path(),
+            &ExtractOptions { jobs: 4, ..options },
+        )?;
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("etc/a.conf"))?,
+            "replacing7"
+        );
+    }
+
+    // resume the extraction interrupted after test0 (and in the middle of the line of test1)
+    let target = tempfile::tempdir()?;
+    let manifest = target.path().join(install::EXTRACTED);
+    std::fs::create_dir_all(manifest.parent().unwrap())?;
+    std::fs::write(
+        &manifest,
+        format!("{}\ntest1", install::extracted_entry(&packages[0])),
+    )?;
+    let resume = ExtractOptions {
+        resume: true,
+        ..options
+    };
+    extract_packages(&packages[..2], target.path(), archives.path(), &resume)?;
+    let doc = |name: &str| {
+        target
+            .path()
+            .join("usr/share/doc")
+            .join(name)
+            .join("README")
+    };
+    assert!(!doc("test0").exists());
+    assert_eq!(std::fs::read_to_string(doc("test1"))?, "test1");
+    let extracted = install::read_extracted(target.path())?;
+    assert!(packages[..2]
+        .iter()
+        .all(|p| extracted.contains(&install::extracted_entry(p))));
+
+    Ok(())
+}
+
+#[test]
+fn test_check_stage1_tree() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    let archive_path = target.path().join("var/cache/apt/archives");
+    let err = check_stage1_tree(target.path(), &archive_path)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("does not contain a stage 1 tree"));
+
+    let package = PackageMeta {
+        version: "5.2".to_string(),
+        sha256: String::new(),
+        path: String::new(),
+        arch: "amd64".to_string(),
+        ..PackageMeta::for_test("bash")
+    };
+    let lockfile_path = target.path().join(STAGE1_LOCKFILE);
+    std::fs::create_dir_all(lockfile_path.parent().unwrap())?;
+    Lockfile::new(&[vec![package.clone()]], std::slice::from_ref(&package))
+        .write(&lockfile_path)?;
+    let err = check_stage1_tree(target.path(), &archive_path)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("/usr/bin/bash is missing"));
+
+    for path in ["usr/bin/bash", "var/lib/dpkg/status"] {
+        let path = target.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, "")?;
+    }
+    let err = check_stage1_tree(target.path(), &archive_path)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains(&package.file_name()));
+
+    std::fs::create_dir_all(&archive_path)?;
+    std::fs::write(archive_path.join(package.file_name()), "")?;
+    assert_eq!(
+        check_stage1_tree(target.path(), &archive_path)?
+            .packages()
+            .len(),
+        1
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_target() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("target");
+    check_target(&target)?;
+    std::fs::create_dir(&target)?;
+    check_target(&target)?;
+    // left by an interrupted run
+    std::fs::create_dir_all(target.join("var/cache/apt/archives"))?;
+    std::fs::write(
+        target.join("var/cache/apt/archives/bash_5.2_amd64.deb.part"),
+        "",
+    )?;
+    std::fs::create_dir_all(target.join("var/lib/apt/lists"))?;
+    check_target(&target)?;
+
+    std::fs::write(target.join("var/lib/notes.txt"), "")?;
+    std::fs::create_dir(target.join("Documents"))?;
+    let err = check_target(&target).err().unwrap().to_string();
+    assert!(err.contains("/Documents\n/var/lib/notes.txt\n"));
+    assert!(check_target(&target.join("var/lib/notes.txt")).is_err());
+    // the stage 1 extracting the packages was interrupted
+    std::fs::create_dir_all(target.join("var/lib/aoscbootstrap"))?;
+    std::fs::write(target.join(install::EXTRACTED), "")?;
+    check_target(&target)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_local_debs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let control = b"Package: hello\nVersion: 1.0-1\nArchitecture: amd64\nInstalled-Size: 4\n";
+    let deb = dir.path().join("hello_1.0-1_amd64.deb");
+    std::fs::write(
+        &deb,
+        install::build_test_deb_with_control(".gz", control, Vec::new())?,
+    )?;
+    let local_deb = install::read_local_deb(&deb)?;
+    assert_eq!(
+        (local_deb.name.as_str(), local_deb.version.as_str()),
+        ("hello", "1.0-1")
+    );
+    assert!(local_deb
+        .stanza
+        .contains("Filename: local/hello_1.0-1_amd64.deb\n"));
+
+    // the repository has a newer version
+    let repo = solv::test_manifest(&[(
+        "hello",
+        "Version: 2.0\nArchitecture: amd64\nFilename: pool/stable/main/h/hello_2.0_amd64.deb\n",
+    )])?;
+    let local = dir.path().join("local_Packages");
+    std::fs::write(&local, &local_deb.stanza)?;
+    let mut pool = solv::Pool::new();
+    solv::populate_pool(
+        &mut pool,
+        &[
+            ("stable".to_string(), vec![repo.path().to_path_buf()]),
+            (LOCAL_SOURCE.to_string(), vec![local]),
+        ],
+    )?;
+    let local_debs = [local_deb];
+    let pins = solv::pin_decisions(&pool, &local_pins(&local_debs))?;
+    let packages = pool
+        .resolve(&["hello".parse()?], &[], &pins, false)?
+        .create_metadata()?;
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].version, "1.0-1");
+
+    let archive = dir.path().join("archives");
+    std::fs::create_dir(&archive)?;
+    copy_local_debs(&local_debs, &packages, &archive)?;
+    assert!(archive.join(packages[0].file_name()).is_file());
+
+    Ok(())
+}
+
+#[test]
+fn test_check_input_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    std::fs::write(path("config.toml"), "")?;
+    std::fs::write(path("good.sh"), "echo hello\n")?;
+    std::fs::write(path("bad.sh"), "if true; then\n")?;
+
+    check_input_files(&path("config.toml"), &[], &[path("good.sh")])?;
+    let err = check_input_files(&path("config.toml"), &[path("missing.lst")], &[]).unwrap_err();
+    assert!(format!("{:#}", err).contains("missing.lst"));
+    assert!(check_input_files(&dir.path().display().to_string(), &[], &[]).is_err());
+    let stdin = STDIN_LIST.to_string();
+    check_input_files(&path("config.toml"), std::slice::from_ref(&stdin), &[])?;
+    assert!(check_input_files(&path("config.toml"), &[stdin.clone(), stdin], &[]).is_err());
+    if which::which("bash").is_ok() {
+        let err = check_input_files(&path("config.toml"), &[], &[path("bad.sh")]).unwrap_err();
+        assert!(err.to_string().contains("Syntax error in"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_host_hooks() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir()?;
+    let target = tempfile::tempdir()?;
+    let hook = |name: &str, content: &str, mode: u32| -> Result<String> {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        Ok(path.display().to_string())
+    };
+    let resolv = hook(
+        "resolv.sh",
+        "#!/bin/sh\necho 'nameserver 1.1.1.1' > \"$1/resolv.conf\"\n",
+        0o755,
+    )?;
+    let failing = hook("failing.sh", "#!/bin/sh\nexit 3\n", 0o755)?;
+    let not_executable = hook("plain.sh", "#!/bin/sh\n", 0o644)?;
+
+    check_host_hooks(&[resolv.clone(), failing.clone()])?;
+    assert!(check_host_hooks(&[not_executable]).is_err());
+    run_host_hooks(std::slice::from_ref(&resolv), target.path())?;
+    assert_eq!(
+        std::fs::read_to_string(target.path().join("resolv.conf"))?,
+        "nameserver 1.1.1.1\n"
+    );
+    let err = run_host_hooks(&[failing, resolv], target.path()).unwrap_err();
+    assert!(err.to_string().contains("exit status: 3"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_check_inode_usage() -> Result<()> {
+    check_inode_usage(60_000, 500_000, 400_000)?;
+    // btrfs
+    check_inode_usage(60_000, 0, 0)?;
+    let err = check_inode_usage(60_000, 65_536, 50_000).unwrap_err();
+    assert!(
+        err.to_string().contains(
+            "about 60000 files are going to be installed, but only 50000 inodes are free"
+        ),
+        "{}",
+        err
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_disk_usage() -> Result<()> {
+    let components = [
+        ("Downloaded packages", 300_000_000),
+        ("Installed system", 1_200_000_000),
+        ("Headroom", 500_000_000),
+    ];
+    check_disk_usage(&components, 2_000_000_000)?;
+    let err = check_disk_usage(&components, 1_500_000_000)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains(
+            "2.0 GB required, but only 1.5 GB is available. You need at least 500.0 MB more."
+        ),
+        "{}",
+        err
+    );
+    assert!(
+        err.contains(
+            "\n  Downloaded packages: 300.0 MB\n  Installed system: 1.2 GB\n  Headroom: 500.0 MB\n"
+        ),
+        "{}",
+        err
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_package_search() -> Result<()> {
+    let names = ["bash", "bash-completion", "zsh"];
+    let found = |search: Option<&str>| -> Result<Vec<&str>> {
+        let search = PackageSearch::new(search)?;
+        Ok(names.into_iter().filter(|n| search.matches(n)).collect())
+    };
+    assert_eq!(found(None)?, names);
+    assert_eq!(found(Some("sh"))?, names);
+    assert_eq!(found(Some("bash*"))?, ["bash", "bash-completion"]);
+    assert_eq!(found(Some("?sh"))?, ["zsh"]);
+    assert!(found(Some("[")).is_err());
+
+    Ok(())
+}
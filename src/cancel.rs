@@ -0,0 +1,81 @@
+//! Cancellation of the bootstrap on SIGINT or SIGTERM.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+/// The exit status of a cancelled bootstrap (128 + SIGINT)
+pub const EXIT_CODE: i32 = 130;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// The bootstrap has been cancelled by the user
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled by user")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+extern "C" fn handle_signal(_: c_int) {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        // the second signal exits immediately, without cleaning up
+        unsafe { libc::_exit(EXIT_CODE) };
+    }
+}
+
+/// Cancel the bootstrap on the first SIGINT or SIGTERM, and exit immediately on the second one.
+///
+/// The downloads in progress are abandoned (their `.part` files are resumed by the next
+/// run), and no new package is downloaded or extracted afterwards.
+pub fn install_handler() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        unsafe { sigaction(signal, &action) }?;
+    }
+
+    Ok(())
+}
+
+/// Cancel the bootstrap, as if SIGINT has been received
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fail with [Cancelled] if the bootstrap has been cancelled
+pub fn check() -> Result<()> {
+    check_flag(&CANCELLED)
+}
+
+fn check_flag(flag: &AtomicBool) -> Result<()> {
+    if flag.load(Ordering::SeqCst) {
+        return Err(Cancelled.into());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check() {
+    // a flag of its own, the global one would cancel the other tests
+    let flag = AtomicBool::new(false);
+    assert!(check_flag(&flag).is_ok());
+    flag.store(true, Ordering::SeqCst);
+    let err = check_flag(&flag).unwrap_err();
+    assert!(err.downcast_ref::<Cancelled>().is_some());
+    assert_eq!(err.to_string(), "Cancelled by user");
+}
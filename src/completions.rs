@@ -0,0 +1,229 @@
+//! Shell completion scripts, generated from the definition of the command line options.
+
+use std::io::Write;
+
+use anyhow::Result;
+use clap::{builder::StyledStr, Arg, Command, ValueEnum};
+
+/// The shells which completion scripts can be generated for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The options of `cmd` which can be completed (the hidden ones are left out)
+fn options(cmd: &Command) -> Vec<&Arg> {
+    cmd.get_arguments()
+        .filter(|a| !a.is_positional() && !a.is_hide_set())
+        .collect()
+}
+
+/// The values accepted by the option, if they are known (e.g. those of a `value_enum`)
+fn possible_values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values()
+        .iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| v.get_name().to_string())
+        .collect()
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+/// The first line of the help of the option, quoted for the shell with `quote`
+fn summary(arg: &Arg, quote: fn(&str) -> String) -> String {
+    let help = arg.get_help().map(StyledStr::to_string).unwrap_or_default();
+    quote(help.lines().next().unwrap_or_default())
+}
+
+/// Write the completion script of `cmd` for `shell`
+pub fn generate<W: Write>(shell: Shell, cmd: &mut Command, out: &mut W) -> Result<()> {
+    // adds --help and --version
+    cmd.build();
+    let script = match shell {
+        Shell::Bash => bash(cmd),
+        Shell::Zsh => zsh(cmd),
+        Shell::Fish => fish(cmd),
+    };
+    out.write_all(script.as_bytes())?;
+
+    Ok(())
+}
+
+fn bash(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let function = format!("_{}", name.replace('-', "_"));
+    let mut words = Vec::new();
+    let mut cases = String::new();
+    for arg in options(cmd) {
+        let mut flags = Vec::new();
+        flags.extend(arg.get_long().map(|l| format!("--{}", l)));
+        flags.extend(arg.get_short().map(|s| format!("-{}", s)));
+        if flags.is_empty() {
+            continue;
+        }
+        words.extend(flags.iter().cloned());
+        if !takes_value(arg) {
+            continue;
+        }
+        let values = possible_values(arg);
+        let reply = if values.is_empty() {
+            "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string()
+        } else {
+            format!(
+                "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                values.join(" ")
+            )
+        };
+        cases.push_str(&format!(
+            "        {})\n            {}\n            return\n            ;;\n",
+            flags.join("|"),
+            reply
+        ));
+    }
+
+    format!(
+        r#"{function}() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "$prev" in
+{cases}    esac
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{words}" -- "$cur"))
+    else
+        COMPREPLY=($(compgen -f -- "$cur"))
+    fi
+}}
+complete -o filenames -F {function} {name}
+"#,
+        words = words.join(" "),
+    )
+}
+
+fn zsh_quote(s: &str) -> String {
+    s.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut specs = Vec::new();
+    for arg in options(cmd) {
+        let help = summary(arg, zsh_quote);
+        let action = if !takes_value(arg) {
+            String::new()
+        } else {
+            let values = possible_values(arg);
+            if values.is_empty() {
+                ":value:_files".to_string()
+            } else {
+                format!(":value:({})", values.join(" "))
+            }
+        };
+        // the repeatable options (e.g. --include) can be given several times
+        let repeat = if matches!(
+            arg.get_action(),
+            clap::ArgAction::Append | clap::ArgAction::Count
+        ) {
+            "*"
+        } else {
+            ""
+        };
+        if let Some(long) = arg.get_long() {
+            specs.push(format!("'{}--{}[{}]{}'", repeat, long, help, action));
+        }
+        if let Some(short) = arg.get_short() {
+            specs.push(format!("'{}-{}[{}]{}'", repeat, short, help, action));
+        }
+    }
+    specs.push("'*:: :_files'".to_string());
+
+    format!(
+        "#compdef {name}\n\n_arguments -s \\\n    {}\n",
+        specs.join(" \\\n    ")
+    )
+}
+
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut script = String::new();
+    for arg in options(cmd) {
+        let mut line = format!("complete -c {}", name);
+        if let Some(long) = arg.get_long() {
+            line.push_str(&format!(" -l {}", long));
+        }
+        if let Some(short) = arg.get_short() {
+            line.push_str(&format!(" -s {}", short));
+        }
+        if takes_value(arg) {
+            line.push_str(" -r");
+            let values = possible_values(arg);
+            if values.is_empty() {
+                line.push_str(" -F");
+            } else {
+                line.push_str(&format!(" -f -a {}", fish_quote(&values.join(" "))));
+            }
+        }
+        line.push_str(&format!(" -d {}", summary(arg, fish_quote)));
+        script.push_str(&line);
+        script.push('\n');
+    }
+
+    script
+}
+
+#[test]
+fn test_generate_completions() -> Result<()> {
+    let mut cmd = Command::new("tool")
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .help("Number of jobs"),
+        )
+        .arg(
+            Arg::new("shell")
+                .long("shell")
+                .value_parser(["bash", "zsh"])
+                .help("The shell: bash or zsh"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .action(clap::ArgAction::SetTrue)
+                .help("Don't print"),
+        )
+        .arg(Arg::new("secret").long("secret").hide(true))
+        .arg(Arg::new("target"));
+    let generated = |shell: Shell, cmd: &mut Command| -> Result<String> {
+        let mut out = Vec::new();
+        generate(shell, cmd, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    };
+
+    let bash = generated(Shell::Bash, &mut cmd)?;
+    assert!(bash.contains("--jobs -j --shell --quiet --help"));
+    assert!(bash.contains("compgen -W \"bash zsh\""));
+    assert!(!bash.contains("secret"));
+    assert!(bash.ends_with("complete -o filenames -F _tool tool\n"));
+
+    let zsh = generated(Shell::Zsh, &mut cmd)?;
+    assert!(zsh.starts_with("#compdef tool\n"));
+    assert!(zsh.contains("'--shell[The shell\\: bash or zsh]:value:(bash zsh)'"));
+    assert!(zsh.contains("'--quiet[Don'\\''t print]'"));
+
+    let fish = generated(Shell::Fish, &mut cmd)?;
+    assert!(fish.contains("complete -c tool -l jobs -s j -r -F -d 'Number of jobs'\n"));
+    assert!(fish.contains("complete -c tool -l quiet -d 'Don\\'t print'\n"));
+
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Summary of a failed bootstrap, and the exit codes of the categories of failures.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{
+    cancel::{self, Cancelled},
+    network::SignatureError,
+    solv::ResolutionError,
+};
+
+/// The stage of the bootstrap which failed, attached to the error returned by
+/// [crate::Bootstrap::run] as its context
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Checking the options and the target
+    Setup,
+    Manifests,
+    Resolution,
+    Download,
+    Stage1,
+    Stage2,
+    Update,
+    Export,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Setup => "Setup",
+            Stage::Manifests => "Manifest download",
+            Stage::Resolution => "Dependency resolution",
+            Stage::Download => "Package download",
+            Stage::Stage1 => "Stage 1",
+            Stage::Stage2 => "Stage 2",
+            Stage::Update => "Update",
+            Stage::Export => "Export",
+        };
+        write!(f, "{} failed", name)
+    }
+}
+
+/// What went wrong, each category has its own exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Network,
+    Resolution,
+    Disk,
+    Signature,
+    Other,
+}
+
+impl Category {
+    /// The exit status of aoscbootstrap (2 is used by the usage errors, 130 by the cancellation)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Category::Other => 1,
+            Category::Network => 3,
+            Category::Resolution => 4,
+            Category::Disk => 5,
+            Category::Signature => 6,
+        }
+    }
+}
+
+/// The summary of a failed bootstrap
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    pub stage: Stage,
+    pub category: Category,
+    /// The underlying error, without the stage
+    pub error: String,
+    pub remedy: Option<&'static str>,
+    pub exit_code: i32,
+}
+
+impl Failure {
+    /// Summarize `err`, which has failed in [Stage::Setup] if it has no stage attached
+    pub fn new(err: &anyhow::Error) -> Self {
+        let stage = err.downcast_ref::<Stage>().copied();
+        let messages = err
+            .chain()
+            .skip(usize::from(stage.is_some()))
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+        let stage = stage.unwrap_or(Stage::Setup);
+        let (category, remedy) = classify(err, stage, &messages.join("\n"));
+
+        Failure {
+            stage,
+            category,
+            error: messages.join(": "),
+            remedy,
+            exit_code: category.exit_code(),
+        }
+    }
+}
+
+/// The exit status of a bootstrap failed with `err`. Once the bootstrap has been `cancelled`,
+/// every error is caused by the cancellation (e.g. the interrupted guest)
+pub fn exit_code(err: &anyhow::Error, cancelled: bool) -> i32 {
+    if cancelled || err.downcast_ref::<Cancelled>().is_some() {
+        return cancel::EXIT_CODE;
+    }
+
+    Failure::new(err).exit_code
+}
+
+fn classify(err: &anyhow::Error, stage: Stage, message: &str) -> (Category, Option<&'static str>) {
+    // the failures of the downloads and of the guest are only available as messages
+    let is_disk_full = err.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+            )
+        })
+    }) || message.contains("No space left on device")
+        || message.contains("disk space not enough");
+    let is_not_found = err.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            .is_some_and(|s| s == reqwest::StatusCode::NOT_FOUND)
+    }) || message.contains("404 Not Found");
+    let is_network = err.chain().any(|e| e.is::<reqwest::Error>());
+
+    if err.chain().any(|e| e.is::<ResolutionError>()) {
+        (
+            Category::Resolution,
+            Some("Check the requested and the excluded packages, `--why <package>` explains why a package is required."),
+        )
+    } else if is_disk_full {
+        (
+            Category::Disk,
+            Some("Free some space on the filesystem of the target, or bootstrap into another target."),
+        )
+    } else if err.downcast_ref::<SignatureError>().is_some() {
+        (
+            Category::Signature,
+            Some("Check that the keyring (--keyring or --keyring-dir) contains the key signing the repository."),
+        )
+    } else if is_not_found {
+        (
+            Category::Network,
+            Some("The mirror does not provide the file: check the branch, or retry later (or with another mirror) if it is being synchronized."),
+        )
+    } else if is_network || matches!(stage, Stage::Manifests | Stage::Download) {
+        (
+            Category::Network,
+            Some("Check the network connection and the proxy, or use another mirror (--mirror)."),
+        )
+    } else if stage == Stage::Resolution {
+        (Category::Resolution, None)
+    } else {
+        (Category::Other, None)
+    }
+}
+
+#[test]
+fn test_classify_failure() {
+    use anyhow::{anyhow, Context};
+
+    let err = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::StorageFull))
+        .context("when extracting the packages")
+        .context(Stage::Stage1)
+        .unwrap_err();
+    let failure = Failure::new(&err);
+    assert_eq!(failure.stage, Stage::Stage1);
+    assert_eq!(failure.category, Category::Disk);
+    assert!(failure.error.starts_with("when extracting the packages: "));
+    assert_eq!(
+        format!("{:#}", err).split(':').next(),
+        Some("Stage 1 failed")
+    );
+
+    let err =
+        anyhow!("Unable to download files:\nbash.deb: HTTP status client error (404 Not Found)")
+            .context(Stage::Download);
+    let failure = Failure::new(&err);
+    assert_eq!(failure.category, Category::Network);
+    assert_eq!(failure.exit_code, 3);
+    assert!(failure.remedy.unwrap().contains("branch"));
+
+    let err = anyhow!("The release is not signed by any trusted key: no signature")
+        .context(SignatureError {
+            url: "https://repo.aosc.io/debs/dists/stable/InRelease".to_string(),
+        })
+        .context("when fetching the manifests")
+        .context(Stage::Manifests);
+    assert_eq!(Failure::new(&err).category, Category::Signature);
+    // only the errors of the verification are signature errors
+    let err = anyhow!("bash: a bad signature in the maintainer script").context(Stage::Stage2);
+    assert_eq!(Failure::new(&err).category, Category::Other);
+
+    let err = anyhow::Error::new(ResolutionError {
+        problems: Vec::new(),
+        excludes: Vec::new(),
+    })
+    .context(Stage::Resolution);
+    assert_eq!(Failure::new(&err).category, Category::Resolution);
+
+    let err = anyhow!("Target already exists. Please remove it first.");
+    let failure = Failure::new(&err);
+    assert_eq!(failure.stage, Stage::Setup);
+    assert_eq!(failure.category, Category::Other);
+    assert_eq!(failure.exit_code, 1);
+}
+
+#[test]
+fn test_cancelled_exit_code() {
+    use anyhow::anyhow;
+
+    let err = anyhow::Error::new(Cancelled)
+        .context("when downloading the packages")
+        .context(Stage::Download);
+    assert_eq!(exit_code(&err, false), 130);
+    // the other errors once the bootstrap has been cancelled, e.g. the interrupted guest
+    let err = anyhow!("dpkg was killed by signal 2").context(Stage::Stage2);
+    assert_eq!(exit_code(&err, true), 130);
+    assert_eq!(exit_code(&err, false), 1);
+}
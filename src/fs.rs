@@ -1,104 +1,607 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use nix::fcntl::{open, OFlag};
-use nix::sys::stat::{fchmodat, FchmodatFlags, Mode};
-use nix::unistd::close;
-use sha2::{Digest, Sha256};
-use std::io::Write;
-use std::path::Path;
+use nix::sys::stat::{fchmodat, makedev, mknod, FchmodatFlags, Mode, SFlag};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::os::fd::FromRawFd;
+use std::path::{Component, Path};
 use std::process::Command;
+use std::sync::Mutex;
 use std::{
-    fs::{create_dir_all, write, File},
+    fs::{set_permissions, DirBuilder, File, OpenOptions, Permissions},
     io::Read,
+    os::unix::fs::{symlink, DirBuilderExt, FileTypeExt, OpenOptionsExt, PermissionsExt},
 };
-use tar::Builder;
+use tar::{Builder, Header, HeaderMode};
+use walkdir::WalkDir;
 use xz2::stream::{Filters, LzmaOptions, MtStreamBuilder, Stream};
 use xz2::write::XzEncoder;
 
 const LZMA_PRESET_EXTREME: u32 = 1 << 31;
 
-pub fn bootstrap_apt(root: &Path, mirror: &str, branch: &str) -> Result<()> {
-    create_dir_all(root.join("var/lib/dpkg"))?;
-    create_dir_all(root.join("etc/apt"))?;
-    create_dir_all(root.join("var/lib/apt/lists"))?;
-    write(root.join("etc/locale.conf"), b"LANG=C.UTF-8\n")?;
-    write(root.join("etc/shadow"), b"root:x:1:0:99999:7:::\n")?;
-    write(
-        root.join("etc/apt/sources.list"),
-        format!("deb {} {} main\n", mirror, branch),
-    )?;
+/// The path of the archives written to stdout
+pub const STDOUT_PATH: &str = "-";
+
+/// The stdout of the process, set aside by [reserve_stdout] for an archive
+static RESERVED_STDOUT: Mutex<Option<File>> = Mutex::new(None);
+
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new(STDOUT_PATH)
+}
+
+/// Set stdout aside for an archive: from now on, everything else written to stdout (e.g.
+/// the package list and the output of the commands run in the guest) goes to stderr
+pub fn reserve_stdout() -> Result<()> {
+    let mut reserved = RESERVED_STDOUT.lock().unwrap();
+    if reserved.is_some() {
+        return Ok(());
+    }
+    if std::io::stdout().is_terminal() {
+        bail!("Refusing to write an archive to the terminal, redirect stdout to a file or a pipe");
+    }
+    std::io::stdout().flush()?;
+    let fd = nix::unistd::dup(1).context("when duplicating stdout")?;
+    nix::unistd::dup2(2, 1).context("when redirecting stdout to stderr")?;
+    *reserved = Some(unsafe { File::from_raw_fd(fd) });
+
+    Ok(())
+}
+
+/// Create the archive `path`, [STDOUT_PATH] is the stdout reserved by [reserve_stdout]
+pub fn create_archive(path: &Path) -> Result<File> {
+    if is_stdout(path) {
+        return RESERVED_STDOUT
+            .lock()
+            .unwrap()
+            .take()
+            .context("stdout is not available for the archive");
+    }
+
+    File::create(path).with_context(|| format!("when creating {}", path.display()))
+}
+
+/// Flush the archive created by [create_archive] to the disk (pipes can not be synced)
+pub fn finish_archive(f: File, path: &Path) -> Result<()> {
+    if !is_stdout(path) {
+        f.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Largest major and minor device numbers supported by Linux
+const MAX_DEVICE_MAJOR: u64 = (1 << 12) - 1;
+const MAX_DEVICE_MINOR: u64 = (1 << 20) - 1;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+    Char,
+    Block,
+}
+
+/// A device node to be created under `/dev`
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceNode {
+    /// Path relative to `/dev` (e.g. `null` or `net/tun`)
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: DeviceType,
+    pub major: u64,
+    pub minor: u64,
+    pub mode: u32,
+}
+
+/// Check the device node list for invalid device numbers, paths and duplicates
+pub fn validate_device_nodes(nodes: &[DeviceNode]) -> Result<()> {
+    let mut names = HashSet::new();
+    for node in nodes {
+        let path = Path::new(&node.name);
+        if node.name.is_empty()
+            || path.is_absolute()
+            || path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(anyhow!("Invalid device node name: {:?}", node.name));
+        }
+        if node.major > MAX_DEVICE_MAJOR || node.minor > MAX_DEVICE_MINOR {
+            return Err(anyhow!(
+                "Invalid device number for {}: {}:{} (major must be at most {}, minor at most {})",
+                node.name,
+                node.major,
+                node.minor,
+                MAX_DEVICE_MAJOR,
+                MAX_DEVICE_MINOR
+            ));
+        }
+        if node.mode > 0o7777 {
+            return Err(anyhow!("Invalid mode for {}: {:o}", node.name, node.mode));
+        }
+        if !names.insert(path) {
+            return Err(anyhow!("Duplicate device node: {}", node.name));
+        }
+    }
+
+    Ok(())
+}
+
+/// An entry of `/etc/fstab`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FstabEntry {
+    /// The block device or remote filesystem (e.g. `UUID=...` or `/dev/sda1`)
+    pub device: String,
+    pub mount_point: String,
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    #[serde(default = "default_fstab_options")]
+    pub options: String,
+    #[serde(default)]
+    pub dump: u32,
+    #[serde(default)]
+    pub pass: u32,
+}
+
+fn default_fstab_options() -> String {
+    "defaults".to_string()
+}
+
+/// Check the system configuration for values that would produce broken files
+pub fn validate_system_config(hostname: Option<&str>, fstab: &[FstabEntry]) -> Result<()> {
+    if let Some(hostname) = hostname {
+        // see hostname(7)
+        let valid = !hostname.is_empty()
+            && hostname.len() <= 64
+            && hostname.split('.').all(|label| {
+                !label.is_empty()
+                    && !label.starts_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            return Err(anyhow!("Invalid hostname: {:?}", hostname));
+        }
+    }
+    for entry in fstab {
+        if [
+            &entry.device,
+            &entry.mount_point,
+            &entry.fs_type,
+            &entry.options,
+        ]
+        .iter()
+        .any(|field| field.is_empty())
+        {
+            return Err(anyhow!("Incomplete fstab entry: {:?}", entry));
+        }
+        if entry.mount_point != "none" && !entry.mount_point.starts_with('/') {
+            return Err(anyhow!(
+                "Invalid mount point in fstab: {:?} (must be an absolute path or `none`)",
+                entry.mount_point
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Escape the whitespaces in an fstab field (see fstab(5))
+fn escape_fstab_field(field: &str) -> String {
+    field
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+}
+
+fn generate_fstab(entries: &[FstabEntry]) -> String {
+    let mut fstab = String::from("# <device>\t<mount point>\t<type>\t<options>\t<dump>\t<pass>\n");
+    for entry in entries {
+        fstab.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            escape_fstab_field(&entry.device),
+            escape_fstab_field(&entry.mount_point),
+            entry.fs_type,
+            entry.options,
+            entry.dump,
+            entry.pass
+        ));
+    }
+
+    fstab
+}
+
+/// Mode of the directories created in the target
+pub const DIR_MODE: u32 = 0o755;
+/// Mode of the regular files created in the target
+pub const FILE_MODE: u32 = 0o644;
+
+/// Create the directory and its missing parents with `mode`, regardless of the umask.
+/// The existing directories are left untouched.
+pub fn make_dirs(path: &Path, mode: u32) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut ancestor = path;
+    while ancestor.symlink_metadata().is_err() {
+        missing.push(ancestor);
+        match ancestor.parent() {
+            Some(parent) => ancestor = parent,
+            None => break,
+        }
+    }
+    for dir in missing.into_iter().rev() {
+        match DirBuilder::new().mode(mode).create(dir) {
+            Ok(()) => set_permissions(dir, Permissions::from_mode(mode))?,
+            // created by another extraction worker in the meantime
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && dir.is_dir() => (),
+            Err(e) => {
+                return Err(e).with_context(|| format!("when creating {}", dir.display()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the file with `mode`, regardless of the umask. The file is never more accessible
+/// than `mode` while it is written (e.g. `/etc/shadow`), even if it already exists.
+pub fn write_file<C: AsRef<[u8]>>(path: &Path, contents: C, mode: u32) -> Result<()> {
+    let context = || format!("when writing {}", path.display());
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .with_context(context)?;
+    // the umask only restricts the mode of a new file, the existing ones keep theirs
+    file.set_permissions(Permissions::from_mode(mode))?;
+    file.write_all(contents.as_ref()).with_context(context)?;
+
+    Ok(())
+}
+
+/// Write `/etc/fstab`, `/etc/hostname` and `/etc/locale.conf`, each only if configured
+pub fn write_system_config(
+    root: &Path,
+    hostname: Option<&str>,
+    locale: Option<&str>,
+    fstab: &[FstabEntry],
+) -> Result<()> {
+    let etc = root.join("etc");
+    make_dirs(&etc, DIR_MODE)?;
+    if !fstab.is_empty() {
+        write_file(&etc.join("fstab"), generate_fstab(fstab), FILE_MODE)?;
+    }
+    if let Some(hostname) = hostname {
+        write_file(&etc.join("hostname"), format!("{}\n", hostname), FILE_MODE)?;
+    }
+    if let Some(locale) = locale {
+        write_file(
+            &etc.join("locale.conf"),
+            format!("LANG={}\n", locale),
+            FILE_MODE,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// How `/dev` is populated during stage 1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DevMode {
+    /// The device nodes of the configuration file (requires root)
+    #[default]
+    Nodes,
+    /// Nothing, the container runtime provides `/dev`
+    Skip,
+    /// Only the symbolic links to `/proc` (see [DEV_SYMLINKS]), which require no privileges
+    Symlinks,
+}
+
+/// The symbolic links created under `/dev` by [DevMode::Symlinks]: (name, destination)
+pub const DEV_SYMLINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx"),
+];
+
+/// Create the symbolic links of [DEV_SYMLINKS] under `root/dev`
+pub fn make_dev_symlinks(root: &Path) -> Result<()> {
+    let dev = root.join("dev");
+    make_dirs(&dev, DIR_MODE)?;
+    for (name, dest) in DEV_SYMLINKS {
+        let path = dev.join(name);
+        // left by an interrupted stage 1
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(&path)?;
+        }
+        symlink(dest, &path).with_context(|| format!("when creating {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Create the device nodes under `root/dev`
+pub fn make_device_nodes(root: &Path, nodes: &[DeviceNode]) -> Result<()> {
+    let dev = root.join("dev");
+    for node in nodes {
+        let path = dev.join(&node.name);
+        if let Some(parent) = path.parent() {
+            make_dirs(parent, DIR_MODE)?;
+        }
+        // left by an interrupted stage 1
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(&path)?;
+        }
+        let kind = match node.kind {
+            DeviceType::Char => SFlag::S_IFCHR,
+            DeviceType::Block => SFlag::S_IFBLK,
+        };
+        mknod(
+            &path,
+            kind,
+            Mode::from_bits_truncate(node.mode),
+            makedev(node.major, node.minor),
+        )
+        .with_context(|| format!("when creating device node {}", path.display()))?;
+        // mknod is subject to umask
+        fchmodat(
+            None,
+            &path,
+            Mode::from_bits_truncate(node.mode),
+            FchmodatFlags::FollowSymlink,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The sources.list pointing at the `comps` of each of the `branches` on `mirror`
+pub fn sources_list(mirror: &str, branches: &[String], comps: &[&str]) -> Result<String> {
+    url::Url::parse(mirror).with_context(|| format!("Invalid mirror URL: {}", mirror))?;
+    let branches = branches.iter().map(|b| b.as_str()).collect::<Vec<_>>();
+    for field in [mirror].iter().chain(&branches).chain(comps) {
+        if field.is_empty() || field.contains(char::is_whitespace) {
+            bail!("Invalid field in the APT sources: {:?}", field);
+        }
+    }
+
+    let mut sources = String::new();
+    for branch in branches {
+        sources.push_str(&format!("deb {} {} {}\n", mirror, branch, comps.join(" ")));
+    }
 
-    close(open(
-        &root.join("var/lib/dpkg/available"),
-        OFlag::O_CREAT,
-        Mode::from_bits_truncate(0o644),
-    )?)
-    .ok();
-    close(open(
-        &root.join("var/lib/dpkg/status"),
-        OFlag::O_CREAT,
-        Mode::from_bits_truncate(0o644),
-    )?)
-    .ok();
+    Ok(sources)
+}
+
+pub fn bootstrap_apt(root: &Path, mirror: &str, branches: &[String]) -> Result<()> {
+    make_dirs(&root.join("var/lib/dpkg"), DIR_MODE)?;
+    make_dirs(&root.join("etc/apt"), DIR_MODE)?;
+    make_dirs(&root.join("var/lib/apt/lists"), DIR_MODE)?;
+    write_file(&root.join("etc/locale.conf"), b"LANG=C.UTF-8\n", FILE_MODE)?;
     // chmod 0000 /etc/shadow
-    fchmodat(
-        None,
-        &root.join("etc/shadow"),
-        Mode::empty(),
-        FchmodatFlags::NoFollowSymlink,
-    )?;
-    // chmod 0644 /etc/apt/sources.list
-    fchmodat(
-        None,
+    write_file(&root.join("etc/shadow"), b"root:x:1:0:99999:7:::\n", 0)?;
+    write_file(
         &root.join("etc/apt/sources.list"),
-        Mode::from_bits_truncate(0o644),
-        FchmodatFlags::NoFollowSymlink,
+        sources_list(mirror, branches, &["main"])?,
+        FILE_MODE,
     )?;
 
+    for name in ["available", "status"] {
+        let path = root.join("var/lib/dpkg").join(name);
+        if !path.exists() {
+            write_file(&path, b"", FILE_MODE)?;
+        }
+    }
+
     Ok(())
 }
 
-/// Make a tarball (xz compressed)
-pub fn archive_xz_tarball(root: &Path, target: &Path, threads: u32) -> Result<()> {
-    let f = File::create(target)?;
+/// The owners of the files extracted without privileges (recorded by `--unprivileged`),
+/// applied when archiving the root: the files which are not listed belong to root
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ownership {
+    /// Keyed by the path relative to the root, e.g. `etc/shadow`
+    owners: BTreeMap<String, (u64, u64)>,
+}
+
+impl Ownership {
+    /// Record the owner of `path` (e.g. `/etc/shadow` or `./etc/shadow`)
+    pub fn insert(&mut self, path: &str, uid: u64, gid: u64) {
+        let path = path.trim_start_matches('.').trim_matches('/');
+        if uid == 0 && gid == 0 {
+            self.owners.remove(path);
+        } else {
+            self.owners.insert(path.to_string(), (uid, gid));
+        }
+    }
+
+    /// The owner (uid, gid) of `path`, relative to the root
+    pub fn owner(&self, path: &Path) -> (u64, u64) {
+        self.owners
+            .get(path.to_string_lossy().as_ref())
+            .copied()
+            .unwrap_or((0, 0))
+    }
+
+    /// Parse the `uid:gid path` lines written by [Ownership::to_lines]
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut ownership = Ownership::default();
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            let parsed = line.split_once(' ').and_then(|(ids, path)| {
+                let (uid, gid) = ids.split_once(':')?;
+                Some((path, uid.parse().ok()?, gid.parse().ok()?))
+            });
+            let Some((path, uid, gid)) = parsed else {
+                bail!("Invalid ownership entry: {}", line);
+            };
+            ownership.insert(path, uid, gid);
+        }
+
+        Ok(ownership)
+    }
+
+    /// Merge the owners recorded in `other`
+    pub fn extend(&mut self, other: Ownership) {
+        self.owners.extend(other.owners);
+    }
+
+    pub fn to_lines(&self) -> String {
+        self.owners
+            .iter()
+            .map(|(path, (uid, gid))| format!("{}:{} {}\n", uid, gid, path))
+            .collect()
+    }
+
+    /// The mksquashfs pseudo definitions changing the owners of the files under `root`
+    fn squashfs_pseudo(&self, root: &Path) -> Result<String> {
+        let mut pseudo = String::new();
+        for (path, (uid, gid)) in &self.owners {
+            // e.g. removed by a maintainer script
+            let Ok(metadata) = root.join(path).symlink_metadata() else {
+                continue;
+            };
+            pseudo.push_str(&format!(
+                "{} m {:o} {} {}\n",
+                path,
+                metadata.permissions().mode() & 0o7777,
+                uid,
+                gid
+            ));
+        }
+
+        Ok(pseudo)
+    }
+}
+
+/// Make a tarball (xz compressed), with the `owners` of a rootless extraction if any
+pub fn archive_xz_tarball(
+    root: &Path,
+    target: &Path,
+    threads: u32,
+    mtime: u64,
+    owners: Option<&Ownership>,
+) -> Result<()> {
+    let f = create_archive(target)?;
     let xz = build_xz_encoder(threads)?;
-    let builder = build_tarball_stream(XzEncoder::new_stream(f, xz), root)?;
-    builder.into_inner()?.finish()?.sync_all()?;
+    let builder = build_tarball_stream(XzEncoder::new_stream(f, xz), root, mtime, owners)?;
+    finish_archive(builder.into_inner()?.finish()?, target)?;
 
     Ok(())
 }
 
-/// Make a tarball (gz compressed)
-pub fn archive_gz_tarball(root: &Path, target: &Path) -> Result<()> {
-    let f = File::create(target)?;
-    let builder = build_tarball_stream(GzEncoder::new(f, Compression::best()), root)?;
-    builder.into_inner()?.finish()?.sync_all()?;
+/// Make a tarball (gz compressed), with the `owners` of a rootless extraction if any
+pub fn archive_gz_tarball(
+    root: &Path,
+    target: &Path,
+    mtime: u64,
+    owners: Option<&Ownership>,
+) -> Result<()> {
+    let f = create_archive(target)?;
+    let builder =
+        build_tarball_stream(GzEncoder::new(f, Compression::best()), root, mtime, owners)?;
+    finish_archive(builder.into_inner()?.finish()?, target)?;
 
     Ok(())
 }
 
-fn build_tarball_stream<W: Write>(stream: W, root: &Path) -> Result<Builder<W>, anyhow::Error> {
+/// Archive the root into a tarball stream, reproducibly: the entries are sorted by path,
+/// all the timestamps are set to `mtime` and only the numeric owners are recorded (taken from
+/// `owners` instead of the files if any)
+pub(crate) fn build_tarball_stream<W: Write>(
+    stream: W,
+    root: &Path,
+    mtime: u64,
+    owners: Option<&Ownership>,
+) -> Result<Builder<W>, anyhow::Error> {
     let mut builder = Builder::new(stream);
-    builder.mode(tar::HeaderMode::Complete);
-    builder.follow_symlinks(false);
-    builder.append_dir_all(".", root)?;
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+        // sockets can not be archived
+        if file_type.is_socket() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(root)?;
+        let path = Path::new(".").join(relative);
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+        header.set_mtime(mtime);
+        if let Some(owners) = owners {
+            let (uid, gid) = owners.owner(relative);
+            header.set_uid(uid);
+            header.set_gid(gid);
+        }
+        // the user and group names would be looked up on the build host
+        header.set_username("")?;
+        header.set_groupname("")?;
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            builder.append_link(&mut header, &path, target)?;
+        } else if file_type.is_file() {
+            builder.append_data(&mut header, &path, File::open(entry.path())?)?;
+        } else {
+            header.set_size(0);
+            builder.append_data(&mut header, &path, std::io::empty())?;
+        }
+    }
     builder.finish()?;
 
     Ok(builder)
 }
 
-/// Make a squashfs (xz compressed)
-pub fn archive_squashfs(root: &Path, target: &Path, threads: u32) -> Result<()> {
-    let output = Command::new("mksquashfs")
+/// Compression algorithms for the squashfs image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SquashfsCompression {
+    #[default]
+    Xz,
+    Zstd,
+    Gzip,
+}
+
+impl SquashfsCompression {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SquashfsCompression::Xz => "xz",
+            SquashfsCompression::Zstd => "zstd",
+            SquashfsCompression::Gzip => "gzip",
+        }
+    }
+}
+
+/// Make a squashfs (device nodes and symlinks are stored as-is), with the `owners` of a
+/// rootless extraction if any
+pub fn archive_squashfs(
+    root: &Path,
+    target: &Path,
+    threads: u32,
+    compression: SquashfsCompression,
+    owners: Option<&Ownership>,
+) -> Result<()> {
+    let mut command = Command::new("mksquashfs");
+    command
         .arg(root)
         .arg(target)
         .arg("-comp")
-        .arg("xz")
+        .arg(compression.as_str())
         .arg("-processors")
-        .arg(threads.to_string())
-        .spawn()?
-        .wait_with_output()?;
+        .arg(threads.to_string());
+    // kept until mksquashfs exits
+    let mut pseudo = None;
+    if let Some(owners) = owners {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(owners.squashfs_pseudo(root)?.as_bytes())?;
+        command.arg("-all-root").arg("-pf").arg(file.path());
+        pseudo = Some(file);
+    }
+    let output = command.spawn()?.wait_with_output()?;
+    drop(pseudo);
     if !output.status.success() {
         return Err(anyhow!("Failed to archive squashfs!"));
     }
@@ -125,3 +628,274 @@ pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
 
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+/// The digest algorithms of the packages in the manifests, from the strongest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Sha512,
+    Sha256,
+    Md5,
+}
+
+impl ChecksumKind {
+    /// The name of the field in the manifests
+    pub fn field(&self) -> &'static str {
+        match self {
+            ChecksumKind::Sha512 => "SHA512",
+            ChecksumKind::Sha256 => "SHA256",
+            ChecksumKind::Md5 => "MD5sum",
+        }
+    }
+}
+
+/// Calculate the checksum of the given stream with the `kind` algorithm
+pub fn checksum<R: Read>(kind: ChecksumKind, mut reader: R) -> Result<String> {
+    match kind {
+        ChecksumKind::Sha512 => {
+            let mut hasher = Sha512::new();
+            std::io::copy(&mut reader, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        ChecksumKind::Sha256 => sha256sum(reader),
+        ChecksumKind::Md5 => {
+            use sequoia_openpgp::{crypto::hash::Digest as _, types::HashAlgorithm};
+
+            let mut hasher = HashAlgorithm::MD5.context()?;
+            std::io::copy(&mut reader, &mut hasher)?;
+            Ok(hex::encode(hasher.into_digest()?))
+        }
+    }
+}
+
+/// Write the file atomically: the data goes to a temporary file in the same directory,
+/// which replaces the destination only after the data is fully written
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let parent = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut f = tempfile::NamedTempFile::new_in(parent)?;
+    f.write_all(data)?;
+    f.as_file().sync_all()?;
+    f.as_file().set_permissions(Permissions::from_mode(0o644))?;
+    f.persist(path)
+        .with_context(|| format!("when writing {}", path.display()))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_sources_list() -> Result<()> {
+    let branches = |b: &[&str]| b.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        sources_list(
+            "https://repo.aosc.io/debs",
+            &branches(&["stable"]),
+            &["main", "bsp-sunxi"]
+        )?,
+        "deb https://repo.aosc.io/debs stable main bsp-sunxi\n"
+    );
+    assert_eq!(
+        sources_list(
+            "https://repo.aosc.io/debs",
+            &branches(&["stable", "security"]),
+            &["main"]
+        )?,
+        "deb https://repo.aosc.io/debs stable main\ndeb https://repo.aosc.io/debs security main\n"
+    );
+    assert!(sources_list("repo.aosc.io/debs", &branches(&["stable"]), &["main"]).is_err());
+    assert!(sources_list(
+        "https://repo.aosc.io/debs",
+        &branches(&["stable\nEOF"]),
+        &["main"]
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_reproducible_tarball() -> Result<()> {
+    // the files are created in a different order and with different timestamps each time
+    let build = |names: [&str; 2], mtime: u64| -> Result<Vec<u8>> {
+        let root = tempfile::tempdir()?;
+        for name in names {
+            std::fs::create_dir_all(root.path().join(name))?;
+            std::fs::write(root.path().join(name).join("file"), name)?;
+            File::options()
+                .write(true)
+                .open(root.path().join(name).join("file"))?
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))?;
+        }
+        std::os::unix::fs::symlink("a/file", root.path().join("link"))?;
+        let builder = build_tarball_stream(
+            GzEncoder::new(Vec::new(), Compression::best()),
+            root.path(),
+            0,
+            None,
+        )?;
+
+        Ok(builder.into_inner()?.finish()?)
+    };
+    assert_eq!(build(["a", "b"], 1_000_000)?, build(["b", "a"], 2_000_000)?);
+
+    let tarball = build(["a", "b"], 1)?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball.as_slice()));
+    let mut paths = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        assert_eq!(entry.header().mtime()?, 0);
+        assert_eq!(entry.header().username()?, Some(""));
+        paths.push(entry.path()?.to_string_lossy().into_owned());
+    }
+    assert_eq!(paths, ["./", "a", "a/file", "b", "b/file", "link"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_archive_to_stdout() -> Result<()> {
+    let stdout = Path::new(STDOUT_PATH);
+    let root = tempfile::tempdir()?;
+    std::fs::write(root.path().join("file"), "hello")?;
+    assert!(archive_gz_tarball(root.path(), stdout, 0, None).is_err());
+
+    // stands for the stdout reserved by reserve_stdout, a pipe can not be synced either
+    let (reader, writer) = nix::unistd::pipe()?;
+    *RESERVED_STDOUT.lock().unwrap() = Some(File::from(writer));
+    let read = std::thread::spawn(move || {
+        let mut tarball = Vec::new();
+        File::from(reader)
+            .read_to_end(&mut tarball)
+            .map(|_| tarball)
+    });
+    archive_gz_tarball(root.path(), stdout, 0, None)?;
+    let tarball = read.join().unwrap()?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball.as_slice()));
+    let paths = archive
+        .entries()?
+        .map(|e| Ok(e?.path()?.to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(paths, ["./", "file"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_tarball_ownership() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    std::fs::create_dir(root.path().join("etc"))?;
+    std::fs::write(root.path().join("etc/shadow"), "")?;
+    std::fs::write(root.path().join("etc/passwd"), "")?;
+    let owners = Ownership::parse("0:42 /etc/shadow\n")?;
+    assert_eq!(Ownership::parse(&owners.to_lines())?, owners);
+    assert!(Ownership::parse("shadow").is_err());
+
+    let builder = build_tarball_stream(Vec::new(), root.path(), 0, Some(&owners))?;
+    let tarball = builder.into_inner()?;
+    let mut archive = tar::Archive::new(tarball.as_slice());
+    let mut ids = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        ids.push((
+            entry.path()?.to_string_lossy().into_owned(),
+            entry.header().uid()?,
+            entry.header().gid()?,
+        ));
+    }
+    // the files extracted without privileges belong to the current user
+    assert_eq!(
+        ids,
+        [
+            ("./".to_string(), 0, 0),
+            ("etc".to_string(), 0, 0),
+            ("etc/passwd".to_string(), 0, 0),
+            ("etc/shadow".to_string(), 0, 42),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_write_system_config() -> Result<()> {
+    let fstab: Vec<FstabEntry> = toml::from_str::<toml::Table>(
+        r#"
+[[fstab]]
+device = "UUID=0a1b2c3d"
+mount-point = "/"
+type = "ext4"
+pass = 1
+
+[[fstab]]
+device = "LABEL=My Data"
+mount-point = "/mnt/my data"
+type = "xfs"
+options = "noatime,nofail"
+"#,
+    )?["fstab"]
+        .clone()
+        .try_into()?;
+    validate_system_config(Some("aosc-ci.local"), &fstab)?;
+    assert!(validate_system_config(Some("-aosc"), &[]).is_err());
+    assert!(validate_system_config(Some("aosc ci"), &[]).is_err());
+
+    let root = tempfile::tempdir()?;
+    write_system_config(root.path(), Some("aosc-ci"), None, &fstab)?;
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("etc/fstab"))?,
+        "# <device>\t<mount point>\t<type>\t<options>\t<dump>\t<pass>\n\
+         UUID=0a1b2c3d\t/\text4\tdefaults\t0\t1\n\
+         LABEL=My\\040Data\t/mnt/my\\040data\txfs\tnoatime,nofail\t0\t0\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("etc/hostname"))?,
+        "aosc-ci\n"
+    );
+    // not configured, left alone
+    assert!(!root.path().join("etc/locale.conf").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_dev_symlinks() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    make_dev_symlinks(root.path())?;
+    let dev = root.path().join("dev");
+    assert_eq!(
+        std::fs::read_link(dev.join("stdin"))?,
+        Path::new("/proc/self/fd/0")
+    );
+    assert_eq!(std::fs::read_link(dev.join("ptmx"))?, Path::new("pts/ptmx"));
+    assert_eq!(std::fs::read_dir(&dev)?.count(), DEV_SYMLINKS.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_created_modes() -> Result<()> {
+    let mode =
+        |path: &Path| -> Result<u32> { Ok(path.symlink_metadata()?.permissions().mode() & 0o7777) };
+    let root = tempfile::tempdir()?;
+    let existing = root.path().join("var");
+    std::fs::create_dir(&existing)?;
+    set_permissions(&existing, Permissions::from_mode(0o700))?;
+    bootstrap_apt(root.path(), "https://repo.aosc.io/debs", &["stable".into()])?;
+    write_system_config(root.path(), Some("aosc"), None, &[])?;
+
+    assert_eq!(mode(&root.path().join("var/lib/apt/lists"))?, 0o755);
+    assert_eq!(mode(&root.path().join("var/lib/dpkg"))?, 0o755);
+    assert_eq!(mode(&root.path().join("etc"))?, 0o755);
+    assert_eq!(mode(&root.path().join("etc/apt/sources.list"))?, 0o644);
+    assert_eq!(mode(&root.path().join("etc/hostname"))?, 0o644);
+    assert_eq!(mode(&root.path().join("etc/shadow"))?, 0);
+    // the existing directories are left untouched
+    assert_eq!(mode(&existing)?, 0o700);
+    // the existing files get the requested mode
+    let secret = root.path().join("etc/secret");
+    std::fs::write(&secret, "public")?;
+    set_permissions(&secret, Permissions::from_mode(0o666))?;
+    write_file(&secret, "secret", 0o600)?;
+    assert_eq!(mode(&secret)?, 0o600);
+    assert_eq!(std::fs::read_to_string(&secret)?, "secret");
+
+    Ok(())
+}
@@ -1,15 +1,115 @@
 use std::{
     ffi::CString,
+    fs::{
+        copy, read, remove_file, rename, set_permissions, symlink_metadata, write, File,
+        Permissions,
+    },
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
     mem::MaybeUninit,
-    process::{Child, Command, Stdio},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
     thread::sleep,
     time::Duration,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
 use libc::{c_char, c_int};
 use libloading::{Library, Symbol};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use rand::random;
+use serde_json::json;
+
+use crate::{
+    fs::{make_dirs, DIR_MODE},
+    install, logging,
+};
+
+/// The interpreter of the stage 2 scripts in the guest
+pub const DEFAULT_SHELL: &str = "/usr/bin/bash";
+/// Abort on errors and unset variables
+const POSIX_SHELL_FLAGS: &[&str] = &["-e", "-u"];
+/// And on the failures in pipelines, which older versions of dash reject
+const PIPEFAIL_SHELL_FLAGS: &[&str] = &["-e", "-u", "-o", "pipefail"];
+
+/// The options of the guest `shell` unless specified: `-e -u -o pipefail` for bash and zsh,
+/// `-e -u` for the other shells
+pub fn default_shell_flags(shell: &str) -> &'static [&'static str] {
+    match Path::new(shell).file_name().and_then(|n| n.to_str()) {
+        Some("bash" | "zsh") => PIPEFAIL_SHELL_FLAGS,
+        _ => POSIX_SHELL_FLAGS,
+    }
+}
+
+/// The environment variables of the commands run in the guest, which `--guest-env` and
+/// the `[guest-env]` section of the configuration file override: the maintainer scripts
+/// must not wait for the answers of debconf, and the host `PATH` may not exist in the guest
+pub const DEFAULT_GUEST_ENV: &[(&str, &str)] = &[
+    ("DEBIAN_FRONTEND", "noninteractive"),
+    (
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    ),
+];
+
+/// The DNS configuration given to the guest by `--guest-network`, unless `--resolv-conf` is set
+pub const DEFAULT_RESOLV_CONF: &str = "/etc/resolv.conf";
+/// The hostname given to the guest by `--guest-network` if it has none
+pub const GUEST_HOSTNAME: &str = "localhost";
+
+/// Check the name of an environment variable (letters, digits and `_`, not starting with a digit)
+pub fn check_env_name(name: &str) -> Result<()> {
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(anyhow!("Invalid environment variable name `{}`", name));
+    }
+
+    Ok(())
+}
+
+/// Parse a `KEY=VALUE` environment variable assignment
+pub fn parse_env(assignment: &str) -> Result<(String, String)> {
+    let (name, value) = assignment
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid assignment `{}`, expected KEY=VALUE", assignment))?;
+    check_env_name(name)?;
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// The mechanism used for entering the guest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GuestBackend {
+    /// chroot(1), with /proc, /sys and /dev mounted from the host
+    Chroot,
+    /// systemd-nspawn(1), boots the guest in a lightweight container
+    Nspawn,
+    /// proot(1), works without privileges
+    Proot,
+}
+
+impl GuestBackend {
+    fn program(&self) -> &'static str {
+        match self {
+            GuestBackend::Chroot => "chroot",
+            GuestBackend::Nspawn => "systemd-nspawn",
+            GuestBackend::Proot => "proot",
+        }
+    }
+}
+
+/// Pseudo filesystems mounted into the guest: (source, mount point, filesystem type).
+/// Entries without a filesystem type are recursively bind-mounted from the host.
+const PSEUDO_FILESYSTEMS: &[(&str, &str, Option<&str>)] = &[
+    ("proc", "proc", Some("proc")),
+    ("sysfs", "sys", Some("sysfs")),
+    ("/dev", "dev", None),
+];
 
 #[allow(non_camel_case_types)]
 enum sd_bus {}
@@ -70,46 +170,299 @@ fn wait_for_container(child: &mut Child, ns_name: &str, retry: usize) -> Result<
     Err(anyhow!("Timeout waiting for container {}", ns_name))
 }
 
-fn chroot_do(target: &str, args: &[&str]) -> Result<()> {
-    let status = Command::new("chroot").arg(target).args(args).status()?;
+/// Undoes the changes made to the guest for running commands in it (the mounts and the
+/// files copied into it) when dropped, so that nothing is left behind on errors or panics
+#[derive(Default)]
+pub struct GuestGuard {
+    mounted: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    replaced: Vec<Replaced>,
+}
+
+/// A file of the guest temporarily replaced, the previous one is moved to `backup`
+struct Replaced {
+    path: PathBuf,
+    backup: Option<PathBuf>,
+    content: Vec<u8>,
+}
+
+impl GuestGuard {
+    pub fn new() -> GuestGuard {
+        GuestGuard::default()
+    }
+
+    /// Remove the file when cleaning up
+    pub fn remove_on_exit(&mut self, path: PathBuf) {
+        self.files.push(path);
+    }
+
+    /// Write `content` to `path` until cleaning up, when the previous file (if any) is put
+    /// back, unless the commands run in the guest have replaced the file in the meantime
+    pub fn replace_until_exit(&mut self, path: PathBuf, content: &[u8]) -> Result<()> {
+        let mut backup = None;
+        // the file may be a dangling symlink (e.g. to the stub of systemd-resolved)
+        if symlink_metadata(&path).is_ok() {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(".aoscbootstrap");
+            let backup_path = path.with_file_name(name);
+            rename(&path, &backup_path)
+                .with_context(|| format!("when moving {} aside", path.display()))?;
+            backup = Some(backup_path);
+        }
+        let replaced = Replaced {
+            path,
+            backup,
+            content: content.to_vec(),
+        };
+        let result = write(&replaced.path, content)
+            .with_context(|| format!("when writing {}", replaced.path.display()));
+        self.replaced.push(replaced);
+
+        result
+    }
+
+    /// Bind-mount the host `source` directories on their guest paths
+    fn mount_binds(&mut self, target: &Path, binds: &[(&Path, &str)]) -> Result<()> {
+        for (source, guest_path) in binds {
+            let mount_point = target.join(guest_path.trim_start_matches('/'));
+            make_dirs(&mount_point, DIR_MODE)?;
+            mount(
+                Some(*source),
+                &mount_point,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .context(format!("when mounting {}", mount_point.display()))?;
+            self.mounted.push(mount_point);
+        }
+
+        Ok(())
+    }
+
+    fn mount_pseudo_filesystems(&mut self, target: &Path) -> Result<()> {
+        for (source, mount_point, fstype) in PSEUDO_FILESYSTEMS {
+            let mount_point = target.join(mount_point);
+            make_dirs(&mount_point, DIR_MODE)?;
+            let flags = match fstype {
+                Some(_) => MsFlags::empty(),
+                None => MsFlags::MS_BIND | MsFlags::MS_REC,
+            };
+            mount(Some(*source), &mount_point, *fstype, flags, None::<&str>)
+                .context(format!("when mounting {}", mount_point.display()))?;
+            self.mounted.push(mount_point);
+        }
+
+        Ok(())
+    }
+
+    /// Unmount everything and remove the files, in the reverse order
+    pub fn cleanup(&mut self) -> Result<()> {
+        let mut errors = Vec::new();
+        while let Some(mount_point) = self.mounted.pop() {
+            // lazy unmount: the recursive bind mount of /dev may have sub-mounts
+            if let Err(e) = umount2(&mount_point, MntFlags::MNT_DETACH) {
+                errors.push(format!("unmounting {}: {}", mount_point.display(), e));
+            }
+        }
+        while let Some(replaced) = self.replaced.pop() {
+            if let Err(e) = replaced.restore() {
+                errors.push(format!("restoring {}: {}", replaced.path.display(), e));
+            }
+        }
+        while let Some(path) = self.files.pop() {
+            if let Err(e) = remove_file(&path) {
+                if e.kind() != ErrorKind::NotFound {
+                    errors.push(format!("removing {}: {}", path.display(), e));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "Failed to clean up the guest:\n{}",
+                errors.join("\n")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Replaced {
+    fn restore(&self) -> std::io::Result<()> {
+        if read(&self.path).is_ok_and(|c| c == self.content) {
+            remove_file(&self.path)?;
+            if let Some(ref backup) = self.backup {
+                rename(backup, &self.path)?;
+            }
+        } else if let Some(ref backup) = self.backup {
+            // the guest has installed its own file
+            remove_file(backup)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for GuestGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.cleanup() {
+            logging::event(
+                "cleanup_failed",
+                json!({ "error": format!("{:#}", e) }),
+                format!("{:#}", e),
+            );
+        }
+    }
+}
+
+/// Run the command, with its output (stdout and stderr) also written to the `log` file if set.
+/// The profile markers of the stage 2 script ([install::PROFILE_MARKER]) are only logged.
+fn run_command(mut command: Command, log: Option<&Path>) -> Result<ExitStatus> {
+    let Some(log) = log else {
+        return Ok(command.status()?);
+    };
+    let mut file = File::create(log)
+        .with_context(|| format!("when creating the guest log {}", log.display()))?;
+    let (reader, writer) = std::io::pipe()?;
+    command.stdout(writer.try_clone()?).stderr(writer);
+    let mut child = command.spawn()?;
+    // the pipe is closed once the command (and the copies of the pipe it holds) exits
+    drop(command);
+    let copied = copy_output(reader, &mut file);
+    let status = child.wait()?;
+    copied.with_context(|| format!("when writing the guest log {}", log.display()))?;
+
+    Ok(status)
+}
+
+fn copy_output<R: Read>(output: R, log: &mut File) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    for line in BufReader::new(output).split(b'\n') {
+        let mut line = line?;
+        line.push(b'\n');
+        log.write_all(&line)?;
+        if !line.starts_with(install::PROFILE_MARKER.as_bytes()) {
+            stdout.write_all(&line)?;
+        }
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
 
+fn chroot_do(
+    target: &str,
+    args: &[&str],
+    binds: &[(&Path, &str)],
+    env: &[(String, String)],
+    log: Option<&Path>,
+    guard: &mut GuestGuard,
+) -> Result<()> {
+    guard.mount_pseudo_filesystems(Path::new(target))?;
+    guard.mount_binds(Path::new(target), binds)?;
+    let mut command = Command::new("chroot");
+    command
+        .arg(target)
+        .args(args)
+        .envs(env.iter().map(|(k, v)| (k, v)));
+    let status = run_command(command, log)?;
     if !status.success() {
         return Err(anyhow!("chroot exited with status {}", status));
     }
 
+    guard.cleanup()
+}
+
+fn proot_do(
+    target: &str,
+    args: &[&str],
+    binds: &[(&Path, &str)],
+    env: &[(String, String)],
+    log: Option<&Path>,
+) -> Result<()> {
+    let mut command = Command::new("proot");
+    command
+        .args(["-0", "-w", "/", "-r", target])
+        .args(["-b", "/proc", "-b", "/sys", "-b", "/dev"])
+        .args(binds.iter().flat_map(|(source, guest_path)| {
+            [
+                "-b".to_string(),
+                format!("{}:{}", source.display(), guest_path),
+            ]
+        }))
+        .args(args)
+        .envs(env.iter().map(|(k, v)| (k, v)));
+    let status = run_command(command, log)?;
+
+    if !status.success() {
+        return Err(anyhow!("proot exited with status {}", status));
+    }
+
     Ok(())
 }
 
 #[inline]
 /// Execute a command in the container
-fn execute_container_command(ns_name: &str, args: &[&str]) -> Result<i32> {
-    let exit_code = Command::new("systemd-run")
-        .args(["-M", ns_name, "-qt", "--"])
-        .args(args)
-        .spawn()?
-        .wait()?
-        .code()
-        .unwrap_or(127);
+fn execute_container_command(
+    ns_name: &str,
+    args: &[&str],
+    env: &[(String, String)],
+    log: Option<&Path>,
+) -> Result<i32> {
+    let mut command = Command::new("systemd-run");
+    command
+        .args(["-M", ns_name, "-qt"])
+        .args(env.iter().map(|(k, v)| format!("--setenv={}={}", k, v)))
+        .arg("--")
+        .args(args);
+    let exit_code = run_command(command, log)?.code().unwrap_or(127);
 
     Ok(exit_code)
 }
 
-fn nspawn_do(target: &str, args: &[&str]) -> Result<()> {
+fn nspawn_do(
+    target: &str,
+    args: &[&str],
+    binds: &[(&Path, &str)],
+    env: &[(String, String)],
+    log: Option<&Path>,
+) -> Result<()> {
     let ns_name = format!("bootstrap-{:x}", random::<u32>());
     let mut child = Command::new("systemd-nspawn")
-        .args(["-qbD", target, "-M", &ns_name, "--"])
+        .args(["-qbD", target, "-M", &ns_name])
+        .args(
+            binds
+                .iter()
+                .map(|(source, guest_path)| format!("--bind={}:{}", source.display(), guest_path)),
+        )
+        .arg("--")
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()?;
-    eprintln!("Waiting for the container ...");
-    wait_for_container(&mut child, &ns_name, 10)?;
-    let status = execute_container_command(&ns_name, args)?;
+    logging::event(
+        "container_wait",
+        json!({ "machine": ns_name }),
+        "Waiting for the container ...",
+    );
+    if let Err(e) = wait_for_container(&mut child, &ns_name, 10) {
+        child.kill().ok();
+        return Err(e);
+    }
+    let status = execute_container_command(&ns_name, args, env, log);
 
-    eprintln!("Powering off the container ...");
+    // power off the container even if the command could not be executed
+    logging::event(
+        "container_poweroff",
+        json!({ "machine": ns_name }),
+        "Powering off the container ...",
+    );
     Command::new("machinectl")
         .args(["poweroff", &ns_name])
         .status()?;
+    child.wait()?;
 
+    let status = status?;
     if status != 0 {
         return Err(anyhow!("nspawn exited with status {}", status));
     }
@@ -117,12 +470,249 @@ fn nspawn_do(target: &str, args: &[&str]) -> Result<()> {
     Ok(())
 }
 
-pub fn run_in_guest(target: &str, args: &[&str]) -> Result<()> {
-    if which::which("systemd-nspawn").is_ok() {
-        return nspawn_do(target, args);
-    } else if which::which("chroot").is_ok() {
-        return chroot_do(target, args);
+/// Run the command in the guest, using the specified backend
+/// (or systemd-nspawn/chroot, whichever is available, when not specified), with the host
+/// directories `binds` (source, guest path) bind-mounted and the environment variables `env` set.
+/// The output of the command is also written to `log`, if set.
+/// The mounts are registered in `guard`, which unmounts them even if the command fails.
+pub fn run_in_guest(
+    target: &str,
+    args: &[&str],
+    backend: Option<GuestBackend>,
+    binds: &[(&Path, &str)],
+    env: &[(String, String)],
+    log: Option<&Path>,
+    guard: &mut GuestGuard,
+) -> Result<()> {
+    let backend = match backend {
+        Some(backend) => {
+            if which::which(backend.program()).is_err() {
+                return Err(anyhow!(
+                    "{} is not available on this host",
+                    backend.program()
+                ));
+            }
+            backend
+        }
+        None if which::which("systemd-nspawn").is_ok() => GuestBackend::Nspawn,
+        None if which::which("chroot").is_ok() => GuestBackend::Chroot,
+        None => return Err(anyhow!("Neither chroot nor systemd-nspawn is available")),
+    };
+
+    match backend {
+        GuestBackend::Chroot => chroot_do(target, args, binds, env, log, guard),
+        GuestBackend::Nspawn => nspawn_do(target, args, binds, env, log),
+        GuestBackend::Proot => proot_do(target, args, binds, env, log),
+    }
+}
+
+/// Map AOSC OS architecture names to the QEMU user-mode emulator CPU names
+fn qemu_cpu_name(arch: &str) -> Option<&'static str> {
+    match arch {
+        "amd64" => Some("x86_64"),
+        "arm64" => Some("aarch64"),
+        "armv4" | "armv6hf" | "armv7hf" => Some("arm"),
+        "i486" => Some("i386"),
+        "loongarch64" => Some("loongarch64"),
+        "loongson3" => Some("mips64el"),
+        "mips64r6el" => Some("mips64el"),
+        "powerpc" => Some("ppc"),
+        "ppc64" => Some("ppc64"),
+        "ppc64el" => Some("ppc64le"),
+        "riscv64" => Some("riscv64"),
+        _ => None,
+    }
+}
+
+/// A QEMU user-mode emulator used for running the guest of a foreign architecture
+pub struct Emulator {
+    binary: PathBuf,
+}
+
+impl Emulator {
+    /// Locate the emulator for `arch` and make sure the host can run foreign binaries with it.
+    /// The binfmt_misc handler is not registered here, it must already be registered on the host
+    /// (e.g. by qemu-user-static or systemd-binfmt)
+    pub fn find(arch: &str, qemu_path: Option<&str>) -> Result<Emulator> {
+        let cpu = qemu_cpu_name(arch)
+            .ok_or_else(|| anyhow!("Don't know how to emulate the {} architecture", arch))?;
+        let binfmt = Path::new("/proc/sys/fs/binfmt_misc");
+        if !binfmt.join("status").is_file() {
+            return Err(anyhow!(
+                "binfmt_misc is not available on this host, which is required for bootstrapping {}.\n\
+                Try loading the module and mounting it: `modprobe binfmt_misc && mount -t binfmt_misc binfmt_misc {}`",
+                arch,
+                binfmt.display()
+            ));
+        }
+        if !binfmt.join(format!("qemu-{}", cpu)).is_file() {
+            return Err(anyhow!(
+                "No binfmt_misc handler is registered for {} binaries.\n\
+                Please install qemu-user-static (and binfmt support) on the host, or register the handler: \
+                `systemctl restart systemd-binfmt` (the handler must be named qemu-{})",
+                cpu,
+                cpu
+            ));
+        }
+        let binary = match qemu_path {
+            Some(path) => PathBuf::from(path),
+            None => which::which(format!("qemu-{}-static", cpu)).map_err(|_| {
+                anyhow!(
+                    "Unable to find qemu-{}-static, please install it or use --qemu-path",
+                    cpu
+                )
+            })?,
+        };
+        if !binary.is_file() {
+            return Err(anyhow!("Emulator {} does not exist", binary.display()));
+        }
+
+        Ok(Emulator { binary })
+    }
+
+    /// Copy the emulator into the guest, the copy is removed by `guard`
+    pub fn install(&self, target: &Path, guard: &mut GuestGuard) -> Result<()> {
+        let file_name = self
+            .binary
+            .file_name()
+            .context("Invalid emulator file name")?;
+        // binfmt handlers registered without the F flag look up the emulator inside the guest
+        let dest = target.join("usr/bin").join(file_name);
+        copy(&self.binary, &dest).context("when copying the emulator into the guest")?;
+        guard.remove_on_exit(dest.clone());
+        set_permissions(&dest, Permissions::from_mode(0o755))?;
+
+        Ok(())
+    }
+}
+
+/// Let the commands run in the guest use the network: copy the DNS configuration
+/// `resolv_conf` into the guest and give it a hostname if it has none. `guard` restores
+/// the previous files, `keep_resolv_conf` leaves the DNS configuration in the guest
+pub fn setup_network(
+    target: &Path,
+    resolv_conf: &Path,
+    keep_resolv_conf: bool,
+    guard: &mut GuestGuard,
+) -> Result<()> {
+    let content =
+        read(resolv_conf).with_context(|| format!("Unable to read {}", resolv_conf.display()))?;
+    let dest = target.join("etc/resolv.conf");
+    if keep_resolv_conf {
+        if symlink_metadata(&dest).is_ok() {
+            remove_file(&dest)?;
+        }
+        write(&dest, &content).with_context(|| format!("when writing {}", dest.display()))?;
+    } else {
+        guard.replace_until_exit(dest, &content)?;
+    }
+    let hostname = target.join("etc/hostname");
+    if symlink_metadata(&hostname).is_err() {
+        guard.replace_until_exit(hostname, format!("{}\n", GUEST_HOSTNAME).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_guest_guard() -> Result<()> {
+    // mounting requires root
+    if !nix::unistd::Uid::effective().is_root() || which::which("chroot").is_err() {
+        return Ok(());
     }
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().to_string_lossy().to_string();
+    let script = dir.path().join("script");
+    std::fs::write(&script, "")?;
+    let mut guard = GuestGuard::new();
+    guard.remove_on_exit(script.clone());
+    // there is nothing to run in an empty guest
+    assert!(run_in_guest(
+        &target,
+        &["/usr/bin/false"],
+        Some(GuestBackend::Chroot),
+        &[],
+        &[],
+        None,
+        &mut guard
+    )
+    .is_err());
+    drop(guard);
 
-    Err(anyhow!("Neither chroot nor systemd-nspawn is available"))
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo")?;
+    assert!(!mounts.contains(&target), "{}", mounts);
+    assert!(!script.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_run_command_log() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = dir.path().join("guest.log");
+    let mut command = Command::new("sh");
+    command.args([
+        "-c",
+        "echo unpacking; echo '@@aoscbootstrap configure-start 1.0 bash'; echo failed >&2; exit 3",
+    ]);
+    let status = run_command(command, Some(&log))?;
+    assert_eq!(status.code(), Some(3));
+    assert_eq!(
+        std::fs::read_to_string(&log)?,
+        "unpacking\n@@aoscbootstrap configure-start 1.0 bash\nfailed\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_env() -> Result<()> {
+    assert_eq!(
+        parse_env("LANG=C.UTF-8")?,
+        ("LANG".to_string(), "C.UTF-8".to_string())
+    );
+    assert_eq!(parse_env("EMPTY=")?.1, "");
+    assert_eq!(parse_env("OPTS=a=b")?.1, "a=b");
+    for invalid in ["NOVALUE", "=value", "1ABC=x", "A-B=x"] {
+        assert!(parse_env(invalid).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_setup_network() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    let etc = target.path().join("etc");
+    std::fs::create_dir(&etc)?;
+    std::os::unix::fs::symlink(
+        "../run/systemd/resolve/stub-resolv.conf",
+        etc.join("resolv.conf"),
+    )?;
+    let host = target.path().join("host-resolv.conf");
+    write(&host, "nameserver 192.0.2.1\n")?;
+
+    let mut guard = GuestGuard::new();
+    setup_network(target.path(), &host, false, &mut guard)?;
+    assert_eq!(
+        std::fs::read_to_string(etc.join("resolv.conf"))?,
+        "nameserver 192.0.2.1\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(etc.join("hostname"))?,
+        "localhost\n"
+    );
+    guard.cleanup()?;
+    assert!(std::fs::read_link(etc.join("resolv.conf")).is_ok());
+    assert!(!etc.join("hostname").exists());
+    assert_eq!(std::fs::read_dir(&etc)?.count(), 1);
+
+    setup_network(target.path(), &host, true, &mut guard)?;
+    guard.cleanup()?;
+    assert_eq!(
+        std::fs::read_to_string(etc.join("resolv.conf"))?,
+        "nameserver 192.0.2.1\n"
+    );
+
+    Ok(())
 }
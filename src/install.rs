@@ -1,75 +1,738 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs::File,
-    io::{BufWriter, Read, Write},
-    path::Path,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use ar::Archive as ArArchive;
+use clap::ValueEnum;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use nix::unistd::Uid;
+use rayon::prelude::*;
 use serde::Deserialize;
-use tar::Archive as TarArchive;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tar::{Archive as TarArchive, Builder as TarBuilder, EntryType, Header as TarHeader};
 use tempfile::NamedTempFile;
-use xz2::read::XzDecoder;
-use zstd::Decoder;
+use xz2::{read::XzDecoder, stream::Stream as XzStream, write::XzEncoder};
+use zstd::{
+    zstd_safe::{DCtx, DParameter, ResetDirective},
+    Decoder,
+};
 
-use crate::solv::PackageMeta;
+use crate::{
+    fs::{
+        self, build_tarball_stream, sha256sum, validate_device_nodes, validate_system_config,
+        DeviceNode, FstabEntry,
+    },
+    solv::{check_package_name, PackageMeta, PackageRequest, Pin},
+};
 
 const BOOTSTRAP_PACK: &[u8] = include_bytes!("../assets/etc-bootstrap.tar.xz");
 const INSTALL_SCRIPT_TPL: &str = include_str!("../assets/bootstrap.sh");
 const CLEANUP_SCRIPT: &[u8] = include_bytes!("../assets/cleanup.sh");
+const SOURCES_SCRIPT_TPL: &str = include_str!("../assets/sources.sh");
+const APT_UPDATE_SCRIPT: &str = include_str!("../assets/apt-update.sh");
+const SMOKE_TEST_TPL: &str = include_str!("../assets/smoke-test.sh");
+/// Prefix of the markers written by the stage 2 script around the configuration of each
+/// package group, when [PROFILE_ENV] is set in the guest
+pub const PROFILE_MARKER: &str = "@@aoscbootstrap";
+pub const PROFILE_ENV: &str = "AOSCBOOTSTRAP_PROFILE";
+/// Always run by the smoke test, before the commands from the configuration file
+pub const SMOKE_TEST_COMMANDS: &[&str] = &["ldconfig -p", "bash --version", "apt --version"];
 
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(rename = "stub-packages")]
-    pub stub_packages: Vec<String>,
+    pub stub_packages: Vec<PackageRequest>,
     #[serde(rename = "base-packages")]
-    pub base_packages: Vec<String>,
+    pub base_packages: Vec<PackageRequest>,
+    /// Packages that must never be installed
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Device nodes to be created in `/dev`
+    #[serde(rename = "device-nodes", default)]
+    pub device_nodes: Vec<DeviceNode>,
+    /// Written to `/etc/hostname`
+    pub hostname: Option<String>,
+    /// Default locale written to `/etc/locale.conf` (`C.UTF-8` if not set)
+    pub locale: Option<String>,
+    /// Entries of `/etc/fstab` (not generated if empty)
+    #[serde(default)]
+    pub fstab: Vec<FstabEntry>,
+    /// Preferred sources of some packages, like apt preferences
+    #[serde(default)]
+    pub pin: Vec<Pin>,
+    /// Extra commands run by `--smoke-test` in the bootstrapped system
+    #[serde(rename = "smoke-test", default)]
+    pub smoke_test: Vec<String>,
+    /// Environment variables of the commands run in the guest (see [crate::guest::DEFAULT_GUEST_ENV])
+    #[serde(rename = "guest-env", default)]
+    pub guest_env: BTreeMap<String, String>,
+    /// Directories created in the target before stage 2, for the maintainer scripts which
+    /// assume that they exist (e.g. the systemd preset directories)
+    #[serde(default)]
+    pub directories: Vec<String>,
+    /// Packages whose triggers (e.g. rebuilding the icon caches) are only processed once at
+    /// the end of stage 2, instead of after each configured package group
+    #[serde(rename = "defer-triggers", default)]
+    pub defer_triggers: Vec<String>,
 }
 
 #[inline]
-pub fn decompress_tar_xz<R: Read>(reader: R, target: &Path) -> Result<()> {
-    let decompress = XzDecoder::new(reader);
-    let mut tar_processor = TarArchive::new(decompress);
+fn unpack_tar<R: Read>(reader: R, target: &Path) -> Result<()> {
+    let mut tar_processor = TarArchive::new(reader);
     tar_processor.set_unpack_xattrs(true);
     tar_processor.set_preserve_permissions(true);
+    // keep the owners recorded in the archive (only possible as root)
+    tar_processor.set_preserve_ownerships(Uid::effective().is_root());
     tar_processor.unpack(target)?;
 
     Ok(())
 }
 
 #[inline]
-pub fn decompress_tar_zst<R: Read>(reader: R, target: &Path) -> Result<()> {
-    let decompress = Decoder::new(reader)?;
-    let mut tar_processor = TarArchive::new(decompress);
-    tar_processor.set_unpack_xattrs(true);
-    tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(target)?;
+pub fn decompress_tar_xz<R: Read>(reader: R, target: &Path) -> Result<()> {
+    unpack_tar(XzDecoder::new(reader), target)
+}
 
-    Ok(())
+/// The decompression state of the deb packages, reused for all the packages extracted
+/// by a worker instead of allocating a zstd context for each package (liblzma offers no
+/// such reuse, only the memory limit applies to the xz decoder)
+pub struct Decoders {
+    zstd: DCtx<'static>,
+    memory_limit: Option<u64>,
+}
+
+impl Decoders {
+    /// Limit the memory of each decoder to `memory_limit` bytes, the packages compressed
+    /// with a larger window cannot be decompressed
+    pub fn new(memory_limit: Option<u64>) -> Self {
+        let mut zstd = DCtx::create();
+        if let Some(limit) = memory_limit {
+            // within the bounds of zstd, which cannot reject it
+            zstd.set_parameter(DParameter::WindowLogMax(zstd_window_log(limit)))
+                .ok();
+        }
+
+        Decoders { zstd, memory_limit }
+    }
 }
 
-pub fn extract_deb<R: Read>(reader: R, target: &Path) -> Result<()> {
+impl Default for Decoders {
+    fn default() -> Self {
+        Decoders::new(None)
+    }
+}
+
+/// The zstd window log fitting in `memory_limit` bytes, the window is the bulk of the
+/// memory of a zstd decoder
+fn zstd_window_log(memory_limit: u64) -> u32 {
+    (u64::BITS - 1 - memory_limit.max(1).leading_zeros()).clamp(10, 31)
+}
+
+/// Open the `<member>.tar[.zst|.xz|.gz]` member (e.g. `data` or `control`) of a deb package
+fn with_deb_member<R: Read, T, F>(reader: R, member: &str, f: F) -> Result<T>
+where
+    F: FnOnce(TarArchive<Box<dyn Read + '_>>) -> Result<T>,
+{
+    with_deb_member_using(reader, member, &mut Decoders::default(), f)
+}
+
+/// [with_deb_member], decompressing the member with the `decoders`
+fn with_deb_member_using<R: Read, T, F>(
+    reader: R,
+    member: &str,
+    decoders: &mut Decoders,
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce(TarArchive<Box<dyn Read + '_>>) -> Result<T>,
+{
     let mut deb = ArArchive::new(reader);
     while let Some(entry) = deb.next_entry() {
         if entry.is_err() {
             continue;
         }
         let entry = entry.unwrap();
-        match entry.header().identifier() {
-            b"data.tar.xz" => {
-                decompress_tar_xz(entry, target)?;
-                return Ok(());
+        let identifier = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+        let Some(suffix) = identifier
+            .strip_prefix(member)
+            .and_then(|s| s.strip_prefix(".tar"))
+        else {
+            continue;
+        };
+        let decompress: Box<dyn Read> = match suffix {
+            ".zst" => {
+                // the previous package may have been left in the middle of its frame
+                decoders
+                    .zstd
+                    .reset(ResetDirective::SessionOnly)
+                    .map_err(|code| anyhow!("{}", zstd::zstd_safe::get_error_name(code)))?;
+                Box::new(Decoder::with_context(
+                    BufReader::with_capacity(DCtx::in_size(), entry),
+                    &mut decoders.zstd,
+                ))
+            }
+            ".xz" => Box::new(XzDecoder::new_stream(
+                entry,
+                XzStream::new_stream_decoder(decoders.memory_limit.unwrap_or(u64::MAX), 0)?,
+            )),
+            ".gz" => Box::new(GzDecoder::new(entry)),
+            "" => Box::new(entry),
+            _ => return Err(anyhow!("{}: unsupported compression format", identifier)),
+        };
+
+        return f(TarArchive::new(decompress));
+    }
+
+    Err(anyhow!("{} archive not found", member))
+}
+
+/// Extract the `<member>.tar[.zst|.xz|.gz]` member (e.g. `data` or `control`) of a deb package
+pub fn extract_deb_member<R: Read>(reader: R, member: &str, target: &Path) -> Result<()> {
+    with_deb_member(reader, member, |mut tar_processor| {
+        tar_processor.set_unpack_xattrs(true);
+        tar_processor.set_preserve_permissions(true);
+        tar_processor.set_preserve_ownerships(Uid::effective().is_root());
+        tar_processor.unpack(target)?;
+
+        Ok(())
+    })
+}
+
+/// List the files shipped by a deb package, in the format of dpkg's `info/*.list`
+fn list_deb_files<R: Read>(reader: R) -> Result<Vec<String>> {
+    with_deb_member(reader, "data", |mut tar_processor| {
+        let mut files = Vec::new();
+        for entry in tar_processor.entries()? {
+            let entry = entry?;
+            let path = entry.path()?;
+            let path = path.to_string_lossy();
+            let path = path.trim_start_matches('.').trim_matches('/');
+            if path.is_empty() {
+                files.push("/.".to_string());
+            } else {
+                files.push(format!("/{}", path));
+            }
+        }
+
+        Ok(files)
+    })
+}
+
+/// Record the owners of the files of a deb package which do not belong to root
+fn read_deb_owners<R: Read>(reader: R, ownership: &mut fs::Ownership) -> Result<()> {
+    with_deb_member(reader, "data", |mut tar_processor| {
+        for entry in tar_processor.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            ownership.insert(
+                &entry.path()?.to_string_lossy(),
+                header.uid()?,
+                header.gid()?,
+            );
+        }
+
+        Ok(())
+    })
+}
+
+/// An extended attribute which could not be restored during the extraction
+/// (e.g. a file capability without `CAP_SETFCAP`)
+pub struct PendingXattr {
+    /// Path of the file, e.g. `/usr/bin/ping`
+    pub path: String,
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// A package built locally, added to the pool next to the repositories (`--local-deb`)
+pub struct LocalDeb {
+    pub path: PathBuf,
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    /// The entry of the package in a `Packages` manifest
+    pub stanza: String,
+}
+
+/// Directory of the local packages in their `Filename`
+pub const LOCAL_DEB_DIR: &str = "local";
+
+/// Read the control file of a local deb package, and describe it like a repository would
+pub fn read_local_deb(path: &Path) -> Result<LocalDeb> {
+    let context = || format!("when reading the local package {}", path.display());
+    let control = with_deb_member(
+        File::open(path).with_context(context)?,
+        "control",
+        |mut tar| {
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                if entry_name(&entry.path()?) == "/control" {
+                    let mut control = String::new();
+                    entry.read_to_string(&mut control)?;
+                    return Ok(control);
+                }
+            }
+            Err(anyhow!("control file not found"))
+        },
+    )
+    .with_context(context)?;
+    let field = |name: &str| {
+        control
+            .lines()
+            .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+            .map(|v| v.trim().to_string())
+            .ok_or_else(|| anyhow!("{} is missing from the control file", name))
+            .with_context(context)
+    };
+    let (name, version, arch) = (field("Package")?, field("Version")?, field("Architecture")?);
+    let mut f = File::open(path).with_context(context)?;
+    let sha256 = sha256sum(&mut f)?;
+    let size = f.metadata()?.len();
+    let filename = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+    let stanza = format!(
+        "{}\nFilename: {}/{}\nSize: {}\nSHA256: {}\n",
+        control.trim_end(),
+        LOCAL_DEB_DIR,
+        filename.to_string_lossy(),
+        size,
+        sha256
+    );
+
+    Ok(LocalDeb {
+        path: path.to_path_buf(),
+        name,
+        version,
+        arch,
+        stanza,
+    })
+}
+
+/// The files unpacked from a deb package
+pub struct ExtractedDeb {
+    /// Everything but the directories, e.g. `/etc/os-release`
+    pub files: Vec<String>,
+    pub pending_xattrs: Vec<PendingXattr>,
+    /// Number and total size of the files skipped by the [PathFilter]
+    pub skipped_files: usize,
+    pub skipped_bytes: u64,
+}
+
+/// Where the filter is recorded for dpkg, so that stage 2 skips the same paths
+pub const DPKG_EXCLUDES: &str = "etc/dpkg/dpkg.cfg.d/aoscbootstrap-excludes";
+
+/// Paths not to extract from the packages (e.g. the documentation in minimal images)
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    /// A path is excluded if it or any of its parents matches
+    exclude: Vec<glob::Pattern>,
+    /// When not empty, only these locales are kept in `/usr/share/locale`
+    keep_locales: Vec<String>,
+}
+
+impl PathFilter {
+    /// Create a filter from glob patterns (e.g. `/usr/share/doc` or `/usr/share/man/*/man3`)
+    /// and the locales to keep (e.g. `en_US`)
+    pub fn new(exclude: &[String], keep_locales: &[String]) -> Result<Self> {
+        let exclude = exclude
+            .iter()
+            .map(|p| {
+                let pattern = format!("/{}", p.trim_start_matches('/').trim_end_matches('/'));
+                glob::Pattern::new(&pattern).with_context(|| format!("Invalid pattern `{}`", p))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(PathFilter {
+            exclude,
+            keep_locales: keep_locales.to_vec(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exclude.is_empty() && self.keep_locales.is_empty()
+    }
+
+    /// Whether the path (e.g. `/usr/share/doc/bash/README`) is excluded
+    pub fn excludes(&self, path: &str) -> bool {
+        let mut ancestor = Some(Path::new(path));
+        while let Some(path) = ancestor.filter(|p| *p != Path::new("/")) {
+            if self.exclude.iter().any(|p| p.matches_path(path)) {
+                return true;
             }
-            b"data.tar.zst" => {
-                decompress_tar_zst(entry, target)?;
-                return Ok(());
+            ancestor = path.parent();
+        }
+        // the files directly in /usr/share/locale (e.g. locale.alias) are kept
+        let locale = path
+            .strip_prefix("/usr/share/locale/")
+            .and_then(|p| p.split_once('/'))
+            .map(|(locale, _)| locale);
+
+        match locale {
+            Some(locale) if !self.keep_locales.is_empty() => !self.keeps_locale(locale),
+            _ => false,
+        }
+    }
+
+    /// `en_US` keeps `en_US`, `en_US.UTF-8`, `en_US@euro` and the `en` fallback
+    fn keeps_locale(&self, locale: &str) -> bool {
+        self.keep_locales.iter().any(|keep| {
+            locale == keep
+                || locale
+                    .strip_prefix(keep.as_str())
+                    .is_some_and(|rest| rest.starts_with(['.', '@']))
+                || keep.split_once('_').is_some_and(|(lang, _)| lang == locale)
+        })
+    }
+
+    /// The `path-exclude` and `path-include` options applying the filter in dpkg
+    pub fn dpkg_config(&self) -> String {
+        let mut config = String::new();
+        for pattern in &self.exclude {
+            config.push_str(&format!(
+                "path-exclude={}\npath-exclude={}/*\n",
+                pattern, pattern
+            ));
+        }
+        if !self.keep_locales.is_empty() {
+            config.push_str("path-exclude=/usr/share/locale/*/*\n");
+            for keep in &self.keep_locales {
+                let include = [
+                    keep.to_string(),
+                    format!("{}.*", keep),
+                    format!("{}@*", keep),
+                ];
+                let fallback = keep.split_once('_').map(|(lang, _)| lang.to_string());
+                for locale in include.into_iter().chain(fallback) {
+                    config.push_str(&format!("path-include=/usr/share/locale/{}/*\n", locale));
+                }
             }
-            _ => continue,
         }
+
+        config
     }
+}
+
+/// Where the extended attributes which could not be restored are recorded,
+/// in the format of `getfattr --dump` (so that `setfattr --restore` can apply them)
+pub const PENDING_XATTRS: &str = "var/lib/aoscbootstrap/pending-xattrs";
+
+/// Where an unprivileged bootstrap records the owners of the files which do not belong
+/// to root (one `uid:gid path` line per file), applied to the exported archives
+pub const OWNERSHIP: &str = "var/lib/aoscbootstrap/ownership";
+
+/// Record the owners of the files shipped by the `packages` in [OWNERSHIP], in addition
+/// to the ones already recorded (e.g. by the stage 1 of an updated target)
+pub fn record_ownership(
+    target: &Path,
+    packages: &[PackageMeta],
+    archive_path: &Path,
+) -> Result<()> {
+    let owners = packages
+        .par_iter()
+        .map(|package| -> Result<fs::Ownership> {
+            let deb = archive_path.join(package.file_name());
+            let mut ownership = fs::Ownership::default();
+            read_deb_owners(
+                File::open(&deb).with_context(|| format!("when opening {}", deb.display()))?,
+                &mut ownership,
+            )
+            .with_context(|| format!("when reading the owners in {}", deb.display()))?;
+            Ok(ownership)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let path = target.join(OWNERSHIP);
+    let mut ownership = if path.exists() {
+        read_ownership(target)?
+    } else {
+        fs::Ownership::default()
+    };
+    for owners in owners {
+        ownership.extend(owners);
+    }
+    fs::make_dirs(path.parent().unwrap(), fs::DIR_MODE)?;
+    fs::write_file(&path, ownership.to_lines(), fs::FILE_MODE)?;
+
+    Ok(())
+}
+
+/// The packages completely extracted by stage 1 so far, one `<file name> <checksum>` line
+/// per package: an interrupted stage 1 does not extract them again when it is resumed
+pub const EXTRACTED: &str = "var/lib/aoscbootstrap/extracted";
+
+/// The line of `package` in [EXTRACTED]
+pub fn extracted_entry(package: &PackageMeta) -> String {
+    let checksum = package.checksum().map(|(_, c)| c).unwrap_or_default();
 
-    Err(anyhow!("data archive not found or format unsupported"))
+    format!("{} {}", package.file_name(), checksum)
+}
+
+/// Read the lines of [EXTRACTED], if any (a line cut short by the interruption matches no package)
+pub fn read_extracted(target: &Path) -> Result<HashSet<String>> {
+    let path = target.join(EXTRACTED);
+    match std::fs::read_to_string(&path) {
+        Ok(content) => Ok(content.lines().map(|l| l.to_string()).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e).with_context(|| format!("when reading {}", path.display())),
+    }
+}
+
+/// Read the owners recorded by [record_ownership]
+pub fn read_ownership(target: &Path) -> Result<fs::Ownership> {
+    let path = target.join(OWNERSHIP);
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("when reading {}", path.display()))?;
+
+    fs::Ownership::parse(&content)
+}
+
+/// Extract the files of a deb package, the data archive is decompressed and unpacked
+/// on the fly (without a temporary copy). Hard links are created in the target, and the
+/// extended attributes (`SCHILY.xattr.*` PAX headers) are restored when permitted.
+pub fn extract_deb<R: Read>(
+    reader: R,
+    target: &Path,
+    filter: &PathFilter,
+    decoders: &mut Decoders,
+) -> Result<ExtractedDeb> {
+    with_deb_member_using(reader, "data", decoders, |tar_processor| {
+        extract_tar(tar_processor, target, filter, true)
+    })
+}
+
+/// Decompress the data archive of a deb package into memory, to extract it later with
+/// [extract_decoded_deb]
+pub fn decode_deb<R: Read>(reader: R, decoders: &mut Decoders) -> Result<Vec<u8>> {
+    with_deb_member_using(reader, "data", decoders, |tar_processor| {
+        let mut data = Vec::new();
+        tar_processor.into_inner().read_to_end(&mut data)?;
+
+        Ok(data)
+    })
+}
+
+/// [extract_deb] (or [scan_extracted_deb] if not `unpack`) for the data archive
+/// decompressed by [decode_deb]
+pub fn extract_decoded_deb(
+    data: &[u8],
+    target: &Path,
+    filter: &PathFilter,
+    unpack: bool,
+) -> Result<ExtractedDeb> {
+    extract_tar(TarArchive::new(data), target, filter, unpack)
+}
+
+/// What [extract_deb] returns for a deb package which has already been extracted into
+/// `target`, without extracting it again: the extended attributes missing from the
+/// extracted files are pending
+pub fn scan_extracted_deb<R: Read>(
+    reader: R,
+    target: &Path,
+    filter: &PathFilter,
+    decoders: &mut Decoders,
+) -> Result<ExtractedDeb> {
+    with_deb_member_using(reader, "data", decoders, |tar_processor| {
+        extract_tar(tar_processor, target, filter, false)
+    })
+}
+
+/// Extract the tarball into `target` (or only scan it if not `unpack`),
+/// skipping the paths excluded by the `filter`
+fn extract_tar<R: Read>(
+    mut tar_processor: TarArchive<R>,
+    target: &Path,
+    filter: &PathFilter,
+    unpack: bool,
+) -> Result<ExtractedDeb> {
+    // restored by unpack_entry, which tolerates the missing privileges
+    tar_processor.set_unpack_xattrs(false);
+    tar_processor.set_preserve_permissions(true);
+    tar_processor.set_preserve_ownerships(Uid::effective().is_root());
+    let mut extracted = ExtractedDeb {
+        files: Vec::new(),
+        pending_xattrs: Vec::new(),
+        skipped_files: 0,
+        skipped_bytes: 0,
+    };
+    // like Archive::unpack, the directories are unpacked last,
+    // so that their permissions do not prevent the creation of their contents
+    let mut directories = Vec::new();
+    for entry in tar_processor.entries()? {
+        let entry = entry?;
+        let name = entry_name(&entry.path()?);
+        let is_directory = entry.header().entry_type() == EntryType::Directory;
+        // never written at all, instead of being removed afterwards
+        if filter.excludes(&name) {
+            if !is_directory {
+                extracted.skipped_files += 1;
+                extracted.skipped_bytes += entry.header().size()?;
+            }
+            continue;
+        }
+        if !unpack {
+            if !is_directory {
+                scan_entry(entry, &name, target, &mut extracted.pending_xattrs)?;
+                extracted.files.push(name);
+            }
+            continue;
+        }
+        if is_directory {
+            directories.push((entry, name));
+            continue;
+        }
+        unpack_entry(entry, &name, target, &mut extracted.pending_xattrs)?;
+        extracted.files.push(name);
+    }
+    for (directory, name) in directories {
+        unpack_entry(directory, &name, target, &mut extracted.pending_xattrs)?;
+    }
+
+    Ok(extracted)
+}
+
+/// The absolute path of an archive entry, e.g. `./usr/bin/ping` -> `/usr/bin/ping`
+fn entry_name(path: &Path) -> String {
+    format!(
+        "/{}",
+        path.to_string_lossy()
+            .trim_start_matches('.')
+            .trim_matches('/')
+    )
+}
+
+/// The parent of the entry `path` if it does not exist yet, and would be created inside `target`
+fn missing_parent(target: &Path, path: &Path) -> Result<Option<PathBuf>> {
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        // skipped by unpack_in
+        return Ok(None);
+    }
+    let parent = match target.join(path).parent() {
+        Some(parent) if parent.symlink_metadata().is_err() => parent.to_path_buf(),
+        _ => return Ok(None),
+    };
+    // the closest existing ancestor may be a symbolic link pointing outside of the target
+    let existing = parent
+        .ancestors()
+        .find(|a| a.symlink_metadata().is_ok())
+        .unwrap_or(target);
+    if !existing.canonicalize()?.starts_with(target.canonicalize()?) {
+        return Ok(None);
+    }
+
+    Ok(Some(parent))
+}
+
+/// The extended attributes of the entry, from its `SCHILY.xattr.*` PAX headers
+fn entry_xattrs<R: Read>(entry: &mut tar::Entry<R>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if let Some(key) = extension.key_bytes().strip_prefix(b"SCHILY.xattr.") {
+                xattrs.push((
+                    String::from_utf8_lossy(key).to_string(),
+                    extension.value_bytes().to_vec(),
+                ));
+            }
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Collect the extended attributes of the entry `name` which the extracted file lacks
+fn scan_entry<R: Read>(
+    mut entry: tar::Entry<R>,
+    name: &str,
+    target: &Path,
+    pending: &mut Vec<PendingXattr>,
+) -> Result<()> {
+    if entry.header().entry_type() == EntryType::Symlink {
+        return Ok(());
+    }
+    let path = target.join(entry.path()?);
+    for (key, value) in entry_xattrs(&mut entry)? {
+        if xattr::get(&path, &key).ok().flatten().as_ref() != Some(&value) {
+            pending.push(PendingXattr {
+                path: name.to_string(),
+                name: key,
+                value,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpack the entry `name` and restore its extended attributes
+fn unpack_entry<R: Read>(
+    mut entry: tar::Entry<R>,
+    name: &str,
+    target: &Path,
+    pending: &mut Vec<PendingXattr>,
+) -> Result<()> {
+    let path = entry.path()?.into_owned();
+    let xattrs = entry_xattrs(&mut entry)?;
+    let kind = entry.header().entry_type();
+    // tar would create the parents missing from the archive with the umask
+    if let Some(parent) = missing_parent(target, &path)? {
+        fs::make_dirs(&parent, fs::DIR_MODE)?;
+    }
+    entry.unpack_in(target)?;
+    // setting them on a symbolic link would follow it
+    if kind == EntryType::Symlink {
+        return Ok(());
+    }
+    for (key, value) in xattrs {
+        match xattr::set(target.join(&path), &key, &value) {
+            Ok(()) => (),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::PermissionDenied | ErrorKind::Unsupported
+                ) =>
+            {
+                pending.push(PendingXattr {
+                    path: name.to_string(),
+                    name: key,
+                    value,
+                })
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("when setting {} on {}", key, name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Record the extended attributes which could not be restored in [PENDING_XATTRS]
+pub fn write_pending_xattrs(target: &Path, pending: &[PendingXattr]) -> Result<()> {
+    let mut dump = String::new();
+    for xattr in pending {
+        dump.push_str(&format!(
+            "# file: {}\n{}=0x{}\n\n",
+            xattr.path.trim_start_matches('/'),
+            xattr.name,
+            hex::encode(&xattr.value)
+        ));
+    }
+    let path = target.join(PENDING_XATTRS);
+    fs::make_dirs(path.parent().unwrap(), fs::DIR_MODE)?;
+    fs::write_file(&path, dump, fs::FILE_MODE)?;
+
+    Ok(())
 }
 
 pub fn read_config<P: AsRef<Path>>(path: P) -> Result<Config> {
@@ -77,11 +740,41 @@ pub fn read_config<P: AsRef<Path>>(path: P) -> Result<Config> {
     let mut content = String::new();
     content.reserve(4096);
     f.read_to_string(&mut content)?;
-    let config = toml::from_str(&content)?;
+    let config: Config = toml::from_str(&content)?;
+    validate_device_nodes(&config.device_nodes)?;
+    validate_system_config(config.hostname.as_deref(), &config.fstab)?;
+    for pin in &config.pin {
+        glob::Pattern::new(&pin.package)
+            .with_context(|| format!("Invalid package pattern `{}` in the pin", pin.package))?;
+    }
+    for name in config.guest_env.keys() {
+        crate::guest::check_env_name(name).context("in the [guest-env] section")?;
+    }
+    for dir in &config.directories {
+        let path = Path::new(dir);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            bail!(
+                "Invalid directory {:?} (must be an absolute path without `..`)",
+                dir
+            );
+        }
+    }
+    for name in &config.defer_triggers {
+        check_package_name(name).context("in defer-triggers")?;
+    }
 
     Ok(config)
 }
 
+/// Create the `directories` of the configuration in the target
+pub fn create_directories(target: &Path, directories: &[String]) -> Result<()> {
+    for dir in directories {
+        fs::make_dirs(&target.join(dir.trim_start_matches('/')), fs::DIR_MODE)?;
+    }
+
+    Ok(())
+}
+
 pub fn extract_bootstrap_pack(target: &Path) -> Result<()> {
     let reader = std::io::Cursor::new(BOOTSTRAP_PACK);
     decompress_tar_xz(reader, target)?;
@@ -89,13 +782,349 @@ pub fn extract_bootstrap_pack(target: &Path) -> Result<()> {
     Ok(())
 }
 
-fn generate_dpkg_install_script(packages: &[String]) -> String {
-    let mut package_list = String::new();
+/// Open a (zstd, xz or gzip compressed) tarball, the compression is detected from its content
+fn open_tarball(path: &Path) -> Result<TarArchive<Box<dyn Read>>> {
+    let mut f = File::open(path).with_context(|| format!("when opening {}", path.display()))?;
+    let mut magic = [0u8; 6];
+    let len = f.read(&mut magic)?;
+    f.seek(SeekFrom::Start(0))?;
+    let reader = std::io::BufReader::new(f);
+    let decompress: Box<dyn Read> = match &magic[..len] {
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Box::new(Decoder::new(reader)?),
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00] => Box::new(XzDecoder::new(reader)),
+        [0x1f, 0x8b, ..] => Box::new(GzDecoder::new(reader)),
+        _ => Box::new(reader),
+    };
+
+    Ok(TarArchive::new(decompress))
+}
+
+/// Make sure the custom bootstrap pack is a tarball which only contains paths inside the target
+pub fn check_bootstrap_pack(path: &Path) -> Result<()> {
+    let check = || -> Result<()> {
+        let mut tarball = open_tarball(path)?;
+        for entry in tarball.entries()? {
+            let entry = entry?;
+            let entry_path = entry.path()?;
+            if entry_path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(anyhow!(
+                    "{} points outside of the target",
+                    entry_path.display()
+                ));
+            }
+        }
+
+        Ok(())
+    };
+
+    check().with_context(|| format!("{} is not a valid bootstrap pack", path.display()))
+}
+
+/// Extract the custom bootstrap pack at `path` (instead of the embedded one),
+/// like the data of a package
+pub fn extract_custom_bootstrap_pack(path: &Path, target: &Path) -> Result<ExtractedDeb> {
+    check_bootstrap_pack(path)?;
+
+    extract_tar(open_tarball(path)?, target, &PathFilter::default(), true)
+}
+
+/// Generate the dpkg status database from the control files of the packages,
+/// which are all in the `state` (e.g. `installed`)
+fn generate_dpkg_status(controls: &[String], state: &str) -> String {
+    let mut status = String::new();
+    for control in controls {
+        for line in control.trim().lines() {
+            status.push_str(line);
+            status.push('\n');
+            if line.starts_with("Package:") {
+                status.push_str(&format!("Status: install ok {}\n", state));
+            }
+        }
+        status.push('\n');
+    }
+
+    status
+}
+
+/// Copy the control files and the file list of `package` to the dpkg `info_dir`,
+/// returning the control stanza
+fn register_package(info_dir: &Path, package: &PackageMeta, archive_path: &Path) -> Result<String> {
+    let deb = archive_path.join(package.file_name());
+    let control_dir = tempfile::tempdir()?;
+    extract_deb_member(File::open(&deb)?, "control", control_dir.path())
+        .with_context(|| format!("when reading the control files of {}", package.name))?;
+    // maintainer scripts, md5sums, conffiles, etc.
+    for entry in std::fs::read_dir(control_dir.path())? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "control" || !entry.file_type()?.is_file() {
+            continue;
+        }
+        std::fs::copy(
+            entry.path(),
+            info_dir.join(format!("{}.{}", package.name, name.to_string_lossy())),
+        )?;
+    }
+
+    let mut files = list_deb_files(File::open(&deb)?)?.join("\n");
+    files.push('\n');
+    fs::write_file(
+        &info_dir.join(format!("{}.list", package.name)),
+        files,
+        fs::FILE_MODE,
+    )?;
+
+    Ok(std::fs::read_to_string(control_dir.path().join("control"))?)
+}
+
+/// The `kind` file (e.g. `list` or `md5sums`) of an installed package in the dpkg `info_dir`
+pub fn package_info_file(info_dir: &Path, package: &PackageMeta, kind: &str) -> Option<PathBuf> {
+    // Multi-Arch: same packages are listed with their architecture
+    let qualified = format!("{}:{}", package.name, package.arch);
+    [package.name.as_str(), qualified.as_str()]
+        .iter()
+        .map(|name| info_dir.join(format!("{}.{}", name, kind)))
+        .find(|path| path.is_file())
+}
+
+/// The files of an installed package, from its list in the dpkg `info_dir`
+pub fn installed_files(info_dir: &Path, package: &PackageMeta) -> Result<Vec<String>> {
+    let Some(path) = package_info_file(info_dir, package, "list") else {
+        bail!(
+            "{} is not installed (no file list in {})",
+            package.name,
+            info_dir.display()
+        );
+    };
+
+    Ok(std::fs::read_to_string(&path)
+        .with_context(|| format!("when reading {}", path.display()))?
+        .lines()
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// The files shipped by the deb of `package` in the `archive_path`
+pub fn package_files(package: &PackageMeta, archive_path: &Path) -> Result<Vec<String>> {
+    let deb = archive_path.join(package.file_name());
+    list_deb_files(File::open(&deb).with_context(|| format!("when opening {}", deb.display()))?)
+}
+
+/// An entry of the data archive of a deb package
+pub struct DebEntry {
+    /// e.g. `/usr/bin/bash`
+    pub path: String,
+    pub kind: EntryType,
+    pub size: u64,
+    /// The target of a symbolic link (or of a hard link)
+    pub link: Option<String>,
+}
+
+/// The entries of the deb of `package` in the `archive_path`, with their sizes
+pub fn package_entries(package: &PackageMeta, archive_path: &Path) -> Result<Vec<DebEntry>> {
+    let deb = archive_path.join(package.file_name());
+    let f = File::open(&deb).with_context(|| format!("when opening {}", deb.display()))?;
+    with_deb_member(BufReader::new(f), "data", |mut tar_processor| {
+        let mut entries = Vec::new();
+        for entry in tar_processor.entries()? {
+            let entry = entry?;
+            entries.push(DebEntry {
+                path: entry_name(&entry.path()?),
+                kind: entry.header().entry_type(),
+                size: entry.header().size()?,
+                link: entry.link_name()?.map(|l| l.to_string_lossy().into_owned()),
+            });
+        }
+
+        Ok(entries)
+    })
+    .with_context(|| format!("when reading {}", deb.display()))
+}
+
+/// Update the dpkg database of an installed system (`--update`): the `removed` packages
+/// are forgotten, and the `unpacked` ones are registered to be configured by dpkg
+pub fn update_dpkg_database(
+    target: &Path,
+    removed: &[PackageMeta],
+    unpacked: &[PackageMeta],
+    archive_path: &Path,
+) -> Result<()> {
+    let admin_dir = target.join("var/lib/dpkg");
+    let info_dir = admin_dir.join("info");
+    let names = removed
+        .iter()
+        .chain(unpacked)
+        .map(|p| p.name.as_str())
+        .collect::<HashSet<_>>();
+    for entry in std::fs::read_dir(&info_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        // e.g. `python3.11.list`, the package names may contain dots
+        let Some((package, _)) = file_name.rsplit_once('.') else {
+            continue;
+        };
+        let package = package.split_once(':').map_or(package, |(name, _)| name);
+        if names.contains(package) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    let path = admin_dir.join("status");
+    let status = std::fs::read_to_string(&path)
+        .with_context(|| format!("when reading {}", path.display()))?;
+    let mut kept = status
+        .split("\n\n")
+        .filter(|stanza| {
+            let name = stanza
+                .lines()
+                .find_map(|l| l.strip_prefix("Package:"))
+                .map(str::trim);
+            !stanza.trim().is_empty() && !name.is_some_and(|n| names.contains(n))
+        })
+        .map(|stanza| format!("{}\n\n", stanza.trim()))
+        .collect::<String>();
+    let mut unpacked = unpacked.iter().collect::<Vec<_>>();
+    unpacked.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    let mut controls = Vec::new();
+    for package in unpacked {
+        controls.push(register_package(&info_dir, package, archive_path)?);
+    }
+    kept.push_str(&generate_dpkg_status(&controls, "unpacked"));
+    fs::write_file(&path, kept, fs::FILE_MODE)?;
+
+    Ok(())
+}
+
+/// Register the packages extracted by stage 1 in the dpkg database as unpacked: their
+/// maintainer scripts have not run yet, `dpkg --configure` installs them during stage 2
+pub fn write_dpkg_database(
+    target: &Path,
+    packages: &[PackageMeta],
+    archive_path: &Path,
+) -> Result<()> {
+    let admin_dir = target.join("var/lib/dpkg");
+    let info_dir = admin_dir.join("info");
+    fs::make_dirs(&info_dir, fs::DIR_MODE)?;
+    let mut packages = packages.iter().collect::<Vec<_>>();
+    packages.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    let mut controls = Vec::new();
     for package in packages {
-        package_list.push_str(&format!("'{}' ", package));
+        controls.push(register_package(&info_dir, package, archive_path)?);
     }
+    fs::write_file(
+        &admin_dir.join("status"),
+        generate_dpkg_status(&controls, "unpacked"),
+        fs::FILE_MODE,
+    )?;
+    for name in ["available", "diversions"] {
+        let path = admin_dir.join(name);
+        if !path.exists() {
+            fs::write_file(&path, b"", fs::FILE_MODE)?;
+        }
+    }
+
+    Ok(())
+}
 
-    INSTALL_SCRIPT_TPL.replacen("{}", &package_list, 1)
+/// Split the configuration `groups` into phases, each of them unpacks and then configures
+/// its groups, so that the pre-dependencies of a package are configured before it is unpacked
+fn install_phases(groups: &[Vec<PackageMeta>]) -> Vec<Range<usize>> {
+    let group_of = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(i, group)| group.iter().map(move |p| (p.name.as_str(), i)))
+        .collect::<HashMap<_, _>>();
+    // the last group of each phase
+    let mut ends = BTreeSet::new();
+    for (i, group) in groups.iter().enumerate() {
+        for dep in group.iter().flat_map(|p| &p.pre_depends) {
+            // a pre-dependency in the same cycle can not be configured first
+            if let Some(&j) = group_of.get(dep.as_str()).filter(|j| **j < i) {
+                ends.insert(j);
+            }
+        }
+    }
+    if !groups.is_empty() {
+        ends.insert(groups.len() - 1);
+    }
+    let mut start = 0;
+    ends.into_iter()
+        .map(|end| {
+            let phase = start..end + 1;
+            start = end + 1;
+            phase
+        })
+        .collect()
+}
+
+/// Unpack and configure the packages (in configuration order), see [install_phases]
+fn generate_dpkg_install_script(
+    install_order: &[Vec<PackageMeta>],
+    defer_triggers: &[String],
+    shell: &str,
+) -> String {
+    let mut phases = String::new();
+    for phase in install_phases(install_order) {
+        let groups = &install_order[phase];
+        phases.push_str("unpack");
+        for package in groups.iter().flatten() {
+            phases.push_str(&format!(" {}", shell_quote(&package.file_name())));
+        }
+        phases.push_str("\nconfigure");
+        for group in groups {
+            let names = group.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+            phases.push_str(&format!(" {}", shell_quote(&names.join(" "))));
+        }
+        phases.push('\n');
+    }
+    let packages = install_order.iter().map(|g| g.len()).sum::<usize>();
+
+    with_shebang(INSTALL_SCRIPT_TPL, shell)
+        .replacen("{}", &packages.to_string(), 1)
+        .replacen("{}", &install_order.len().to_string(), 1)
+        .replacen("{}", &defer_triggers.join(" "), 1)
+        .replacen("{}", phases.trim_end(), 1)
+}
+
+/// The slowest package groups to configure (with the pending triggers), slowest first,
+/// from the captured output of the stage 2 script
+pub fn slowest_configures(output: &str, count: usize) -> Vec<(String, Duration)> {
+    let mut started = HashMap::new();
+    let mut durations = Vec::new();
+    for line in output.lines() {
+        let Some(marker) = line.trim_end().strip_prefix(PROFILE_MARKER) else {
+            continue;
+        };
+        let mut fields = marker.trim_start().splitn(3, ' ');
+        let (Some(kind), Some(Ok(time)), Some(group)) = (
+            fields.next(),
+            fields.next().map(str::parse::<f64>),
+            fields.next(),
+        ) else {
+            continue;
+        };
+        match kind {
+            "configure-start" => {
+                started.insert(group, time);
+            }
+            "configure-end" => {
+                if let Some(start) = started.remove(group) {
+                    let duration = Duration::from_secs_f64((time - start).max(0.0));
+                    durations.push((group.to_string(), duration));
+                }
+            }
+            _ => (),
+        }
+    }
+    durations.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+    durations.truncate(count);
+
+    durations
 }
 
 pub fn generate_apt_extended_state(
@@ -104,8 +1133,7 @@ pub fn generate_apt_extended_state(
     all_packages: &[PackageMeta],
     main_arch: &str,
 ) -> Result<()> {
-    let extended_state = File::create(target.join("var/lib/apt/extended_states"))?;
-    let mut extended_state = BufWriter::new(extended_state);
+    let mut extended_state = Vec::new();
     let mut manual_installed = HashSet::new();
 
     for p in manual_pkgs {
@@ -122,20 +1150,971 @@ pub fn generate_apt_extended_state(
             pkg.name, main_arch
         )?;
     }
+    fs::write_file(
+        &target.join("var/lib/apt/extended_states"),
+        extended_state,
+        fs::FILE_MODE,
+    )?;
 
     Ok(())
 }
 
+/// Replace the interpreter line of a script template with `shell`
+fn with_shebang(template: &str, shell: &str) -> String {
+    let body = template.split_once('\n').map_or("", |(_, body)| body);
+
+    format!("#!{}\n{}", shell, body)
+}
+
+/// Quote `s` as a single argument of a shell script
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Run each of the `commands` with sh, the script fails if any of them does
+fn generate_smoke_test_script(commands: &[String], shell: &str) -> String {
+    let command_list = commands
+        .iter()
+        .map(|c| shell_quote(c))
+        .collect::<Vec<_>>()
+        .join(" \\\n");
+
+    with_shebang(SMOKE_TEST_TPL, shell).replacen("{}", &command_list, 1)
+}
+
+pub fn write_smoke_test_script(
+    commands: &[String],
+    shell: &str,
+    target: &Path,
+) -> Result<NamedTempFile> {
+    let mut f = NamedTempFile::new_in(target)?;
+    f.write_all(generate_smoke_test_script(commands, shell).as_bytes())?;
+
+    Ok(f)
+}
+
+/// Write the final `sources_list`, and check that apt can update from it if `apt_update` is set
+fn generate_sources_script(sources_list: &str, apt_update: bool) -> String {
+    let mut script = SOURCES_SCRIPT_TPL.replacen("{}", sources_list, 1);
+    if apt_update {
+        script.push_str(APT_UPDATE_SCRIPT);
+    }
+
+    script
+}
+
+/// Write the stage 2 script, which unpacks and configures the packages of `install_order`
+/// one group after another and then writes the final `sources_list`. The script is run by `shell`.
+/// The triggers of the `defer_triggers` packages are processed once, after all the groups.
 pub fn write_install_script(
-    packages: &[String],
+    install_order: &[Vec<PackageMeta>],
+    defer_triggers: &[String],
+    sources_list: &str,
+    apt_update: bool,
     cleanup: bool,
+    shell: &str,
     target: &Path,
 ) -> Result<NamedTempFile> {
     let mut f = NamedTempFile::new_in(target)?;
-    f.write_all(generate_dpkg_install_script(packages).as_bytes())?;
+    f.write_all(generate_dpkg_install_script(install_order, defer_triggers, shell).as_bytes())?;
+    f.write_all(generate_sources_script(sources_list, apt_update).as_bytes())?;
     if cleanup {
         f.write_all(CLEANUP_SCRIPT)?;
     }
 
     Ok(f)
 }
+
+const OCI_MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_CONFIG_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// Compression algorithms for the OCI image layer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LayerCompression {
+    Zstd,
+    #[default]
+    Gzip,
+    /// Not defined by the OCI specification, only some of the runtimes (e.g. podman) accept it
+    Xz,
+    None,
+}
+
+impl LayerCompression {
+    fn media_type(&self) -> &'static str {
+        match self {
+            LayerCompression::Zstd => "application/vnd.oci.image.layer.v1.tar+zstd",
+            LayerCompression::Gzip => "application/vnd.oci.image.layer.v1.tar+gzip",
+            LayerCompression::Xz => "application/vnd.oci.image.layer.v1.tar+xz",
+            LayerCompression::None => "application/vnd.oci.image.layer.v1.tar",
+        }
+    }
+
+    /// Check whether the compression level is supported by the algorithm
+    pub fn check_level(&self, level: i32) -> Result<()> {
+        let range = match self {
+            LayerCompression::Zstd => zstd::compression_level_range(),
+            LayerCompression::Gzip | LayerCompression::Xz => 0..=9,
+            LayerCompression::None => 0..=0,
+        };
+        if !range.contains(&level) {
+            return Err(anyhow!(
+                "Invalid compression level {} for {:?}, expected {} to {}",
+                level,
+                self,
+                range.start(),
+                range.end()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Wrap the writer with the encoder, using the default level of the algorithm if `level` is not set
+    fn encoder<W: Write>(&self, inner: W, level: Option<i32>) -> Result<LayerEncoder<W>> {
+        if let Some(level) = level {
+            self.check_level(level)?;
+        }
+        let encoder = match self {
+            LayerCompression::Zstd => LayerEncoder::Zstd(zstd::Encoder::new(
+                inner,
+                level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+            )?),
+            LayerCompression::Gzip => LayerEncoder::Gzip(GzEncoder::new(
+                inner,
+                level.map_or(Compression::default(), |l| Compression::new(l as u32)),
+            )),
+            LayerCompression::Xz => {
+                LayerEncoder::Xz(XzEncoder::new(inner, level.unwrap_or(6) as u32))
+            }
+            LayerCompression::None => LayerEncoder::None(inner),
+        };
+
+        Ok(encoder)
+    }
+}
+
+/// A streaming encoder for the OCI image layer
+enum LayerEncoder<W: Write> {
+    Zstd(zstd::Encoder<'static, W>),
+    Gzip(GzEncoder<W>),
+    Xz(XzEncoder<W>),
+    None(W),
+}
+
+impl<W: Write> LayerEncoder<W> {
+    /// Flush the remaining compressed data and return the inner writer
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            LayerEncoder::Zstd(e) => e.finish(),
+            LayerEncoder::Gzip(e) => e.finish(),
+            LayerEncoder::Xz(e) => e.finish(),
+            LayerEncoder::None(w) => Ok(w),
+        }
+    }
+}
+
+impl<W: Write> Write for LayerEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LayerEncoder::Zstd(e) => e.write(buf),
+            LayerEncoder::Gzip(e) => e.write(buf),
+            LayerEncoder::Xz(e) => e.write(buf),
+            LayerEncoder::None(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LayerEncoder::Zstd(e) => e.flush(),
+            LayerEncoder::Gzip(e) => e.flush(),
+            LayerEncoder::Xz(e) => e.flush(),
+            LayerEncoder::None(w) => w.flush(),
+        }
+    }
+}
+
+/// A writer wrapper that calculates the SHA256 checksum and the size of the written data
+struct DigestWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn new(inner: W) -> Self {
+        DigestWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Returns the inner writer, the digest (`sha256:...`) and the size of the written data
+    fn finish(self) -> (W, String, u64) {
+        let digest = format!("sha256:{:x}", self.hasher.finalize());
+
+        (self.inner, digest, self.size)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Map AOSC OS architecture names to the OCI (Go) architecture names
+fn oci_arch_name(arch: &str) -> &str {
+    match arch {
+        "i486" => "386",
+        "loongarch64" => "loong64",
+        "loongson3" | "mips64r6el" => "mips64le",
+        "ppc64el" => "ppc64le",
+        "armv4" | "armv6hf" | "armv7hf" => "arm",
+        _ => arch,
+    }
+}
+
+fn append_oci_blob<W: Write, R: Read>(
+    builder: &mut TarBuilder<W>,
+    digest: &str,
+    size: u64,
+    data: R,
+) -> Result<()> {
+    let path = format!("blobs/{}", digest.replacen(':', "/", 1));
+    let mut header = TarHeader::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, data)?;
+
+    Ok(())
+}
+
+fn append_oci_json<W: Write>(
+    builder: &mut TarBuilder<W>,
+    path: Option<&str>,
+    value: &serde_json::Value,
+) -> Result<(String, u64)> {
+    let content = serde_json::to_vec(value)?;
+    let digest = format!("sha256:{:x}", Sha256::digest(&content));
+    let size = content.len() as u64;
+    match path {
+        Some(path) => {
+            let mut header = TarHeader::new_gnu();
+            header.set_size(size);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, content.as_slice())?;
+        }
+        None => append_oci_blob(builder, &digest, size, content.as_slice())?,
+    }
+
+    Ok((digest, size))
+}
+
+/// Export the target root as an OCI image layout tarball (importable with `docker load` or `podman load`)
+pub fn write_oci_image(
+    root: &Path,
+    output: &Path,
+    arch: &str,
+    compression: LayerCompression,
+    level: Option<i32>,
+    mtime: u64,
+    owners: Option<&fs::Ownership>,
+) -> Result<()> {
+    // the layer is staged in a temporary file to calculate its digest without buffering it in memory
+    let layer_file = match output.parent() {
+        _ if fs::is_stdout(output) => tempfile::tempfile()?,
+        Some(parent) => tempfile::tempfile_in(parent)?,
+        None => tempfile::tempfile_in(".")?,
+    };
+    let diff_writer = DigestWriter::new(compression.encoder(DigestWriter::new(layer_file), level)?);
+    let builder = build_tarball_stream(diff_writer, root, mtime, owners)?;
+    let (compressor, diff_id, _) = builder.into_inner()?.finish();
+    let (mut layer_file, layer_digest, layer_size) = compressor.finish()?.finish();
+    layer_file.seek(SeekFrom::Start(0))?;
+
+    let mut builder = TarBuilder::new(fs::create_archive(output)?);
+    append_oci_json(
+        &mut builder,
+        Some("oci-layout"),
+        &json!({ "imageLayoutVersion": "1.0.0" }),
+    )?;
+    append_oci_blob(&mut builder, &layer_digest, layer_size, layer_file)?;
+    let config = json!({
+        "architecture": oci_arch_name(arch),
+        "os": "linux",
+        "config": {
+            "Env": ["PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"],
+            "Cmd": ["/bin/bash"],
+        },
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": [diff_id],
+        },
+    });
+    let (config_digest, config_size) = append_oci_json(&mut builder, None, &config)?;
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST_TYPE,
+        "config": {
+            "mediaType": OCI_CONFIG_TYPE,
+            "digest": config_digest,
+            "size": config_size,
+        },
+        "layers": [{
+            "mediaType": compression.media_type(),
+            "digest": layer_digest,
+            "size": layer_size,
+        }],
+    });
+    let (manifest_digest, manifest_size) = append_oci_json(&mut builder, None, &manifest)?;
+    let index = json!({
+        "schemaVersion": 2,
+        "manifests": [{
+            "mediaType": OCI_MANIFEST_TYPE,
+            "digest": manifest_digest,
+            "size": manifest_size,
+            "annotations": { "org.opencontainers.image.ref.name": "latest" },
+        }],
+    });
+    append_oci_json(&mut builder, Some("index.json"), &index)?;
+    fs::finish_archive(builder.into_inner()?, output)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn build_test_deb(extension: &str) -> Result<Vec<u8>> {
+    build_test_deb_with(
+        extension,
+        &[
+            ("./usr/bin/test", b"#!/bin/sh\n"),
+            ("./usr/share/doc/test/README", b"hello\n"),
+        ],
+    )
+}
+
+/// Build a deb package containing `files` (path, content)
+#[cfg(test)]
+pub fn build_test_deb_with(extension: &str, files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    fn build_tar(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+        let mut builder = TarBuilder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = TarHeader::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *content)?;
+        }
+
+        Ok(builder.into_inner()?)
+    }
+
+    build_test_deb_from_data(extension, build_tar(files)?)
+}
+
+/// Build a deb package with the (uncompressed) `data` archive
+#[cfg(test)]
+fn build_test_deb_from_data(extension: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+    build_test_deb_with_control(extension, b"Package: test\n", data)
+}
+
+/// Build a deb package with the `control` file and the (uncompressed) `data` archive
+#[cfg(test)]
+pub fn build_test_deb_with_control(
+    extension: &str,
+    control: &[u8],
+    data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    fn compress(data: Vec<u8>, extension: &str) -> Result<Vec<u8>> {
+        Ok(match extension {
+            ".zst" => zstd::encode_all(&data[..], 0)?,
+            ".xz" => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(&data)?;
+                encoder.finish()?
+            }
+            ".gz" => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?
+            }
+            _ => data,
+        })
+    }
+
+    let mut header = TarHeader::new_gnu();
+    header.set_size(control.len() as u64);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_cksum();
+    let mut tar = TarBuilder::new(Vec::new());
+    tar.append_data(&mut header, "./control", control)?;
+    let control = compress(tar.into_inner()?, extension)?;
+    let data = compress(data, extension)?;
+    let mut deb = ar::Builder::new(Vec::new());
+    let members: [(String, &[u8]); 3] = [
+        ("debian-binary".to_string(), b"2.0\n"),
+        (format!("control.tar{}", extension), &control),
+        (format!("data.tar{}", extension), &data),
+    ];
+    for (name, content) in members {
+        let header = ar::Header::new(name.into_bytes(), content.len() as u64);
+        deb.append(&header, content)?;
+    }
+
+    Ok(deb.into_inner()?)
+}
+
+#[test]
+fn test_extract_deb() -> Result<()> {
+    for extension in [".zst", ".xz", ".gz", ""] {
+        let deb = build_test_deb(extension)?;
+        let target = tempfile::tempdir()?;
+        assert_eq!(
+            extract_deb(
+                &deb[..],
+                target.path(),
+                &PathFilter::default(),
+                &mut Decoders::default(),
+            )?
+            .files,
+            ["/usr/bin/test", "/usr/share/doc/test/README"]
+        );
+        assert_eq!(
+            std::fs::read(target.path().join("usr/bin/test"))?,
+            b"#!/bin/sh\n"
+        );
+        assert_eq!(
+            std::fs::read(target.path().join("usr/share/doc/test/README"))?,
+            b"hello\n"
+        );
+        assert!(!target.path().join("control").exists());
+        assert_eq!(
+            list_deb_files(&deb[..])?,
+            ["/usr/bin/test", "/usr/share/doc/test/README"]
+        );
+
+        let control = tempfile::tempdir()?;
+        extract_deb_member(&deb[..], "control", control.path())?;
+        assert_eq!(
+            std::fs::read(control.path().join("control"))?,
+            b"Package: test\n"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_reuse_decoders() -> Result<()> {
+    let deb = build_test_deb(".zst")?;
+    let mut decoders = Decoders::default();
+    let target = tempfile::tempdir()?;
+    // the context is left in the middle of the frame
+    assert!(extract_deb(
+        &deb[..deb.len() - 16],
+        target.path(),
+        &PathFilter::default(),
+        &mut decoders
+    )
+    .is_err());
+    for _ in 0..2 {
+        let target = tempfile::tempdir()?;
+        extract_deb(
+            &deb[..],
+            target.path(),
+            &PathFilter::default(),
+            &mut decoders,
+        )?;
+        assert_eq!(
+            std::fs::read(target.path().join("usr/share/doc/test/README"))?,
+            b"hello\n"
+        );
+    }
+
+    let deb = build_test_deb(".xz")?;
+    let err = extract_deb(
+        &deb[..],
+        target.path(),
+        &PathFilter::default(),
+        &mut Decoders::new(Some(1024)),
+    )
+    .err()
+    .unwrap();
+    assert!(format!("{:#}", err).contains("limit"), "{:#}", err);
+    assert_eq!(zstd_window_log(8 * 1024 * 1024), 23);
+    assert_eq!(zstd_window_log(0), 10);
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_bootstrap_pack() -> Result<()> {
+    let tarball = |name: &[u8]| -> Result<Vec<u8>> {
+        let mut builder = TarBuilder::new(Vec::new());
+        let mut header = TarHeader::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(12);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_cksum();
+        builder.append(&header, &b"NAME=Custom\n"[..])?;
+        Ok(builder.into_inner()?)
+    };
+    let dir = tempfile::tempdir()?;
+    let pack = dir.path().join("pack.tar.zst");
+    std::fs::write(
+        &pack,
+        zstd::encode_all(&tarball(b"./etc/os-release")?[..], 0)?,
+    )?;
+    let target = dir.path().join("target");
+    std::fs::create_dir(&target)?;
+    let extracted = extract_custom_bootstrap_pack(&pack, &target)?;
+    assert_eq!(extracted.files, ["/etc/os-release"]);
+    assert_eq!(
+        std::fs::read_to_string(target.join("etc/os-release"))?,
+        "NAME=Custom\n"
+    );
+
+    std::fs::write(&pack, tarball(b"../escape")?)?;
+    let err = check_bootstrap_pack(&pack).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("points outside of the target"),
+        "{:#}",
+        err
+    );
+    std::fs::write(
+        &pack,
+        b"not a tarball, but long enough to fill a header block",
+    )?;
+    assert!(check_bootstrap_pack(&pack).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_extract_deb_links_and_xattrs() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // VFS_CAP_REVISION_2, cap_net_raw permitted and effective
+    let capability = [
+        1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut record = b"SCHILY.xattr.security.capability=".to_vec();
+    record.extend(capability);
+    record.push(b'\n');
+    // the length of a PAX record includes the length itself
+    let mut length = record.len() + 2;
+    while length != record.len() + 1 + length.to_string().len() {
+        length = record.len() + 1 + length.to_string().len();
+    }
+    let record = [format!("{} ", length).into_bytes(), record].concat();
+
+    let mut builder = TarBuilder::new(Vec::new());
+    let mut header = TarHeader::new_ustar();
+    header.set_entry_type(EntryType::XHeader);
+    header.set_size(record.len() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, "./PaxHeaders/ping", &record[..])?;
+    let mut header = TarHeader::new_gnu();
+    header.set_size(4);
+    header.set_mode(0o755);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_cksum();
+    builder.append_data(&mut header, "./usr/bin/ping", &b"ping"[..])?;
+    let mut header = TarHeader::new_gnu();
+    header.set_entry_type(EntryType::Link);
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_uid(0);
+    header.set_gid(0);
+    builder.append_link(&mut header, "./usr/bin/ping6", "./usr/bin/ping")?;
+    let deb = build_test_deb_from_data(".gz", builder.into_inner()?)?;
+
+    let target = tempfile::tempdir()?;
+    let extracted = extract_deb(
+        &deb[..],
+        target.path(),
+        &PathFilter::default(),
+        &mut Decoders::default(),
+    )?;
+    assert_eq!(extracted.files, ["/usr/bin/ping", "/usr/bin/ping6"]);
+    let ping = target.path().join("usr/bin/ping");
+    let ping6 = target.path().join("usr/bin/ping6");
+    assert_eq!(
+        std::fs::metadata(&ping)?.ino(),
+        std::fs::metadata(&ping6)?.ino()
+    );
+    assert_eq!(std::fs::read(&ping6)?, b"ping");
+    // restored when permitted, recorded otherwise
+    match extracted.pending_xattrs.first() {
+        Some(pending) => assert_eq!(pending.value, capability),
+        None => assert_eq!(
+            xattr::get(&ping, "security.capability")?.as_deref(),
+            Some(&capability[..])
+        ),
+    }
+    let pending = PendingXattr {
+        path: "/usr/bin/ping".to_string(),
+        name: "security.capability".to_string(),
+        value: capability.to_vec(),
+    };
+    write_pending_xattrs(target.path(), &[pending])?;
+    assert_eq!(
+        std::fs::read_to_string(target.path().join(PENDING_XATTRS))?,
+        format!(
+            "# file: usr/bin/ping\nsecurity.capability=0x{}\n\n",
+            hex::encode(capability)
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_path_filter() -> Result<()> {
+    let filter = PathFilter::new(
+        &[
+            "/usr/share/doc".to_string(),
+            "usr/share/man/*/man3/".to_string(),
+        ],
+        &["en_US".to_string()],
+    )?;
+    for path in [
+        "/usr/share/doc",
+        "/usr/share/doc/bash/README",
+        "/usr/share/man/zh_CN/man3/printf.3",
+        "/usr/share/locale/zh_CN/LC_MESSAGES/bash.mo",
+    ] {
+        assert!(filter.excludes(path), "{}", path);
+    }
+    for path in [
+        "/usr/bin/bash",
+        "/usr/share/documentation",
+        "/usr/share/man/man1/bash.1",
+        "/usr/share/locale/locale.alias",
+        "/usr/share/locale/en_US/LC_MESSAGES/bash.mo",
+        "/usr/share/locale/en_US.UTF-8/LC_MESSAGES/bash.mo",
+        "/usr/share/locale/en/LC_MESSAGES/bash.mo",
+    ] {
+        assert!(!filter.excludes(path), "{}", path);
+    }
+    assert!(filter.dpkg_config().starts_with(
+        "path-exclude=/usr/share/doc\npath-exclude=/usr/share/doc/*\npath-exclude=/usr/share/man/*/man3\n"
+    ));
+    assert!(filter
+        .dpkg_config()
+        .ends_with("path-exclude=/usr/share/locale/*/*\npath-include=/usr/share/locale/en_US/*\npath-include=/usr/share/locale/en_US.*/*\npath-include=/usr/share/locale/en_US@*/*\npath-include=/usr/share/locale/en/*\n"));
+    assert!(PathFilter::new(&["/usr/[".to_string()], &[]).is_err());
+
+    let target = tempfile::tempdir()?;
+    let filter = PathFilter::new(&["/usr/share/doc".to_string()], &[])?;
+    let extracted = extract_deb(
+        &build_test_deb(".gz")?[..],
+        target.path(),
+        &filter,
+        &mut Decoders::default(),
+    )?;
+    assert_eq!(extracted.files, ["/usr/bin/test"]);
+    assert_eq!((extracted.skipped_files, extracted.skipped_bytes), (1, 6));
+    assert!(!target.path().join("usr/share/doc").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_sources_script() {
+    let sources_list = "deb https://repo.aosc.io/debs stable main\n";
+    let script = generate_sources_script(sources_list, true);
+    assert!(script
+        .contains("<< 'EOF_SOURCES'\ndeb https://repo.aosc.io/debs stable main\nEOF_SOURCES\n"));
+    assert!(script.contains("apt-get update"));
+    assert!(!generate_sources_script(sources_list, false).contains("apt-get update"));
+}
+
+#[test]
+fn test_smoke_test_script() -> Result<()> {
+    let commands = ["true".to_string(), "test \"it's\" = \"it's\"".to_string()];
+    let script = generate_smoke_test_script(&commands, "/bin/sh");
+    assert!(script.starts_with("#!/bin/sh\n"));
+    assert!(script.contains("set -- \\\n'true' \\\n'test \"it'\\''s\" = \"it'\\''s\"'\n"));
+
+    if which::which("sh").is_err() {
+        return Ok(());
+    }
+    let dir = tempfile::tempdir()?;
+    let run = |commands: &[String]| -> Result<bool> {
+        let script = write_smoke_test_script(commands, "/bin/sh", dir.path())?;
+        let status = std::process::Command::new("sh")
+            .args(["-e", "-u"])
+            .arg(script.path())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?;
+        Ok(status.success())
+    };
+    assert!(run(&commands)?);
+    assert!(!run(&["true".to_string(), "false".to_string()])?);
+
+    Ok(())
+}
+
+#[test]
+fn test_scripts_syntax() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let install_order = [
+        vec![PackageMeta::for_test("glibc")],
+        vec![PackageMeta::for_test("bash")],
+    ];
+    for shell in ["sh", "dash", "bash"] {
+        let Ok(path) = which::which(shell) else {
+            continue;
+        };
+        let path = path.to_string_lossy();
+        let install = write_install_script(
+            &install_order,
+            &["man-db".to_string()],
+            "deb https://repo.aosc.io/debs stable main\n",
+            true,
+            true,
+            &path,
+            dir.path(),
+        )?;
+        let smoke_test = write_smoke_test_script(&["true".to_string()], &path, dir.path())?;
+        for script in [install.path(), smoke_test.path()] {
+            // the shell accepts its default options, and the script parses
+            let status = std::process::Command::new(path.as_ref())
+                .args(crate::guest::default_shell_flags(&path))
+                .arg("-n")
+                .arg(script)
+                .status()?;
+            assert!(status.success(), "{} -n {}", path, script.display());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_dpkg_status() {
+    let controls = [
+        "Package: bash\nVersion: 5.2.15\nArchitecture: amd64\nDepends: glibc\n".to_string(),
+        "Package: glibc\nVersion: 2.38\nArchitecture: amd64\nDescription: GNU C Library\n Multi-line description\n".to_string(),
+    ];
+    let expected = "\
+Package: bash
+Status: install ok unpacked
+Version: 5.2.15
+Architecture: amd64
+Depends: glibc
+
+Package: glibc
+Status: install ok unpacked
+Version: 2.38
+Architecture: amd64
+Description: GNU C Library
+ Multi-line description
+
+";
+    assert_eq!(generate_dpkg_status(&controls, "unpacked"), expected);
+}
+
+#[test]
+fn test_record_ownership() -> Result<()> {
+    let mut builder = TarBuilder::new(Vec::new());
+    for (path, gid) in [("./etc/passwd", 0), ("./etc/shadow", 42)] {
+        let mut header = TarHeader::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o640);
+        header.set_uid(0);
+        header.set_gid(gid);
+        header.set_cksum();
+        builder.append_data(&mut header, path, std::io::empty())?;
+    }
+    let archive = tempfile::tempdir()?;
+    std::fs::write(
+        archive.path().join("shadow_1.0_all.deb"),
+        build_test_deb_from_data(".gz", builder.into_inner()?)?,
+    )?;
+    let package = PackageMeta {
+        path: "pool/stable/main/shadow_1.0_all.deb".to_string(),
+        ..PackageMeta::for_test("shadow")
+    };
+    let target = tempfile::tempdir()?;
+    record_ownership(target.path(), &[package], archive.path())?;
+
+    assert_eq!(
+        std::fs::read_to_string(target.path().join(OWNERSHIP))?,
+        "0:42 etc/shadow\n"
+    );
+    assert_eq!(
+        read_ownership(target.path())?.owner(Path::new("etc/shadow")),
+        (0, 42)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_write_oci_image() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    std::fs::create_dir_all(root.path().join("etc"))?;
+    std::fs::write(root.path().join("etc/os-release"), "NAME=\"AOSC OS\"\n")?;
+    let output = tempfile::tempdir()?;
+    for compression in LayerCompression::value_variants() {
+        let path = output.path().join("image.tar");
+        write_oci_image(root.path(), &path, "amd64", *compression, None, 0, None)?;
+
+        let mut blobs = std::collections::HashMap::new();
+        for entry in TarArchive::new(File::open(&path)?).entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            blobs.insert(name.replacen("blobs/sha256/", "sha256:", 1), content);
+        }
+        let index: serde_json::Value = serde_json::from_slice(&blobs["index.json"])?;
+        let manifest = index["manifests"][0]["digest"].as_str().unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&blobs[manifest])?;
+        let layer = &manifest["layers"][0];
+        assert_eq!(layer["mediaType"], compression.media_type());
+        let layer = &blobs[layer["digest"].as_str().unwrap()];
+        let decoder: Box<dyn Read> = match compression {
+            LayerCompression::Zstd => Box::new(Decoder::new(layer.as_slice())?),
+            LayerCompression::Gzip => Box::new(GzDecoder::new(layer.as_slice())),
+            LayerCompression::Xz => Box::new(XzDecoder::new(layer.as_slice())),
+            LayerCompression::None => Box::new(layer.as_slice()),
+        };
+        let mut layer = TarArchive::new(decoder);
+        assert!(layer
+            .entries()?
+            .any(|e| e.unwrap().path().unwrap().ends_with("etc/os-release")));
+    }
+    assert!(LayerCompression::Gzip.check_level(10).is_err());
+    assert!(LayerCompression::Zstd.check_level(19).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_pre_depends_order() -> Result<()> {
+    use crate::solv;
+
+    let manifest = solv::test_manifest(&[
+        ("glibc", ""),
+        ("bash", "Depends: glibc\n"),
+        ("xz", "Depends: glibc\n"),
+        ("dpkg", "Pre-Depends: xz\nDepends: bash\n"),
+        ("apt", "Depends: dpkg\n"),
+    ])?;
+    let mut pool = solv::Pool::new();
+    solv::populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let install_order = pool
+        .resolve(&["apt".parse()?], &[], &[], false)?
+        .install_order()?;
+    let dpkg = install_order
+        .iter()
+        .flatten()
+        .find(|p| p.name == "dpkg")
+        .unwrap();
+    assert_eq!(dpkg.pre_depends, ["xz"]);
+
+    let script = generate_dpkg_install_script(&install_order, &[], "/bin/dash");
+    assert!(script.starts_with("#!/bin/dash\n# === bootstrap.sh\n"));
+    let position = |needle: &str| script.find(needle).unwrap();
+    // xz (and glibc, which it depends on) is configured before dpkg is unpacked
+    let configure_xz = position("configure 'glibc' 'xz'\n");
+    assert!(configure_xz < position("'dpkg_1.0_all.deb'"));
+    assert!(position("unpack 'glibc_1.0_all.deb' 'xz_1.0_all.deb'\n") < configure_xz);
+    // the packages without pre-dependencies are unpacked all at once
+    assert_eq!(script.matches("\nunpack ").count(), 2);
+    assert!(script.contains("length=5\n"));
+    assert!(script.contains("deferred=''\n"));
+    let script = generate_dpkg_install_script(
+        &install_order,
+        &["fontconfig".to_string(), "man-db".to_string()],
+        "/bin/dash",
+    );
+    assert!(script.contains("deferred='fontconfig man-db'\n"));
+    assert!(script.contains("configure 'glibc' 'xz'\n"));
+
+    // the quotes of the file names cannot end the arguments
+    let quoted = PackageMeta {
+        version: "1'2".to_string(),
+        ..PackageMeta::for_test("quoted")
+    };
+    let script = generate_dpkg_install_script(&[vec![quoted]], &[], "/bin/dash");
+    assert!(
+        script.contains("unpack 'quoted_1'\\''2_all.deb'\n"),
+        "{}",
+        script
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_slowest_configures() {
+    let output = "\
+[1/3] Configuring glibc...
+@@aoscbootstrap configure-start 100.0 glibc
+@@aoscbootstrap configure-end 100.5 glibc
+@@aoscbootstrap configure-start 100.5 fontconfig libxft
+Regenerating the font cache ...\r
+@@aoscbootstrap configure-end 112.25 fontconfig libxft\r
+@@aoscbootstrap configure-start 112.25 bash
+@@aoscbootstrap configure-end 112.5 bash
+@@aoscbootstrap configure-start 112.5 (pending triggers)
+@@aoscbootstrap configure-end 114.5 (pending triggers)
+@@aoscbootstrap configure-start 114.5 broken
+";
+    let slowest = slowest_configures(output, 3);
+    let names = slowest.iter().map(|(g, _)| g.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["fontconfig libxft", "(pending triggers)", "glibc"]);
+    assert_eq!(slowest[0].1, Duration::from_millis(11750));
+}
+
+#[test]
+fn test_create_directories() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config_path = dir.path().join("config.toml");
+    let write_config = |directories: &str| {
+        std::fs::write(
+            &config_path,
+            format!(
+                "stub-packages = []\nbase-packages = []\ndirectories = {}\n",
+                directories
+            ),
+        )
+    };
+    write_config(r#"["/usr/lib/systemd/system-preset", "/etc/systemd/system/"]"#)?;
+    let config = read_config(&config_path)?;
+    let target = dir.path().join("target");
+    create_directories(&target, &config.directories)?;
+    assert!(target.join("usr/lib/systemd/system-preset").is_dir());
+    assert!(target.join("etc/systemd/system").is_dir());
+
+    for invalid in [r#"["usr/lib"]"#, r#"["/usr/../../etc"]"#] {
+        write_config(invalid)?;
+        assert!(read_config(&config_path).is_err());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,27 @@
+//! Helper for bootstrapping AOSC OS from scratch.
+//!
+//! The whole pipeline (dependency resolution, download, stage 1 and stage 2, export)
+//! is driven by [Bootstrap], the `aoscbootstrap` binary is a thin wrapper over it.
+
+mod bootstrap;
+pub mod cancel;
+pub mod completions;
+pub mod failure;
+pub mod fs;
+pub mod guest;
+pub mod install;
+mod lockfile;
+pub mod logging;
+pub mod network;
+mod progress;
+pub mod ratelimit;
+pub mod recipe;
+pub mod sbom;
+mod solv;
+mod timings;
+mod topics;
+mod update;
+mod verify;
+
+pub use bootstrap::{Bootstrap, ExportOptions, PostResolve, DEFAULT_MIRROR};
+pub use solv::{PackageMeta, ResolutionError};
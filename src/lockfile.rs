@@ -0,0 +1,515 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    solv::{PackageMeta, RedundantRequest},
+    timings::Timing,
+};
+
+/// A resolved package. Serializes to the lockfile.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct LockedPackage {
+    name: String,
+    version: String,
+    arch: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha512: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    md5: Option<String>,
+    /// Installed size in bytes
+    install_size: u64,
+    /// Size of the .deb file in bytes
+    #[serde(default)]
+    download_size: u64,
+    /// Packages whose files may be overwritten by this package
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    replaces: Vec<String>,
+    /// Packages which must be configured before this package is unpacked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pre_depends: Vec<String>,
+    #[serde(default)]
+    in_topic: bool,
+    /// Packages with the same group number are configured together
+    group: usize,
+    /// Whether the package is extracted during stage 1
+    #[serde(default)]
+    stage1: bool,
+}
+
+impl LockedPackage {
+    fn new(meta: &PackageMeta, group: usize, stage1: bool) -> Self {
+        LockedPackage {
+            name: meta.name.clone(),
+            version: meta.version.clone(),
+            arch: meta.arch.clone(),
+            path: meta.path.clone(),
+            sha256: meta.sha256.clone(),
+            sha512: meta.sha512.clone(),
+            md5: meta.md5.clone(),
+            install_size: meta.install_size,
+            download_size: meta.download_size,
+            replaces: meta.replaces.clone(),
+            pre_depends: meta.pre_depends.clone(),
+            in_topic: meta.in_topic,
+            group,
+            stage1,
+        }
+    }
+}
+
+impl From<&LockedPackage> for PackageMeta {
+    fn from(package: &LockedPackage) -> Self {
+        PackageMeta {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            sha256: package.sha256.clone(),
+            sha512: package.sha512.clone(),
+            md5: package.md5.clone(),
+            // only checked during the dependency resolution
+            essential: false,
+            priority: None,
+            path: package.path.clone(),
+            arch: package.arch.clone(),
+            in_topic: package.in_topic,
+            install_size: package.install_size,
+            download_size: package.download_size,
+            replaces: package.replaces.clone(),
+            pre_depends: package.pre_depends.clone(),
+        }
+    }
+}
+
+/// The result of the dependency resolution, which can be saved to and replayed from a file
+#[derive(Deserialize, Serialize, Default)]
+pub struct Lockfile {
+    #[serde(rename = "package", default)]
+    packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+    /// Create a lockfile from the install order and the packages extracted during stage 1,
+    /// each package is only installed (and counted in the sizes) once
+    pub fn new(install_order: &[Vec<PackageMeta>], stub_packages: &[PackageMeta]) -> Self {
+        let mut packages = Vec::new();
+        let mut seen = HashSet::new();
+        for (group, metas) in install_order.iter().enumerate() {
+            for meta in metas {
+                if !seen.insert((&meta.name, &meta.arch)) {
+                    continue;
+                }
+                let stage1 = stub_packages
+                    .iter()
+                    .any(|p| p.name == meta.name && p.version == meta.version);
+                packages.push(LockedPackage::new(meta, group, stage1));
+            }
+        }
+
+        Lockfile { packages }
+    }
+
+    /// Rewrite the packages with `f`, keeping the position (group and stage 1) of the
+    /// packages by name (see [crate::Bootstrap::post_resolve])
+    pub fn rewrite<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<PackageMeta>) -> Result<()>,
+    {
+        let mut packages = self.packages();
+        f(&mut packages)?;
+        let last = self.packages.iter().map(|p| p.group + 1).max().unwrap_or(0);
+        let mut rewritten = packages
+            .iter()
+            .map(
+                |meta| match self.packages.iter().find(|p| p.name == meta.name) {
+                    Some(old) => LockedPackage::new(meta, old.group, old.stage1),
+                    None => LockedPackage::new(meta, last, false),
+                },
+            )
+            .collect::<Vec<_>>();
+        // the install order is made of the consecutive packages of the same group
+        rewritten.sort_by_key(|p| p.group);
+        self.packages = rewritten;
+
+        Ok(())
+    }
+
+    /// Read a lockfile, or the packages of a build report (`.json`)
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("when reading lockfile {}", path.display()))?;
+        let lockfile = if path.extension().is_some_and(|e| e == "json") {
+            serde_json::from_str(&content)
+                .with_context(|| format!("when parsing build report {}", path.display()))?
+        } else {
+            toml::from_str(&content)
+                .with_context(|| format!("when parsing lockfile {}", path.display()))?
+        };
+
+        Ok(lockfile)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        crate::fs::write_atomic(path, toml::to_string(self)?.as_bytes())
+            .with_context(|| format!("when writing lockfile {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// All the packages, in installation order
+    pub fn packages(&self) -> Vec<PackageMeta> {
+        self.packages.iter().map(PackageMeta::from).collect()
+    }
+
+    /// The packages grouped by configuration order
+    pub fn install_order(&self) -> Vec<Vec<PackageMeta>> {
+        let mut groups: Vec<Vec<PackageMeta>> = Vec::new();
+        let mut last_group = None;
+        for package in &self.packages {
+            if last_group != Some(package.group) {
+                groups.push(Vec::new());
+                last_group = Some(package.group);
+            }
+            groups.last_mut().unwrap().push(package.into());
+        }
+
+        groups
+    }
+
+    /// The packages extracted during stage 1
+    pub fn stub_packages(&self) -> Vec<PackageMeta> {
+        self.packages
+            .iter()
+            .filter(|p| p.stage1)
+            .map(PackageMeta::from)
+            .collect()
+    }
+
+    /// Total installed size in KiB
+    pub fn install_size(&self) -> u64 {
+        self.packages.iter().map(|p| p.install_size).sum::<u64>() / 1024
+    }
+
+    /// Total download size in bytes
+    pub fn download_size(&self) -> u64 {
+        self.packages.iter().map(|p| p.download_size).sum()
+    }
+
+    /// Installed size of the stage 1 packages in KiB
+    pub fn stub_install_size(&self) -> u64 {
+        self.packages
+            .iter()
+            .filter(|p| p.stage1)
+            .map(|p| p.install_size)
+            .sum::<u64>()
+            / 1024
+    }
+}
+
+/// The changes between the packages of two lockfiles (`--update`)
+#[derive(Debug, Default)]
+pub struct PackageDiff {
+    pub added: Vec<PackageMeta>,
+    pub removed: Vec<PackageMeta>,
+    /// The new versions of the packages whose version or checksum has changed
+    pub upgraded: Vec<PackageMeta>,
+    /// The previous versions of the upgraded packages
+    pub replaced: Vec<PackageMeta>,
+}
+
+impl PackageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.upgraded.is_empty()
+    }
+
+    /// The packages to be downloaded and extracted
+    pub fn changed(&self) -> Vec<PackageMeta> {
+        self.added.iter().chain(&self.upgraded).cloned().collect()
+    }
+}
+
+impl Lockfile {
+    /// The changes from the packages of this lockfile to the ones of `new`
+    pub fn diff(&self, new: &Lockfile) -> PackageDiff {
+        let old = self
+            .packages
+            .iter()
+            .map(|p| (p.name.as_str(), p))
+            .collect::<HashMap<_, _>>();
+        let new_names = new
+            .packages
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut diff = PackageDiff::default();
+        for package in &new.packages {
+            match old.get(package.name.as_str()) {
+                None => diff.added.push(package.into()),
+                Some(previous)
+                    if previous.version != package.version
+                        || (&previous.sha512, &previous.sha256, &previous.md5)
+                            != (&package.sha512, &package.sha256, &package.md5) =>
+                {
+                    diff.upgraded.push(package.into());
+                    diff.replaced.push(PackageMeta::from(*previous));
+                }
+                Some(_) => (),
+            }
+        }
+        diff.removed = self
+            .packages
+            .iter()
+            .filter(|p| !new_names.contains(p.name.as_str()))
+            .map(PackageMeta::from)
+            .collect();
+
+        diff
+    }
+}
+
+/// A machine-readable report of a bootstrap. The packages are listed like in the
+/// lockfile, so that the report can be replayed with `--lock`.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Report<'a> {
+    pub mirrors: &'a [String],
+    pub branches: &'a [String],
+    pub arches: &'a [&'a str],
+    pub components: &'a [&'a str],
+    pub topics: &'a [String],
+    /// The included packages which the other requested packages already require
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub redundant_includes: &'a [RedundantRequest],
+    /// Durations of the phases before the report (with `--timings`)
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub timings: &'a [Timing],
+    #[serde(flatten)]
+    pub lockfile: &'a Lockfile,
+}
+
+impl Report<'_> {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let packages = self.lockfile.packages();
+        let mut report = serde_json::to_value(self)?;
+        report["version"] = env!("CARGO_PKG_VERSION").into();
+        report["download-size"] = self.lockfile.download_size().into();
+        report["install-size"] = packages.iter().map(|p| p.install_size).sum::<u64>().into();
+        let report = serde_json::to_vec_pretty(&report)?;
+        crate::fs::write_atomic(path, &report)
+            .with_context(|| format!("when writing build report {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Directory of the cached resolutions (`$XDG_CACHE_HOME/aoscbootstrap/resolutions`)
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))?;
+
+    Some(cache.join("aoscbootstrap/resolutions"))
+}
+
+/// Path of the cached resolution in `dir`, keyed by the hash of the manifests and of the
+/// resolution parameters (requested packages, excludes, ...)
+pub fn cache_path(
+    dir: &Path,
+    manifests: &[PathBuf],
+    parameters: &serde_json::Value,
+) -> Result<PathBuf> {
+    let mut hasher = Sha256::new();
+    // the format of the lockfile may change between versions
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    for path in manifests {
+        let mut f = File::open(path).with_context(|| format!("when opening {}", path.display()))?;
+        hasher.update(path.file_name().unwrap_or_default().as_encoded_bytes());
+        std::io::copy(&mut f, &mut hasher)?;
+    }
+    hasher.update(parameters.to_string());
+
+    Ok(dir.join(format!("{}.toml", hex::encode(hasher.finalize()))))
+}
+
+#[test]
+fn test_cache_path() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Packages");
+    std::fs::write(&manifest, "Package: bash\nVersion: 5.2\n")?;
+    let manifests = [manifest.clone()];
+    let parameters = serde_json::json!({ "requests": ["bash"] });
+    let key = cache_path(dir.path(), &manifests, &parameters)?;
+
+    assert_eq!(key, cache_path(dir.path(), &manifests, &parameters)?);
+    assert_ne!(
+        key,
+        cache_path(
+            dir.path(),
+            &manifests,
+            &serde_json::json!({ "requests": ["bash", "zsh"] })
+        )?
+    );
+    std::fs::write(&manifest, "Package: bash\nVersion: 5.3\n")?;
+    assert_ne!(key, cache_path(dir.path(), &manifests, &parameters)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_report() -> Result<()> {
+    let package = PackageMeta {
+        version: "5.2".to_string(),
+        path: "pool/stable/main/b/bash_5.2_amd64.deb".to_string(),
+        arch: "amd64".to_string(),
+        install_size: 2048,
+        download_size: 1000,
+        ..PackageMeta::for_test("bash")
+    };
+    let lockfile = Lockfile::new(&[vec![package]], &[]);
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("report.json");
+    Report {
+        mirrors: &["https://repo.aosc.io/debs".to_string()],
+        branches: &["stable".to_string()],
+        arches: &["amd64", "all"],
+        components: &["main"],
+        topics: &[],
+        redundant_includes: &[RedundantRequest {
+            name: "bash".to_string(),
+            required_by: "base".to_string(),
+        }],
+        timings: &[],
+        lockfile: &lockfile,
+    }
+    .write(&path)?;
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+
+    assert_eq!(report["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(report["branches"], serde_json::json!(["stable"]));
+    assert_eq!(report["install-size"], 2048);
+    assert_eq!(report["redundant-includes"][0]["required-by"], "base");
+    assert_eq!(report["package"][0]["sha256"], "0".repeat(64));
+    // the report can be replayed like a lockfile
+    let replayed = Lockfile::read(&path)?.packages();
+    assert_eq!(replayed.len(), 1);
+    assert_eq!(replayed[0].version, "5.2");
+
+    Ok(())
+}
+
+#[test]
+fn test_lockfile_roundtrip() -> Result<()> {
+    let package = |name: &str| PackageMeta {
+        path: format!("pool/stable/main/{}.deb", name),
+        arch: "amd64".to_string(),
+        install_size: 2048,
+        download_size: 1000,
+        ..PackageMeta::for_test(name)
+    };
+    let order = vec![vec![package("glibc")], vec![package("a"), package("b")]];
+    let lockfile = Lockfile::new(&order, &[package("glibc")]);
+    let file = tempfile::NamedTempFile::new()?;
+    lockfile.write(file.path())?;
+    let lockfile = Lockfile::read(file.path())?;
+
+    let names =
+        |packages: Vec<PackageMeta>| packages.into_iter().map(|p| p.name).collect::<Vec<_>>();
+    assert_eq!(names(lockfile.packages()), ["glibc", "a", "b"]);
+    assert_eq!(names(lockfile.stub_packages()), ["glibc"]);
+    assert_eq!(
+        lockfile
+            .install_order()
+            .into_iter()
+            .map(names)
+            .collect::<Vec<_>>(),
+        [vec!["glibc"], vec!["a", "b"]]
+    );
+    assert_eq!(lockfile.install_size(), 6);
+    assert_eq!(lockfile.stub_install_size(), 2);
+    assert_eq!(lockfile.download_size(), 3000);
+
+    Ok(())
+}
+
+#[test]
+fn test_lockfile_rewrite() -> Result<()> {
+    let package = |name: &str| PackageMeta {
+        path: format!("pool/stable/main/{}.deb", name),
+        arch: "amd64".to_string(),
+        install_size: 2048,
+        download_size: 1000,
+        ..PackageMeta::for_test(name)
+    };
+    let order = vec![vec![package("glibc")], vec![package("a"), package("b")]];
+    let mut lockfile = Lockfile::new(&order, &[package("glibc")]);
+    lockfile.rewrite(|packages| {
+        packages.retain(|p| p.name != "a");
+        packages.insert(0, package("vendor-keyring"));
+        packages[1].version = "1.0+fork".to_string();
+        Ok(())
+    })?;
+
+    let names =
+        |packages: Vec<PackageMeta>| packages.into_iter().map(|p| p.name).collect::<Vec<_>>();
+    assert_eq!(
+        lockfile
+            .install_order()
+            .into_iter()
+            .map(names)
+            .collect::<Vec<_>>(),
+        [vec!["glibc"], vec!["b"], vec!["vendor-keyring"]]
+    );
+    assert_eq!(names(lockfile.stub_packages()), ["glibc"]);
+    assert_eq!(lockfile.packages()[0].version, "1.0+fork");
+
+    Ok(())
+}
+
+#[test]
+fn test_lockfile_diff() {
+    let package = |name: &str, version: &str| PackageMeta {
+        version: version.to_string(),
+        path: format!("pool/stable/main/{}_{}.deb", name, version),
+        arch: "amd64".to_string(),
+        install_size: 2048,
+        download_size: 1000,
+        ..PackageMeta::for_test(name)
+    };
+    let old = Lockfile::new(
+        &[vec![
+            package("glibc", "2.38"),
+            package("bash", "5.2"),
+            package("nano", "7.2"),
+        ]],
+        &[],
+    );
+    let new = Lockfile::new(
+        &[vec![
+            package("glibc", "2.39"),
+            package("bash", "5.2"),
+            package("vim", "9.1"),
+        ]],
+        &[],
+    );
+    let diff = old.diff(&new);
+
+    let names =
+        |packages: &[PackageMeta]| packages.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names(&diff.added), ["vim"]);
+    assert_eq!(names(&diff.removed), ["nano"]);
+    assert_eq!(names(&diff.upgraded), ["glibc"]);
+    assert_eq!(diff.upgraded[0].version, "2.39");
+    assert_eq!(diff.replaced[0].version, "2.38");
+    assert_eq!(names(&diff.changed()), ["vim", "glibc"]);
+    assert!(new.diff(&new).is_empty());
+}
@@ -0,0 +1,80 @@
+use std::{
+    fmt::Display,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable messages
+    #[default]
+    Human,
+    /// One JSON object per event
+    Json,
+}
+
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+pub fn init(format: LogFormat) {
+    LOG_FORMAT.set(format).ok();
+}
+
+pub fn is_json() -> bool {
+    LOG_FORMAT.get() == Some(&LogFormat::Json)
+}
+
+/// Report an event: `message` is printed in the human-readable mode,
+/// and an object with the event name and `fields` in the JSON mode
+pub fn event<M: Display>(name: &str, fields: Value, message: M) {
+    if is_json() {
+        emit_json(name, fields);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+/// Report an event that only appears in the JSON mode
+/// (e.g. the ones covered by the progress bars otherwise)
+pub fn json_event(name: &str, fields: Value) {
+    if is_json() {
+        emit_json(name, fields);
+    }
+}
+
+fn emit_json(name: &str, fields: Value) {
+    eprintln!("{}", json_line(name, fields));
+}
+
+fn json_line(name: &str, fields: Value) -> String {
+    let mut object = Map::new();
+    object.insert("event".to_string(), name.into());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    object.insert("timestamp".to_string(), timestamp.into());
+    if let Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+
+    Value::Object(object).to_string()
+}
+
+#[test]
+fn test_json_line() -> anyhow::Result<()> {
+    let line = json_line(
+        "download",
+        serde_json::json!({ "package": "bash", "size": 42 }),
+    );
+    let value: Value = serde_json::from_str(&line)?;
+    assert!(!line.contains('\n'));
+    assert_eq!(value["event"], "download");
+    assert_eq!(value["package"], "bash");
+    assert_eq!(value["size"], 42);
+    assert!(value["timestamp"].as_f64().unwrap() > 0.0);
+
+    Ok(())
+}
@@ -1,436 +1,567 @@
-mod fs;
-mod guest;
-mod install;
-mod network;
-mod solv;
-mod topics;
-
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
+use aoscbootstrap::{
+    cancel, completions,
+    failure::{self, Failure},
+    fs, guest, install, logging, network, ratelimit, recipe, sbom, Bootstrap, ExportOptions,
+    ResolutionError,
+};
 use bytesize::ByteSize;
-use clap::Parser;
-use nix::unistd::Uid;
+use clap::{CommandFactory, Parser};
 use owo_colors::colored::*;
-use solv::PackageMeta;
-use topics::{fetch_topics, filter_topics, Topic};
-use std::{
-    borrow::Cow,
-    fs::File,
-    io::{BufRead, BufReader, Write},
-    path::Path,
-    process::exit,
-};
-
-const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
+use serde_json::json;
+use std::{path::PathBuf, process::exit, time::Duration};
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Args {
+    /// Read the options from a TOML recipe (the options given on the command line take precedence)
+    #[clap(long)]
+    recipe: Option<PathBuf>,
+    /// Print the completion script for the shell to stdout and exit
+    #[clap(long = "generate-completions", value_enum, hide = true)]
+    generate_completions: Option<completions::Shell>,
     /// Sets a custom config file
-    #[clap(short, long)]
-    config: String,
+    #[clap(short, long, required_unless_present_any = ["recipe", "generate_completions", "list_packages"])]
+    config: Option<String>,
     /// Clean up (factory-reset) the bootstrapped environment
     #[clap(short = 'x', long)]
     clean: bool,
     /// Run specified custom scripts during stage 2 (after clean up, if any)
     #[clap(short, long, num_args = 1..)]
     scripts: Option<Vec<String>>,
+    /// Run the executable on the host with the target as `$1`, after stage 1 and before
+    /// entering the guest for stage 2 (can be repeated)
+    #[clap(long = "host-hook")]
+    host_hook: Vec<String>,
     /// CPU architectures to consider
     #[clap(short, long, num_args = 1..)]
     arch: Vec<String>,
-    /// Extra packages to include
+    /// Extra packages to include (`grub [amd64 arm64]` only includes it for these architectures)
     #[clap(short, long, num_args = 1..)]
     include: Vec<String>,
-    /// Extra packages to include (read from files)
+    /// Extra packages to include (read from files, `-` reads a list from stdin)
     #[clap(short = 'f', long = "include-files", num_args = 1..)]
     include_files: Option<Vec<String>>,
     /// Only downloads packages, do not progress further
     #[clap(short = 'g', long = "download-only")]
     download_only: bool,
+    /// Only resolve the dependencies and print the resulting package list, do not download or install anything
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+    /// Only print the packages offered by the branch (and the topics), with their versions
+    /// and sizes, the target can be omitted
+    #[clap(long = "list-packages")]
+    list_packages: bool,
+    /// Only list the packages matching the glob pattern, or containing the text
+    #[clap(long, value_name = "PATTERN", requires = "list_packages")]
+    search: Option<String>,
+    /// Only list the package names, one per line
+    #[clap(long = "names-only", requires = "list_packages")]
+    names_only: bool,
+    /// Explain why a package is pulled in (with --dry-run)
+    #[clap(long, requires = "dry_run")]
+    why: Option<String>,
+    /// Never install the specified package (can be specified multiple times)
+    #[clap(long)]
+    exclude: Vec<String>,
+    /// Also install the recommended packages when possible
+    #[clap(long = "install-recommends", overrides_with = "no_install_recommends")]
+    install_recommends: bool,
+    /// Only install the hard dependencies (default)
+    #[clap(long = "no-install-recommends", overrides_with = "install_recommends")]
+    no_install_recommends: bool,
+    /// Only warn if essential packages of the repository (`Essential: yes` or
+    /// `Priority: required`) are not installed, instead of failing
+    #[clap(long = "allow-missing-essential")]
+    allow_missing_essential: bool,
+    /// Write the resolved packages to the specified lockfile
+    #[clap(long = "write-lock")]
+    write_lock: Option<PathBuf>,
+    /// Write the software bill of materials of the resolved packages to the specified file
+    /// (also with --dry-run)
+    #[clap(long)]
+    sbom: Option<PathBuf>,
+    /// Format of the software bill of materials
+    #[clap(
+        long = "sbom-format",
+        value_enum,
+        default_value = "spdx",
+        requires = "sbom"
+    )]
+    sbom_format: sbom::SbomFormat,
+    /// Install exactly the packages from the specified lockfile (skips the dependency resolution)
+    #[clap(long, conflicts_with_all = ["no_verify", "why", "graph", "write_lock", "install_recommends"])]
+    lock: Option<PathBuf>,
+    /// Write a report of the bootstrap to the specified file (in JSON, can be replayed with `--lock`)
+    #[clap(long, conflicts_with = "dry_run")]
+    report: Option<PathBuf>,
+    /// Extract this tarball (optionally zstd, xz or gzip compressed) as the base files, instead of the built-in one
+    #[clap(long = "bootstrap-pack")]
+    bootstrap_pack: Option<PathBuf>,
+    /// Install this locally built package (can be specified multiple times), preferred over
+    /// the package of the same name in the repositories
+    #[clap(long = "local-deb", conflicts_with = "lock")]
+    local_deb: Vec<PathBuf>,
+    /// Print how long each phase of the bootstrap took (and include it in the `--report`)
+    #[clap(long)]
+    timings: bool,
+    /// Write the resolved dependency graph to the specified file (in Graphviz DOT format)
+    #[clap(long)]
+    graph: Option<PathBuf>,
     /// Only finishes stage 1, do not progress further
     #[clap(short = '1', long = "stage1-only")]
     stage1: bool,
+    /// Only run stage 2 in a target where stage 1 has been run (skips the download and the extraction)
+    #[clap(long = "stage2-only", conflicts_with_all = ["stage1", "download_only", "dry_run", "lock", "write_lock"])]
+    stage2_only: bool,
+    /// Update an installed target in place: only download and extract the packages which
+    /// have changed since the last run, and remove the files of the removed packages
+    #[clap(long, conflicts_with_all = ["stage1", "stage2_only"])]
+    update: bool,
     /// Add additional components
     #[clap(short = 'm', long, num_args = 1..)]
     comps: Vec<String>,
-    /// Limit the number of parallel jobs
+    /// Limit the aggregate download rate across all the workers (e.g. `500k` or `2M`)
+    #[clap(long = "max-rate", value_parser = ratelimit::parse_rate)]
+    max_rate: Option<u64>,
+    /// Program downloading the packages (`aria2c` is invoked once for all of them)
+    #[clap(long, value_enum, default_value = "builtin")]
+    downloader: network::Downloader,
+    /// Stop using a mirror after this many packages failed to download from it (built-in downloader)
+    #[clap(long = "mirror-fail-threshold", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    mirror_fail_threshold: Option<usize>,
+    /// Leave out the optional packages missing from the mirrors (404) if the other packages
+    /// can do without them, instead of failing (built-in downloader)
+    #[clap(long = "skip-missing", conflicts_with = "lock")]
+    skip_missing: bool,
+    /// Use the snapshot of the mirrors at this UTC timestamp (YYYYMMDDTHHMMSSZ)
+    #[clap(long, value_name = "TIMESTAMP")]
+    snapshot: Option<String>,
+    /// Where the snapshots are, `{mirror}` and `{timestamp}` are substituted
+    #[clap(long = "snapshot-template", default_value = network::DEFAULT_SNAPSHOT_TEMPLATE, requires = "snapshot")]
+    snapshot_template: String,
+    /// Maximum number of attempts for each download, including the first one (only transient
+    /// failures are retried)
+    #[clap(long, alias = "retries", default_value = "3", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    attempts: usize,
+    /// Limit the number of parallel jobs (downloads use 4 workers when unset)
     #[clap(short = 'j', long)]
     jobs: Option<usize>,
-    /// Allow existing target directory
+    /// Allow a target directory which is not empty (e.g. to bootstrap over an existing system)
     #[clap(long = "force", default_value = "false")]
     force: bool,
-    /// Export a xz compressed tar archive
+    /// Export a xz compressed tar archive (`-` writes it to stdout)
     #[clap(long = "export-tar-xz")]
     tar_xz: Option<String>,
-    /// Export a gz compressed tar archive
+    /// Export a gz compressed tar archive (`-` writes it to stdout)
     #[clap(long = "export-tar-gz")]
     tar_gz: Option<String>,
-    /// Export a xz compressed squashfs archive
-    #[clap(long = "export-squashfs")]
+    /// Export a squashfs archive
+    #[clap(long = "export-squashfs", alias = "squashfs")]
     squashfs: Option<String>,
-    /// Branch to use
-    branch: String,
+    /// Compression algorithm for the squashfs archive
+    #[clap(long = "squashfs-comp", value_enum, default_value = "xz")]
+    squashfs_comp: fs::SquashfsCompression,
+    /// Export an OCI image layout tarball (for `docker load` or `podman load`, `-` writes
+    /// it to stdout)
+    #[clap(long = "oci-output")]
+    oci_output: Option<String>,
+    /// Compression algorithm for the layer of the OCI image
+    #[clap(long = "compress", value_enum, default_value = "gzip")]
+    compress: install::LayerCompression,
+    /// Compression level for the layer of the OCI image (default: the algorithm's default)
+    #[clap(long = "compress-level", allow_negative_numbers = true)]
+    compress_level: Option<i32>,
+    /// Timestamp of all the entries in the exported archives (in seconds since the Unix epoch)
+    #[clap(
+        long = "source-date-epoch",
+        env = "SOURCE_DATE_EPOCH",
+        default_value = "0"
+    )]
+    source_date_epoch: u64,
+    /// After stage 2, check that the basic commands (and the `smoke-test` ones of the
+    /// configuration file) run in the bootstrapped system
+    #[clap(long = "smoke-test")]
+    smoke_test: bool,
+    /// Also write the output of stage 2 (or --update) to FILE, and report the packages
+    /// which took the longest to configure
+    #[clap(long = "capture-guest-log", value_name = "FILE")]
+    capture_guest_log: Option<PathBuf>,
+    /// At the end, check that the files of the packages are in the tree with their sizes
+    /// (and the checksums recorded by dpkg), and report the files no package ships
+    #[clap(long = "verify-tree", conflicts_with_all = ["download_only", "dry_run", "update"])]
+    verify_tree: bool,
+    /// Only warn about the files of the packages which do not match the tree
+    #[clap(long = "verify-tree-warn", requires = "verify_tree")]
+    verify_tree_warn: bool,
+    /// Fail when a file is shipped by more than one package during stage 1 (instead of warning)
+    #[clap(long = "strict-overwrites")]
+    strict_overwrites: bool,
+    /// Do not verify the checksums of the downloaded packages
+    #[clap(long = "no-verify")]
+    no_verify: bool,
+    /// Always resolve the dependencies, instead of reusing the result of a previous run
+    /// with the same manifests and packages
+    #[clap(long = "no-cache")]
+    no_cache: bool,
+    /// Keyring used for verifying the signature of the repository (default: the host's APT keyrings)
+    #[clap(long)]
+    keyring: Option<PathBuf>,
+    /// Trust all the keyrings (*.gpg and *.asc) in the specified directory (can be specified
+    /// multiple times, e.g. /usr/share/keyrings), in addition to the --keyring
+    #[clap(long = "keyring-dir")]
+    keyring_dir: Vec<PathBuf>,
+    /// Proxy used for all the connections (default: from the `HTTP_PROXY`/`HTTPS_PROXY` variables)
+    #[clap(long)]
+    proxy: Option<String>,
+    /// Timeout of the connection to the mirrors, in seconds
+    #[clap(long = "connect-timeout", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+    /// Timeout waiting for data from the mirrors, in seconds (transfers slower than 1 KiB/s
+    /// over this duration are also retried)
+    #[clap(long = "read-timeout", default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    read_timeout: u64,
+    /// Abort the download of the packages if it takes longer than this, in seconds
+    #[clap(long)]
+    deadline: Option<u64>,
+    /// Do not install the paths matching the pattern (e.g. `/usr/share/doc`, can be specified multiple times)
+    #[clap(long = "exclude-paths", num_args = 1..)]
+    exclude_paths: Vec<String>,
+    /// Only keep these locales in /usr/share/locale (e.g. `en_US`, can be specified multiple times)
+    #[clap(long = "keep-locale", num_args = 1..)]
+    keep_locale: Vec<String>,
+    /// Keep the downloaded packages in this directory, and reuse them in the other bootstraps
+    #[clap(long = "cache-dir")]
+    cache_dir: Option<PathBuf>,
+    /// Download the packages into this directory instead of the target's /var/cache/apt/archives
+    /// (e.g. on a faster scratch storage, and to keep them out of the image)
+    #[clap(long = "archive-dir")]
+    archive_dir: Option<PathBuf>,
+    /// Do not check the available disk space before downloading the packages
+    #[clap(long = "skip-space-check")]
+    skip_space_check: bool,
+    /// Extra disk space required on top of the packages and the installed system
+    #[clap(long = "space-headroom", default_value = "512MiB")]
+    space_headroom: ByteSize,
+    /// Limit the memory of each package decompressor during stage 1 (advanced, the
+    /// packages compressed with a larger window fail to extract)
+    #[clap(long = "decoder-memory-limit")]
+    decoder_memory_limit: Option<ByteSize>,
+    /// Mirror written to the sources.list of the bootstrapped system (default: the primary
+    /// mirror, or https://repo.aosc.io/debs if it is a local directory)
+    #[clap(long = "final-mirror")]
+    final_mirror: Option<String>,
+    /// Do not check that apt can update from the final mirror during stage 2 (for offline builds)
+    #[clap(long = "no-apt-update")]
+    no_apt_update: bool,
+    /// Do not verify the signature of the repository (for local or offline mirrors)
+    #[clap(long = "no-check-signatures")]
+    no_check_signatures: bool,
+    /// Compression formats of the package manifests to try, in the order of preference
+    #[clap(
+        long = "manifest-compression",
+        value_enum,
+        num_args = 1..,
+        value_delimiter = ',',
+        default_value = "zst,xz,gz,plain"
+    )]
+    manifest_compression: Vec<network::ManifestCompression>,
+    /// Path to the QEMU user-mode emulator (for bootstrapping a foreign architecture)
+    #[clap(long = "qemu-path")]
+    qemu_path: Option<String>,
+    /// Bootstrap without privileges (e.g. in a user namespace): the owners of the files are
+    /// recorded instead of applied, and restored in the exported archives (implies the proot
+    /// guest backend, requires --no-device-nodes or --minimal-dev)
+    #[clap(long)]
+    unprivileged: bool,
+    /// Do not create any device node, the container runtime provides /dev (does not require root)
+    #[clap(long = "no-device-nodes", conflicts_with = "minimal_dev")]
+    no_device_nodes: bool,
+    /// Only create the /dev/fd, /dev/stdin, /dev/stdout, /dev/stderr and /dev/ptmx symlinks
+    /// instead of the device nodes (does not require root)
+    #[clap(long = "minimal-dev")]
+    minimal_dev: bool,
+    /// Mechanism used for entering the guest during stage 2 (default: systemd-nspawn or chroot)
+    #[clap(long = "guest-backend", value_enum)]
+    guest_backend: Option<guest::GuestBackend>,
+    /// Interpreter of the stage 2 scripts inside the guest
+    #[clap(long = "guest-shell", default_value = guest::DEFAULT_SHELL)]
+    guest_shell: String,
+    /// Options of the guest shell, separated by spaces (default: "-e -u -o pipefail" for bash
+    /// and zsh, "-e -u" for the other shells)
+    #[clap(long = "guest-shell-flags", allow_hyphen_values = true)]
+    guest_shell_flags: Option<String>,
+    /// Set an environment variable of the commands run in the guest (KEY=VALUE, can be repeated;
+    /// default: DEBIAN_FRONTEND=noninteractive and a standard PATH)
+    #[clap(long = "guest-env", value_name = "KEY=VALUE", value_parser = guest::parse_env)]
+    guest_env: Vec<(String, String)>,
+    /// Give the commands run in the guest the DNS configuration of the host and a hostname
+    /// (for the scripts using the network), removed afterwards
+    #[clap(long = "guest-network")]
+    guest_network: bool,
+    /// The DNS configuration given to the guest by --guest-network (default: /etc/resolv.conf)
+    #[clap(long = "resolv-conf", value_name = "FILE", requires = "guest_network")]
+    resolv_conf: Option<String>,
+    /// Leave the DNS configuration of --guest-network in the target
+    #[clap(long = "keep-resolv-conf", requires = "guest_network")]
+    keep_resolv_conf: bool,
+    /// Do not show the download and extraction progress
+    #[clap(short, long)]
+    quiet: bool,
+    /// List the packages to be installed in the summary
+    #[clap(short, long)]
+    verbose: bool,
+    /// Ask for confirmation before downloading the packages (only when attached to a terminal)
+    #[clap(long)]
+    confirm: bool,
+    /// Do not ask for confirmation
+    #[clap(short = 'y', long = "assume-yes")]
+    assume_yes: bool,
+    /// Format of the messages printed to stderr (`json` prints one object per event)
+    #[clap(long = "log-format", value_enum, default_value = "human")]
+    log_format: logging::LogFormat,
+    /// Branch to use (can be omitted if set by the recipe, the first argument is then the target).
+    /// Overlay branches can be appended after commas (e.g. `stable,security`), the later
+    /// branches take precedence when they provide the same version of a package
+    #[clap(required_unless_present_any = ["recipe", "generate_completions"])]
+    branch: Option<String>,
     /// Path to the destination
-    target: String,
-    /// Mirror to be used
-    #[clap(default_value = DEFAULT_MIRROR)]
-    mirror: String,
+    #[clap(required_unless_present_any = ["recipe", "generate_completions", "list_packages"])]
+    target: Option<String>,
+    /// Mirror(s) to be used, later ones are used as fallbacks [default: https://repo.aosc.io/debs]
+    #[clap(num_args = 1.., value_delimiter = ',')]
+    mirror: Vec<String>,
     /// Include topics
     #[clap(short, long, num_args = 1..)]
     topics: Option<Vec<String>>,
 }
 
-/// AOSC OS specific architecture mapping for ppc64
-#[cfg(target_arch = "powerpc64")]
-#[inline]
-fn get_arch_name() -> Option<&'static str> {
-    let mut endian: libc::c_int = -1;
-    let result;
-    unsafe {
-        result = libc::prctl(libc::PR_GET_ENDIAN, &mut endian as *mut libc::c_int);
-    }
-    if result < 0 {
-        return None;
-    }
-    match endian {
-        libc::PR_ENDIAN_LITTLE | libc::PR_ENDIAN_PPC_LITTLE => Some("ppc64el"),
-        libc::PR_ENDIAN_BIG => Some("ppc64"),
-        _ => None,
-    }
-}
-
-/// AOSC OS specific architecture mapping table
-#[cfg(not(target_arch = "powerpc64"))]
-#[inline]
-fn get_arch_name() -> Option<&'static str> {
-    use std::env::consts::ARCH;
-
-    match ARCH {
-        "x86_64" => Some("amd64"),
-        "x86" => Some("i486"),
-        "powerpc" => Some("powerpc"),
-        "aarch64" => Some("arm64"),
-        "mips64" => Some("loongson3"),
-        _ => None,
-    }
-}
-
-fn get_default_arch() -> Vec<String> {
-    let mut arches = vec!["all".to_string()];
-    if let Some(arch) = get_arch_name() {
-        arches.push(arch.to_string());
-    }
-
-    arches
-}
-
-fn extract_packages(packages: &[PackageMeta], target: &Path, archive_path: &Path) -> Result<()> {
-    let mut count = 0usize;
-    for package in packages {
-        count += 1;
-        let filename = package.file_name();
-        eprintln!(
-            "[{}/{}] Extracting {} ...",
-            count,
-            packages.len(),
-            package.name.cyan()
-        );
-        let f = File::open(archive_path.join(filename))?;
-        install::extract_deb(f, target)?;
-    }
-
-    Ok(())
-}
-
-fn collect_packages_from_lists(paths: &[String]) -> Result<Vec<String>> {
-    let mut packages = Vec::new();
-    packages.reserve(1024);
-
-    for path in paths {
-        collect_packages_from_list(path, &mut packages, 0)?;
-    }
-
-    Ok(packages)
-}
-
-fn collect_packages_from_list<P: AsRef<Path>>(
-    path: P,
-    packages: &mut Vec<String>,
-    depth: usize,
-) -> Result<()> {
-    if depth > 32 {
-        return Err(anyhow!("Recursion limit exceeded. Is there a loop?"));
-    }
-    let f = File::open(path.as_ref())
-        .context(format!("Failed to open file: {}", path.as_ref().display()))?;
-    let reader = BufReader::new(f);
-    for line in reader.lines() {
-        let line = line?;
-        if let Some(inc) = line.strip_prefix("%include ") {
-            let real_path = path.as_ref().canonicalize()?;
-            let real_path = real_path.parent().ok_or_else(|| anyhow!("Invalid path"))?;
-            collect_packages_from_list(real_path.join(inc.trim()), packages, depth + 1)?;
+impl Args {
+    /// Fill in the options not given on the command line from the recipe
+    fn apply_recipe(&mut self) -> Result<()> {
+        if let Some(ref path) = self.recipe {
+            let recipe = recipe::Recipe::read(path)?;
+            if recipe.branch.is_some() && self.target.is_none() {
+                // the only positional argument is the target
+                self.target = self.branch.take();
+            }
+            self.branch = self.branch.take().or(recipe.branch);
+            self.config = self.config.take().or(recipe.config);
+            self.final_mirror = self.final_mirror.take().or(recipe.final_mirror);
+            let merge = |flag: &mut Vec<String>, value: Vec<String>| {
+                if flag.is_empty() {
+                    *flag = value;
+                }
+            };
+            merge(&mut self.mirror, recipe.mirrors);
+            merge(&mut self.arch, recipe.arch);
+            merge(&mut self.include, recipe.include);
+            merge(&mut self.host_hook, recipe.host_hooks);
+            merge(&mut self.exclude, recipe.exclude);
+            merge(&mut self.comps, recipe.comps);
+            let merge = |flag: &mut Option<Vec<String>>, value: Vec<String>| {
+                if flag.is_none() && !value.is_empty() {
+                    *flag = Some(value);
+                }
+            };
+            merge(&mut self.include_files, recipe.include_files);
+            merge(&mut self.scripts, recipe.scripts);
+            merge(&mut self.topics, recipe.topics);
         }
-        // skip comment
-        if line.starts_with('#') || line.is_empty() {
-            continue;
+        if self.config.is_none() && !self.list_packages {
+            bail!("No configuration file specified (use --config or set `config` in the recipe)");
+        }
+        if self.branch.is_none() || (self.target.is_none() && !self.list_packages) {
+            bail!("Both the branch and the target must be specified");
         }
-        // trim whitespace
-        let trimmed = line.trim();
-        packages.push(trimmed.to_owned());
-    }
-
-    Ok(())
-}
 
-#[inline]
-fn collect_filenames(packages: &[PackageMeta]) -> Result<Vec<String>> {
-    let mut output = Vec::new();
-    for package in packages {
-        output.push(package.file_name());
+        Ok(())
     }
 
-    Ok(output)
-}
-
-fn include_extra_scripts<W: Write>(
-    extra_scripts: &Option<Vec<String>>,
-    output: &mut W,
-) -> Result<()> {
-    if let Some(scripts) = extra_scripts {
-        eprintln!("Including {} extra scripts ...", scripts.len().bold());
-        output.write_all(b"\necho 'Running additional scripts ...';")?;
-        for s in scripts {
-            let mut f = File::open(s)?;
-            output.write_all(format!("\n# === {}\n", &s).as_bytes())?;
-            std::io::copy(&mut f, output)?;
+    /// Map the command line options to the bootstrap pipeline
+    fn into_bootstrap(mut self) -> Result<Bootstrap> {
+        // listing the packages needs neither a configuration nor a target
+        let config = match self.config.take() {
+            Some(config) => config,
+            None if self.list_packages => String::new(),
+            None => bail!("No configuration file specified"),
+        };
+        let branch = self.branch.take().context("No branch specified")?;
+        if self.list_packages {
+            // without a target, the second argument is the first mirror
+            if let Some(mirrors) = self.target.take() {
+                let mirrors = mirrors.split(',').map(|m| m.to_string());
+                self.mirror.splice(0..0, mirrors);
+            }
         }
-    }
-
-    Ok(())
-}
-
-fn check_disk_usage(required: u64, target: &Path) -> Result<()> {
-    use fs3::available_space;
+        let target = match self.target.take() {
+            Some(target) => target,
+            None if self.list_packages => String::new(),
+            None => bail!("No target specified"),
+        };
+        let branches = branch
+            .split(',')
+            .map(|b| b.trim().to_string())
+            .filter(|b| !b.is_empty())
+            .collect::<Vec<_>>();
+        let bootstrap = Bootstrap::new(&config, &branch, &target).branches(branches);
+        let bootstrap = if self.mirror.is_empty() {
+            bootstrap
+        } else {
+            bootstrap.mirrors(self.mirror)
+        };
 
-    let available = available_space(target)?;
-    if (available / 1024) < required {
-        return Err(anyhow!("It's not possible to continue, disk space not enough: {} required, but only {} is available. You need at least {} more.", ByteSize::kb(required), ByteSize::b(available),  ByteSize::kb(required - (available / 1024))));
+        Ok(bootstrap
+            .final_mirror(self.final_mirror)
+            .apt_update(!self.no_apt_update)
+            .arches(self.arch)
+            .include(self.include)
+            .include_files(self.include_files.unwrap_or_default())
+            .exclude(self.exclude)
+            .scripts(self.scripts.unwrap_or_default())
+            .host_hooks(self.host_hook)
+            .topics(self.topics.unwrap_or_default())
+            .comps(self.comps)
+            .clean(self.clean)
+            .download_only(self.download_only)
+            .dry_run(self.dry_run)
+            .why(self.why)
+            .install_recommends(self.install_recommends)
+            .allow_missing_essential(self.allow_missing_essential)
+            .write_lock(self.write_lock)
+            .sbom(self.sbom)
+            .sbom_format(self.sbom_format)
+            .lock(self.lock)
+            .report(self.report)
+            .bootstrap_pack(self.bootstrap_pack)
+            .local_debs(self.local_deb)
+            .graph(self.graph)
+            .stage1_only(self.stage1)
+            .stage2_only(self.stage2_only)
+            .update(self.update)
+            .smoke_test(self.smoke_test)
+            .capture_guest_log(self.capture_guest_log)
+            .verify_tree(self.verify_tree)
+            .verify_tree_warn(self.verify_tree_warn)
+            .max_rate(self.max_rate)
+            .downloader(self.downloader)
+            .mirror_fail_threshold(self.mirror_fail_threshold)
+            .skip_missing(self.skip_missing)
+            .snapshot(self.snapshot)
+            .snapshot_template(self.snapshot_template)
+            .attempts(self.attempts)
+            .jobs(self.jobs)
+            .force(self.force)
+            .export(ExportOptions {
+                tar_xz: self.tar_xz,
+                tar_gz: self.tar_gz,
+                squashfs: self.squashfs,
+                squashfs_comp: self.squashfs_comp,
+                oci_output: self.oci_output,
+                compress: self.compress,
+                compress_level: self.compress_level,
+                source_date_epoch: self.source_date_epoch,
+            })
+            .strict_overwrites(self.strict_overwrites)
+            .verify(!self.no_verify)
+            .cache(!self.no_cache)
+            .keyring(self.keyring)
+            .keyring_dirs(self.keyring_dir)
+            .proxy(self.proxy)
+            .timeouts(network::Timeouts {
+                connect: Duration::from_secs(self.connect_timeout),
+                read: Duration::from_secs(self.read_timeout),
+            })
+            .deadline(self.deadline.map(Duration::from_secs))
+            .space_check(!self.skip_space_check)
+            .package_cache(self.cache_dir)
+            .archive_dir(self.archive_dir)
+            .exclude_paths(self.exclude_paths)
+            .keep_locales(self.keep_locale)
+            .space_headroom(self.space_headroom.as_u64())
+            .decoder_memory_limit(self.decoder_memory_limit.map(|s| s.as_u64()))
+            .check_signatures(!self.no_check_signatures)
+            .manifest_compression(self.manifest_compression)
+            .qemu_path(self.qemu_path)
+            .dev_mode(if self.no_device_nodes {
+                fs::DevMode::Skip
+            } else if self.minimal_dev {
+                fs::DevMode::Symlinks
+            } else {
+                fs::DevMode::Nodes
+            })
+            .unprivileged(self.unprivileged)
+            .guest_backend(self.guest_backend)
+            .guest_shell(self.guest_shell)
+            .guest_env(self.guest_env)
+            .guest_network(self.guest_network)
+            .resolv_conf(self.resolv_conf)
+            .keep_resolv_conf(self.keep_resolv_conf)
+            .guest_shell_flags(
+                self.guest_shell_flags
+                    .map(|flags| flags.split_whitespace().map(|f| f.to_string()).collect()),
+            )
+            .quiet(self.quiet)
+            .verbose(self.verbose)
+            .confirm(self.confirm && !self.assume_yes)
+            .timings(self.timings))
     }
-
-    Ok(())
 }
 
-fn do_stage1(
-    st: solv::Transaction,
-    target_path: &Path,
-    mirror: &str,
-    args: &Args,
-    archive_path: std::path::PathBuf,
-    all_packages: Vec<PackageMeta>,
-    topics: Vec<Topic>,
-) -> Result<Option<tempfile::NamedTempFile>> {
-    check_disk_usage(st.get_size_change() as u64, target_path)?;
-    let stub_install = st.create_metadata()?;
-    eprintln!("Stage 1: Creating filesystem skeleton ...");
-    std::fs::create_dir_all(target_path.join("dev"))?;
-    fs::bootstrap_apt(target_path, mirror, &args.branch).context("when preparing apt files")?;
-    topics::save_topics(target_path, topics)?;
-    install::extract_bootstrap_pack(target_path).context("when extracting base files")?;
-    eprintln!("Stage 1: Extracting packages ...");
-    extract_packages(&stub_install, target_path, &archive_path)?;
-    let names: Vec<String> = collect_filenames(&all_packages)?;
-    let mut script = install::write_install_script(&names, args.clean, target_path)?;
-    include_extra_scripts(&args.scripts, &mut script).context("when including extra scripts")?;
-    nix::unistd::sync();
-    if args.stage1 {
-        let (_, path) = script.keep().context("when persisting the script file")?;
-        eprintln!("Stage 1 finished.");
-        eprintln!(
-            "If you want to continue stage 2, you can run `bash {:?}` inside the container.",
-            path.file_name().unwrap().underline()
-        );
-        return Ok(None);
+/// Print the reason of a failed bootstrap and exit
+fn report_error(err: anyhow::Error) -> ! {
+    // the errors caused by the cancellation (e.g. the interrupted guest) are not relevant
+    if failure::exit_code(&err, cancel::is_cancelled()) == cancel::EXIT_CODE {
+        logging::event("cancelled", json!({}), "Cancelled by user.".red());
+        exit(cancel::EXIT_CODE);
     }
-
-    Ok(Some(script))
-}
-
-fn do_stage2(
-    t: solv::Transaction,
-    target_path: &Path,
-    script: tempfile::NamedTempFile,
-    target: &str,
-    args: &Args,
-    threads: usize,
-) -> Result<()> {
-    eprintln!("Stage 2: Installing packages ...");
-    check_disk_usage(t.get_size_change() as u64, target_path)?;
-    let script_file = script.path().file_name().unwrap().to_string_lossy();
-    guest::run_in_guest(target, &["/usr/bin/bash", "-e", &script_file])
-        .context("when running install scripts in the container")?;
-    drop(script);
-    nix::unistd::sync();
-    eprintln!("{}", "Stage 2 finished.\nBase system ready!".green().bold());
-    if let Some(ref xz) = args.tar_xz {
-        eprintln!("Compressing the xz tarball, please wait patiently ...");
-        let path = Path::new(&xz);
-        fs::archive_xz_tarball(target_path, path, threads as u32)?;
-        network::sha256sum_file_tag(path)?;
-        eprintln!("Tarball available at {}", path.display().cyan());
+    let failure = Failure::new(&err);
+    let mut fields = json!(failure);
+    if let Some(e) = err.downcast_ref::<ResolutionError>() {
+        fields["problems"] = json!(e.problems);
+        fields["excludes"] = json!(e.excludes);
     }
-    if let Some(ref gz) = args.tar_gz {
-        eprintln!("Compressing the gz tarball, please wait patiently ...");
-        let path = Path::new(&gz);
-        fs::archive_gz_tarball(target_path, path)?;
-        network::sha256sum_file_tag(path)?;
-        eprintln!("Tarball available at {}", path.display().cyan());
+    let mut summary = format!("{}: {}", failure.stage, failure.error)
+        .red()
+        .to_string();
+    if let Some(remedy) = failure.remedy {
+        summary.push_str(&format!("\n{} {}", "Hint:".yellow().bold(), remedy));
     }
-    if let Some(ref squashfs) = args.squashfs {
-        eprintln!("Compressing the squashfs, please wait patiently ...");
-        let path = Path::new(&squashfs);
-        fs::archive_squashfs(target_path, path, threads as u32)?;
-        network::sha256sum_file_tag(path)?;
-        eprintln!("SquashFS available at {}", path.display().cyan());
+    logging::event("failure", fields, summary);
+    // the events of the earlier versions, kept for the existing consumers
+    match err.downcast_ref::<ResolutionError>() {
+        Some(e) => logging::json_event(
+            "resolution_failed",
+            json!({ "problems": e.problems, "excludes": e.excludes }),
+        ),
+        None => logging::json_event("error", json!({ "error": format!("{:#}", err) })),
     }
-
-    Ok(())
+    exit(failure.exit_code);
 }
 
 fn main() {
-    let args = Args::parse();
-
-    if !Uid::current().is_root() {
-        eprintln!("aoscbootstrap must be run as root.");
-        exit(1);
-    }
-
-    let target = &args.target;
-    let mirror = &args.mirror;
-    if args.squashfs.is_some() && which::which("mksquashfs").is_err() {
-        eprintln!("Cannot find mksquashfs binary!");
-        exit(1)
-    }
-    let mut arches = if args.arch.is_empty() {
-        get_default_arch()
-    } else {
-        args.arch.clone()
-    };
-    let config_path = &args.config;
-    let config = install::read_config(config_path)
-        .context(format!("when reading configuration file '{}'", config_path))
-        .unwrap();
-    let client = network::make_new_client().unwrap();
-    let target_path = Path::new(target);
-    let force = args.force;
-    let archive_path = target_path.join("var/cache/apt/archives");
-    let threads = args.jobs.unwrap_or_else(num_cpus::get);
-    if target_path.exists() && !force {
-        panic!(
-            "{}",
-            "Target already exists. Please remove it first."
-                .red()
-                .bold()
-        );
-    }
-    if let Some(jobs) = args.jobs {
-        std::env::set_var("RAYON_NUM_THREADS", jobs.to_string());
+    let mut args = Args::parse();
+    logging::init(args.log_format);
+    if let Some(shell) = args.generate_completions {
+        if let Err(e) = completions::generate(shell, &mut Args::command(), &mut std::io::stdout()) {
+            report_error(e.context("when writing the completion script"));
+        }
+        return;
     }
-    let mut extra_packages = args.include.clone();
-    if let Some(ref extra_files) = args.include_files {
-        let extras = collect_packages_from_lists(extra_files).unwrap();
-        eprintln!(
-            "Read {} extra packages from the lists.",
-            extras.len().cyan().bold()
-        );
-        extra_packages.extend(extras);
-        extra_packages = extra_packages
-            .into_iter()
-            .filter(|x| !x.starts_with("%include "))
-            .collect();
+    if let Err(e) = cancel::install_handler() {
+        report_error(e.context("when installing the signal handler"));
     }
-    // append the `noarch` architecture if it does not exist.
-    // this is to avoid confusing issues with dependency resolving.
-    if !arches.contains(&"all".to_string()) {
-        arches.push("all".to_string());
+    if let Err(e) = args.apply_recipe() {
+        report_error(e);
     }
-    let mut comps = args.comps.clone();
-    comps.push("main".to_string());
-    let comps_str = comps.iter().map(|s| s.as_str()).collect::<Vec<_>>();
-
-    std::fs::create_dir_all(target_path.join("var/lib/apt/lists")).unwrap();
-    std::fs::create_dir_all(&archive_path).unwrap();
-    eprintln!("Downloading manifests ...");
-    let arches = arches.iter().map(|a| a.as_str()).collect::<Vec<_>>();
-
-    let topics = if let Some(ref t) = args.topics {
-        Cow::Borrowed(t)
+    let result = if args.list_packages {
+        let (search, names_only) = (args.search.take(), args.names_only);
+        args.into_bootstrap()
+            .and_then(|b| b.list_packages(search.as_deref(), names_only))
     } else {
-        Cow::Owned(vec![] as Vec<String>)
+        args.into_bootstrap().and_then(|b| b.run())
     };
-    let all_topics = fetch_topics().unwrap();
-    let filtered = if !topics.is_empty() {
-        filter_topics(topics.to_vec(), all_topics).unwrap()
-    } else {
-	Vec::new()
-    };
-    let manifests = network::fetch_manifests(
-        &client,
-        mirror,
-        &args.branch,
-        &topics,
-        &arches,
-        &comps_str,
-        target_path,
-    )
-    .unwrap();
-
-    let mut paths = Vec::new();
-    for p in manifests {
-        paths.push(target_path.join("var/lib/apt/lists").join(p));
+    if let Err(e) = result {
+        report_error(e);
     }
-
-    eprintln!("Resolving dependencies ...");
-    let mut all_stages = config.stub_packages.clone();
-    all_stages.extend(config.base_packages);
-    all_stages.extend(extra_packages);
-
-    let mut s = String::new();
-    for i in &paths {
-        let f = std::fs::read_to_string(i).unwrap();
-        s.push_str(&f);
-    }
-
-    let mut pool = solv::Pool::new();
-    solv::populate_pool(&mut pool, &paths).unwrap();
-    let t = solv::calculate_deps(&mut pool, &all_stages).unwrap();
-    let all_packages = t.create_metadata().unwrap();
-    eprintln!(
-        "Total installed size: {}",
-        ByteSize::kb(t.get_size_change().unsigned_abs())
-            .cyan()
-            .bold()
-    );
-    check_disk_usage(t.get_size_change() as u64, target_path).unwrap();
-    eprintln!("Downloading packages ...");
-    network::batch_download(&all_packages, mirror, &archive_path).unwrap();
-    nix::unistd::sync();
-    if args.download_only {
-        eprintln!("{}", "Download finished.".green().bold());
-        return;
-    }
-
-    let st = solv::calculate_deps(&mut pool, &config.stub_packages).unwrap();
-    let main_arch = arches
-        .iter()
-        .find(|a| **a != "all")
-        .expect("Did not find the main architecture");
-    install::generate_apt_extended_state(target_path, &all_stages, &all_packages, main_arch)
-        .expect("Unable to generate APT extended state");
-    let script =
-        match do_stage1(st, target_path, mirror, &args, archive_path, all_packages, filtered).unwrap() {
-            Some(value) => value,
-            None => return,
-        };
-
-    do_stage2(t, target_path, script, target, &args, threads).unwrap();
 }
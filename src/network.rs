@@ -1,20 +1,51 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+use rand::random;
 use rayon::prelude::*;
-use reqwest::blocking::Client;
-use std::{fs::File, io::Write};
+use reqwest::{blocking::Client, header::RANGE, NoProxy, Proxy, StatusCode};
+use sequoia_openpgp::{
+    cert::{amalgamation::ValidAmalgamation, CertParser},
+    parse::{
+        stream::{
+            DetachedVerifierBuilder, GoodChecksum, MessageLayer, MessageStructure,
+            VerificationError, VerificationHelper, VerifierBuilder,
+        },
+        Parse,
+    },
+    policy::{AsymmetricAlgorithm, StandardPolicy},
+    types::HashAlgorithm,
+    Cert, Fingerprint, KeyHandle,
+};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+};
 use std::{
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
 use std::{
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, Ordering},
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use url::Url;
 
+use crate::cancel;
+use crate::logging;
+use crate::progress::{Progress, ProgressWriter};
+use crate::ratelimit::{RateLimitedWriter, RateLimiter, Watchdog, WatchdogWriter};
 use crate::DEFAULT_MIRROR;
-use crate::{fs::sha256sum, solv::PackageMeta};
+use crate::{
+    fs::{checksum, sha256sum, write_atomic, ChecksumKind},
+    solv::{check_package_checksums, check_package_paths, PackageMeta},
+};
 
 fn sha256sum_file(path: &Path) -> Result<String> {
     let mut f = File::open(path)?;
@@ -38,21 +69,504 @@ pub(crate) fn sha256sum_file_tag(path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn make_new_client() -> Result<Client> {
-    Ok(Client::builder()
+/// Timeouts of the HTTP connections
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    pub connect: Duration,
+    /// Maximum time waiting for the response or for the next chunk of the body
+    pub read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            read: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Create the HTTP client.
+///
+/// Without an explicit `proxy`, the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment
+/// variables are used. `NO_PROXY` is honored in both cases.
+pub fn make_new_client(proxy: Option<&str>, timeouts: &Timeouts) -> Result<Client> {
+    let mut builder = Client::builder()
         .user_agent("Wget/1.20.3 (linux-gnu)")
-        .build()?)
+        .connect_timeout(timeouts.connect)
+        .timeout(timeouts.read);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(make_proxy(proxy, NoProxy::from_env())?);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn make_proxy(url: &str, no_proxy: Option<NoProxy>) -> Result<Proxy> {
+    Ok(Proxy::all(url)
+        .with_context(|| format!("Invalid proxy URL: {}", url))?
+        .no_proxy(no_proxy))
+}
+
+/// Convert a mirror given as an absolute path to a `file://` URL
+pub fn normalize_mirror(mirror: &str) -> String {
+    let mirror = mirror.trim_end_matches('/');
+    if mirror.starts_with('/') {
+        if let Ok(url) = Url::from_file_path(mirror) {
+            return url.to_string();
+        }
+    }
+
+    mirror.to_string()
+}
+
+/// Where the snapshots of a mirror are, `{mirror}` and `{timestamp}` are substituted
+pub const DEFAULT_SNAPSHOT_TEMPLATE: &str = "{mirror}/snapshots/{timestamp}";
+
+/// Check that `timestamp` is a UTC timestamp like `20240131T120000Z`
+pub fn check_snapshot_timestamp(timestamp: &str) -> Result<()> {
+    let bytes = timestamp.as_bytes();
+    let is_digits = |range: std::ops::Range<usize>| bytes[range].iter().all(u8::is_ascii_digit);
+    let well_formed = bytes.len() == 16
+        && is_digits(0..8)
+        && bytes[8] == b'T'
+        && is_digits(9..15)
+        && bytes[15] == b'Z';
+    if !well_formed {
+        bail!(
+            "Invalid snapshot timestamp '{}': expected YYYYMMDDTHHMMSSZ (e.g. 20240131T120000Z)",
+            timestamp
+        );
+    }
+    let field = |range: std::ops::Range<usize>| timestamp[range].parse::<u32>().unwrap_or(0);
+    if !(1..=12).contains(&field(4..6))
+        || !(1..=31).contains(&field(6..8))
+        || field(9..11) > 23
+        || field(11..13) > 59
+        || field(13..15) > 59
+    {
+        bail!(
+            "Invalid snapshot timestamp '{}': no such date or time",
+            timestamp
+        );
+    }
+
+    Ok(())
+}
+
+/// The URL of the snapshot of `mirror` at `timestamp`, according to `template`
+pub fn snapshot_mirror(template: &str, mirror: &str, timestamp: &str) -> Result<String> {
+    if !template.contains("{timestamp}") {
+        bail!(
+            "The snapshot template '{}' does not contain {{timestamp}}",
+            template
+        );
+    }
+
+    Ok(normalize_mirror(
+        &template
+            .replace("{mirror}", mirror.trim_end_matches('/'))
+            .replace("{timestamp}", timestamp),
+    ))
 }
 
-pub fn fetch_url(client: &Client, url: &str, path: &Path) -> Result<()> {
-    let mut f = File::create(path)?;
-    let mut resp = client.get(url).send()?;
+/// Check that at least one of the (snapshot) mirrors provides the release of `branch`
+pub fn check_snapshot(
+    client: &Client,
+    mirrors: &[String],
+    branch: &str,
+    attempts: usize,
+) -> Result<()> {
+    for mirror in mirrors {
+        for file in ["InRelease", "Release"] {
+            let url = format!("{}/dists/{}/{}", mirror, branch, file);
+            if fetch_optional(client, &url, attempts)?.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    bail!(
+        "The snapshot is not available: no release of {} at {} (404 Not Found)",
+        branch,
+        mirrors.join(", ")
+    )
+}
+
+/// Return the local path of `url` if it is a `file://` URL
+fn local_path(url: &str) -> Option<PathBuf> {
+    Url::parse(url)
+        .ok()
+        .filter(|u| u.scheme() == "file")
+        .and_then(|u| u.to_file_path().ok())
+}
+
+/// A file of a local mirror which cannot be read: a failure of the mirror, unlike the
+/// errors writing the downloaded files
+#[derive(Debug)]
+struct LocalMirrorError {
+    path: PathBuf,
+    source: std::io::Error,
+}
+
+impl std::fmt::Display for LocalMirrorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to read {}: {}", self.path.display(), self.source)
+    }
+}
+
+impl std::error::Error for LocalMirrorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Fetch the content of `url`, returns `None` if the file does not exist on the mirror
+fn fetch_optional(client: &Client, url: &str, attempts: usize) -> Result<Option<Vec<u8>>> {
+    with_retries(attempts, url, None, || fetch_optional_once(client, url))
+}
+
+fn fetch_optional_once(client: &Client, url: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(path) = local_path(url) {
+        return match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(LocalMirrorError { path, source }.into()),
+        };
+    }
+    let resp = client.get(url).send()?;
+    if matches!(resp.status(), StatusCode::NOT_FOUND | StatusCode::FORBIDDEN) {
+        return Ok(None);
+    }
+
+    // read in chunks (unlike `bytes`, which applies the read timeout to the whole body), so
+    // that a large manifest on a slow link only fails when the data stops arriving
+    let mut data = Vec::new();
+    resp.error_for_status()?.copy_to(&mut data)?;
+
+    Ok(Some(data))
+}
+
+/// Link (or copy if not possible) the file from a local mirror to `path`
+fn copy_local_file(source: &Path, path: &Path, progress: Option<&Progress>) -> Result<()> {
+    // the files missing from (or unreadable in) the mirror
+    File::open(source).map_err(|e| LocalMirrorError {
+        path: source.to_path_buf(),
+        source: e,
+    })?;
+    // never copy into a stale link to the source, that would truncate the source itself
+    std::fs::remove_file(path).ok();
+    if std::fs::hard_link(source, path).is_err() {
+        std::fs::copy(source, path)?;
+    }
+    if let Some(progress) = progress {
+        progress.add_bytes(std::fs::metadata(path)?.len());
+    }
+
+    Ok(())
+}
+
+/// Download the file to `path`, resuming from the `.part` file left by an earlier attempt.
+///
+/// The file is moved into place only after the download (and the checksum verification,
+/// if `checksum` is given) succeeds, so `path` never contains a partial or corrupted file.
+pub fn fetch_url_resumable(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    checksum: Option<(ChecksumKind, &str)>,
+    progress: Option<&Progress>,
+    limiter: Option<&RateLimiter>,
+    watchdog: Option<&Watchdog>,
+) -> Result<()> {
+    let part_path = PathBuf::from(format!("{}.part", path.display()));
+    match local_path(url) {
+        Some(source) => copy_local_file(&source, &part_path, progress)?,
+        None => download_part(client, url, &part_path, progress, limiter, watchdog)?,
+    }
+    if let Some((kind, expected)) = checksum {
+        if let Some(actual) = verify_file(&part_path, kind, expected)? {
+            std::fs::remove_file(&part_path).ok();
+            return Err(anyhow!(
+                "{} checksum mismatch: expected {}, got {}",
+                kind.field(),
+                expected,
+                actual
+            ));
+        }
+    }
+    std::fs::rename(&part_path, path)?;
+
+    Ok(())
+}
+
+fn download_part(
+    client: &Client,
+    url: &str,
+    part_path: &Path,
+    progress: Option<&Progress>,
+    limiter: Option<&RateLimiter>,
+    watchdog: Option<&Watchdog>,
+) -> Result<()> {
+    let offset = std::fs::metadata(part_path).map(|m| m.len()).unwrap_or(0);
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    let mut resp = request.send()?;
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // the partial file is not usable (e.g. the file changed on the server), start over
+        std::fs::remove_file(part_path)?;
+        return download_part(client, url, part_path, progress, limiter, watchdog);
+    }
     resp.error_for_status_ref()?;
-    resp.copy_to(&mut f)?;
+    let (mut f, expected) = if offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT {
+        let f = OpenOptions::new().append(true).open(part_path)?;
+        (f, resp.content_length().map(|len| len + offset))
+    } else {
+        // the server does not support range requests, download the whole file again
+        (File::create(part_path)?, resp.content_length())
+    };
+    let mut writer = WatchdogWriter::new(RateLimitedWriter::new(&mut f, limiter), watchdog);
+    match progress {
+        Some(progress) => resp.copy_to(&mut ProgressWriter::new(&mut writer, progress))?,
+        None => resp.copy_to(&mut writer)?,
+    };
+    f.sync_all()?;
+    let written = f.metadata()?.len();
+    if let Some(expected) = expected {
+        if written != expected {
+            // the connection was dropped, the download can be resumed
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "Incomplete download: expected {} bytes, got {} bytes",
+                    expected, written
+                ),
+            )
+            .into());
+        }
+    }
 
     Ok(())
 }
 
+/// Whether the error is likely temporary (so that the same request should be retried)
+fn is_transient(err: &anyhow::Error) -> bool {
+    if let Some(e) = err.downcast_ref::<std::io::Error>() {
+        return matches!(
+            e.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+        );
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+        // connections dropped in the middle of the body are reported as body or decode errors
+        Some(e) => {
+            e.is_connect()
+                || e.is_timeout()
+                || e.is_body()
+                || e.is_decode()
+                || e.status().is_some_and(|s| s.is_server_error())
+        }
+        None => false,
+    }
+}
+
+/// Delay before the next attempt: exponential backoff (capped at 30 seconds) with jitter
+fn retry_delay(attempt: usize) -> Duration {
+    let base = Duration::from_millis(500).saturating_mul(1 << attempt.min(16)) / 2;
+    let base = base.min(Duration::from_secs(30));
+
+    base.mul_f64(0.5 + random::<f64>())
+}
+
+/// Run `f` up to `attempts` times, as long as the failures are transient
+fn with_retries<T, F>(
+    attempts: usize,
+    what: &str,
+    progress: Option<&Progress>,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        let err = match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if !is_transient(&err) {
+            return Err(err);
+        }
+        if attempt >= attempts {
+            let message = format!("{} failed after {} attempt(s): {}", what, attempt, err);
+            return Err(err.context(message));
+        }
+        let delay = retry_delay(attempt);
+        let message = format!(
+            "[{}/{}] {} failed: {}, retrying in {:.1}s ...",
+            attempt,
+            attempts,
+            what,
+            err,
+            delay.as_secs_f64()
+        );
+        let fields = json!({
+            "url": what,
+            "attempt": attempt,
+            "attempts": attempts,
+            "error": err.to_string(),
+            "delay_ms": delay.as_millis() as u64,
+        });
+        match progress {
+            Some(progress) if !logging::is_json() => progress.println(&message),
+            _ => logging::event("retry", fields, message),
+        }
+        sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Whether the error indicates a problem with the mirror itself (so that the next one should be tried)
+fn is_mirror_failure(err: &anyhow::Error) -> bool {
+    if err.downcast_ref::<LocalMirrorError>().is_some() {
+        return true;
+    }
+    // the local errors (e.g. no space left for the `.part` file) are returned right away
+    if let Some(e) = err.downcast_ref::<std::io::Error>() {
+        return matches!(
+            e.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof
+        );
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) => {
+            e.is_connect()
+                || e.is_timeout()
+                || e.status().map(|s| s.is_server_error()).unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+/// Try each of the mirrors in order, until one of them succeeds.
+///
+/// Only connection failures, server errors (5xx) and the files missing from (or unreadable
+/// in) the local mirrors cause the next mirror to be tried, other errors (e.g. writing the
+/// downloaded files) are returned immediately.
+pub fn try_mirrors<T, F>(mirrors: &[String], f: F) -> Result<T>
+where
+    F: FnMut(&str) -> Result<T>,
+{
+    try_live_mirrors(mirrors, &MirrorHealth::default(), f)
+}
+
+/// The failures of the mirrors during a batch of downloads: a mirror which has failed
+/// `threshold` times is blacklisted for the rest of the batch (`--mirror-fail-threshold`)
+#[derive(Default)]
+pub struct MirrorHealth {
+    threshold: Option<usize>,
+    failures: Mutex<HashMap<String, usize>>,
+}
+
+impl MirrorHealth {
+    pub fn new(threshold: Option<usize>) -> Self {
+        MirrorHealth {
+            threshold,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_blacklisted(&self, mirror: &str) -> bool {
+        let failures = self.failures.lock().unwrap();
+        self.threshold
+            .is_some_and(|t| failures.get(mirror).copied().unwrap_or(0) >= t)
+    }
+
+    /// Count a failure of the `mirror`, blacklisting it when it reaches the threshold
+    fn record_failure(&self, mirror: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(mirror.to_string()).or_insert(0);
+        *count += 1;
+        if Some(*count) == self.threshold {
+            logging::event(
+                "mirror_blacklisted",
+                json!({ "mirror": mirror, "failures": *count }),
+                format!(
+                    "Mirror {} failed for {} package(s), it is not used anymore.",
+                    mirror, count
+                ),
+            );
+        }
+    }
+}
+
+/// [try_mirrors], skipping the mirrors blacklisted by the `health`
+fn try_live_mirrors<T, F>(mirrors: &[String], health: &MirrorHealth, mut f: F) -> Result<T>
+where
+    F: FnMut(&str) -> Result<T>,
+{
+    let mut failures = Vec::new();
+    let mut last = None;
+    let mut missing = true;
+    let live = mirrors
+        .iter()
+        .filter(|m| !health.is_blacklisted(m))
+        .collect::<Vec<_>>();
+    if live.is_empty() {
+        bail!(
+            "All mirrors have been blacklisted (each failed for {} package(s)): {}",
+            health.threshold.unwrap_or_default(),
+            mirrors.join(", ")
+        );
+    }
+    for mirror in live {
+        match f(mirror) {
+            Ok(value) => return Ok(value),
+            Err(e) if is_mirror_failure(&e) => {
+                logging::event(
+                    "mirror_failed",
+                    json!({ "mirror": mirror, "error": e.to_string() }),
+                    format!("Mirror {} failed: {}", mirror, e),
+                );
+                failures.push(format!("  {}: {}", mirror, e));
+                health.record_failure(mirror);
+                missing &= is_missing_file(&e);
+                last = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let message = format!("All mirrors failed:\n{}", failures.join("\n"));
+
+    match last {
+        // keeps the cause, so that the files missing from all the mirrors can be told apart
+        Some(e) if missing => Err(e.context(message)),
+        _ => Err(anyhow!(message)),
+    }
+}
+
+/// Whether the error is caused by the file missing from the mirror (404, or not in a local mirror)
+fn is_missing_file(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            .is_some_and(|s| s == StatusCode::NOT_FOUND)
+            || e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == ErrorKind::NotFound)
+    })
+}
+
 #[inline]
 fn combination<'a, 'b>(a: &'a [&str], b: &'b [&str]) -> Vec<(&'a str, &'b str)> {
     let mut ret = Vec::new();
@@ -65,146 +579,1906 @@ fn combination<'a, 'b>(a: &'a [&str], b: &'b [&str]) -> Vec<(&'a str, &'b str)>
     ret
 }
 
+/// Compression formats of the `Packages` manifests served by the mirrors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ManifestCompression {
+    #[value(name = "zst")]
+    Zstd,
+    Xz,
+    #[value(name = "gz")]
+    Gzip,
+    Plain,
+}
+
+impl ManifestCompression {
+    fn extension(&self) -> &'static str {
+        match self {
+            ManifestCompression::Zstd => ".zst",
+            ManifestCompression::Xz => ".xz",
+            ManifestCompression::Gzip => ".gz",
+            ManifestCompression::Plain => "",
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        match self {
+            ManifestCompression::Zstd => {
+                zstd::stream::read::Decoder::new(data)?.read_to_end(&mut output)?;
+            }
+            ManifestCompression::Xz => {
+                xz2::read::XzDecoder::new(data).read_to_end(&mut output)?;
+            }
+            ManifestCompression::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut output)?;
+            }
+            ManifestCompression::Plain => output.extend_from_slice(data),
+        }
+
+        Ok(output)
+    }
+}
+
+/// Options controlling how the package manifests are fetched
+pub struct ManifestOptions {
+    /// Verify the signature of the InRelease file and the checksums of the manifests
+    pub check_signatures: bool,
+    /// Keyring to verify the signature with (instead of the host's APT keyrings)
+    pub keyring: Option<PathBuf>,
+    /// Directories of keyrings to verify the signature with, in addition to `keyring`
+    pub keyring_dirs: Vec<PathBuf>,
+    /// Compression formats to try, in the order of preference
+    pub compressions: Vec<ManifestCompression>,
+    /// Maximum number of attempts for each file
+    pub attempts: usize,
+    /// Number of parallel downloads
+    pub jobs: usize,
+}
+
+/// The keyring files (`*.gpg` and `*.asc`) in `dir`, sorted by name
+fn keyrings_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut keyrings = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let ext = path.extension().and_then(|x| x.to_str());
+        if (ext == Some("gpg") || ext == Some("asc")) && path.is_file() {
+            keyrings.push(path);
+        }
+    }
+    keyrings.sort();
+
+    Ok(keyrings)
+}
+
+/// Collect the keyrings trusted for verifying the repository metadata: the `keyring` and
+/// the keyrings in the `keyring_dirs`, or the host's APT keyrings if none is specified
+fn trusted_keyrings(keyring: Option<&Path>, keyring_dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    if keyring.is_some() || !keyring_dirs.is_empty() {
+        let mut keyrings = Vec::new();
+        if let Some(keyring) = keyring {
+            keyrings.push(
+                keyring
+                    .canonicalize()
+                    .with_context(|| format!("Failed to open keyring {}", keyring.display()))?,
+            );
+        }
+        for dir in keyring_dirs {
+            let found = keyrings_in(dir)
+                .with_context(|| format!("Failed to read keyring directory {}", dir.display()))?;
+            if found.is_empty() {
+                bail!("No keyring (*.gpg or *.asc) found in {}", dir.display());
+            }
+            keyrings.extend(found);
+        }
+        return Ok(keyrings);
+    }
+
+    let mut keyrings = Vec::new();
+    for dir in ["/etc/apt/trusted.gpg.d", "/etc/apt/keyrings"] {
+        keyrings.extend(keyrings_in(Path::new(dir)).unwrap_or_default());
+    }
+    let trusted_main = Path::new("/etc/apt/trusted.gpg");
+    if trusted_main.is_file() {
+        keyrings.push(trusted_main.to_path_buf());
+    }
+    if keyrings.is_empty() {
+        return Err(anyhow!(
+            "No trusted keyring found in /etc/apt, please specify one with --keyring or --keyring-dir"
+        ));
+    }
+
+    Ok(keyrings)
+}
+
+/// The certificates of the trusted keyrings, merged into one keyring
+pub struct Keyring {
+    /// Certificate, and the keyring it was first found in
+    certs: BTreeMap<Fingerprint, (Cert, PathBuf)>,
+}
+
+impl Keyring {
+    pub fn load(paths: &[PathBuf]) -> Result<Keyring> {
+        let mut certs: BTreeMap<Fingerprint, (Cert, PathBuf)> = BTreeMap::new();
+        for path in paths {
+            let parser = CertParser::from_file(path)
+                .with_context(|| format!("Failed to read keyring {}", path.display()))?;
+            for cert in parser {
+                let cert =
+                    cert.with_context(|| format!("Bad certificate in {}", path.display()))?;
+                // the same key may be shipped by several keyrings, with different signatures
+                match certs.remove(&cert.fingerprint()) {
+                    Some((existing, source)) => {
+                        let merged = existing.merge_public(cert)?;
+                        certs.insert(merged.fingerprint(), (merged, source));
+                    }
+                    None => {
+                        certs.insert(cert.fingerprint(), (cert, path.clone()));
+                    }
+                }
+            }
+        }
+
+        Ok(Keyring { certs })
+    }
+}
+
+/// The key which made a signature valid
+#[derive(Clone, Debug)]
+pub struct Signer {
+    /// Fingerprint of the signing (sub)key
+    pub fingerprint: String,
+    /// Primary user ID of the certificate
+    pub user_id: Option<String>,
+    /// Keyring the certificate comes from
+    pub keyring: PathBuf,
+}
+
+/// Checks that the release is signed by any of the certificates in the keyring
+struct KeyringVerifier<'a> {
+    keyring: &'a Keyring,
+    signer: Option<Signer>,
+}
+
+impl VerificationHelper for KeyringVerifier<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> Result<Vec<Cert>> {
+        Ok(self
+            .keyring
+            .certs
+            .values()
+            .map(|(c, _)| c.clone())
+            .collect())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> Result<()> {
+        let mut error = None;
+        for layer in structure {
+            let MessageLayer::SignatureGroup { results } = layer else {
+                bail!("Malformed PGP signature, the release must be signed");
+            };
+            for result in results {
+                match result {
+                    Ok(GoodChecksum { ka, .. }) => {
+                        let cert = ka.cert();
+                        self.signer = Some(Signer {
+                            fingerprint: ka.key().fingerprint().to_hex(),
+                            user_id: cert
+                                .primary_userid()
+                                .ok()
+                                .map(|u| String::from_utf8_lossy(u.userid().value()).to_string()),
+                            keyring: self.keyring.certs[&cert.fingerprint()].1.clone(),
+                        });
+                    }
+                    // a missing key is only an error if no other key made the signature valid
+                    Err(e @ VerificationError::MissingKey { .. }) => {
+                        error.get_or_insert(e.to_string());
+                    }
+                    Err(e) => bail!("The release contains a bad signature: {}", e),
+                }
+            }
+        }
+        if self.signer.is_none() {
+            bail!(
+                "The release is not signed by any trusted key: {}",
+                error.unwrap_or_else(|| "no signature".to_string())
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The signature of the repository metadata at `url` is missing, bad or not trusted,
+/// attached to the error of the verification as its context
+#[derive(Debug)]
+pub struct SignatureError {
+    pub url: String,
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to verify the signature of {}", self.url)
+    }
+}
+
+/// Report which key made the signature of `url` valid
+fn report_signer(url: &str, signer: &Signer) {
+    logging::event(
+        "signature_verified",
+        json!({
+            "url": url,
+            "fingerprint": signer.fingerprint,
+            "user_id": signer.user_id,
+            "keyring": signer.keyring,
+        }),
+        format!(
+            "Verified {} with key {} ({}) from {}",
+            url,
+            signer.fingerprint,
+            signer.user_id.as_deref().unwrap_or("no user ID"),
+            signer.keyring.display()
+        ),
+    );
+}
+
+/// The standard policy, rejecting SHA-1 and the keys under 2048 bits regardless of the
+/// signature dates
+fn signature_policy() -> StandardPolicy<'static> {
+    let mut policy = StandardPolicy::new();
+    policy.reject_hash(HashAlgorithm::SHA1);
+    for algo in [
+        AsymmetricAlgorithm::RSA1024,
+        AsymmetricAlgorithm::DSA1024,
+        AsymmetricAlgorithm::ElGamal1024,
+    ] {
+        policy.reject_asymmetric_algo(algo);
+    }
+
+    policy
+}
+
+/// Download and verify `InRelease` (or `Release` + `Release.gpg`) under `base_url`,
+/// returning the signed content
+fn fetch_signed_release(
+    client: &Client,
+    base_url: &str,
+    keyring: &Keyring,
+    attempts: usize,
+) -> Result<String> {
+    let policy = signature_policy();
+    let url = format!("{}/InRelease", base_url);
+    if let Some(inrelease) = fetch_optional(client, &url, attempts)? {
+        let helper = KeyringVerifier {
+            keyring,
+            signer: None,
+        };
+        let mut verifier = VerifierBuilder::from_bytes(&inrelease)?
+            .with_policy(&policy, None, helper)
+            .with_context(|| SignatureError { url: url.clone() })?;
+        let mut content = String::new();
+        verifier
+            .read_to_string(&mut content)
+            .with_context(|| SignatureError { url: url.clone() })?;
+        if let Some(ref signer) = verifier.helper_ref().signer {
+            report_signer(&url, signer);
+        }
+
+        return Ok(content);
+    }
+
+    // fall back to the detached signature
+    let url = format!("{}/Release", base_url);
+    let release = fetch_optional(client, &url, attempts)?
+        .ok_or_else(|| anyhow!("Neither InRelease nor Release exists in {}", base_url))?;
+    let signature = fetch_optional(client, &format!("{}.gpg", url), attempts)?
+        .ok_or_else(|| anyhow!("{}.gpg does not exist", url))?;
+    let helper = KeyringVerifier {
+        keyring,
+        signer: None,
+    };
+    let mut verifier = DetachedVerifierBuilder::from_bytes(&signature)?
+        .with_policy(&policy, None, helper)
+        .with_context(|| SignatureError { url: url.clone() })?;
+    verifier
+        .verify_bytes(&release)
+        .with_context(|| SignatureError { url: url.clone() })?;
+    if let Some(ref signer) = verifier.helper_ref().signer {
+        report_signer(&url, signer);
+    }
+
+    String::from_utf8(release).context("Release file is not valid UTF-8")
+}
+
+/// Parse the `SHA256` field of a Release file into a map of file name -> checksum
+fn parse_release_checksums(release: &str) -> Result<HashMap<String, String>> {
+    let release = oma_debcontrol::parse_str(release).map_err(|e| anyhow!("{e}"))?;
+    let release = release.first().context("InRelease is empty")?;
+
+    let sha256 = &release
+        .fields
+        .iter()
+        .find(|x| x.name == "SHA256")
+        .context("Illage InRelease")?
+        .value;
+
+    let mut checksums = HashMap::new();
+    for i in sha256.trim().lines() {
+        let mut fields = i.split_ascii_whitespace();
+        let checksum = fields.next().context("Illage InRelease")?;
+        let name = fields.next_back().context("Illage InRelease")?;
+        checksums.insert(name.to_string(), checksum.to_string());
+    }
+
+    Ok(checksums)
+}
+
+/// Make sure the branch provides all the requested architectures (except `all`, which is implicit)
+fn check_architectures(release: &str, branch: &str, arches: &[&str]) -> Result<()> {
+    let release = oma_debcontrol::parse_str(release).map_err(|e| anyhow!("{e}"))?;
+    // nothing to check against (e.g. a hand-made local mirror)
+    let Some(field) = release
+        .first()
+        .and_then(|r| r.fields.iter().find(|x| x.name == "Architectures"))
+    else {
+        return Ok(());
+    };
+    let available = field.value.split_ascii_whitespace().collect::<Vec<_>>();
+    let missing = arches
+        .iter()
+        .filter(|arch| **arch != "all" && !available.contains(arch))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "The branch {} does not provide the architecture(s): {}\nAvailable architectures: {}",
+            branch,
+            missing.join(", "),
+            available.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_manifest(checksums: &HashMap<String, String>, name: &str, data: &[u8]) -> Result<()> {
+    let expected = checksums
+        .get(name)
+        .ok_or_else(|| anyhow!("{} is not listed in the signed Release file", name))?;
+    let actual = sha256sum(data)?;
+    if &actual != expected {
+        return Err(anyhow!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            name,
+            expected,
+            actual
+        ));
+    }
+
+    Ok(())
+}
+
+/// Download the manifest `name` (relative to `base_url`) using the first compression
+/// available on the mirror, and write the decompressed manifest to `path`
+fn fetch_manifest(
+    client: &Client,
+    base_url: &str,
+    name: &str,
+    path: &Path,
+    compressions: &[ManifestCompression],
+    checksums: Option<&HashMap<String, String>>,
+    attempts: usize,
+) -> Result<()> {
+    for compression in compressions {
+        let name = format!("{}{}", name, compression.extension());
+        // the signed Release file tells which variants exist, no need to probe for the others
+        if checksums.is_some_and(|c| !c.contains_key(&name)) {
+            continue;
+        }
+        let Some(data) = fetch_optional(client, &format!("{}/{}", base_url, name), attempts)?
+        else {
+            continue;
+        };
+        if let Some(checksums) = checksums {
+            check_manifest(checksums, &name, &data)?;
+        }
+        let data = compression
+            .decompress(&data)
+            .with_context(|| format!("Failed to decompress {}/{}", base_url, name))?;
+        write_atomic(path, &data)?;
+
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "{}/{} is not available in any of the formats: {:?}",
+        base_url,
+        name,
+        compressions
+    ))
+}
+
+/// Download the manifests of each branch and of the topics, returns the names of the
+/// manifests (in `root/var/lib/apt/lists`), grouped by branch in the same order, with the
+/// topics in the last group (if any)
+#[allow(clippy::too_many_arguments)]
 pub fn fetch_manifests(
     client: &Client,
-    mirror: &str,
-    branch: &str,
+    mirrors: &[String],
+    branches: &[String],
     topics: &[String],
     arches: &[&str],
     comps: &[&str],
     root: &Path,
+    options: &ManifestOptions,
+) -> Result<Vec<Vec<String>>> {
+    let workers = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()
+        .context("Failed to create the download worker pool")?;
+    // all the branches, architectures, components and topics are fetched at once,
+    // the groups are collected in the order of the branches regardless
+    let (groups, topics) = workers.install(|| {
+        rayon::join(
+            || {
+                branches
+                    .par_iter()
+                    .map(|branch| {
+                        fetch_branch_manifests(
+                            client, mirrors, branch, arches, comps, root, options,
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()
+            },
+            || {
+                if topics.is_empty() {
+                    return Ok(None);
+                }
+                fetch_topic_manifests(client, topics, arches, root, options).map(Some)
+            },
+        )
+    });
+    let mut groups = groups?;
+    groups.extend(topics?);
+
+    Ok(groups)
+}
+
+fn fetch_branch_manifests(
+    client: &Client,
+    mirrors: &[String],
+    branch: &str,
+    arches: &[&str],
+    comps: &[&str],
+    root: &Path,
+    options: &ManifestOptions,
 ) -> Result<Vec<String>> {
-    let manifests = Arc::new(Mutex::new(Vec::new()));
-    let manifests_clone = manifests.clone();
-    let manifests_clone_2 = manifests.clone();
+    // verify the repository metadata before trusting any of the manifests
+    let checksums = if options.check_signatures {
+        let keyring = Keyring::load(&trusted_keyrings(
+            options.keyring.as_deref(),
+            &options.keyring_dirs,
+        )?)?;
+        let release = try_mirrors(mirrors, |mirror| {
+            fetch_signed_release(
+                client,
+                &format!("{}/dists/{}", mirror, branch),
+                &keyring,
+                options.attempts,
+            )
+        })?;
+        check_architectures(&release, branch, arches)?;
+        Some(parse_release_checksums(&release)?)
+    } else {
+        let release = try_mirrors(mirrors, |mirror| {
+            let url = format!("{}/dists/{}/Release", mirror, branch);
+            fetch_optional(client, &url, options.attempts)
+        })?;
+        if let Some(release) = release {
+            check_architectures(&String::from_utf8_lossy(&release), branch, arches)?;
+        }
+        None
+    };
+
+    let manifests = Mutex::new(Vec::new());
     let combined = combination(arches, comps);
     combined
         .par_iter()
-        .try_for_each(move |(arch, comp)| -> Result<()> {
-            let url = format!(
-                "{}/dists/{}/{}/binary-{}/Packages",
-                mirror, branch, comp, arch
-            );
-            let parsed = Url::parse(&url)?;
-            let manifest_name = parsed.host_str().unwrap_or_default().to_string() + parsed.path();
+        .try_for_each(|(arch, comp)| -> Result<()> {
+            let name = format!("{}/binary-{}/Packages", comp, arch);
+            let manifest_name = try_mirrors(mirrors, |mirror| {
+                let base_url = format!("{}/dists/{}", mirror, branch);
+                let parsed = Url::parse(&format!("{}/{}", base_url, name))?;
+                let manifest_name =
+                    parsed.host_str().unwrap_or_default().to_string() + parsed.path();
+                let manifest_name = manifest_name.replace('/', "_");
+
+                fetch_manifest(
+                    client,
+                    &base_url,
+                    &name,
+                    &root.join("var/lib/apt/lists").join(manifest_name.clone()),
+                    &options.compressions,
+                    checksums.as_ref(),
+                    options.attempts,
+                )?;
+
+                Ok(manifest_name)
+            })?;
+            manifests.lock().unwrap().push(manifest_name);
+
+            Ok(())
+        })?;
+    let mut manifests = manifests.into_inner().unwrap();
+    // the order of the manifests is part of the key of the resolution cache
+    manifests.sort();
+
+    Ok(manifests)
+}
+
+fn fetch_topic_manifests(
+    client: &Client,
+    topics: &[String],
+    arches: &[&str],
+    root: &Path,
+    options: &ManifestOptions,
+) -> Result<Vec<String>> {
+    let manifests = Mutex::new(Vec::new());
+    // verified against the same keyrings as the branches
+    let keyring = if options.check_signatures {
+        Some(Keyring::load(&trusted_keyrings(
+            options.keyring.as_deref(),
+            &options.keyring_dirs,
+        )?)?)
+    } else {
+        None
+    };
+    topics.par_iter().try_for_each(|topic| -> Result<()> {
+        // Always use AOSC OS Repo for topics
+        let base_url = format!("{}/dists/{}", DEFAULT_MIRROR, topic);
+        let release = if let Some(keyring) = &keyring {
+            fetch_signed_release(client, &base_url, keyring, options.attempts)?
+        } else {
+            let url = format!("{}/Release", base_url);
+            let release = fetch_optional(client, &url, options.attempts)?
+                .ok_or_else(|| anyhow!("{} does not exist", url))?;
+            String::from_utf8(release).context("Release file is not valid UTF-8")?
+        };
+        let checksums = parse_release_checksums(&release)?;
+
+        // the Release file lists every compressed variant of the manifests
+        let names = checksums
+            .keys()
+            .map(|name| {
+                ManifestCompression::value_variants()
+                    .iter()
+                    .filter(|c| **c != ManifestCompression::Plain)
+                    .find_map(|c| name.strip_suffix(c.extension()))
+                    .unwrap_or(name)
+            })
+            .filter(|name| {
+                arches
+                    .iter()
+                    .any(|arch| name.ends_with(&format!("binary-{}/Packages", arch)))
+            })
+            .collect::<BTreeSet<_>>();
+
+        for name in names {
+            let url = Url::parse(&format!("{}/{}", base_url, name))?;
+            let manifest_name = url.host_str().unwrap_or_default().to_string() + url.path();
             let manifest_name = manifest_name.replace('/', "_");
 
-            fetch_url(
+            fetch_manifest(
                 client,
-                &url,
+                &base_url,
+                name,
                 &root.join("var/lib/apt/lists").join(manifest_name.clone()),
+                &options.compressions,
+                keyring.is_some().then_some(&checksums),
+                options.attempts,
             )?;
-            manifests_clone.lock().unwrap().push(manifest_name);
+            manifests.lock().unwrap().push(manifest_name);
+        }
 
-            Ok(())
-        })?;
+        Ok(())
+    })?;
+    let mut manifests = manifests.into_inner().unwrap();
+    manifests.sort();
+
+    Ok(manifests)
+}
 
-    topics.par_iter().try_for_each(move |topic| -> Result<()> {
-	// Always use AOSC OS Repo for topics
-        let url = format!("{}/dists/{}/InRelease", DEFAULT_MIRROR, topic);
+/// Options controlling how packages are downloaded
+pub struct DownloadOptions {
+    /// Verify the checksums of the downloaded files
+    pub verify: bool,
+    /// Number of parallel downloads
+    pub jobs: usize,
+    /// Do not report progress
+    pub quiet: bool,
+    /// Limit of the aggregate download rate in bytes per second
+    pub max_rate: Option<u64>,
+    /// Maximum number of attempts for each file
+    pub attempts: usize,
+    /// Transfers receiving less than 1 KiB/s (or half of their share of `max_rate`) over this
+    /// window are retried
+    pub stall_window: Duration,
+    /// Abort the downloads still in progress after this time
+    pub deadline: Option<Duration>,
+    /// Shared cache of the packages: the packages are downloaded to (or reused from)
+    /// this directory, and linked into the target
+    pub cache_dir: Option<PathBuf>,
+    /// Program downloading the packages
+    pub downloader: Downloader,
+    /// Number of failed files after which a mirror is not used anymore (built-in downloader)
+    pub mirror_fail_threshold: Option<usize>,
+    /// Record the packages missing from the mirrors (see [DownloadStats::missing]) and
+    /// download the others, instead of failing (built-in downloader)
+    pub skip_missing: bool,
+}
+
+#[cfg(test)]
+impl DownloadOptions {
+    /// Verified and quiet downloads with the built-in downloader, one file at a time and
+    /// one attempt each
+    pub fn for_test() -> Self {
+        DownloadOptions {
+            verify: true,
+            jobs: 1,
+            quiet: true,
+            max_rate: None,
+            attempts: 1,
+            stall_window: Duration::from_secs(30),
+            deadline: None,
+            cache_dir: None,
+            downloader: Downloader::Builtin,
+            mirror_fail_threshold: None,
+            skip_missing: false,
+        }
+    }
+}
 
-        let inrelease = client.get(&url).send()?.error_for_status()?.text()?;
-        let inrelease = oma_repo_verify::verify(&inrelease, None, "/")?;
-        let inrelease = oma_debcontrol::parse_str(&inrelease).map_err(|e| anyhow!("{e}"))?;
-        let inrelease = inrelease.first().context("InRelease is empty")?;
+/// Transfers slower than this (in bytes per second) are considered stalled
+const STALL_MIN_RATE: u64 = 1024;
 
-        let sha256 = &inrelease
-            .fields
-            .iter()
-            .find(|x| x.name == "SHA256")
-            .context("Illage InRelease")?
-            .value;
+/// The rate under which a transfer is stalled: the parallel transfers share the rate limit,
+/// a share below the threshold would make every transfer look stalled
+fn stall_min_rate(options: &DownloadOptions) -> u64 {
+    let share = options.max_rate.unwrap_or(u64::MAX) / options.jobs.max(1) as u64;
+
+    STALL_MIN_RATE.min(share / 2)
+}
 
-        for i in sha256.trim().lines() {
-            let name = i
-                .split_ascii_whitespace()
-                .next_back()
-                .context("Illage InRelease")?;
+/// How long to wait for another run downloading the same file into the package cache
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(600);
 
-            if arches
+/// What [batch_download] has done besides downloading
+#[derive(Clone, Debug, Default)]
+pub struct DownloadStats {
+    /// Total size of the existing files whose checksum has been verified
+    pub verified_bytes: u64,
+    /// Time spent verifying the existing files
+    pub verify_time: Duration,
+    /// The packages missing from the mirrors, with [DownloadOptions::skip_missing]
+    pub missing: Vec<String>,
+}
+
+pub fn batch_download(
+    client: &Client,
+    pkgs: &[PackageMeta],
+    mirrors: &[String],
+    root: &Path,
+    options: &DownloadOptions,
+) -> Result<DownloadStats> {
+    check_package_paths(pkgs)?;
+    if options.verify {
+        check_package_checksums(pkgs)?;
+    }
+    let workers = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()
+        .context("Failed to create the download worker pool")?;
+    // shared between all the workers, so that the limit applies to the total throughput
+    let limiter = options.max_rate.map(RateLimiter::new);
+    // aria2c reports the progress itself
+    let quiet = options.quiet || options.downloader == Downloader::Aria2c;
+    let download_root = match options.cache_dir {
+        Some(ref cache_dir) => {
+            std::fs::create_dir_all(cache_dir).with_context(|| {
+                format!("when creating the package cache {}", cache_dir.display())
+            })?;
+            cache_dir.as_path()
+        }
+        None => root,
+    };
+    let mut stats = DownloadStats::default();
+    let pending = verify_existing(pkgs, download_root, options.verify, &mut stats)?;
+    // the files downloaded into `root` instead of the package cache
+    let mut uncached = HashSet::new();
+    let progress = Progress::new("Downloading", pending.len(), quiet)
+        .total_bytes(pending.iter().map(|p| p.download_size).sum());
+    let result = match options.downloader {
+        Downloader::Builtin => workers.install(|| {
+            batch_download_inner(
+                client,
+                &pending,
+                mirrors,
+                download_root,
+                options,
+                &progress,
+                limiter.as_ref(),
+            )
+        }),
+        Downloader::Aria2c => {
+            // aria2c downloads all the files at once, they are locked beforehand
+            let _locks = match options.cache_dir {
+                Some(_) => {
+                    let deadline = Instant::now() + CACHE_LOCK_TIMEOUT;
+                    let (locks, unlocked) = lock_cached_files(&pending, download_root, deadline)?;
+                    uncached = unlocked;
+                    locks
+                }
+                None => Vec::new(),
+            };
+            let (private, pending): (Vec<_>, Vec<_>) = pending
                 .iter()
-                .any(|arch| name.ends_with(&format!("binary-{}/Packages", arch)))
-            {
-                let url = format!("{}/dists/{}/{}", DEFAULT_MIRROR, topic, name);
-                let url = Url::parse(&url)?;
-                let manifest_name =
-                    url.host_str().unwrap_or_default().to_string() + url.path();
-                let manifest_name = manifest_name.replace('/', "_");
+                .copied()
+                .filter(|p| !is_downloaded(p, &download_root.join(p.file_name()), options.verify))
+                .partition(|p| uncached.contains(&p.file_name()));
+            // never share the partial files of the other run
+            aria2_download(&pending, mirrors, download_root, options)
+                .and_then(|_| aria2_download(&private, mirrors, root, options))
+                .map(|_| Vec::new())
+        }
+    };
+    progress.finish();
+    stats.missing = result.context("Failed to download packages")?;
+    if download_root != root {
+        // the cached files have been verified (or downloaded) above
+        for pkg in pkgs
+            .iter()
+            .filter(|p| !stats.missing.contains(&p.name) && !uncached.contains(&p.file_name()))
+        {
+            let filename = pkg.file_name();
+            copy_local_file(&download_root.join(&filename), &root.join(&filename), None)
+                .with_context(|| format!("when copying {} from the package cache", filename))?;
+        }
+    }
 
-                fetch_url(
-                    client,
-                    url.as_str(),
-                    &root.join("var/lib/apt/lists").join(manifest_name.clone()),
-                )?;
-                manifests_clone_2.lock().unwrap().push(manifest_name);
+    Ok(stats)
+}
+
+/// Lock the file of the package cache with `flock` on `<file>.lock`, so that the runs
+/// sharing the cache do not download it at the same time. The lock is released when it is
+/// dropped or when its owner dies; it is not taken (`None`) if still held at `deadline`,
+/// reporting the `fallback` of the caller.
+fn lock_cached_file(
+    path: &Path,
+    deadline: Instant,
+    progress: Option<&Progress>,
+    fallback: &str,
+) -> Result<Option<Flock<File>>> {
+    let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+    // never removed, another run may be waiting on it
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("when opening {}", lock_path.display()))?;
+    let start = Instant::now();
+    loop {
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => return Ok(Some(lock)),
+            Err((f, Errno::EWOULDBLOCK)) => file = f,
+            Err((_, e)) => {
+                return Err(e).with_context(|| format!("when locking {}", lock_path.display()))
+            }
+        }
+        cancel::check()?;
+        if Instant::now() >= deadline {
+            let message = format!(
+                "{} is still locked by another download after {}s, {}",
+                lock_path.display(),
+                start.elapsed().as_secs(),
+                fallback
+            );
+            match progress {
+                Some(progress) if !logging::is_json() => progress.println(&message),
+                _ => logging::event("cache_lock_timeout", json!({ "file": lock_path }), message),
             }
+            return Ok(None);
         }
+        sleep(Duration::from_millis(200));
+    }
+}
 
-        Ok(())
-    })?;
+/// Lock the files of `pkgs` in the package cache `root` in a stable order, all of them by
+/// `deadline`. Returns the locks, and the names of the files still locked by other runs,
+/// which are downloaded into the target instead (never into the partial files of the others)
+fn lock_cached_files(
+    pkgs: &[&PackageMeta],
+    root: &Path,
+    deadline: Instant,
+) -> Result<(Vec<Flock<File>>, HashSet<String>)> {
+    let mut locks = Vec::new();
+    let mut unlocked = HashSet::new();
+    let mut sorted = pkgs.to_vec();
+    sorted.sort_by_key(|p| p.file_name());
+    for pkg in sorted {
+        let path = root.join(pkg.file_name());
+        let fallback = "downloading it into the target instead";
+        match lock_cached_file(&path, deadline, None, fallback)? {
+            Some(lock) => locks.push(lock),
+            None => {
+                unlocked.insert(pkg.file_name());
+            }
+        }
+    }
 
-    Ok(Arc::try_unwrap(manifests).unwrap().into_inner().unwrap())
+    Ok((locks, unlocked))
 }
 
-pub fn batch_download(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Result<()> {
-    for i in 1..=3 {
-        if batch_download_inner(pkgs, mirror, root).is_ok() {
-            return Ok(());
+/// Whether another run has downloaded the package to `path` while it was locked
+fn is_downloaded(pkg: &PackageMeta, path: &Path, verify: bool) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    match pkg.checksum().filter(|_| verify) {
+        Some((kind, expected)) => matches!(verify_file(path, kind, expected), Ok(None)),
+        None => true,
+    }
+}
+
+/// Verify the files already in `root` on all the cores, the ones which do not match their
+/// checksum are removed. Returns the packages which have to be downloaded.
+fn verify_existing<'a>(
+    pkgs: &'a [PackageMeta],
+    root: &Path,
+    verify: bool,
+    stats: &mut DownloadStats,
+) -> Result<Vec<&'a PackageMeta>> {
+    let start = Instant::now();
+    // (intact, bytes hashed)
+    let results = pkgs
+        .par_iter()
+        .map(|pkg| -> Result<(bool, u64)> {
+            cancel::check()?;
+            let filename = pkg.file_name();
+            let path = root.join(&filename);
+            match std::fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => {
+                    let Some((kind, expected)) = pkg.checksum().filter(|_| verify) else {
+                        return Ok((true, 0));
+                    };
+                    let mismatch = verify_file(&path, kind, expected)
+                        .with_context(|| format!("when verifying {}", filename))?;
+                    if mismatch.is_some() {
+                        std::fs::remove_file(&path)
+                            .with_context(|| format!("when removing {}", filename))?;
+                    }
+                    Ok((mismatch.is_none(), metadata.len()))
+                }
+                _ => Ok((false, 0)),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    stats.verified_bytes = results.iter().map(|(_, bytes)| bytes).sum();
+    stats.verify_time = start.elapsed();
+
+    Ok(pkgs
+        .iter()
+        .zip(results)
+        .filter_map(|(pkg, (intact, _))| (!intact).then_some(pkg))
+        .collect())
+}
+
+/// The URL of the package at `path` (from the manifest) on the `mirror`: the reserved
+/// characters of the path are percent-encoded (e.g. the `+` and `:` of the versions,
+/// some servers decode a literal `+` as a space). The path is a file name, not a URL: its
+/// `%` are encoded too, e.g. `a%2bb` names the file `a%2bb` and not `a+b`
+pub fn package_url(mirror: &str, path: &str) -> String {
+    let mut url = format!("{}/", mirror);
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            url.push(b as char);
+        } else {
+            url.push_str(&format!("%{:02X}", b));
         }
-        eprintln!("[{}/3] Retrying ...", i);
-        sleep(Duration::from_secs(2));
     }
 
-    Err(anyhow!("Failed to download packages"))
+    url
 }
 
-fn batch_download_inner(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Result<()> {
-    let client = make_new_client()?;
-    let total = pkgs.len() * 2;
-    let count = AtomicUsize::new(0);
-    let error = AtomicBool::new(false);
-    pkgs.par_iter().for_each_init(
-        move || client.clone(),
-        |client, pkg| {
-            let filename = pkg.file_name();
-            count.fetch_add(1, Ordering::SeqCst);
-            println!(
-                "[{}/{}] Downloading {}...",
-                count.load(Ordering::SeqCst),
-                total,
-                pkg.name
-            );
+/// Check the file against the expected checksum, returns the actual checksum on mismatch
+fn verify_file(path: &Path, kind: ChecksumKind, expected: &str) -> Result<Option<String>> {
+    let actual = checksum(kind, File::open(path)?)?;
+    if actual.eq_ignore_ascii_case(expected) {
+        return Ok(None);
+    }
+
+    Ok(Some(actual))
+}
+
+/// The program downloading the packages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Downloader {
+    /// The built-in HTTP client
+    #[default]
+    Builtin,
+    /// aria2c(1), invoked once for all the packages
+    Aria2c,
+}
+
+/// Describe the exit status of aria2c (see the EXIT STATUS section of aria2c(1))
+fn aria2_exit_reason(code: i32) -> &'static str {
+    match code {
+        0 => "all downloads were successful",
+        2 => "time out",
+        3 => "resource was not found",
+        6 => "network problem",
+        7 => "there were unfinished downloads",
+        9 => "not enough disk space",
+        13 => "file already existed",
+        19 => "name resolution failed",
+        22 => "the HTTP response header was bad or unexpected",
+        24 => "HTTP authorization failed",
+        32 => "checksum validation failed",
+        _ => "unknown error",
+    }
+}
+
+/// Generate an aria2c input file downloading `pkgs` into `root`, each from all the `mirrors`
+fn aria2_input_file(
+    pkgs: &[&PackageMeta],
+    mirrors: &[String],
+    root: &Path,
+    verify: bool,
+) -> Result<String> {
+    let mut input = String::new();
+    let topic_mirror = [DEFAULT_MIRROR.to_string()];
+    for pkg in pkgs {
+        let mirrors = if pkg.in_topic { &topic_mirror } else { mirrors };
+        if let Some(mirror) = mirrors.iter().find(|m| local_path(m).is_some()) {
+            bail!("aria2c cannot download from the local mirror {}", mirror);
+        }
+        let urls = mirrors
+            .iter()
+            .map(|m| package_url(m, &pkg.path))
+            .collect::<Vec<_>>();
+        // the URLs on the same line are mirrors of the same file
+        input.push_str(&urls.join("\t"));
+        input.push('\n');
+        input.push_str(&format!("  dir={}\n", root.display()));
+        input.push_str(&format!("  out={}\n", pkg.file_name()));
+        if let Some((kind, expected)) = pkg.checksum().filter(|_| verify) {
+            let kind = match kind {
+                ChecksumKind::Sha512 => "sha-512",
+                ChecksumKind::Sha256 => "sha-256",
+                ChecksumKind::Md5 => "md5",
+            };
+            input.push_str(&format!("  checksum={}={}\n", kind, expected));
+        }
+    }
+
+    Ok(input)
+}
+
+/// Download the `pending` packages (see [verify_existing]) with aria2c
+fn aria2_download(
+    pending: &[&PackageMeta],
+    mirrors: &[String],
+    root: &Path,
+    options: &DownloadOptions,
+) -> Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let mut input = tempfile::NamedTempFile::new()?;
+    input.write_all(aria2_input_file(pending, mirrors, root, options.verify)?.as_bytes())?;
+
+    let mut command = std::process::Command::new("aria2c");
+    command
+        .arg(format!("--input-file={}", input.path().display()))
+        .arg(format!("--max-concurrent-downloads={}", options.jobs))
+        .arg(format!("--max-tries={}", options.attempts))
+        .arg(format!("--lowest-speed-limit={}", stall_min_rate(options)))
+        .args([
+            "--continue=true",
+            "--allow-overwrite=true",
+            "--auto-file-renaming=false",
+            "--summary-interval=0",
+            "--console-log-level=warn",
+        ]);
+    if let Some(rate) = options.max_rate {
+        command.arg(format!("--max-overall-download-limit={}", rate));
+    }
+    if let Some(deadline) = options.deadline {
+        command.arg(format!("--stop={}", deadline.as_secs().max(1)));
+    }
+    if options.quiet {
+        command.arg("--quiet=true");
+    }
+    let status = command
+        .status()
+        .context("when running aria2c, is it installed?")?;
+    // aria2c also receives the signal from the terminal
+    cancel::check()?;
+
+    // aria2c keeps a control file next to the unfinished downloads
+    let failed = pending
+        .iter()
+        .filter(|pkg| {
+            let path = root.join(pkg.file_name());
+            !path.is_file() || Path::new(&format!("{}.aria2", path.display())).exists()
+        })
+        .map(|pkg| pkg.name.as_str())
+        .collect::<Vec<_>>();
+    if !status.success() || !failed.is_empty() {
+        let reason = match status.code() {
+            Some(code) => format!("{} ({})", aria2_exit_reason(code), code),
+            None => status.to_string(),
+        };
+        return Err(anyhow!(
+            "aria2c failed: {}, {} package(s) not downloaded:\n{}",
+            reason,
+            failed.len(),
+            failed.join("\n")
+        ));
+    }
+    for pkg in pending {
+        let path = root.join(pkg.file_name());
+        logging::json_event(
+            "download",
+            json!({
+                "package": pkg.name,
+                "version": pkg.version,
+                "size": std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+            }),
+        );
+    }
+
+    Ok(())
+}
 
-            let path = root.join(filename);
-	    let mirror = if pkg.in_topic { DEFAULT_MIRROR } else { mirror };
-            if !path.is_file()
-                && fetch_url(client, &format!("{}/{}", mirror, pkg.path), &path).is_err()
-            {
-                error.store(true, Ordering::SeqCst);
-                eprintln!("Download failed: {}", pkg.name);
+/// Download the `pkgs` which are not intact yet (see [verify_existing]), returns the
+/// packages missing from the mirrors if they are skipped
+fn batch_download_inner(
+    client: &Client,
+    pkgs: &[&PackageMeta],
+    mirrors: &[String],
+    root: &Path,
+    options: &DownloadOptions,
+    progress: &Progress,
+    limiter: Option<&RateLimiter>,
+) -> Result<Vec<String>> {
+    let verify = options.verify;
+    let aborted = AtomicBool::new(false);
+    let errors = Mutex::new(Vec::new());
+    let missing = Mutex::new(Vec::new());
+    let watchdog = Watchdog {
+        min_rate: stall_min_rate(options),
+        window: options.stall_window,
+        deadline: options.deadline.map(|d| Instant::now() + d),
+    };
+    let health = MirrorHealth::new(options.mirror_fail_threshold);
+    pkgs.par_iter().for_each(|pkg| {
+        // stop picking up new packages once any of the downloads has failed
+        if aborted.load(Ordering::SeqCst) || watchdog.deadline_exceeded() || cancel::is_cancelled()
+        {
+            return;
+        }
+        let path = root.join(pkg.file_name());
+        progress.start_item(&pkg.name);
+        let start = Instant::now();
+        // another run sharing the package cache may be downloading the same file
+        let lock = match options.cache_dir {
+            Some(_) => lock_cached_file(
+                &path,
+                Instant::now() + CACHE_LOCK_TIMEOUT,
+                Some(progress),
+                "ignoring the lock",
+            ),
+            None => Ok(None),
+        };
+        match &lock {
+            Ok(Some(_)) if is_downloaded(pkg, &path, verify) => return,
+            Ok(_) => (),
+            Err(e) => {
+                aborted.store(true, Ordering::SeqCst);
+                errors
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {:#}", pkg.name, e));
                 return;
             }
-            count.fetch_add(1, Ordering::SeqCst);
-            println!(
-                "[{}/{}] Verifying {}...",
-                count.load(Ordering::SeqCst),
-                total,
-                pkg.name
-            );
-            if !sha256sum_file(&path)
-                .map(|x| x == pkg.sha256)
-                .unwrap_or(false)
-            {
-                std::fs::remove_file(path).ok();
-                error.store(true, Ordering::SeqCst);
-                eprintln!("Verification failed: {}", pkg.name);
+        }
+
+        let topic_mirror = [DEFAULT_MIRROR.to_string()];
+        let mirrors = if pkg.in_topic { &topic_mirror } else { mirrors };
+        let result = try_live_mirrors(mirrors, &health, |mirror| {
+            let url = package_url(mirror, &pkg.path);
+            with_retries(options.attempts, &url, Some(progress), || {
+                cancel::check()?;
+                if watchdog.deadline_exceeded() {
+                    // not transient, the retries and the other mirrors are skipped
+                    return Err(anyhow!("download deadline exceeded"));
+                }
+                fetch_url_resumable(
+                    client,
+                    &url,
+                    &path,
+                    pkg.checksum().filter(|_| verify),
+                    Some(progress),
+                    limiter,
+                    Some(&watchdog),
+                )
+            })
+        });
+        if result.is_err() && (watchdog.deadline_exceeded() || cancel::is_cancelled()) {
+            // reported with the other unfinished downloads below
+            return;
+        }
+        if let Err(e) = result {
+            if options.skip_missing && is_missing_file(&e) {
+                progress.println(&format!("Missing from the mirrors: {}", pkg.name));
+                missing.lock().unwrap().push(pkg.name.clone());
+                return;
             }
-        },
+            // the partial file is kept so that the next run can resume from it
+            // (unless its checksum does not match)
+            aborted.store(true, Ordering::SeqCst);
+            progress.println(&format!("Download failed: {}", pkg.name));
+            errors
+                .lock()
+                .unwrap()
+                .push(format!("{}: download failed: {}", pkg.name, e));
+            return;
+        }
+        logging::json_event(
+            "download",
+            json!({
+                "package": pkg.name,
+                "version": pkg.version,
+                "size": std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+                "duration_ms": start.elapsed().as_millis() as u64,
+            }),
+        );
+    });
+
+    cancel::check()?;
+    let errors = errors.into_inner().unwrap();
+    if !errors.is_empty() {
+        return Err(anyhow!("Unable to download files:\n{}", errors.join("\n")));
+    }
+    if watchdog.deadline_exceeded() {
+        let unfinished = pkgs
+            .iter()
+            .filter(|p| !root.join(p.file_name()).is_file())
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        if !unfinished.is_empty() {
+            return Err(anyhow!(
+                "Download deadline of {}s exceeded, {} package(s) not downloaded:\n{}",
+                options.deadline.unwrap_or_default().as_secs(),
+                unfinished.len(),
+                unfinished.join("\n")
+            ));
+        }
+    }
+    let mut missing = missing.into_inner().unwrap();
+    missing.sort();
+
+    Ok(missing)
+}
+
+#[test]
+fn test_interrupted_download() -> Result<()> {
+    let mirror = tempfile::tempdir()?;
+    let archive = tempfile::tempdir()?;
+    let content = b"package content".repeat(64);
+    std::fs::write(mirror.path().join("foo.deb"), &content)?;
+    let url = Url::from_file_path(mirror.path().join("foo.deb"))
+        .unwrap()
+        .to_string();
+    let sha512 = checksum(ChecksumKind::Sha512, &content[..])?;
+    let client = make_new_client(None, &Timeouts::default())?;
+    let path = archive.path().join("foo.deb");
+    let part_path = archive.path().join("foo.deb.part");
+    let expected = Some((ChecksumKind::Sha512, sha512.as_str()));
+
+    // the server drops the connection after 100 bytes, then serves the rest of the file
+    let cut = 100;
+    let mut truncated = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content.len()
+    )
+    .into_bytes();
+    truncated.extend_from_slice(&content[..cut]);
+    let mut rest = format!(
+        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+        content.len() - cut,
+        cut,
+        content.len() - 1,
+        content.len()
+    )
+    .into_bytes();
+    rest.extend_from_slice(&content[cut..]);
+    let (port, server) = serve_raw(vec![truncated, rest])?;
+    let http_url = format!("http://127.0.0.1:{}/debs/foo.deb", port);
+
+    // an interrupted download leaves a truncated `.part` file, but never the file itself
+    assert!(fetch_url_resumable(&client, &http_url, &path, expected, None, None, None).is_err());
+    assert!(!path.exists());
+    assert_eq!(std::fs::read(&part_path)?, &content[..cut]);
+    // the next attempt only requests the missing bytes
+    fetch_url_resumable(&client, &http_url, &path, expected, None, None, None)?;
+    assert_eq!(std::fs::read(&path)?, content);
+    assert!(!part_path.exists());
+    let heads = server.join().unwrap();
+    assert!(!heads[0].to_ascii_lowercase().contains("range:"));
+    assert!(heads[1]
+        .to_ascii_lowercase()
+        .contains(&format!("range: bytes={}-", cut)));
+
+    // a corrupted download is discarded instead of being moved into place
+    std::fs::remove_file(&path)?;
+    let result = fetch_url_resumable(
+        &client,
+        &url,
+        &path,
+        Some((ChecksumKind::Md5, &"0".repeat(32))),
+        None,
+        None,
+        None,
     );
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("MD5sum checksum mismatch"));
+    assert!(!path.exists());
+    assert!(!part_path.exists());
+
+    // the mirror itself is left untouched
+    assert_eq!(std::fs::read(mirror.path().join("foo.deb"))?, content);
 
-    if error.load(Ordering::SeqCst) {
-        return Err(anyhow!("Unable to download files"));
+    Ok(())
+}
+
+/// Serve one HTTP request per raw response on a local port (the connection is closed after
+/// each response), returns the port and the received request heads
+#[cfg(test)]
+fn serve_raw(responses: Vec<Vec<u8>>) -> Result<(u16, std::thread::JoinHandle<Vec<String>>)> {
+    use std::io::{BufRead, BufReader};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let handle = std::thread::spawn(move || {
+        let mut heads = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            reader.get_mut().write_all(&response).unwrap();
+            heads.push(head);
+        }
+        heads
+    });
+
+    Ok((port, handle))
+}
+
+/// Serve a single HTTP request on a local port, returns the port and the received request head
+#[cfg(test)]
+fn serve_once(body: &'static str) -> Result<(u16, std::thread::JoinHandle<String>)> {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let (port, handle) = serve_raw(vec![response.into_bytes()])?;
+    let handle = std::thread::spawn(move || handle.join().unwrap().remove(0));
+
+    Ok((port, handle))
+}
+
+#[test]
+fn test_slow_manifest() -> Result<()> {
+    use std::io::{BufRead, BufReader};
+
+    // the body takes longer than the read timeout, but each chunk arrives in time
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let stream = reader.get_mut();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        for chunk in [b"Pac", b"kag", b"es\n"] {
+            stream.write_all(chunk).unwrap();
+            stream.flush().unwrap();
+            sleep(Duration::from_millis(600));
+        }
+    });
+    let timeouts = Timeouts {
+        connect: Duration::from_secs(1),
+        read: Duration::from_secs(1),
+    };
+    let client = make_new_client(None, &timeouts)?;
+    let url = format!("http://127.0.0.1:{}/debs/Packages", port);
+    assert_eq!(
+        fetch_optional_once(&client, &url)?.as_deref(),
+        Some(&b"Packages\n"[..])
+    );
+    server.join().unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn test_proxy() -> Result<()> {
+    let (proxy_port, proxy) = serve_once("proxied")?;
+    let client = make_new_client(
+        Some(&format!("http://127.0.0.1:{}", proxy_port)),
+        &Timeouts::default(),
+    )?;
+    let body = client
+        .get("http://mirror.aoscbootstrap.invalid/debs/Release")
+        .send()?
+        .text()?;
+    assert_eq!(body, "proxied");
+    let head = proxy.join().unwrap();
+    assert!(head.starts_with("GET http://mirror.aoscbootstrap.invalid/debs/Release HTTP/1.1"));
+
+    // hosts matching NO_PROXY are connected to directly
+    let (mirror_port, mirror) = serve_once("direct")?;
+    let proxy = make_proxy("http://127.0.0.1:9", NoProxy::from_string("127.0.0.1"))?;
+    let client = Client::builder().proxy(proxy).build()?;
+    let body = client
+        .get(format!("http://127.0.0.1:{}/debs/Release", mirror_port))
+        .send()?
+        .text()?;
+    assert_eq!(body, "direct");
+    assert!(mirror
+        .join()
+        .unwrap()
+        .starts_with("GET /debs/Release HTTP/1.1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_retries() -> Result<()> {
+    let reset = || anyhow::Error::from(std::io::Error::from(ErrorKind::ConnectionReset));
+
+    let mut calls = 0;
+    let value = with_retries(3, "test", None, || {
+        calls += 1;
+        if calls < 3 {
+            return Err(reset());
+        }
+        Ok(calls)
+    })?;
+    assert_eq!(value, 3);
+
+    // permanent failures are not retried
+    let mut calls = 0;
+    let result: Result<()> = with_retries(3, "test", None, || {
+        calls += 1;
+        Err(std::io::Error::from(ErrorKind::NotFound).into())
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+
+    let result: Result<()> = with_retries(2, "test", None, || Err(reset()));
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("after 2 attempt(s)"));
+    assert!(is_mirror_failure(&err));
+    // a full disk is not the fault of the mirror
+    let full = anyhow::Error::from(std::io::Error::from(ErrorKind::StorageFull));
+    assert!(!is_mirror_failure(&full));
+
+    Ok(())
+}
+
+/// A local mirror with `foo.deb` (containing `foo`), for the download tests
+#[cfg(test)]
+struct TestMirror {
+    dir: tempfile::TempDir,
+    mirrors: Vec<String>,
+    client: Client,
+}
+
+#[cfg(test)]
+impl TestMirror {
+    fn new() -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("foo.deb"), b"foo")?;
+        let mirrors = vec![normalize_mirror(&dir.path().display().to_string())];
+        let client = make_new_client(None, &Timeouts::default())?;
+
+        Ok(TestMirror {
+            dir,
+            mirrors,
+            client,
+        })
+    }
+
+    /// The package `name` at `<name>.deb` of the mirror, with the checksum and the size
+    /// of `foo.deb`
+    fn package(name: &str) -> PackageMeta {
+        PackageMeta {
+            sha256: sha256sum(&b"foo"[..]).unwrap(),
+            path: format!("{}.deb", name),
+            download_size: 3,
+            ..PackageMeta::for_test(name)
+        }
+    }
+
+    fn download(
+        &self,
+        packages: &[PackageMeta],
+        root: &Path,
+        options: &DownloadOptions,
+    ) -> Result<DownloadStats> {
+        batch_download(&self.client, packages, &self.mirrors, root, options)
+    }
+}
+
+#[test]
+fn test_download_deadline() -> Result<()> {
+    let mirror = TestMirror::new()?;
+    let archive = tempfile::tempdir()?;
+    let options = DownloadOptions {
+        deadline: Some(Duration::ZERO),
+        ..DownloadOptions::for_test()
+    };
+    let err = mirror
+        .download(&[TestMirror::package("foo")], archive.path(), &options)
+        .unwrap_err();
+    let err = format!("{:#}", err);
+    assert!(
+        err.contains("deadline of 0s exceeded, 1 package(s)"),
+        "{}",
+        err
+    );
+    assert!(err.ends_with("\nfoo"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_stall_min_rate() {
+    // the 8 transfers share 4 KiB/s, 512 B/s each
+    let limited = DownloadOptions {
+        jobs: 8,
+        max_rate: Some(4096),
+        ..DownloadOptions::for_test()
+    };
+    assert_eq!(stall_min_rate(&limited), 256);
+    assert_eq!(
+        stall_min_rate(&DownloadOptions { jobs: 1, ..limited }),
+        1024
+    );
+    assert_eq!(stall_min_rate(&DownloadOptions::for_test()), 1024);
+}
+
+#[test]
+fn test_check_architectures() -> Result<()> {
+    let release =
+        "Origin: AOSC\nSuite: stable\nArchitectures: amd64 arm64 loongarch64\nComponents: main\n";
+    check_architectures(release, "stable", &["amd64", "all"])?;
+    let err = check_architectures(release, "stable", &["mips64r6", "all"])
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err,
+        "The branch stable does not provide the architecture(s): mips64r6\nAvailable architectures: amd64, arm64, loongarch64"
+    );
+    check_architectures("Origin: AOSC\nSuite: stable\n", "stable", &["mips64r6"])?;
+
+    Ok(())
+}
+
+#[test]
+fn test_package_cache() -> Result<()> {
+    let mirror = TestMirror::new()?;
+    let cache = tempfile::tempdir()?;
+    let package = TestMirror::package("foo");
+    let packages = [package.clone()];
+    let options = DownloadOptions {
+        cache_dir: Some(cache.path().to_path_buf()),
+        ..DownloadOptions::for_test()
+    };
+    let first = tempfile::tempdir()?;
+    mirror.download(&packages, first.path(), &options)?;
+    let cached = cache.path().join(package.file_name());
+    assert_eq!(std::fs::read(&cached)?, b"foo");
+    assert_eq!(
+        std::fs::read(first.path().join(package.file_name()))?,
+        b"foo"
+    );
+
+    // the second target is populated from the cache, even if the mirror is gone
+    std::fs::remove_file(mirror.dir.path().join("foo.deb"))?;
+    let second = tempfile::tempdir()?;
+    mirror.download(&packages, second.path(), &options)?;
+    assert_eq!(
+        std::fs::read(second.path().join(package.file_name()))?,
+        b"foo"
+    );
+
+    // corrupted entries are not reused
+    std::fs::remove_file(&cached)?;
+    std::fs::write(&cached, b"bar")?;
+    let third = tempfile::tempdir()?;
+    assert!(mirror.download(&packages, third.path(), &options).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_skip_missing() -> Result<()> {
+    let mirror = TestMirror::new()?;
+    let archive = tempfile::tempdir()?;
+    // the mirror does not have bar.deb
+    let packages = [TestMirror::package("foo"), TestMirror::package("bar")];
+    let mut options = DownloadOptions {
+        jobs: 2,
+        ..DownloadOptions::for_test()
+    };
+    let err = mirror
+        .download(&packages, archive.path(), &options)
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("bar: download failed"));
+
+    options.skip_missing = true;
+    let stats = mirror.download(&packages, archive.path(), &options)?;
+    assert_eq!(stats.missing, ["bar"]);
+    assert!(archive.path().join(packages[0].file_name()).is_file());
+
+    Ok(())
+}
+
+#[test]
+fn test_mirror_blacklist() -> Result<()> {
+    let mirrors = [
+        "https://broken.example.com".to_string(),
+        "https://ok.example.com".to_string(),
+    ];
+    let health = MirrorHealth::new(Some(2));
+    let mut tried = Vec::new();
+    for _ in 0..3 {
+        let mirror = try_live_mirrors(&mirrors, &health, |mirror| {
+            tried.push(mirror.to_string());
+            if mirror.contains("broken") {
+                return Err(std::io::Error::from(ErrorKind::ConnectionRefused).into());
+            }
+            Ok(mirror.to_string())
+        })?;
+        assert_eq!(mirror, mirrors[1]);
+    }
+    // the broken mirror is skipped after its second failure
+    assert_eq!(tried.iter().filter(|m| m.contains("broken")).count(), 2);
+    assert_eq!(tried.len(), 5);
+
+    let health = MirrorHealth::new(Some(1));
+    let fail = |_: &str| -> Result<()> {
+        Err(LocalMirrorError {
+            path: PathBuf::from("/srv/debs/foo.deb"),
+            source: std::io::Error::from(ErrorKind::NotFound),
+        }
+        .into())
+    };
+    assert!(try_live_mirrors(&mirrors, &health, fail).is_err());
+    let err = try_live_mirrors(&mirrors, &health, fail).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("All mirrors have been blacklisted (each failed for 1 package(s))"));
+
+    Ok(())
+}
+
+#[test]
+fn test_package_url() {
+    let path = "debs/pool/stable/main/l/libfoo_1:1.0+git~rc1-1_amd64.deb";
+    assert_eq!(
+        package_url("https://repo.aosc.io/debs", path),
+        "https://repo.aosc.io/debs/debs/pool/stable/main/l/libfoo_1%3A1.0%2Bgit~rc1-1_amd64.deb"
+    );
+    assert_eq!(
+        package_url("https://repo.aosc.io/debs", "pool/a%2bb_1.0%_all.deb"),
+        "https://repo.aosc.io/debs/pool/a%252bb_1.0%25_all.deb"
+    );
+    let url = package_url("file:///srv/debs", "pool/a%2bb.deb");
+    assert_eq!(
+        local_path(&url),
+        Some(PathBuf::from("/srv/debs/pool/a%2bb.deb"))
+    );
+    // the local mirrors are read from the decoded path
+    let url = package_url("file:///srv/debs", path);
+    assert_eq!(
+        local_path(&url),
+        Some(PathBuf::from("/srv/debs").join(path))
+    );
+}
+
+#[test]
+fn test_snapshot() -> Result<()> {
+    check_snapshot_timestamp("20240131T120000Z")?;
+    for invalid in [
+        "2024-01-31",
+        "20240131120000Z",
+        "20241331T120000Z",
+        "20240131T250000Z",
+    ] {
+        assert!(check_snapshot_timestamp(invalid).is_err());
+    }
+    assert_eq!(
+        snapshot_mirror(
+            DEFAULT_SNAPSHOT_TEMPLATE,
+            "https://repo.aosc.io/debs/",
+            "20240131T120000Z"
+        )?,
+        "https://repo.aosc.io/debs/snapshots/20240131T120000Z"
+    );
+    assert!(snapshot_mirror(
+        "https://example.org/",
+        "https://repo.aosc.io/debs",
+        "20240131T120000Z"
+    )
+    .is_err());
+
+    let dir = tempfile::tempdir()?;
+    let mirror = snapshot_mirror(
+        DEFAULT_SNAPSHOT_TEMPLATE,
+        dir.path().to_str().unwrap(),
+        "20240131T120000Z",
+    )?;
+    let client = make_new_client(None, &Timeouts::default())?;
+    let err = check_snapshot(&client, std::slice::from_ref(&mirror), "stable", 1).unwrap_err();
+    assert!(err.to_string().contains("not available"));
+    let dists = dir.path().join("snapshots/20240131T120000Z/dists/stable");
+    std::fs::create_dir_all(&dists)?;
+    std::fs::write(dists.join("Release"), "Suite: stable\n")?;
+    check_snapshot(&client, &[mirror], "stable", 1)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_aria2_input_file() -> Result<()> {
+    let package = |name: &str, in_topic: bool| PackageMeta {
+        version: "1:1.0".to_string(),
+        path: format!("pool/stable/main/{}.deb", name),
+        arch: "amd64".to_string(),
+        in_topic,
+        ..PackageMeta::for_test(name)
+    };
+    let (bash, topic) = (package("bash", false), package("topic", true));
+    let mirrors = [
+        "https://mirror.example.com/debs".to_string(),
+        "https://repo.aosc.io/debs".to_string(),
+    ];
+    let input = aria2_input_file(&[&bash, &topic], &mirrors, Path::new("/archives"), true)?;
+
+    assert_eq!(
+        input,
+        format!(
+            "https://mirror.example.com/debs/pool/stable/main/bash.deb\thttps://repo.aosc.io/debs/pool/stable/main/bash.deb\n  dir=/archives\n  out=bash_1%3a1.0_amd64.deb\n  checksum=sha-256={0}\nhttps://repo.aosc.io/debs/pool/stable/main/topic.deb\n  dir=/archives\n  out=topic_1%3a1.0_amd64.deb\n  checksum=sha-256={0}\n",
+            "0".repeat(64)
+        )
+    );
+    assert!(
+        !aria2_input_file(&[&bash], &mirrors, Path::new("/archives"), false)?.contains("checksum")
+    );
+    assert!(aria2_input_file(
+        &[&bash],
+        &["file:///srv/debs".to_string()],
+        Path::new("/"),
+        true
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_existing() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let package = |name: &str| -> Result<PackageMeta> {
+        Ok(PackageMeta {
+            sha256: sha256sum(name.as_bytes())?,
+            path: format!("{}.deb", name),
+            download_size: 3,
+            ..PackageMeta::for_test(name)
+        })
+    };
+    let packages = [package("foo")?, package("bar")?, package("baz")?];
+    std::fs::write(root.path().join(packages[0].file_name()), "foo")?;
+    std::fs::write(root.path().join(packages[1].file_name()), "corrupted")?;
+
+    let mut stats = DownloadStats::default();
+    let pending = verify_existing(&packages, root.path(), true, &mut stats)?;
+    let names = pending.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["bar", "baz"]);
+    assert_eq!(stats.verified_bytes, 12);
+    // the corrupted file is downloaded again
+    assert!(!root.path().join(packages[1].file_name()).exists());
+    let pending = verify_existing(&packages, root.path(), false, &mut stats)?;
+    assert_eq!(pending.len(), 2);
+    assert_eq!(stats.verified_bytes, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_keyring_dir() -> Result<()> {
+    use sequoia_openpgp::{
+        cert::CertBuilder,
+        serialize::{
+            stream::{Message, Signer as MessageSigner},
+            Serialize,
+        },
+    };
+
+    let dir = tempfile::tempdir()?;
+    let (signing, _) = CertBuilder::general_purpose(None, Some("AOSC OS Repository")).generate()?;
+    let (other, _) = CertBuilder::general_purpose(None, Some("Other")).generate()?;
+    for (name, cert) in [("aosc.gpg", &signing), ("other.asc", &other)] {
+        let mut f = File::create(dir.path().join(name))?;
+        cert.serialize(&mut f)?;
     }
+    std::fs::write(dir.path().join("README"), "not a keyring")?;
+    let keyrings = trusted_keyrings(None, &[dir.path().to_path_buf()])?;
+    assert_eq!(
+        keyrings,
+        [dir.path().join("aosc.gpg"), dir.path().join("other.asc")]
+    );
+    let keyring = Keyring::load(&keyrings)?;
+
+    let policy = signature_policy();
+    let keypair = signing
+        .keys()
+        .with_policy(&policy, None)
+        .for_signing()
+        .secret()
+        .next()
+        .context("no signing key")?
+        .key()
+        .clone()
+        .into_keypair()?;
+    let keypair_sha1 = keypair.clone();
+    let mut inrelease = Vec::new();
+    let mut writer = MessageSigner::new(Message::new(&mut inrelease), keypair)
+        .cleartext()
+        .build()?;
+    writer.write_all(b"Suite: stable\n")?;
+    writer.finalize()?;
+
+    let helper = KeyringVerifier {
+        keyring: &keyring,
+        signer: None,
+    };
+    let mut verifier =
+        VerifierBuilder::from_bytes(&inrelease)?.with_policy(&policy, None, helper)?;
+    let mut content = String::new();
+    verifier.read_to_string(&mut content)?;
+    assert_eq!(content, "Suite: stable\n");
+    let signer = verifier.helper_ref().signer.clone().context("no signer")?;
+    assert_eq!(signer.user_id.as_deref(), Some("AOSC OS Repository"));
+    assert_eq!(signer.keyring, dir.path().join("aosc.gpg"));
+
+    // not signed by any of the trusted keys
+    let other_keyring = Keyring::load(&[dir.path().join("other.asc")])?;
+    let helper = KeyringVerifier {
+        keyring: &other_keyring,
+        signer: None,
+    };
+    assert!(VerifierBuilder::from_bytes(&inrelease)?
+        .with_policy(&policy, None, helper)
+        .is_err());
+    assert!(trusted_keyrings(None, &[tempfile::tempdir()?.path().to_path_buf()]).is_err());
+
+    // SHA-1 signatures are rejected
+    let mut inrelease = Vec::new();
+    let mut writer = MessageSigner::new(Message::new(&mut inrelease), keypair_sha1)
+        .hash_algo(HashAlgorithm::SHA1)?
+        .cleartext()
+        .build()?;
+    writer.write_all(b"Suite: stable\n")?;
+    writer.finalize()?;
+    let helper = KeyringVerifier {
+        keyring: &keyring,
+        signer: None,
+    };
+    let result = VerifierBuilder::from_bytes(&inrelease)?
+        .with_policy(&policy, None, helper)
+        .and_then(|mut verifier| {
+            verifier
+                .read_to_string(&mut String::new())
+                .map_err(Into::into)
+        });
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_cache_lock() -> Result<()> {
+    let mirror = TestMirror::new()?;
+    std::fs::remove_file(mirror.dir.path().join("foo.deb"))?;
+    let cache = tempfile::tempdir()?;
+    let package = TestMirror::package("foo");
+    let cached = cache.path().join(package.file_name());
+    let lock = lock_cached_file(&cached, Instant::now(), None, "ignoring the lock")?;
+    assert!(lock.is_some());
+    assert!(lock_cached_file(&cached, Instant::now(), None, "ignoring the lock")?.is_none());
+
+    // waits for the other run, which downloads the file (the mirror does not have it)
+    let other = std::thread::spawn(move || {
+        sleep(Duration::from_millis(300));
+        std::fs::write(&cached, b"foo").unwrap();
+        drop(lock);
+    });
+    let options = DownloadOptions {
+        cache_dir: Some(cache.path().to_path_buf()),
+        ..DownloadOptions::for_test()
+    };
+    let target = tempfile::tempdir()?;
+    mirror.download(std::slice::from_ref(&package), target.path(), &options)?;
+    other.join().unwrap();
+    assert_eq!(
+        std::fs::read(target.path().join(package.file_name()))?,
+        b"foo"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_lock_cached_files() -> Result<()> {
+    let cache = tempfile::tempdir()?;
+    let packages = ["bar", "baz", "foo"].map(TestMirror::package);
+    let packages = packages.iter().collect::<Vec<_>>();
+    // another run holds bar and baz
+    let held = packages[..2]
+        .iter()
+        .map(|p| {
+            let path = cache.path().join(p.file_name());
+            lock_cached_file(&path, Instant::now(), None, "ignoring the lock")
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert!(held.iter().all(Option::is_some));
+    let start = Instant::now();
+    let (locks, unlocked) = lock_cached_files(
+        &packages,
+        cache.path(),
+        Instant::now() + Duration::from_millis(600),
+    )?;
+    // a single timeout for all the files, instead of one for each of them
+    assert!(start.elapsed() < Duration::from_millis(1100));
+    assert_eq!(locks.len(), 1);
+    assert_eq!(
+        unlocked,
+        HashSet::from([packages[0].file_name(), packages[1].file_name()])
+    );
+    // the released files are locked even past the deadline
+    drop(held);
+    let (locks, unlocked) = lock_cached_files(&packages[..2], cache.path(), Instant::now())?;
+    assert_eq!((locks.len(), unlocked.len()), (2, 0));
 
     Ok(())
 }
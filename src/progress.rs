@@ -0,0 +1,237 @@
+use std::{
+    io::{stderr, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytesize::ByteSize;
+use owo_colors::OwoColorize;
+
+const BAR_WIDTH: usize = 30;
+/// Minimum interval between the redraws of the bar while the bytes are transferred
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ProgressMode {
+    /// Render an updating progress bar (interactive terminals)
+    Bar,
+    /// Print one line per item (logs)
+    Lines,
+    /// Print nothing
+    Quiet,
+}
+
+/// Progress reporter shared between the worker threads
+pub struct Progress {
+    action: &'static str,
+    total: usize,
+    done: AtomicUsize,
+    bytes: AtomicU64,
+    /// Expected number of bytes transferred in total (0 if unknown)
+    total_bytes: u64,
+    start: Instant,
+    mode: ProgressMode,
+    // serializes the output so that lines from different threads do not interleave
+    output: Mutex<RenderState>,
+}
+
+/// What the last redraw of the bar showed
+struct RenderState {
+    name: String,
+    last: Instant,
+}
+
+impl Progress {
+    pub fn new(action: &'static str, total: usize, quiet: bool) -> Progress {
+        let mode = if quiet || crate::logging::is_json() {
+            ProgressMode::Quiet
+        } else if stderr().is_terminal() {
+            ProgressMode::Bar
+        } else {
+            ProgressMode::Lines
+        };
+
+        Progress {
+            action,
+            total,
+            done: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            total_bytes: 0,
+            start: Instant::now(),
+            mode,
+            output: Mutex::new(RenderState {
+                name: String::new(),
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Show the transferred bytes against `bytes` in total, and estimate the remaining time
+    /// from them
+    pub fn total_bytes(mut self, bytes: u64) -> Self {
+        self.total_bytes = bytes;
+        self
+    }
+
+    /// Mark the start of the processing of the next item
+    pub fn start_item(&self, name: &str) {
+        let current = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut state = self.output.lock().unwrap();
+        state.name = name.to_string();
+        self.render(&mut state, current);
+    }
+
+    /// Record the number of bytes transferred, redrawing the bar now and then
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if self.mode != ProgressMode::Bar {
+            return;
+        }
+        let mut state = self.output.lock().unwrap();
+        if state.last.elapsed() >= REDRAW_INTERVAL {
+            self.render(&mut state, self.done.load(Ordering::SeqCst));
+        }
+    }
+
+    /// Print a message without breaking the progress bar
+    pub fn println(&self, message: &str) {
+        if self.mode == ProgressMode::Quiet {
+            return;
+        }
+        let _guard = self.output.lock().unwrap();
+        if self.mode == ProgressMode::Bar {
+            eprint!("\r\x1b[2K");
+        }
+        eprintln!("{}", message);
+    }
+
+    /// Finish the progress bar (moving the cursor to the next line)
+    pub fn finish(&self) {
+        if self.mode == ProgressMode::Bar {
+            let _guard = self.output.lock().unwrap();
+            eprintln!();
+        }
+    }
+
+    fn render(&self, state: &mut RenderState, current: usize) {
+        match self.mode {
+            ProgressMode::Quiet => (),
+            ProgressMode::Lines => {
+                eprintln!(
+                    "[{}/{}] {} {} ...",
+                    current,
+                    self.total,
+                    self.action,
+                    state.name.cyan()
+                );
+            }
+            ProgressMode::Bar => {
+                let line = self.bar_line(current, self.bytes.load(Ordering::Relaxed));
+                eprint!("\r\x1b[2K{} {}", line, state.name.cyan());
+                stderr().flush().ok();
+            }
+        }
+        state.last = Instant::now();
+    }
+
+    fn bar_line(&self, current: usize, bytes: u64) -> String {
+        // the bytes tell the progress of the files being transferred as well
+        let ratio = if self.total_bytes > 0 {
+            bytes.min(self.total_bytes) as f64 / self.total_bytes as f64
+        } else if self.total > 0 {
+            current as f64 / self.total as f64
+        } else {
+            0.0
+        };
+        let filled = ((ratio * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+        let mut line = format!(
+            "{} [{}{}] {}/{}",
+            self.action,
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            current,
+            self.total,
+        );
+        if self.total_bytes > 0 {
+            line.push_str(&format!(
+                " {}/{}",
+                ByteSize::b(bytes),
+                ByteSize::b(self.total_bytes)
+            ));
+        } else if bytes > 0 {
+            line.push_str(&format!(" {}", ByteSize::b(bytes)));
+        }
+        if let Some(eta) = self.eta(current, bytes) {
+            line.push_str(&format!(" ETA {}", format_duration(eta)));
+        }
+
+        line
+    }
+
+    fn eta(&self, current: usize, bytes: u64) -> Option<Duration> {
+        let elapsed = self.start.elapsed();
+        if self.total_bytes > 0 {
+            let done = bytes.min(self.total_bytes);
+            return (done > 0)
+                .then(|| elapsed.mul_f64((self.total_bytes - done) as f64 / done as f64));
+        }
+        // the current item has just started, do not count it as done
+        let finished = current.checked_sub(1).filter(|n| *n > 0)?;
+        let remaining = self.total.saturating_sub(finished);
+
+        Some(elapsed.mul_f64(remaining as f64 / finished as f64))
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// A writer wrapper that reports the number of bytes written to a [Progress]
+pub struct ProgressWriter<'a, W: Write> {
+    inner: W,
+    progress: &'a Progress,
+}
+
+impl<'a, W: Write> ProgressWriter<'a, W> {
+    pub fn new(inner: W, progress: &'a Progress) -> Self {
+        ProgressWriter { inner, progress }
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.progress.add_bytes(written as u64);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_bar_line() {
+    let progress = Progress::new("Downloading", 4, true).total_bytes(4096);
+    let line = progress.bar_line(1, 1024);
+    // a quarter of the bytes fills a quarter of the bar, whatever the number of files done
+    assert!(line.starts_with(&format!(
+        "Downloading [{}{}] 1/4",
+        "=".repeat(7),
+        " ".repeat(23)
+    )));
+    assert!(line.contains(&format!(" {}/{}", ByteSize::b(1024), ByteSize::b(4096))));
+    assert!(line.contains(" ETA "));
+    // nothing transferred yet, nothing to estimate from
+    assert!(!progress.bar_line(1, 0).contains("ETA"));
+}
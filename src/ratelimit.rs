@@ -0,0 +1,213 @@
+use std::{
+    io::{Error, ErrorKind, Write},
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::cancel;
+
+/// A token bucket shared between the download workers, limiting the aggregate throughput
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: u64,
+    /// Available tokens (may go negative, the debt is paid by waiting) and the last refill time
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` tokens from the bucket, blocking until the rate allows it
+    pub fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (ref mut tokens, ref mut last) = *state;
+            let now = Instant::now();
+            // the bucket holds at most one second worth of tokens
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate as f64)
+                .min(self.rate as f64);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-*tokens / self.rate as f64)
+        };
+        // the lock is released, so that the other workers can queue up their debts in the meantime
+        sleep(wait);
+    }
+}
+
+/// A writer wrapper that throttles the writes with a [RateLimiter] (if any)
+pub struct RateLimitedWriter<'a, W: Write> {
+    inner: W,
+    limiter: Option<&'a RateLimiter>,
+}
+
+impl<'a, W: Write> RateLimitedWriter<'a, W> {
+    pub fn new(inner: W, limiter: Option<&'a RateLimiter>) -> Self {
+        RateLimitedWriter { inner, limiter }
+    }
+}
+
+impl<W: Write> Write for RateLimitedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(limiter) = self.limiter {
+            limiter.acquire(buf.len());
+        }
+
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Detects the stalled transfers and enforces the deadline of the download phase
+pub struct Watchdog {
+    /// Transfers receiving less than `min_rate` bytes per second over `window` are stalled
+    pub min_rate: u64,
+    pub window: Duration,
+    pub deadline: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn deadline_exceeded(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+}
+
+/// A writer wrapper that fails with [ErrorKind::TimedOut] when the transfer stalls
+/// (so that it is retried) or when the deadline has passed
+pub struct WatchdogWriter<'a, W: Write> {
+    inner: W,
+    watchdog: Option<&'a Watchdog>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl<'a, W: Write> WatchdogWriter<'a, W> {
+    pub fn new(inner: W, watchdog: Option<&'a Watchdog>) -> Self {
+        WatchdogWriter {
+            inner,
+            watchdog,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for WatchdogWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if cancel::is_cancelled() {
+            // not transient, the partial file is kept for the next run
+            return Err(Error::other(cancel::Cancelled));
+        }
+        let written = self.inner.write(buf)?;
+        let Some(watchdog) = self.watchdog else {
+            return Ok(written);
+        };
+        if watchdog.deadline_exceeded() {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "download deadline exceeded",
+            ));
+        }
+        self.window_bytes += written as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= watchdog.window {
+            if (self.window_bytes as f64) < watchdog.min_rate as f64 * elapsed.as_secs_f64() {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "transfer stalled: {} bytes received in {:.0}s",
+                        self.window_bytes,
+                        elapsed.as_secs_f64()
+                    ),
+                ));
+            }
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Parse a transfer rate like `500k` or `2M` (binary units) into bytes per second
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let rate = rate.trim();
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1 << 30),
+        _ => (rate, 1),
+    };
+    let number: f64 = number.parse().map_err(|_| {
+        anyhow!(
+            "Invalid rate `{}`, expected a value like `500k` or `2M`",
+            rate
+        )
+    })?;
+    let bytes = (number * multiplier as f64) as u64;
+    if !number.is_finite() || bytes == 0 {
+        return Err(anyhow!("The rate must be at least 1 byte per second"));
+    }
+
+    Ok(bytes)
+}
+
+#[test]
+fn test_parse_rate() -> Result<()> {
+    assert_eq!(parse_rate("2M")?, 2 * 1024 * 1024);
+    assert_eq!(parse_rate("500k")?, 500 * 1024);
+    assert_eq!(parse_rate("1.5K")?, 1536);
+    assert_eq!(parse_rate("4096")?, 4096);
+    assert!(parse_rate("fast").is_err());
+    assert!(parse_rate("0k").is_err());
+    assert!(parse_rate("").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_watchdog() -> Result<()> {
+    let watchdog = Watchdog {
+        min_rate: 1024,
+        window: Duration::from_millis(50),
+        deadline: None,
+    };
+    let mut writer = WatchdogWriter::new(Vec::new(), Some(&watchdog));
+    writer.write_all(&[0; 4096])?;
+    sleep(Duration::from_millis(60));
+    // 4 KiB in 60ms is fast enough
+    writer.write_all(&[0; 16])?;
+    sleep(Duration::from_millis(60));
+    let err = writer.write_all(&[0; 16]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(err.to_string().contains("stalled"), "{}", err);
+
+    let watchdog = Watchdog {
+        deadline: Some(Instant::now()),
+        ..watchdog
+    };
+    let err = WatchdogWriter::new(Vec::new(), Some(&watchdog))
+        .write_all(b"late")
+        .unwrap_err();
+    assert!(err.to_string().contains("deadline"));
+
+    Ok(())
+}
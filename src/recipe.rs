@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// A recipe collects the options that would otherwise be passed on the command line
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Recipe {
+    /// Configuration file (relative to the recipe)
+    pub config: Option<String>,
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// Mirror written to the sources.list of the bootstrapped system
+    pub final_mirror: Option<String>,
+    #[serde(default)]
+    pub arch: Vec<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Package lists (relative to the recipe)
+    #[serde(default)]
+    pub include_files: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Scripts to run during stage 2 (relative to the recipe)
+    #[serde(default)]
+    pub scripts: Vec<String>,
+    /// Scripts to run on the host before stage 2 (relative to the recipe)
+    #[serde(default)]
+    pub host_hooks: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub comps: Vec<String>,
+}
+
+impl Recipe {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("when reading recipe {}", path.display()))?;
+        let mut recipe: Recipe = toml::from_str(&content)
+            .with_context(|| format!("when parsing recipe {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let resolve = |p: &mut String| *p = base.join(&*p).to_string_lossy().to_string();
+        if let Some(ref mut config) = recipe.config {
+            resolve(config);
+        }
+        recipe.include_files.iter_mut().for_each(resolve);
+        recipe.scripts.iter_mut().for_each(resolve);
+        recipe.host_hooks.iter_mut().for_each(resolve);
+
+        Ok(recipe)
+    }
+}
+
+#[test]
+fn test_read_recipe() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("desktop.toml");
+    std::fs::write(
+        &path,
+        "config = \"aosc-mainline.toml\"\nbranch = \"stable\"\narch = [\"amd64\"]\ninclude-files = [\"lists/desktop.lst\", \"/tmp/extra.lst\"]\n",
+    )?;
+    let recipe = Recipe::read(&path)?;
+    assert_eq!(
+        recipe,
+        Recipe {
+            config: Some(
+                dir.path()
+                    .join("aosc-mainline.toml")
+                    .to_string_lossy()
+                    .to_string()
+            ),
+            branch: Some("stable".to_string()),
+            arch: vec!["amd64".to_string()],
+            include_files: vec![
+                dir.path()
+                    .join("lists/desktop.lst")
+                    .to_string_lossy()
+                    .to_string(),
+                "/tmp/extra.lst".to_string()
+            ],
+            ..Default::default()
+        }
+    );
+
+    std::fs::write(
+        &path,
+        "branch = \"stable\"\nmirror = [\"https://example.com\"]\n",
+    )?;
+    let err = format!("{:#}", Recipe::read(&path).unwrap_err());
+    assert!(err.contains("unknown field `mirror`"), "{}", err);
+
+    Ok(())
+}
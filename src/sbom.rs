@@ -0,0 +1,298 @@
+//! Software bill of materials of the bootstrapped system (`--sbom`), in the SPDX 2.3
+//! or the CycloneDX 1.5 JSON format.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    fs::{self, ChecksumKind},
+    install, network,
+    solv::PackageMeta,
+};
+
+/// Not known: the manifests of the repository do not declare the licenses
+const NOASSERTION: &str = "NOASSERTION";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SbomFormat {
+    #[default]
+    Spdx,
+    Cyclonedx,
+}
+
+/// The packages of the bootstrapped system
+pub struct Sbom<'a> {
+    pub packages: &'a [PackageMeta],
+    /// Where the packages are downloaded from (the primary mirror)
+    pub mirror: &'a str,
+    pub branches: &'a [String],
+    /// Creation time, in seconds since the epoch
+    pub created: u64,
+}
+
+impl Sbom<'_> {
+    pub fn write(&self, path: &Path, format: SbomFormat) -> Result<()> {
+        let document = match format {
+            SbomFormat::Spdx => self.spdx(),
+            SbomFormat::Cyclonedx => self.cyclonedx(),
+        };
+        fs::write_atomic(path, &serde_json::to_vec_pretty(&document)?)
+            .with_context(|| format!("when writing the SBOM {}", path.display()))
+    }
+
+    /// The download URL of the package, the `--local-deb` packages have none
+    fn download_url(&self, package: &PackageMeta) -> Option<String> {
+        if package
+            .path
+            .starts_with(&format!("{}/", install::LOCAL_DEB_DIR))
+        {
+            return None;
+        }
+
+        Some(network::package_url(self.mirror, &package.path))
+    }
+
+    /// Identifies the document, derived from the packages so that it is reproducible
+    fn digest(&self) -> String {
+        let packages = self
+            .packages
+            .iter()
+            .map(|p| {
+                let checksum = p.checksum().map(|(_, c)| c).unwrap_or_default();
+                format!("{} {}\n", p.file_name(), checksum)
+            })
+            .collect::<String>();
+
+        fs::sha256sum(packages.as_bytes()).unwrap_or_default()
+    }
+
+    fn spdx(&self) -> Value {
+        let name = format!("aoscbootstrap-{}", self.branches.join("-"));
+        let packages = self
+            .packages
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": p.name,
+                    "SPDXID": spdx_id(p),
+                    "versionInfo": p.version,
+                    "supplier": "Organization: AOSC",
+                    "downloadLocation": self.download_url(p).unwrap_or_else(|| NOASSERTION.to_string()),
+                    "filesAnalyzed": false,
+                    "licenseConcluded": NOASSERTION,
+                    "licenseDeclared": NOASSERTION,
+                    "copyrightText": NOASSERTION,
+                    "checksums": p.checksums()
+                        .map(|(kind, value)| {
+                            json!({ "algorithm": kind.field().trim_end_matches("sum"), "checksumValue": value })
+                        })
+                        .collect::<Vec<_>>(),
+                    "externalRefs": [{
+                        "referenceCategory": "PACKAGE-MANAGER",
+                        "referenceType": "purl",
+                        "referenceLocator": purl(p),
+                    }],
+                })
+            })
+            .collect::<Vec<_>>();
+        let relationships = self
+            .packages
+            .iter()
+            .map(|p| {
+                json!({
+                    "spdxElementId": "SPDXRef-DOCUMENT",
+                    "relationshipType": "DESCRIBES",
+                    "relatedSpdxElement": spdx_id(p),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": name,
+            "documentNamespace": format!("https://aosc.io/spdx/{}-{}", name, self.digest()),
+            "creationInfo": {
+                "created": rfc3339(self.created),
+                "creators": [format!("Tool: aoscbootstrap-{}", env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": packages,
+            "relationships": relationships,
+        })
+    }
+
+    fn cyclonedx(&self) -> Value {
+        let components = self
+            .packages
+            .iter()
+            .map(|p| {
+                let mut component = json!({
+                    "type": "library",
+                    "bom-ref": purl(p),
+                    "name": p.name,
+                    "version": p.version,
+                    "purl": purl(p),
+                    "hashes": p.checksums()
+                        .map(|(kind, value)| json!({ "alg": cyclonedx_alg(kind), "content": value }))
+                        .collect::<Vec<_>>(),
+                });
+                if let Some(url) = self.download_url(p) {
+                    component["externalReferences"] =
+                        json!([{ "type": "distribution", "url": url }]);
+                }
+                component
+            })
+            .collect::<Vec<_>>();
+        let digest = self.digest();
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "serialNumber": format!(
+                "urn:uuid:{}-{}-{}-{}-{}",
+                &digest[..8],
+                &digest[8..12],
+                &digest[12..16],
+                &digest[16..20],
+                &digest[20..32]
+            ),
+            "version": 1,
+            "metadata": {
+                "timestamp": rfc3339(self.created),
+                "tools": [{ "name": "aoscbootstrap", "version": env!("CARGO_PKG_VERSION") }],
+                "component": {
+                    "type": "operating-system",
+                    "name": "AOSC OS",
+                    "version": self.branches.join(","),
+                },
+            },
+            "components": components,
+        })
+    }
+}
+
+fn cyclonedx_alg(kind: ChecksumKind) -> &'static str {
+    match kind {
+        ChecksumKind::Sha512 => "SHA-512",
+        ChecksumKind::Sha256 => "SHA-256",
+        ChecksumKind::Md5 => "MD5",
+    }
+}
+
+/// The SPDX identifiers only contain letters, numbers, `.` and `-`
+fn spdx_id(package: &PackageMeta) -> String {
+    format!("SPDXRef-Package-{}-{}", package.name, package.arch)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// The package URL of the package, e.g. `pkg:deb/aosc/bash@5.2.15%2B1?arch=amd64`
+fn purl(package: &PackageMeta) -> String {
+    let encode = |s: &str| {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect::<String>()
+    };
+
+    format!(
+        "pkg:deb/aosc/{}@{}?arch={}",
+        encode(&package.name),
+        encode(&package.version),
+        encode(&package.arch)
+    )
+}
+
+/// Format the seconds since the epoch as a UTC timestamp, e.g. `2024-01-31T12:00:00Z`
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+    // the civil date of the days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[test]
+fn test_sbom() {
+    let package = |name: &str, version: &str, path: &str| PackageMeta {
+        version: version.to_string(),
+        sha256: "ab".repeat(32),
+        path: path.to_string(),
+        arch: "amd64".to_string(),
+        ..PackageMeta::for_test(name)
+    };
+    let packages = [
+        package("libstdc++", "1:13.2.0+1", "debs/pool/stable/main/g/gcc.deb"),
+        package("hello", "1.0", "local/hello_1.0_amd64.deb"),
+    ];
+    let sbom = Sbom {
+        packages: &packages,
+        mirror: "https://repo.aosc.io/debs",
+        branches: &["stable".to_string()],
+        created: 1706702400,
+    };
+
+    let spdx = sbom.spdx();
+    assert_eq!(spdx["creationInfo"]["created"], "2024-01-31T12:00:00Z");
+    let first = &spdx["packages"][0];
+    assert_eq!(first["SPDXID"], "SPDXRef-Package-libstdc---amd64");
+    assert_eq!(
+        first["downloadLocation"],
+        "https://repo.aosc.io/debs/debs/pool/stable/main/g/gcc.deb"
+    );
+    assert_eq!(
+        first["externalRefs"][0]["referenceLocator"],
+        "pkg:deb/aosc/libstdc%2B%2B@1%3A13.2.0%2B1?arch=amd64"
+    );
+    assert_eq!(spdx["packages"][1]["downloadLocation"], NOASSERTION);
+    assert_eq!(spdx["relationships"].as_array().unwrap().len(), 2);
+
+    let cyclonedx = sbom.cyclonedx();
+    assert_eq!(
+        cyclonedx["components"][0]["hashes"][0]["content"],
+        "ab".repeat(32)
+    );
+    assert!(cyclonedx["components"][1]
+        .get("externalReferences")
+        .is_none());
+    assert_eq!(
+        cyclonedx["serialNumber"].as_str().unwrap().len(),
+        "urn:uuid:".len() + 36
+    );
+    assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+    assert_eq!(rfc3339(951782400), "2000-02-29T00:00:00Z");
+}
@@ -1,29 +1,86 @@
-use super::PackageMeta;
+use super::{ManifestFields, PackageMeta, ProblemKind, ProblemRule};
 use anyhow::{anyhow, Result};
 use hex::encode;
 use libc::{c_char, c_int};
 use libsolv_sys::ffi;
-use std::{convert::TryInto, ffi::CStr, os::unix::ffi::OsStrExt, slice};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryInto,
+    ffi::CStr,
+    slice,
+};
 use std::{ffi::CString, path::Path, ptr::null_mut};
 
 pub const SELECTION_NAME: c_int = 1 << 0;
+pub const SELECTION_REL: c_int = 1 << 5;
 pub const SELECTION_FLAT: c_int = 1 << 10;
 pub const SELECTION_ADD: c_int = 1 << 28;
 
+pub const SOLVER_SOLVABLE: c_int = 0x01;
+pub const SOLVER_SOLVABLE_NAME: c_int = 0x02;
 pub const SOLVER_INSTALL: c_int = 0x100;
+pub const SOLVER_LOCK: c_int = 0x600;
+pub const SOLVER_DISFAVOR: c_int = 0xd00;
 
+pub const SOLVER_FLAG_IGNORE_RECOMMENDED: c_int = 7;
 pub const SOLVER_FLAG_BEST_OBEY_POLICY: c_int = 12;
 
+pub const SOLVER_RULE_PKG_NOT_INSTALLABLE: c_int = 0x101;
+pub const SOLVER_RULE_PKG_NOTHING_PROVIDES_DEP: c_int = 0x102;
+pub const SOLVER_RULE_PKG_REQUIRES: c_int = 0x103;
+pub const SOLVER_RULE_PKG_CONFLICTS: c_int = 0x105;
+pub const SOLVER_RULE_JOB: c_int = 0x400;
+pub const SOLVER_RULE_JOB_NOTHING_PROVIDES_DEP: c_int = 0x401;
+pub const SOLVER_RULE_JOB_UNKNOWN_PACKAGE: c_int = 0x403;
+
+pub const SOLVER_TRANSACTION_KEEP_ORDERCYCLES: c_int = 1 << 1;
+pub const SOLVER_ORDERCYCLE_HARMLESS: c_int = 0;
+
 pub struct Pool {
     pool: *mut ffi::Pool,
 }
 
+/// A package available in the pool
+pub struct Candidate {
+    pub id: c_int,
+    pub name: String,
+    pub version: String,
+    /// Name of the repository providing the package
+    pub repo: String,
+    pub arch: String,
+    /// In bytes, like [PackageMeta::install_size]
+    pub install_size: u64,
+    pub download_size: u64,
+    /// See [PackageMeta::is_essential]
+    pub essential: bool,
+}
+
 macro_rules! cstr {
     ($s:expr) => {
         CString::new($s)?.as_ptr() as *const c_char
     };
 }
 
+/// The keys of the checksums read from the manifests by [super::read_manifest_fields]
+const SHA512_KEY: &CStr = c"aoscbootstrap:sha512";
+const MD5_KEY: &CStr = c"aoscbootstrap:md5";
+/// The keys of the `Essential` and `Priority` fields, which repo_deb ignores
+const ESSENTIAL_KEY: &CStr = c"aoscbootstrap:essential";
+const PRIORITY_KEY: &CStr = c"aoscbootstrap:priority";
+/// The key of the `Replaces` names (separated by commas), see [ManifestFields::replaces]
+const REPLACES_KEY: &CStr = c"aoscbootstrap:replaces";
+
+/// The string `key` of the solvable, if it is set
+unsafe fn lookup_custom_str(s: *mut ffi::Solvable, key: &CStr) -> Option<String> {
+    let key = ffi::pool_str2id((*(*s).repo).pool, key.as_ptr(), 0);
+    if key == 0 {
+        return None;
+    }
+    let value = ffi::solvable_lookup_str(s, key);
+
+    (!value.is_null()).then(|| CStr::from_ptr(value).to_string_lossy().to_string())
+}
+
 #[inline]
 fn solvable_to_meta(s: *mut ffi::Solvable) -> Result<PackageMeta> {
     let mut sum_type: ffi::Id = 0;
@@ -34,10 +91,22 @@ fn solvable_to_meta(s: *mut ffi::Solvable) -> Result<PackageMeta> {
             &mut sum_type,
         )
     };
-    if sum_type != (ffi::solv_knownid_REPOKEY_TYPE_SHA256 as i32) {
-        return Err(anyhow!("Unsupported checksum type: {}", sum_type));
-    }
-    let checksum = unsafe { slice::from_raw_parts(checksum, 32) };
+    // repo_deb only keeps one checksum (SHA256 if any), the others are read separately
+    let sha256 = if !checksum.is_null() && sum_type == ffi::solv_knownid_REPOKEY_TYPE_SHA256 as i32
+    {
+        encode(unsafe { slice::from_raw_parts(checksum, 32) })
+    } else {
+        String::new()
+    };
+    let (sha512, md5, essential, priority, replaces) = unsafe {
+        (
+            lookup_custom_str(s, SHA512_KEY),
+            lookup_custom_str(s, MD5_KEY),
+            lookup_custom_str(s, ESSENTIAL_KEY).is_some(),
+            lookup_custom_str(s, PRIORITY_KEY),
+            lookup_custom_str(s, REPLACES_KEY),
+        )
+    };
     let name = unsafe {
         CStr::from_ptr(ffi::solvable_lookup_str(
             s,
@@ -69,17 +138,52 @@ fn solvable_to_meta(s: *mut ffi::Solvable) -> Result<PackageMeta> {
         ))
     };
     let in_topic = !String::from(path.to_string_lossy()).contains("stable");
+    let install_size =
+        unsafe { ffi::solvable_lookup_num(s, ffi::solv_knownid_SOLVABLE_INSTALLSIZE as i32, 0) };
+    let download_size =
+        unsafe { ffi::solvable_lookup_num(s, ffi::solv_knownid_SOLVABLE_DOWNLOADSIZE as i32, 0) };
+    let replaces = replaces.map_or_else(Vec::new, |r| r.split(',').map(str::to_string).collect());
+    // repo_deb stores `Pre-Depends` as the requires after the pre-requires marker
+    let pre_depends = dependency_names(s, ffi::solv_knownid_SOLVABLE_REQUIRES, 1);
 
     Ok(PackageMeta {
         name: name.to_string_lossy().to_string(),
         version: version.to_string_lossy().to_string(),
-        sha256: encode(checksum),
+        sha256,
+        sha512,
+        md5,
         path: path.to_string_lossy().to_string() + "/" + &filename.to_string_lossy(),
         arch: arch.to_string_lossy().to_string(),
-	in_topic,
+        in_topic,
+        install_size,
+        download_size,
+        replaces,
+        pre_depends,
+        essential,
+        priority,
     })
 }
 
+/// The names of the packages in the dependencies `keyname` of the solvable, without the
+/// version constraints (all the alternatives are kept). See `solvable_lookup_deparray`
+/// for the `marker`.
+fn dependency_names(s: *mut ffi::Solvable, keyname: u32, marker: ffi::Id) -> Vec<String> {
+    let mut deps = Queue::new();
+    unsafe { ffi::solvable_lookup_deparray(s, keyname as i32, &mut deps.queue, marker) };
+    let pool = unsafe { (*(*s).repo).pool };
+    let mut names = Vec::new();
+    for dep in deps.as_slice() {
+        let dep = unsafe { CStr::from_ptr(ffi::pool_dep2str(pool, *dep)) };
+        for alternative in dep.to_string_lossy().split('|') {
+            if let Some(name) = alternative.split_whitespace().next() {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    names
+}
+
 impl Pool {
     pub fn new() -> Pool {
         Pool {
@@ -99,13 +203,166 @@ impl Pool {
                 self.pool,
                 &mut queue.queue,
                 cstr!(name),
-                SELECTION_NAME | SELECTION_FLAT | SELECTION_ADD,
+                SELECTION_NAME | SELECTION_REL | SELECTION_FLAT | SELECTION_ADD,
             );
         }
 
         Ok(queue)
     }
 
+    /// Forbid the installation of the package `name`
+    pub fn lock_package(&self, name: &str, mut queue: Queue) -> Result<Queue> {
+        let id = unsafe { ffi::pool_str2id(self.pool, cstr!(name), 0) };
+        // unknown names can not be installed anyway
+        if id != 0 {
+            unsafe {
+                ffi::queue_insert2(
+                    &mut queue.queue,
+                    queue.queue.count,
+                    SOLVER_LOCK | SOLVER_SOLVABLE_NAME,
+                    id,
+                )
+            };
+        }
+
+        Ok(queue)
+    }
+
+    /// Forbid the installation of the solvable `id`
+    pub fn lock_solvable(&self, id: c_int, mut queue: Queue) -> Queue {
+        unsafe {
+            ffi::queue_insert2(
+                &mut queue.queue,
+                queue.queue.count,
+                SOLVER_LOCK | SOLVER_SOLVABLE,
+                id,
+            )
+        };
+
+        queue
+    }
+
+    /// Avoid installing the solvable `id` unless nothing else satisfies a dependency
+    pub fn disfavor_solvable(&self, id: c_int, mut queue: Queue) -> Queue {
+        unsafe {
+            ffi::queue_insert2(
+                &mut queue.queue,
+                queue.queue.count,
+                SOLVER_DISFAVOR | SOLVER_SOLVABLE,
+                id,
+            )
+        };
+
+        queue
+    }
+
+    /// Return all the packages in the pool
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let mut candidates = Vec::new();
+        unsafe {
+            // the first two solvables are reserved
+            for p in 2..(*self.pool).nsolvables {
+                let s = (*self.pool).solvables.offset(p as isize);
+                if (*s).repo.is_null() {
+                    continue;
+                }
+                let name = CStr::from_ptr(ffi::pool_id2str(self.pool, (*s).name));
+                let version = CStr::from_ptr(ffi::pool_id2str(self.pool, (*s).evr));
+                let repo = CStr::from_ptr((*(*s).repo).name);
+                let arch = CStr::from_ptr(ffi::pool_id2str(self.pool, (*s).arch));
+                candidates.push(Candidate {
+                    id: p,
+                    name: name.to_string_lossy().to_string(),
+                    version: version.to_string_lossy().to_string(),
+                    repo: repo.to_string_lossy().to_string(),
+                    arch: arch.to_string_lossy().to_string(),
+                    install_size: ffi::solvable_lookup_num(
+                        s,
+                        ffi::solv_knownid_SOLVABLE_INSTALLSIZE as i32,
+                        0,
+                    ),
+                    download_size: ffi::solvable_lookup_num(
+                        s,
+                        ffi::solv_knownid_SOLVABLE_DOWNLOADSIZE as i32,
+                        0,
+                    ),
+                    essential: lookup_custom_str(s, ESSENTIAL_KEY).is_some()
+                        || lookup_custom_str(s, PRIORITY_KEY).as_deref() == Some("required"),
+                });
+            }
+        }
+
+        candidates
+    }
+
+    /// Return the `name-version.arch` string of the solvable (`None` if `id` is 0)
+    fn solvable_name(&self, id: ffi::Id) -> Option<String> {
+        if id == 0 {
+            return None;
+        }
+        let name = unsafe {
+            CStr::from_ptr(ffi::pool_solvable2str(
+                self.pool,
+                (*self.pool).solvables.offset(id as isize),
+            ))
+        };
+
+        Some(name.to_string_lossy().to_string())
+    }
+
+    fn dep_to_string(&self, dep: ffi::Id) -> String {
+        let dep = unsafe { CStr::from_ptr(ffi::pool_dep2str(self.pool, dep)) };
+
+        dep.to_string_lossy().to_string()
+    }
+
+    /// Return the names of all the packages in the pool
+    pub fn package_names(&self) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        unsafe {
+            // the first two solvables are reserved
+            for p in 2..(*self.pool).nsolvables {
+                let s = (*self.pool).solvables.offset(p as isize);
+                if (*s).repo.is_null() {
+                    continue;
+                }
+                let name = CStr::from_ptr(ffi::pool_id2str(self.pool, (*s).name));
+                names.insert(name.to_string_lossy().to_string());
+            }
+        }
+
+        names.into_iter().collect()
+    }
+
+    /// Return the available versions of the package `name`
+    pub fn package_versions(&self, name: &str) -> Result<Vec<String>> {
+        let mut selection = Queue::new();
+        let mut solvables = Queue::new();
+        unsafe {
+            ffi::selection_make(
+                self.pool,
+                &mut selection.queue,
+                cstr!(name),
+                SELECTION_NAME | SELECTION_FLAT,
+            );
+            ffi::selection_solvables(self.pool, &mut selection.queue, &mut solvables.queue);
+        }
+        let mut versions = Vec::new();
+        for p in solvables.as_slice() {
+            let version = unsafe {
+                CStr::from_ptr(ffi::solvable_lookup_str(
+                    (*self.pool).solvables.offset(*p as isize),
+                    ffi::solv_knownid_SOLVABLE_EVR as i32,
+                ))
+            };
+            versions.push(version.to_string_lossy().to_string());
+        }
+        versions.sort_unstable();
+        versions.dedup();
+
+        Ok(versions)
+    }
+
     pub fn createwhatprovides(&mut self) {
         unsafe { ffi::pool_createwhatprovides(self.pool) }
     }
@@ -129,10 +386,33 @@ impl Repo {
         })
     }
 
+    /// Among identical versions of a package, the one from the repository with the
+    /// highest sub-priority is preferred
+    pub fn set_subpriority(&mut self, subpriority: i32) {
+        unsafe { (*self.repo).subpriority = subpriority };
+    }
+
     pub fn add_debpackages(&mut self, path: &Path) -> Result<()> {
-        let mut path_buf = path.as_os_str().as_bytes().to_owned();
-        path_buf.push(0);
-        let fp = unsafe { libc::fopen(path_buf.as_ptr() as *const c_char, cstr!("rb")) };
+        let manifest = std::fs::read(path)
+            .map_err(|e| anyhow!("Failed to open '{}': {}", path.display(), e))?;
+        // repo_deb copies the checksums into a buffer which only fits SHA256 (and would
+        // overflow with SHA512), the SHA512 ones are read by `read_manifest_fields` instead
+        let mut manifest = manifest
+            .split_inclusive(|b| *b == b'\n')
+            .filter(|line| !line.starts_with(b"SHA512:"))
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        if manifest.is_empty() {
+            return Ok(());
+        }
+        let fp = unsafe {
+            libc::fmemopen(
+                manifest.as_mut_ptr() as *mut libc::c_void,
+                manifest.len(),
+                cstr!("rb"),
+            )
+        };
         if fp.is_null() {
             return Err(anyhow!("Failed to open '{}'", path.display()));
         }
@@ -144,6 +424,85 @@ impl Repo {
 
         Ok(())
     }
+
+    /// Remove the `all` packages listed more than once (with the same version and checksum)
+    /// in the manifests of this repository, keeping the first one
+    pub fn remove_duplicates(&mut self) -> usize {
+        let mut seen = HashSet::new();
+        let mut duplicates = Vec::new();
+        unsafe {
+            let pool = (*self.repo).pool;
+            for p in (*self.repo).start..(*self.repo).end {
+                let s = (*pool).solvables.offset(p as isize);
+                if (*s).repo != self.repo
+                    || CStr::from_ptr(ffi::pool_id2str(pool, (*s).arch)).to_bytes() != b"all"
+                {
+                    continue;
+                }
+                let mut sum_type: ffi::Id = 0;
+                let checksum = ffi::solvable_lookup_checksum(
+                    s,
+                    ffi::solv_knownid_SOLVABLE_CHECKSUM as i32,
+                    &mut sum_type,
+                );
+                let checksum = (!checksum.is_null())
+                    .then(|| CStr::from_ptr(checksum).to_string_lossy().to_string());
+                if !seen.insert(((*s).name, (*s).evr, checksum)) {
+                    duplicates.push(p);
+                }
+            }
+            for p in &duplicates {
+                ffi::repo_free_solvable(self.repo, *p, 0);
+            }
+        }
+
+        duplicates.len()
+    }
+
+    /// Record the download sizes, the other checksums, the essential flags and the Replaces
+    /// (keyed by file name) of the packages in this repository, since repo_add_debpackages
+    /// ignores (or drops some of) these fields
+    pub fn set_manifest_fields(&mut self, fields: &HashMap<String, ManifestFields>) {
+        unsafe {
+            let pool = (*self.repo).pool;
+            let sha512_key = ffi::pool_str2id(pool, SHA512_KEY.as_ptr(), 1);
+            let md5_key = ffi::pool_str2id(pool, MD5_KEY.as_ptr(), 1);
+            let essential_key = ffi::pool_str2id(pool, ESSENTIAL_KEY.as_ptr(), 1);
+            let priority_key = ffi::pool_str2id(pool, PRIORITY_KEY.as_ptr(), 1);
+            let replaces_key = ffi::pool_str2id(pool, REPLACES_KEY.as_ptr(), 1);
+            for p in (*self.repo).start..(*self.repo).end {
+                let s = (*pool).solvables.offset(p as isize);
+                if (*s).repo != self.repo {
+                    continue;
+                }
+                let location = ffi::solvable_get_location(s, null_mut());
+                if location.is_null() {
+                    continue;
+                }
+                let location = CStr::from_ptr(location).to_string_lossy();
+                let Some(fields) = fields.get(location.as_ref()) else {
+                    continue;
+                };
+                if let Some(size) = fields.size {
+                    ffi::solvable_set_num(s, ffi::solv_knownid_SOLVABLE_DOWNLOADSIZE as i32, size);
+                }
+                let essential = fields.essential.then(|| "yes".to_string());
+                let replaces = (!fields.replaces.is_empty()).then(|| fields.replaces.join(","));
+                for (key, value) in [
+                    (sha512_key, &fields.sha512),
+                    (md5_key, &fields.md5),
+                    (essential_key, &essential),
+                    (priority_key, &fields.priority),
+                    (replaces_key, &replaces),
+                ] {
+                    if let Some(value) = value.as_deref().and_then(|v| CString::new(v).ok()) {
+                        ffi::solvable_set_str(s, key, value.as_ptr());
+                    }
+                }
+            }
+            ffi::repo_internalize(self.repo);
+        }
+    }
 }
 
 pub struct Queue {
@@ -162,6 +521,19 @@ impl Queue {
         }
     }
 
+    /// Number of elements in the queue (jobs take two elements each)
+    pub fn count(&self) -> usize {
+        self.queue.count as usize
+    }
+
+    fn as_slice(&self) -> &[ffi::Id] {
+        if self.queue.elements.is_null() {
+            return &[];
+        }
+
+        unsafe { slice::from_raw_parts(self.queue.elements, self.queue.count as usize) }
+    }
+
     pub fn mark_all_for_install(&mut self) {
         for item in (0..self.queue.count).step_by(2) {
             unsafe {
@@ -183,10 +555,6 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    pub fn get_size_change(&self) -> i64 {
-        unsafe { ffi::transaction_calc_installsizechange(self.t) }
-    }
-
     pub fn order(&self, flags: c_int) {
         unsafe { ffi::transaction_order(self.t, flags) }
     }
@@ -204,6 +572,172 @@ impl Transaction {
 
         Ok(results)
     }
+
+    /// Return the packages in the order they should be configured,
+    /// packages in a dependency cycle are grouped together.
+    ///
+    /// The transaction must be ordered with [SOLVER_TRANSACTION_KEEP_ORDERCYCLES].
+    pub fn install_order(&self) -> Result<Vec<Vec<PackageMeta>>> {
+        let steps = unsafe {
+            slice::from_raw_parts((*self.t).steps.elements, (*self.t).steps.count as usize)
+        };
+        let mut cycle_ids = Queue::new();
+        unsafe {
+            ffi::transaction_order_get_cycleids(
+                self.t,
+                &mut cycle_ids.queue,
+                SOLVER_ORDERCYCLE_HARMLESS,
+            )
+        };
+        let mut cycles = Vec::new();
+        for cid in cycle_ids.as_slice() {
+            let mut cycle = Queue::new();
+            unsafe { ffi::transaction_order_get_cycle(self.t, *cid, &mut cycle.queue) };
+            cycles.push(cycle.as_slice().to_vec());
+        }
+
+        let mut results = Vec::new();
+        for group in group_order_cycles(steps, &cycles) {
+            let mut packages = Vec::new();
+            for p in group {
+                let pool = unsafe { (*self.t).pool };
+                packages.push(solvable_to_meta(unsafe {
+                    (*pool).solvables.offset(p as isize)
+                })?);
+            }
+            results.push(packages);
+        }
+
+        Ok(results)
+    }
+}
+
+const REL_DEP_MARK: ffi::Id = 0x80000000u32 as ffi::Id;
+
+/// Return the solvables providing `dep` (port of the `pool_whatprovides` inline function)
+unsafe fn whatprovides(pool: *mut ffi::Pool, dep: ffi::Id) -> Vec<ffi::Id> {
+    let offset = if dep & REL_DEP_MARK == 0 {
+        *(*pool).whatprovides.offset(dep as isize)
+    } else {
+        *(*pool)
+            .whatprovides_rel
+            .offset((dep ^ REL_DEP_MARK) as isize)
+    };
+    let offset = if offset != 0 {
+        offset as ffi::Id
+    } else {
+        ffi::pool_addrelproviders(pool, dep)
+    };
+
+    let mut results = Vec::new();
+    let mut p = (*pool).whatprovidesdata.offset(offset as isize);
+    while *p != 0 {
+        results.push(*p);
+        p = p.offset(1);
+    }
+
+    results
+}
+
+/// A dependency between two packages of a transaction
+pub struct Dependency {
+    /// Index of the depending package (in the transaction order)
+    pub from: usize,
+    /// Index of the package satisfying the dependency
+    pub to: usize,
+    /// The dependency as written in the manifest (e.g. `glibc >= 2.38`)
+    pub dep: String,
+    /// Whether this is a `Pre-Depends` dependency
+    pub pre_depends: bool,
+}
+
+impl Transaction {
+    /// Return the dependencies between the packages of this transaction,
+    /// the indices correspond to the order of [Transaction::create_metadata]
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        let pool = unsafe { (*self.t).pool };
+        let steps = unsafe {
+            slice::from_raw_parts((*self.t).steps.elements, (*self.t).steps.count as usize)
+        };
+        let index = steps
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (*p, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut results = Vec::new();
+        for (from, p) in steps.iter().enumerate() {
+            let s = unsafe { (*pool).solvables.offset(*p as isize) };
+            // -1: the dependencies before the pre-requires marker, 1: the ones after it
+            for (marker, pre_depends) in [(-1, false), (1, true)] {
+                let mut requires = Queue::new();
+                unsafe {
+                    ffi::solvable_lookup_deparray(
+                        s,
+                        ffi::solv_knownid_SOLVABLE_REQUIRES as i32,
+                        &mut requires.queue,
+                        marker,
+                    )
+                };
+                for dep in requires.as_slice() {
+                    let providers = unsafe { whatprovides(pool, *dep) };
+                    let Some(to) = providers.iter().find_map(|p| index.get(p)) else {
+                        continue;
+                    };
+                    let dep = unsafe { CStr::from_ptr(ffi::pool_dep2str(pool, *dep)) };
+                    results.push(Dependency {
+                        from,
+                        to: *to,
+                        dep: dep.to_string_lossy().to_string(),
+                        pre_depends,
+                    });
+                }
+            }
+        }
+
+        results
+    }
+}
+
+/// Group the solvables of overlapping cycles together, each group is placed
+/// at the position of its last member (when all of its members are unpacked)
+fn group_order_cycles(steps: &[ffi::Id], cycles: &[Vec<ffi::Id>]) -> Vec<Vec<ffi::Id>> {
+    fn find(parent: &HashMap<ffi::Id, ffi::Id>, mut p: ffi::Id) -> ffi::Id {
+        while let Some(&next) = parent.get(&p) {
+            if next == p {
+                break;
+            }
+            p = next;
+        }
+
+        p
+    }
+
+    let mut parent = HashMap::new();
+    for cycle in cycles {
+        let Some(&first) = cycle.first() else {
+            continue;
+        };
+        let root = find(&parent, first);
+        for &p in &cycle[1..] {
+            let other = find(&parent, p);
+            if other != root {
+                parent.insert(other, root);
+            }
+        }
+    }
+
+    let mut last_index = HashMap::new();
+    for (i, &p) in steps.iter().enumerate() {
+        last_index.insert(find(&parent, p), i);
+    }
+    let mut groups: BTreeMap<usize, Vec<ffi::Id>> = BTreeMap::new();
+    for &p in steps {
+        let root = find(&parent, p);
+        groups.entry(last_index[&root]).or_default().push(p);
+    }
+
+    groups.into_values().collect()
 }
 
 impl Drop for Transaction {
@@ -250,15 +784,63 @@ impl Solver {
         Ok(())
     }
 
-    pub fn get_problems(&self) -> Result<Vec<String>> {
+    /// Return the rules involved in each of the problems of the last solve
+    pub fn problem_rules(&self, pool: &Pool) -> Result<Vec<Vec<ProblemRule>>> {
         let mut problems = Vec::new();
         let count = unsafe { ffi::solver_problem_count(self.solver) };
-        for i in 1..=count {
-            let problem = unsafe { ffi::solver_problem2str(self.solver, i as c_int) };
-            if problem.is_null() {
-                return Err(anyhow!("problem2str failed: {}", i));
+        for problem in 1..=count {
+            let mut rules = Queue::new();
+            unsafe {
+                ffi::solver_findallproblemrules(self.solver, problem as c_int, &mut rules.queue)
+            };
+            let mut problem_rules: Vec<ProblemRule> = Vec::new();
+            for rid in rules.as_slice() {
+                let (mut source, mut target, mut dep) = (0, 0, 0);
+                let kind = unsafe {
+                    ffi::solver_ruleinfo(self.solver, *rid, &mut source, &mut target, &mut dep)
+                };
+                let description = unsafe {
+                    ffi::solver_problemruleinfo2str(self.solver, kind, source, target, dep)
+                };
+                if description.is_null() {
+                    return Err(anyhow!("problemruleinfo2str failed: {}", rid));
+                }
+                let kind = kind as c_int;
+                // job rules refer to the job instead of solvables
+                let is_job = kind & 0xff00 == SOLVER_RULE_JOB;
+                let rule = ProblemRule {
+                    kind: match kind {
+                        SOLVER_RULE_PKG_NOTHING_PROVIDES_DEP
+                        | SOLVER_RULE_JOB_NOTHING_PROVIDES_DEP => ProblemKind::NothingProvides,
+                        SOLVER_RULE_PKG_REQUIRES => ProblemKind::Requires,
+                        SOLVER_RULE_PKG_CONFLICTS => ProblemKind::Conflicts,
+                        SOLVER_RULE_PKG_NOT_INSTALLABLE => ProblemKind::NotInstallable,
+                        SOLVER_RULE_JOB_UNKNOWN_PACKAGE => ProblemKind::UnknownPackage,
+                        _ => ProblemKind::Other,
+                    },
+                    description: unsafe { CStr::from_ptr(description) }
+                        .to_string_lossy()
+                        .to_string(),
+                    source: (!is_job).then(|| pool.solvable_name(source)).flatten(),
+                    target: (!is_job).then(|| pool.solvable_name(target)).flatten(),
+                    dep: match kind {
+                        SOLVER_RULE_JOB_NOTHING_PROVIDES_DEP | SOLVER_RULE_JOB_UNKNOWN_PACKAGE => {
+                            Some(pool.dep_to_string(dep))
+                        }
+                        _ if is_job || dep == 0 => None,
+                        _ => Some(pool.dep_to_string(dep)),
+                    },
+                    hint: None,
+                };
+                // the learnt rules often expand to the same package rules
+                if !problem_rules
+                    .iter()
+                    .any(|r| r.description == rule.description)
+                {
+                    problem_rules.push(rule);
+                }
             }
-            problems.push(unsafe { CStr::from_ptr(problem).to_string_lossy().to_string() });
+            problems.push(problem_rules);
         }
 
         Ok(problems)
@@ -1,20 +1,74 @@
 mod ffi;
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::{bail, Result};
-pub use ffi::{Pool, Queue, Repo, Solver, Transaction, SOLVER_FLAG_BEST_OBEY_POLICY};
+use bytesize::ByteSize;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
+use anyhow::{bail, Context, Result};
+
+use crate::fs::ChecksumKind;
+pub use ffi::{
+    Candidate, Dependency, Pool, Queue, Repo, Solver, Transaction, SOLVER_FLAG_BEST_OBEY_POLICY,
+    SOLVER_FLAG_IGNORE_RECOMMENDED, SOLVER_TRANSACTION_KEEP_ORDERCYCLES,
+};
+
+/// A resolved package, as described by the manifest of the repository
 #[derive(Clone, Debug)]
 pub struct PackageMeta {
     pub name: String,
     pub version: String,
+    /// Empty if the manifest only has the other checksums
     pub sha256: String,
+    pub sha512: Option<String>,
+    pub md5: Option<String>,
     pub path: String,
     pub arch: String,
     pub in_topic: bool,
+    /// Installed size in bytes
+    pub install_size: u64,
+    /// Size of the .deb file in bytes (0 if unknown)
+    pub download_size: u64,
+    /// Packages whose files may be overwritten by this package (`Replaces`)
+    pub replaces: Vec<String>,
+    /// Packages which must be configured before this package is unpacked (`Pre-Depends`)
+    pub pre_depends: Vec<String>,
+    /// `Essential: yes` in the manifest
+    pub essential: bool,
+    /// e.g. `required`, `important` or `optional`
+    pub priority: Option<String>,
 }
 
 impl PackageMeta {
+    /// The checksums of the package in the manifest, from the strongest
+    pub fn checksums(&self) -> impl Iterator<Item = (ChecksumKind, &str)> {
+        [
+            (ChecksumKind::Sha512, self.sha512.as_deref()),
+            (ChecksumKind::Sha256, Some(self.sha256.as_str())),
+            (ChecksumKind::Md5, self.md5.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(kind, value)| Some((kind, value.filter(|v| !v.is_empty())?)))
+    }
+
+    /// Whether the system is broken without the package (`Essential: yes` or
+    /// `Priority: required`)
+    pub fn is_essential(&self) -> bool {
+        self.essential || self.priority.as_deref() == Some("required")
+    }
+
+    /// The strongest checksum of the package (SHA512 > SHA256 > MD5)
+    pub fn checksum(&self) -> Option<(ChecksumKind, &str)> {
+        self.checksums().next()
+    }
+
     /// Return apt-style file name for this package
     pub fn file_name(&self) -> String {
         let package = &self.name;
@@ -22,36 +76,1384 @@ impl PackageMeta {
         let arch = &self.arch;
         format!("{package}_{version}_{arch}.deb").replace("%2b", "+")
     }
+
+    /// Make sure the paths derived from the manifest cannot escape the mirror or the
+    /// archive directory: the path must be relative without `..`, and the file name
+    /// must be a single component
+    pub fn check_paths(&self) -> Result<()> {
+        let path = Path::new(&self.path);
+        if self.path.is_empty()
+            || !path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            bail!("{}: suspicious path `{}`", self.name, self.path);
+        }
+        let file_name = self.file_name();
+        if file_name.contains(['/', '\0']) {
+            bail!("{}: suspicious file name `{}`", self.name, file_name);
+        }
+        check_control_value("Package", &self.name)
+            .and_then(|_| check_control_value("Version", &self.version))
+            .and_then(|_| check_control_value("Architecture", &self.arch))
+            .with_context(|| format!("{}: suspicious metadata", self.name))?;
+
+        Ok(())
+    }
+}
+
+/// Make sure a package name, version or architecture only uses the characters allowed by
+/// Debian, as they end up in the file names and in the scripts run as root
+fn check_control_value(field: &str, value: &str) -> Result<()> {
+    let lower = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    let valid = match field {
+        "Package" => {
+            value.starts_with(lower) && value.chars().all(|c| lower(c) || "+.-".contains(c))
+        }
+        "Version" => {
+            !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ".+~:-".contains(c))
+        }
+        "Architecture" => !value.is_empty() && value.chars().all(|c| lower(c) || c == '-'),
+        _ => true,
+    };
+    if !valid {
+        bail!("invalid {} `{}`", field, value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+impl PackageMeta {
+    /// The package `name` 1.0 for `all`, in the stable pool
+    pub fn for_test(name: &str) -> Self {
+        PackageMeta {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            sha256: "0".repeat(64),
+            sha512: None,
+            md5: None,
+            path: format!("pool/stable/main/{}_1.0_all.deb", name),
+            arch: "all".to_string(),
+            in_topic: false,
+            install_size: 0,
+            download_size: 0,
+            replaces: Vec::new(),
+            pre_depends: Vec::new(),
+            essential: false,
+            priority: None,
+        }
+    }
+}
+
+/// A manifest of the `packages`, given with the fields of their stanzas (e.g.
+/// `Depends: libc\n`). The packages are 1.0 for `all` in `pool/<name>.deb`, with a zero
+/// SHA256, unless the fields set their own `Version`, `Architecture`, `Filename` or checksums.
+#[cfg(test)]
+pub fn test_manifest<S: AsRef<str>>(packages: &[(&str, S)]) -> Result<tempfile::NamedTempFile> {
+    let mut manifest = tempfile::NamedTempFile::new()?;
+    for (name, fields) in packages {
+        let fields = fields.as_ref();
+        let has = |field: &str| fields.lines().any(|l| l.starts_with(field));
+        let mut stanza = format!("Package: {}\n", name);
+        if !has("Version:") {
+            stanza.push_str("Version: 1.0\n");
+        }
+        if !has("Architecture:") {
+            stanza.push_str("Architecture: all\n");
+        }
+        if !has("Filename:") {
+            stanza.push_str(&format!("Filename: pool/{}.deb\n", name));
+        }
+        if !["SHA512:", "SHA256:", "SHA1:", "MD5sum:"]
+            .iter()
+            .any(|f| has(f))
+        {
+            stanza.push_str(&format!("SHA256: {}\n", "0".repeat(64)));
+        }
+        stanza.push_str(fields);
+        if !stanza.ends_with('\n') {
+            stanza.push('\n');
+        }
+        writeln!(manifest, "{}", stanza)?;
+    }
+
+    Ok(manifest)
+}
+
+/// Check the paths of all the packages, reporting all the suspicious ones at once
+pub fn check_package_paths(packages: &[PackageMeta]) -> Result<()> {
+    let errors = packages
+        .iter()
+        .filter_map(|p| p.check_paths().err())
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        bail!(
+            "Refusing to use the packages with suspicious paths (is the mirror compromised?):\n{}",
+            errors.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Make sure that all the packages can be verified, reporting all the others at once
+pub fn check_package_checksums(packages: &[PackageMeta]) -> Result<()> {
+    let unverifiable = packages
+        .iter()
+        .filter(|p| p.checksum().is_none())
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>();
+    if !unverifiable.is_empty() {
+        bail!(
+            "The manifests have no usable checksum (SHA512, SHA256 or MD5sum) for: {}\nUse --no-verify to install them anyway.",
+            unverifiable.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// A requested package, optionally with a version constraint (e.g. `foo`, `foo >= 2.0` or `foo (= 1.2-1)`)
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct PackageRequest {
+    pub name: String,
+    /// Version constraint in the Debian syntax (e.g. `>= 2.0`)
+    pub constraint: Option<String>,
+}
+
+impl FromStr for PackageRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let Some(pos) = s.find(['<', '=', '>']) else {
+            check_package_name(s)?;
+            return Ok(PackageRequest {
+                name: s.to_string(),
+                constraint: None,
+            });
+        };
+        let name = s[..pos].trim().trim_end_matches('(').trim_end();
+        let constraint = s[pos..].trim().trim_end_matches(')').trim_end();
+        let version = constraint.trim_start_matches(['<', '=', '>']).trim_start();
+        let op = constraint[..constraint.len() - version.len()].trim_end();
+        if name.is_empty() || version.is_empty() || version.contains(char::is_whitespace) {
+            bail!("Invalid package specification: {}", s);
+        }
+        check_package_name(name)?;
+        if !["<<", "<=", "=", ">=", ">>", "<", ">"].contains(&op) {
+            bail!("Invalid version relation {} in {}", op, s);
+        }
+
+        Ok(PackageRequest {
+            name: name.to_string(),
+            constraint: Some(format!("{} {}", op, version)),
+        })
+    }
+}
+
+pub(crate) fn check_package_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._".contains(c));
+    if !valid {
+        bail!("Invalid package name: {:?}", name);
+    }
+
+    Ok(())
+}
+
+impl TryFrom<String> for PackageRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
 }
 
-/// Simulate the apt dependency resolution
-pub fn calculate_deps(pool: &mut Pool, names: &[String]) -> Result<Transaction> {
-    let mut q = Queue::new();
-    for name in names {
-        q = pool.match_package(name, q)?;
+impl fmt::Display for PackageRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.constraint {
+            Some(constraint) => write!(f, "{} {}", self.name, constraint),
+            None => write!(f, "{}", self.name),
+        }
     }
-    q.mark_all_for_install();
-    let mut solver = Solver::new(pool);
-    solver.set_flag(SOLVER_FLAG_BEST_OBEY_POLICY, 1)?;
+}
 
-    if let Err(e) = solver.solve(&mut q) {
-        eprintln!("{e}");
-        bail!("{}", solver.get_problems()?.join("\n"));
+/// A chain of dependencies leading from a requested package to another package
+pub struct DependencyChain {
+    /// The requested package
+    pub root: String,
+    /// The dependencies followed, with the package satisfying each of them
+    pub links: Vec<(String, String)>,
+}
+
+impl fmt::Display for DependencyChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root)?;
+        for (dep, package) in &self.links {
+            write!(f, " -> {} ({})", package, dep)?;
+        }
+
+        Ok(())
     }
+}
 
-    let trans = solver.create_transaction()?;
-    trans.order(0);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProblemKind {
+    /// No package provides a dependency (or a requested package)
+    NothingProvides,
+    /// None of the providers of a dependency can be installed
+    Requires,
+    Conflicts,
+    NotInstallable,
+    /// A requested package does not exist
+    UnknownPackage,
+    Other,
+}
+
+/// A rule that takes part in a resolution problem
+#[derive(Clone, Debug, Serialize)]
+pub struct ProblemRule {
+    pub kind: ProblemKind,
+    /// Description of the rule from libsolv (e.g. `nothing provides foo needed by bar`)
+    pub description: String,
+    /// The package the rule comes from
+    pub source: Option<String>,
+    /// The other package involved (e.g. the conflicting one)
+    pub target: Option<String>,
+    /// The dependency involved
+    pub dep: Option<String>,
+    /// What might help, e.g. the available versions or similar package names
+    pub hint: Option<String>,
+}
 
-    Ok(trans)
+/// The requested packages can not be resolved
+#[derive(Debug)]
+pub struct ResolutionError {
+    /// The rules involved in each of the problems
+    pub problems: Vec<Vec<ProblemRule>>,
+    /// Packages excluded by the user (which may be the cause)
+    pub excludes: Vec<String>,
 }
 
-/// Populate the packages pool with metadata
-pub fn populate_pool(pool: &mut Pool, paths: &[PathBuf]) -> Result<()> {
-    let mut repo = Repo::new(pool, "stable")?;
-    for path in paths {
-        repo.add_debpackages(path)?;
+impl fmt::Display for ResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unable to resolve the dependencies:")?;
+        for (i, rules) in self.problems.iter().enumerate() {
+            write!(f, "\nProblem {}:", i + 1)?;
+            for rule in rules {
+                write!(f, "\n  - {}", rule.description)?;
+                if let Some(ref hint) = rule.hint {
+                    write!(f, "\n    ({})", hint)?;
+                }
+            }
+        }
+        if !self.excludes.is_empty() {
+            write!(
+                f,
+                "\nNote: the following packages are excluded: {}",
+                self.excludes.join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ResolutionError {}
+
+/// Levenshtein distance between the two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Find up to 3 package names similar to `name`
+fn similar_names(pool: &Pool, name: &str) -> Vec<String> {
+    let threshold = (name.chars().count() / 3).max(2);
+    let mut candidates = pool
+        .package_names()
+        .into_iter()
+        .map(|candidate| (edit_distance(name, &candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    candidates.into_iter().take(3).map(|(_, c)| c).collect()
+}
+
+/// Suggest the available versions, or similar names if the package does not exist at all
+fn suggest(pool: &Pool, name: &str) -> Result<Option<String>> {
+    let versions = pool.package_versions(name)?;
+    if !versions.is_empty() {
+        return Ok(Some(format!(
+            "available versions of {}: {}",
+            name,
+            versions.join(", ")
+        )));
+    }
+    let similar = similar_names(pool, name);
+    if similar.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(format!("did you mean: {}?", similar.join(", "))))
+}
+
+impl Transaction {
+    /// Explain why `name` is installed, returning the shortest dependency chain
+    /// from each of the requested packages (`roots`) that pulls it in
+    pub fn explain(&self, roots: &[PackageRequest], name: &str) -> Result<Vec<DependencyChain>> {
+        let packages = self.create_metadata()?;
+        let Some(target) = packages.iter().position(|p| p.name == name) else {
+            bail!("{} is not going to be installed", name);
+        };
+        let mut edges: HashMap<usize, Vec<Dependency>> = HashMap::new();
+        for dep in self.dependencies() {
+            edges.entry(dep.from).or_default().push(dep);
+        }
+
+        let mut chains = Vec::new();
+        for (root, package) in packages.iter().enumerate() {
+            if !roots.iter().any(|r| r.name == package.name) {
+                continue;
+            }
+            // breadth-first search, remembering how each package has been reached
+            let mut previous: HashMap<usize, (usize, &str)> = HashMap::new();
+            let mut queue = VecDeque::from([root]);
+            while let Some(current) = queue.pop_front() {
+                if current == target {
+                    break;
+                }
+                for dep in edges.get(&current).into_iter().flatten() {
+                    if dep.to != root && !previous.contains_key(&dep.to) {
+                        previous.insert(dep.to, (current, &dep.dep));
+                        queue.push_back(dep.to);
+                    }
+                }
+            }
+            if root != target && !previous.contains_key(&target) {
+                continue;
+            }
+            let mut links = Vec::new();
+            let mut current = target;
+            while let Some((from, dep)) = previous.get(&current) {
+                links.push((dep.to_string(), packages[current].name.clone()));
+                current = *from;
+            }
+            links.reverse();
+            chains.push(DependencyChain {
+                root: package.name.clone(),
+                links,
+            });
+        }
+        chains.sort_by_key(|c| c.links.len());
+
+        Ok(chains)
+    }
+}
+
+impl Transaction {
+    /// The packages of this transaction which `roots` require, directly or not, in
+    /// transaction order. Unlike a resolution of `roots` alone, the versions always
+    /// agree with the rest of the transaction. The recommended packages are left out.
+    pub fn closure(&self, roots: &[PackageRequest]) -> Result<Vec<PackageMeta>> {
+        let packages = self.create_metadata()?;
+        let mut edges: HashMap<usize, Vec<usize>> = HashMap::new();
+        for dep in self.dependencies() {
+            edges.entry(dep.from).or_default().push(dep.to);
+        }
+        let mut required = vec![false; packages.len()];
+        let mut queue = VecDeque::new();
+        for root in roots {
+            let Some(i) = packages.iter().position(|p| p.name == root.name) else {
+                bail!("{} is not part of the resolution", root.name);
+            };
+            queue.push_back(i);
+        }
+        while let Some(current) = queue.pop_front() {
+            if std::mem::replace(&mut required[current], true) {
+                continue;
+            }
+            queue.extend(edges.get(&current).into_iter().flatten());
+        }
+
+        Ok(packages
+            .into_iter()
+            .zip(required)
+            .filter_map(|(package, required)| required.then_some(package))
+            .collect())
+    }
+}
+
+/// A requested package which the other requested packages already require
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RedundantRequest {
+    pub name: String,
+    /// The closest of the other requested packages requiring it
+    pub required_by: String,
+}
+
+impl Transaction {
+    /// Find the requests among `extras` which the other `roots` require, directly or not,
+    /// so that requesting them explicitly is unnecessary. The requests always name real
+    /// packages (the virtual ones are not matched), so each of them is in the transaction.
+    pub fn redundant_requests(
+        &self,
+        roots: &[PackageRequest],
+        extras: &[PackageRequest],
+    ) -> Result<Vec<RedundantRequest>> {
+        let packages = self.create_metadata()?;
+        let mut edges: HashMap<usize, Vec<usize>> = HashMap::new();
+        for dep in self.dependencies() {
+            edges.entry(dep.from).or_default().push(dep.to);
+        }
+        let index = |name: &str| packages.iter().position(|p| p.name == name);
+
+        let mut redundant = Vec::new();
+        for extra in extras {
+            let Some(target) = index(&extra.name) else {
+                continue;
+            };
+            // breadth-first search from all the other roots, remembering where each
+            // package has been reached from
+            let mut reached_from: HashMap<usize, usize> = HashMap::new();
+            let mut queue = VecDeque::new();
+            for root in roots.iter().filter(|r| r.name != extra.name) {
+                if let Some(i) = index(&root.name) {
+                    reached_from.entry(i).or_insert(i);
+                    queue.push_back(i);
+                }
+            }
+            while let Some(current) = queue.pop_front() {
+                if current == target {
+                    break;
+                }
+                for &next in edges.get(&current).into_iter().flatten() {
+                    if !reached_from.contains_key(&next) {
+                        reached_from.insert(next, reached_from[&current]);
+                        queue.push_back(next);
+                    }
+                }
+            }
+            if let Some(&root) = reached_from.get(&target) {
+                redundant.push(RedundantRequest {
+                    name: extra.name.clone(),
+                    required_by: packages[root].name.clone(),
+                });
+            }
+        }
+
+        Ok(redundant)
+    }
+}
+
+/// Quote a string for use in a Graphviz DOT file
+fn dot_quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+
+    format!("\"{}\"", escaped)
+}
+
+impl Transaction {
+    /// Write the dependency graph of this transaction in the Graphviz DOT format,
+    /// `Pre-Depends` relationships are drawn in bold
+    pub fn write_graph<W: Write>(&self, mut writer: W) -> Result<()> {
+        let packages = self.create_metadata()?;
+        writeln!(writer, "digraph dependencies {{")?;
+        writeln!(writer, "    node [shape=box];")?;
+        for (i, package) in packages.iter().enumerate() {
+            let label = format!(
+                "{}\n{}\n{}",
+                package.name,
+                package.version,
+                ByteSize::b(package.install_size)
+            );
+            writeln!(writer, "    p{} [label={}];", i, dot_quote(&label))?;
+        }
+        // the same package may satisfy several dependencies, only draw one edge
+        let mut edges: BTreeMap<(usize, usize), bool> = BTreeMap::new();
+        for dep in self.dependencies() {
+            if dep.from != dep.to {
+                *edges.entry((dep.from, dep.to)).or_default() |= dep.pre_depends;
+            }
+        }
+        for ((from, to), pre_depends) in edges {
+            let style = if pre_depends { " [style=bold]" } else { "" };
+            writeln!(writer, "    p{} -> p{}{};", from, to, style)?;
+        }
+        writeln!(writer, "}}")?;
+
+        Ok(())
+    }
+}
+
+/// Priority of the sources not mentioned by a pin (like in apt preferences)
+pub const DEFAULT_PIN_PRIORITY: i32 = 500;
+/// The candidates pinned below this priority (and above 0) are a last resort, like the
+/// apt pins below 100: only installed if requested or if nothing else satisfies a dependency
+pub const LAST_RESORT_PIN_PRIORITY: i32 = 100;
+
+/// Bias the candidate selection of the packages matching `package` (a glob pattern):
+/// only the candidates from the sources with the highest priority are considered, even
+/// if a newer version is available from another source, a priority below
+/// [LAST_RESORT_PIN_PRIORITY] avoids them and a negative priority keeps the candidates
+/// from that source out
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pin {
+    pub package: String,
+    /// A branch, or `topics`
+    pub source: String,
+    pub priority: i32,
+}
+
+/// The effect of the pins on a package
+#[derive(Clone, Debug)]
+pub struct PinDecision {
+    pub package: String,
+    /// Priority of the candidates kept
+    pub priority: i32,
+    /// Versions and sources of the candidates kept
+    pub kept: Vec<(String, String)>,
+    /// Versions and sources of the candidates which are never installed
+    pub rejected: Vec<(String, String)>,
+    rejected_ids: Vec<i32>,
+    /// The candidates kept are only installed if nothing else satisfies a dependency
+    pub last_resort: bool,
+    kept_ids: Vec<i32>,
+}
+
+/// Apply the `pins` to the packages in the pool, only the packages for which some
+/// candidates are rejected, or which are a last resort, are returned
+pub fn pin_decisions(pool: &Pool, pins: &[Pin]) -> Result<Vec<PinDecision>> {
+    let patterns = pins
+        .iter()
+        .map(|pin| {
+            glob::Pattern::new(&pin.package)
+                .with_context(|| format!("Invalid package pattern `{}` in the pin", pin.package))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut by_name: BTreeMap<String, Vec<(Candidate, Option<i32>)>> = BTreeMap::new();
+    for candidate in pool.candidates() {
+        // the first matching pin wins
+        let priority = pins
+            .iter()
+            .zip(&patterns)
+            .find(|(pin, pattern)| pin.source == candidate.repo && pattern.matches(&candidate.name))
+            .map(|(pin, _)| pin.priority);
+        by_name
+            .entry(candidate.name.clone())
+            .or_default()
+            .push((candidate, priority));
+    }
+
+    let mut decisions = Vec::new();
+    for (package, candidates) in by_name {
+        if candidates.iter().all(|(_, priority)| priority.is_none()) {
+            continue;
+        }
+        let priority_of = |p: &Option<i32>| p.unwrap_or(DEFAULT_PIN_PRIORITY);
+        let best = candidates
+            .iter()
+            .map(|(_, p)| priority_of(p))
+            .max()
+            .unwrap_or(DEFAULT_PIN_PRIORITY);
+        let (kept, rejected): (Vec<_>, Vec<_>) = candidates
+            .into_iter()
+            .partition(|(_, p)| best >= 0 && priority_of(p) == best);
+        let last_resort = best > 0 && best < LAST_RESORT_PIN_PRIORITY;
+        if rejected.is_empty() && !last_resort {
+            continue;
+        }
+        let describe = |c: &[(Candidate, Option<i32>)]| {
+            c.iter()
+                .map(|(c, _)| (c.version.clone(), c.repo.clone()))
+                .collect::<Vec<_>>()
+        };
+        decisions.push(PinDecision {
+            package,
+            priority: best,
+            kept: describe(&kept),
+            rejected: describe(&rejected),
+            rejected_ids: rejected.iter().map(|(c, _)| c.id).collect(),
+            last_resort,
+            kept_ids: kept.iter().map(|(c, _)| c.id).collect(),
+        });
+    }
+
+    Ok(decisions)
+}
+
+/// The essential packages of the pool (see [PackageMeta::is_essential]) which are not part
+/// of the `resolved` packages, sorted by name
+pub fn missing_essential(pool: &Pool, resolved: &[PackageMeta]) -> Vec<String> {
+    let installed = resolved
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<HashSet<_>>();
+    pool.candidates()
+        .into_iter()
+        .filter(|c| c.essential && !installed.contains(c.name.as_str()))
+        .map(|c| c.name)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+impl Pool {
+    /// Simulate the apt dependency resolution, the `excludes` packages are never installed.
+    /// The pool can be resolved several times, e.g. with different requests.
+    pub fn resolve(
+        &mut self,
+        requests: &[PackageRequest],
+        excludes: &[String],
+        pins: &[PinDecision],
+        install_recommends: bool,
+    ) -> Result<Transaction> {
+        let mut q = Queue::new();
+        let mut unknown = Vec::new();
+        for request in requests {
+            let count = q.count();
+            q = self.match_package(&request.to_string(), q)?;
+            if q.count() != count {
+                continue;
+            }
+            let rule = match request.constraint {
+                Some(ref constraint) => ProblemRule {
+                    kind: ProblemKind::NothingProvides,
+                    description: format!(
+                        "no candidate of {} satisfies the constraint `{}`",
+                        request.name, constraint
+                    ),
+                    source: None,
+                    target: None,
+                    dep: Some(request.to_string()),
+                    hint: suggest(self, &request.name)?,
+                },
+                None => ProblemRule {
+                    kind: ProblemKind::UnknownPackage,
+                    description: format!("package {} does not exist", request.name),
+                    source: None,
+                    target: None,
+                    dep: Some(request.name.clone()),
+                    hint: suggest(self, &request.name)?,
+                },
+            };
+            unknown.push(vec![rule]);
+        }
+        if !unknown.is_empty() {
+            return Err(ResolutionError {
+                problems: unknown,
+                excludes: excludes.to_vec(),
+            }
+            .into());
+        }
+        q.mark_all_for_install();
+        for name in excludes {
+            q = self.lock_package(name, q)?;
+        }
+        for id in pins.iter().flat_map(|p| &p.rejected_ids) {
+            q = self.lock_solvable(*id, q);
+        }
+        for id in pins
+            .iter()
+            .filter(|p| p.last_resort)
+            .flat_map(|p| &p.kept_ids)
+        {
+            q = self.disfavor_solvable(*id, q);
+        }
+        let mut solver = Solver::new(self);
+        solver.set_flag(SOLVER_FLAG_BEST_OBEY_POLICY, 1)?;
+        // recommended packages are weak dependencies, dropped if they can not be installed
+        solver.set_flag(SOLVER_FLAG_IGNORE_RECOMMENDED, (!install_recommends).into())?;
+
+        if solver.solve(&mut q).is_err() {
+            let mut problems = solver.problem_rules(self)?;
+            for rule in problems.iter_mut().flatten() {
+                if rule.kind != ProblemKind::NothingProvides {
+                    continue;
+                }
+                // the dependency may come with a version constraint, e.g. `foo >= 1.0`
+                if let Some(name) = rule
+                    .dep
+                    .as_deref()
+                    .and_then(|d| d.split_whitespace().next())
+                {
+                    rule.hint = suggest(self, name)?;
+                }
+            }
+            return Err(ResolutionError {
+                problems,
+                excludes: excludes.to_vec(),
+            }
+            .into());
+        }
+
+        let trans = solver.create_transaction()?;
+        // keep the dependency cycles so that they can be configured together
+        trans.order(SOLVER_TRANSACTION_KEEP_ORDERCYCLES);
+
+        Ok(trans)
+    }
+}
+
+/// The fields of a package which repo_add_debpackages ignores
+#[derive(Clone, Debug, Default)]
+pub struct ManifestFields {
+    pub size: Option<u64>,
+    pub sha512: Option<String>,
+    pub md5: Option<String>,
+    pub essential: bool,
+    pub priority: Option<String>,
+    /// The names in `Replaces` (repo_deb drops the ones without `Conflicts` or `Breaks`)
+    pub replaces: Vec<String>,
+}
+
+/// The names of the packages in a relationship field (e.g. `a (>= 1), b:any | c`)
+fn relationship_names(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split([',', '|'])
+        .filter_map(|dep| dep.split(['(', ':', '[']).next())
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// Read the [ManifestFields] of the packages (keyed by file name) from a manifest
+fn read_manifest_fields(path: &Path, fields: &mut HashMap<String, ManifestFields>) -> Result<()> {
+    let f = File::open(path).with_context(|| format!("when opening {}", path.display()))?;
+    let (mut filename, mut package) = (None, ManifestFields::default());
+    // `Replaces` may be folded over several lines
+    let mut in_replaces = false;
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        for field in ["Package", "Version", "Architecture"] {
+            if let Some(value) = line.strip_prefix(field).and_then(|l| l.strip_prefix(':')) {
+                check_control_value(field, value.trim())
+                    .with_context(|| format!("in {}", path.display()))?;
+            }
+        }
+        if line.starts_with([' ', '\t']) {
+            if in_replaces {
+                package.replaces.extend(relationship_names(&line));
+            }
+            continue;
+        }
+        in_replaces = false;
+        if line.is_empty() {
+            if let Some(filename) = filename.take() {
+                fields.insert(filename, std::mem::take(&mut package));
+            }
+        } else if let Some(value) = line.strip_prefix("Filename:") {
+            filename = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("Size:") {
+            package.size = value.trim().parse().ok();
+        } else if let Some(value) = line.strip_prefix("SHA512:") {
+            package.sha512 = Some(value.trim().to_ascii_lowercase());
+        } else if let Some(value) = line.strip_prefix("MD5sum:") {
+            package.md5 = Some(value.trim().to_ascii_lowercase());
+        } else if let Some(value) = line.strip_prefix("Essential:") {
+            package.essential = value.trim().eq_ignore_ascii_case("yes");
+        } else if let Some(value) = line.strip_prefix("Priority:") {
+            package.priority = Some(value.trim().to_ascii_lowercase());
+        } else if let Some(value) = line.strip_prefix("Replaces:") {
+            package.replaces.extend(relationship_names(value));
+            in_replaces = true;
+        }
+    }
+    if let Some(filename) = filename {
+        fields.insert(filename, package);
+    }
+
+    Ok(())
+}
+
+/// Populate the packages pool with metadata, with one repository per named group of
+/// manifests (the names are used by the pins). The newest version of a package is always
+/// preferred, but among identical versions the one from the later group wins.
+pub fn populate_pool(pool: &mut Pool, groups: &[(String, Vec<PathBuf>)]) -> Result<()> {
+    // libsolv is not thread-safe: the manifests are added to the pool one after another
+    // (always in the same order), while the other fields are read in parallel
+    let (repos, fields) = std::thread::scope(|scope| {
+        let fields = scope.spawn(|| {
+            groups
+                .par_iter()
+                .map(|(_, paths)| -> Result<HashMap<String, ManifestFields>> {
+                    let fields = paths
+                        .par_iter()
+                        .map(|path| {
+                            let mut fields = HashMap::new();
+                            read_manifest_fields(path, &mut fields)?;
+                            Ok(fields)
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    // merged in the order of the paths: the later manifests win, like in a
+                    // sequential read
+                    Ok(fields.into_iter().flatten().collect())
+                })
+                .collect::<Result<Vec<_>>>()
+        });
+        let repos = groups
+            .iter()
+            .enumerate()
+            .map(|(i, (name, paths))| -> Result<Repo> {
+                let mut repo = Repo::new(pool, name)?;
+                repo.set_subpriority(i.try_into()?);
+                for path in paths {
+                    repo.add_debpackages(path)?;
+                }
+                // e.g. the `all` packages listed in the manifests of every architecture
+                repo.remove_duplicates();
+                Ok(repo)
+            })
+            .collect::<Result<Vec<_>>>();
+        // a panic of the reader is passed on as is, instead of aborting in `expect`
+        let fields = fields
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e));
+
+        (repos, fields)
+    });
+    // `collect` keeps the order of the groups
+    for (mut repo, fields) in repos?.into_iter().zip(fields?) {
+        repo.set_manifest_fields(&fields);
     }
     pool.createwhatprovides();
 
     Ok(())
 }
+
+#[test]
+fn test_parse_package_request() -> Result<()> {
+    let parse = |s: &str| s.parse::<PackageRequest>();
+    assert_eq!(
+        parse("bash")?,
+        PackageRequest {
+            name: "bash".to_string(),
+            constraint: None
+        }
+    );
+    for s in ["bash >= 5.2", "bash>=5.2", "bash (>= 5.2)"] {
+        assert_eq!(
+            parse(s)?,
+            PackageRequest {
+                name: "bash".to_string(),
+                constraint: Some(">= 5.2".to_string())
+            }
+        );
+    }
+    assert_eq!(parse("glibc = 1:2.38-1")?.to_string(), "glibc = 1:2.38-1");
+    assert!(parse("bash >=").is_err());
+    assert!(parse("bash => 5.2").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_exclude_packages() -> Result<()> {
+    let manifest = test_manifest(&[
+        ("base", "Depends: libfoo\nRecommends: extra\nSize: 100\n"),
+        ("libfoo", "Size: 100\n"),
+        ("extra", "Size: 100\n"),
+    ])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let requests = ["base".parse()?];
+    let packages = pool
+        .resolve(&requests, &[], &[], false)?
+        .create_metadata()?;
+    assert!(packages.iter().all(|p| p.download_size == 100));
+    let names = |t: Transaction| -> Result<Vec<String>> {
+        let mut names = t
+            .create_metadata()?
+            .into_iter()
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    };
+
+    assert_eq!(
+        names(pool.resolve(&requests, &[], &[], true)?)?,
+        ["base", "extra", "libfoo"]
+    );
+    assert_eq!(
+        names(pool.resolve(&requests, &[], &[], false)?)?,
+        ["base", "libfoo"]
+    );
+    assert_eq!(
+        names(pool.resolve(&requests, &["extra".to_string()], &[], true)?)?,
+        ["base", "libfoo"]
+    );
+    assert!(pool
+        .resolve(&requests, &["libfoo".to_string()], &[], true)
+        .is_err());
+
+    // the recommended packages are part of the transaction but not of the closure
+    let transaction = pool.resolve(&requests, &[], &[], true)?;
+    let mut closure = transaction
+        .closure(&requests)?
+        .into_iter()
+        .map(|p| p.name)
+        .collect::<Vec<_>>();
+    closure.sort();
+    assert_eq!(closure, ["base", "libfoo"]);
+    let closure = transaction.closure(&["libfoo".parse()?])?;
+    assert_eq!(closure.len(), 1);
+    assert!(transaction.closure(&["missing".parse()?]).is_err());
+
+    // libfoo is required by base anyway, extra is only recommended
+    let requests = ["base".parse()?, "libfoo".parse()?, "extra".parse()?];
+    let transaction = pool.resolve(&requests, &[], &[], true)?;
+    let redundant = transaction.redundant_requests(&requests, &requests[1..])?;
+    assert_eq!(redundant.len(), 1);
+    assert_eq!(redundant[0].name, "libfoo");
+    assert_eq!(redundant[0].required_by, "base");
+
+    Ok(())
+}
+
+#[test]
+fn test_resolution_error() -> Result<()> {
+    let manifest = test_manifest(&[("bash", ""), ("broken", "Depends: libmissing\n")])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let mut error = |requests: &[&str]| -> Result<String> {
+        let requests = requests
+            .iter()
+            .map(|r| r.parse())
+            .collect::<Result<Vec<PackageRequest>>>()?;
+        let err = pool.resolve(&requests, &[], &[], false).err().unwrap();
+        assert!(err.downcast_ref::<ResolutionError>().is_some());
+        Ok(err.to_string())
+    };
+
+    let message = error(&["broken"])?;
+    assert!(
+        message.contains("nothing provides libmissing"),
+        "{}",
+        message
+    );
+    let message = error(&["bsah"])?;
+    assert!(message.contains("did you mean: bash?"), "{}", message);
+    let message = error(&["bash (>= 2.0)"])?;
+    assert!(
+        message.contains("available versions of bash: 1.0"),
+        "{}",
+        message
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_branch_precedence() -> Result<()> {
+    let manifest = |branch: &str, packages: &[(&str, &str)]| {
+        let packages = packages
+            .iter()
+            .map(|(name, version)| {
+                let fields = format!(
+                    "Version: {}\nFilename: pool/{}/{}_{}.deb\n",
+                    version, branch, name, version
+                );
+                (*name, fields)
+            })
+            .collect::<Vec<_>>();
+        test_manifest(&packages)
+    };
+    let stable = manifest("stable", &[("bash", "5.2"), ("zsh", "5.9")])?;
+    let security = manifest("security", &[("bash", "5.2"), ("zsh", "5.8")])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[
+            ("stable".to_string(), vec![stable.path().to_path_buf()]),
+            ("security".to_string(), vec![security.path().to_path_buf()]),
+        ],
+    )?;
+    let requests = ["bash".parse()?, "zsh".parse()?];
+    let paths = |pool: &mut Pool, pins: &[PinDecision]| -> Result<Vec<String>> {
+        let mut packages = pool
+            .resolve(&requests, &[], pins, false)?
+            .create_metadata()?;
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(packages.into_iter().map(|p| p.path).collect())
+    };
+
+    // the later branch wins on version ties, but the newest version is still preferred
+    assert_eq!(
+        paths(&mut pool, &[])?,
+        ["pool/security/bash_5.2.deb", "pool/stable/zsh_5.9.deb"]
+    );
+
+    // a pin wins over a newer version from another source
+    let pin = |package: &str, source: &str, priority: i32| Pin {
+        package: package.to_string(),
+        source: source.to_string(),
+        priority,
+    };
+    let decisions = pin_decisions(&pool, &[pin("z*", "security", 990)])?;
+    assert_eq!(decisions.len(), 1);
+    assert_eq!(decisions[0].package, "zsh");
+    assert_eq!(
+        decisions[0].kept,
+        [("5.8".to_string(), "security".to_string())]
+    );
+    assert_eq!(
+        decisions[0].rejected,
+        [("5.9".to_string(), "stable".to_string())]
+    );
+    assert_eq!(
+        paths(&mut pool, &decisions)?,
+        ["pool/security/bash_5.2.deb", "pool/security/zsh_5.8.deb"]
+    );
+    // the pins rejecting nothing are not reported
+    assert!(pin_decisions(&pool, &[pin("bash", "security", 500)])?.is_empty());
+    let decisions = pin_decisions(&pool, &[pin("bash", "security", -1)])?;
+    assert_eq!(paths(&mut pool, &decisions)?[0], "pool/stable/bash_5.2.deb");
+    assert!(pin_decisions(&pool, &[pin("[", "stable", 500)]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_last_resort_pin() -> Result<()> {
+    let manifest = test_manifest(&[
+        ("app", "Depends: exim | postfix\n"),
+        ("exim-tools", "Depends: exim\n"),
+        ("exim", ""),
+        ("postfix", ""),
+    ])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let names = |pool: &mut Pool, request: &str, pins: &[PinDecision]| -> Result<Vec<String>> {
+        let mut names = pool
+            .resolve(&[request.parse()?], &[], pins, false)?
+            .create_metadata()?
+            .into_iter()
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    };
+    assert_eq!(names(&mut pool, "app", &[])?, ["app", "exim"]);
+
+    let pin = Pin {
+        package: "exim".to_string(),
+        source: "stable".to_string(),
+        priority: 1,
+    };
+    let decisions = pin_decisions(&pool, &[pin])?;
+    assert_eq!(decisions.len(), 1);
+    assert!(decisions[0].last_resort && decisions[0].rejected.is_empty());
+    // avoided when an alternative exists, installed when it is the only option
+    assert_eq!(names(&mut pool, "app", &decisions)?, ["app", "postfix"]);
+    assert_eq!(
+        names(&mut pool, "exim-tools", &decisions)?,
+        ["exim", "exim-tools"]
+    );
+    assert_eq!(names(&mut pool, "exim", &decisions)?, ["exim"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_essential() -> Result<()> {
+    let manifest = test_manifest(&[
+        ("base", "Depends: libc\nPriority: optional\n"),
+        ("libc", "Priority: required\n"),
+        ("coreutils", "Essential: yes\n"),
+        ("extra", "Essential: no\n"),
+    ])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let packages = pool
+        .resolve(&["base".parse()?], &[], &[], false)?
+        .create_metadata()?;
+    let libc = packages.iter().find(|p| p.name == "libc").unwrap();
+    assert!(libc.is_essential() && !libc.essential);
+    assert_eq!(libc.priority.as_deref(), Some("required"));
+    assert_eq!(missing_essential(&pool, &packages), ["coreutils"]);
+
+    let requests = ["base".parse()?, "coreutils".parse()?];
+    let packages = pool
+        .resolve(&requests, &[], &[], false)?
+        .create_metadata()?;
+    assert!(packages
+        .iter()
+        .any(|p| p.name == "coreutils" && p.essential));
+    assert!(missing_essential(&pool, &packages).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_duplicate_noarch_packages() -> Result<()> {
+    let manifest = |arch: &str, name: &str| {
+        let fields = |name: &str, arch: &str, sha256: &str| {
+            format!(
+                "Architecture: {}\nInstalled-Size: 1024\nFilename: pool/stable/main/{}_1.0_{}.deb\nSHA256: {}\n",
+                arch,
+                name,
+                arch,
+                sha256.repeat(64)
+            )
+        };
+        test_manifest(&[
+            (name, fields(name, arch, "1")),
+            ("tzdata", fields("tzdata", "all", "2")),
+        ])
+    };
+    // the manifests of the same branch, for two architectures
+    let amd64 = manifest("amd64", "bash")?;
+    let arm64 = manifest("arm64", "bash")?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[(
+            "stable".to_string(),
+            vec![amd64.path().to_path_buf(), arm64.path().to_path_buf()],
+        )],
+    )?;
+    let names = pool
+        .candidates()
+        .into_iter()
+        .map(|c| c.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names.iter().filter(|n| *n == "tzdata").count(), 1);
+    assert_eq!(names.iter().filter(|n| *n == "bash").count(), 2);
+
+    let t = pool.resolve(&["tzdata".parse()?], &[], &[], false)?;
+    let resolved = crate::lockfile::Lockfile::new(&t.install_order()?, &[]);
+    assert_eq!(resolved.packages().len(), 1);
+    assert_eq!(resolved.install_size(), 1024);
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest_checksums() -> Result<()> {
+    let manifest = test_manifest(&[
+        (
+            "strong",
+            format!("SHA512: {}\nSHA256: {}\n", "A".repeat(128), "b".repeat(64)),
+        ),
+        ("legacy", format!("MD5sum: {}\n", "c".repeat(32))),
+        // SHA1 is not used
+        ("unverifiable", format!("SHA1: {}\n", "d".repeat(40))),
+    ])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let requests = ["strong".parse()?, "legacy".parse()?];
+    let packages = pool
+        .resolve(&requests, &[], &[], false)?
+        .create_metadata()?;
+    let checksum = |name: &str| {
+        let package = packages.iter().find(|p| p.name == name).unwrap();
+        package.checksum().map(|(kind, c)| (kind, c.to_string()))
+    };
+    assert_eq!(
+        checksum("strong"),
+        Some((ChecksumKind::Sha512, "a".repeat(128)))
+    );
+    assert_eq!(
+        checksum("legacy"),
+        Some((ChecksumKind::Md5, "c".repeat(32)))
+    );
+    check_package_checksums(&packages)?;
+
+    let packages = pool
+        .resolve(&["unverifiable".parse()?], &[], &[], false)?
+        .create_metadata()?;
+    assert!(check_package_checksums(&packages)
+        .unwrap_err()
+        .to_string()
+        .contains("unverifiable"));
+    assert_eq!(
+        crate::fs::checksum(ChecksumKind::Md5, &b"foo"[..])?,
+        "acbd18db4cc2f85cedef654fccc4a4d8"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_check_paths() -> Result<()> {
+    let package = |name: &str, path: &str| PackageMeta {
+        path: path.to_string(),
+        ..PackageMeta::for_test(name)
+    };
+    package("bash", "pool/stable/main/b/bash_1.0_amd64.deb").check_paths()?;
+    for path in ["", "/etc/passwd", "pool/../../etc/passwd", ".."] {
+        assert!(package("bash", path).check_paths().is_err(), "{}", path);
+    }
+    let err = package("../../bin/bash", "pool/bash.deb")
+        .check_paths()
+        .unwrap_err();
+    assert!(err.to_string().contains("suspicious file name"));
+    let err = check_package_paths(&[package("a", "/a.deb"), package("b", "b/../../b.deb")])
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("a: suspicious path `/a.deb`\nb: "), "{}", err);
+    let quoted = PackageMeta {
+        version: "1.0'; reboot; '".to_string(),
+        ..PackageMeta::for_test("bash")
+    };
+    assert!(quoted.check_paths().is_err());
+
+    // the manifests with such fields are rejected when the pool is populated
+    for fields in ["Version: 1.0'x\n", "Architecture: AMD64\n"] {
+        let manifest = test_manifest(&[("bash", fields)])?;
+        let err = populate_pool(
+            &mut Pool::new(),
+            &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("invalid"), "{:#}", err);
+    }
+    let manifest = test_manifest(&[("Bash", "")])?;
+    assert!(populate_pool(
+        &mut Pool::new(),
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )
+    .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_replaces() -> Result<()> {
+    // repo_deb keeps only the Replaces with a matching Conflicts or Breaks
+    let manifest = test_manifest(&[
+        ("old", ""),
+        ("other", ""),
+        ("new", "Replaces: old (<< 2.0), other:any\n"),
+        ("folded", "Replaces: old,\n other | new\nBreaks: old\n"),
+    ])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let packages = pool
+        .resolve(&["new".parse()?, "folded".parse()?], &[], &[], false)?
+        .create_metadata()?;
+    let replaces = |name: &str| {
+        packages
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.replaces.clone())
+    };
+    assert_eq!(replaces("new").unwrap(), ["old", "other"]);
+    assert_eq!(replaces("folded").unwrap(), ["old", "other", "new"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_explain() -> Result<()> {
+    let manifest = test_manifest(&[
+        ("base", "Depends: libfoo\n"),
+        ("libfoo", "Depends: libbar\n"),
+        ("libbar", ""),
+        ("cli", "Depends: libbar\n"),
+        ("tools", "Depends: libfoo\n"),
+        ("unrelated", ""),
+    ])?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[("stable".to_string(), vec![manifest.path().to_path_buf()])],
+    )?;
+    let requests = ["base".parse()?, "cli".parse()?, "unrelated".parse()?];
+    let transaction = pool.resolve(&requests, &[], &[], false)?;
+    let links = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(dep, name)| (dep.to_string(), name.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    // the chains from the requested packages which pull it in, the shortest first
+    let chains = transaction.explain(&requests, "libbar")?;
+    assert_eq!(chains.len(), 2);
+    assert_eq!(chains[0].root, "cli");
+    assert_eq!(chains[0].links, links(&[("libbar", "libbar")]));
+    assert_eq!(chains[1].root, "base");
+    assert_eq!(
+        chains[1].links,
+        links(&[("libfoo", "libfoo"), ("libbar", "libbar")])
+    );
+    // a requested package is explained by itself
+    let chains = transaction.explain(&requests, "unrelated")?;
+    assert_eq!(chains.len(), 1);
+    assert!(chains[0].links.is_empty());
+    // tools is not installed
+    assert!(transaction.explain(&requests, "tools").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest_fields_order() -> Result<()> {
+    // the same file listed by two manifests of a group, read in parallel
+    let first = test_manifest(&[("foo", "Size: 100\n")])?;
+    let second = test_manifest(&[("foo", "Size: 200\n")])?;
+    for (paths, expected) in [([&first, &second], 200), ([&second, &first], 100)] {
+        let mut pool = Pool::new();
+        populate_pool(
+            &mut pool,
+            &[(
+                "stable".to_string(),
+                paths.iter().map(|m| m.path().to_path_buf()).collect(),
+            )],
+        )?;
+        let packages = pool
+            .resolve(&["foo".parse()?], &[], &[], false)?
+            .create_metadata()?;
+        // the later manifest wins, like in a sequential read
+        assert_eq!(packages[0].download_size, expected);
+    }
+
+    Ok(())
+}
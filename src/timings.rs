@@ -0,0 +1,99 @@
+//! Durations of the phases of the bootstrap (`--timings`).
+
+use std::time::{Duration, Instant};
+
+use bytesize::ByteSize;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::logging;
+
+/// The time spent in a phase of the bootstrap
+#[derive(Clone, Debug, Serialize)]
+pub struct Timing {
+    pub phase: &'static str,
+    pub seconds: f64,
+    /// Bytes processed during the phase, for the throughput
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Timings {
+    phases: Vec<Timing>,
+}
+
+impl Timings {
+    /// Record the time elapsed since `start` in `phase`, which has processed `bytes`
+    pub fn record(&mut self, phase: &'static str, start: Instant, bytes: Option<u64>) {
+        self.record_duration(phase, start.elapsed(), bytes);
+    }
+
+    /// Record a phase which took `duration` (e.g. measured by another module)
+    pub fn record_duration(&mut self, phase: &'static str, duration: Duration, bytes: Option<u64>) {
+        self.phases.push(Timing {
+            phase,
+            seconds: duration.as_secs_f64(),
+            bytes,
+        });
+    }
+
+    pub fn phases(&self) -> &[Timing] {
+        &self.phases
+    }
+
+    /// Print the summary of the phases, the bootstrap took `total`
+    pub fn print(&self, total: Duration) {
+        logging::event(
+            "timings",
+            json!({ "phases": self.phases, "total_seconds": total.as_secs_f64() }),
+            format_table(&self.phases, total),
+        );
+    }
+}
+
+fn format_table(phases: &[Timing], total: Duration) -> String {
+    let mut table = format!("{:<20} {:>10}  {}\n", "Phase", "Time", "Throughput");
+    for timing in phases {
+        let throughput = match timing.bytes {
+            Some(bytes) if timing.seconds > 0.0 => {
+                format!("{}/s", ByteSize::b((bytes as f64 / timing.seconds) as u64))
+            }
+            _ => String::new(),
+        };
+        table.push_str(&format!(
+            "{:<20} {:>9.2}s  {}\n",
+            timing.phase, timing.seconds, throughput
+        ));
+    }
+    table.push_str(&format!("{:<20} {:>9.2}s", "Total", total.as_secs_f64()));
+
+    table
+}
+
+#[test]
+fn test_format_table() {
+    let phases = [
+        Timing {
+            phase: "Resolution",
+            seconds: 1.5,
+            bytes: None,
+        },
+        Timing {
+            phase: "Download",
+            seconds: 2.0,
+            bytes: Some(4_000_000),
+        },
+    ];
+    let table = format_table(&phases, Duration::from_millis(4250));
+
+    assert_eq!(
+        table.lines().map(str::trim_end).collect::<Vec<_>>(),
+        [
+            "Phase                      Time  Throughput",
+            "Resolution                1.50s",
+            "Download                  2.00s  2.0 MB/s",
+            "Total                     4.25s",
+        ]
+    );
+}
@@ -1,6 +1,5 @@
 use std::{
-    fs::{create_dir_all, File},
-    io::Write,
+    io::Read,
     path::{Path, PathBuf},
 };
 
@@ -8,8 +7,9 @@ use anyhow::{anyhow, Result};
 use owo_colors::OwoColorize;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::DEFAULT_MIRROR;
+use crate::{fs, logging, DEFAULT_MIRROR};
 
 /// Represents a topic. Serializes to /var/lib/atm/state.
 #[derive(Deserialize, Serialize, Clone)]
@@ -38,23 +38,31 @@ const ATM_STATE: &str = "var/lib/atm/state";
 const ATM_LIST: &str = "etc/apt/sources.list.d/atm.list";
 const TOPIC_MANIFEST_URL: &str = "https://repo.aosc.io/debs/manifest/topics.json";
 
-pub fn fetch_topics() -> Result<Vec<Topic>> {
-    eprintln!("Fetching topics manifest ...");
-    let client = Client::builder()
-        .user_agent("Wget/1.20.3 (linux-gnu)")
-        .build()?;
-    let response = client.get(TOPIC_MANIFEST_URL).send()?;
+pub fn fetch_topics(client: &Client) -> Result<Vec<Topic>> {
+    logging::event(
+        "topics_fetch",
+        json!({ "url": TOPIC_MANIFEST_URL }),
+        "Fetching topics manifest ...",
+    );
+    let mut response = client.get(TOPIC_MANIFEST_URL).send()?;
     response.error_for_status_ref()?;
-    let topics: Vec<Topic> = serde_json::from_str(&response.text()?)?;
+    // read in chunks, the read timeout applies to the whole body of `text`
+    let mut text = String::new();
+    response.read_to_string(&mut text)?;
+    let topics: Vec<Topic> = serde_json::from_str(&text)?;
     Ok(topics)
 }
 
 pub fn filter_topics(specified: Vec<String>, all: Vec<Topic>) -> Result<Vec<Topic>> {
-    eprintln!("Checking availability of specified topics ...");
+    logging::event(
+        "topics_filter",
+        json!({ "topics": specified }),
+        "Checking availability of specified topics ...",
+    );
     let mut filtered = Vec::<Topic>::new();
     let mut specified = specified.clone();
     specified.sort();
-    (&all).into_iter().for_each(|t| {
+    all.iter().for_each(|t| {
         if specified.contains(&t.name) {
             filtered.push(t.clone());
         }
@@ -72,7 +80,11 @@ pub fn filter_topics(specified: Vec<String>, all: Vec<Topic>) -> Result<Vec<Topi
 }
 
 pub fn save_topics(sysroot: &Path, topics: Vec<Topic>) -> Result<()> {
-    eprintln!("{}", "Saving topic sources and ATM state ...".bold());
+    logging::event(
+        "topics_save",
+        json!({ "topics": topics.iter().map(|t| &t.name).collect::<Vec<_>>() }),
+        "Saving topic sources and ATM state ...".bold(),
+    );
     // Prepare paths
     let mut atm_list_path = PathBuf::from(sysroot);
     atm_list_path.push(ATM_LIST);
@@ -86,42 +98,50 @@ pub fn save_topics(sysroot: &Path, topics: Vec<Topic>) -> Result<()> {
         "Failed to get parent path of {:#?}",
         &atm_state_path
     ))?;
-    create_dir_all(atm_list_parent)?;
-    create_dir_all(atm_state_parent)?;
+    fs::make_dirs(atm_list_parent, fs::DIR_MODE)?;
+    fs::make_dirs(atm_state_parent, fs::DIR_MODE)?;
 
     // Prepare APT sources
-    let topic_sources: Vec<String> = (&topics)
-        .into_iter()
-        .map(|x| String::from(format!("deb {} {} main", DEFAULT_MIRROR, x.name.clone())))
+    let topic_sources: Vec<String> = topics
+        .iter()
+        .map(|x| format!("deb {} {} main", DEFAULT_MIRROR, x.name.clone()))
         .collect();
 
     // Save atm.list
-    eprintln!("{}", "Saving topic sources ...".bold().cyan());
+    logging::event(
+        "topics_save_sources",
+        json!({}),
+        "Saving topic sources ...".bold().cyan(),
+    );
     let content = topic_sources
         .into_iter()
-        .map(|x| String::from(x + "\n"))
+        .map(|x| x + "\n")
         .collect::<String>();
-    let buf = content.as_bytes();
-    let mut writer = File::create(atm_list_path)?;
-    writer.write_all(buf)?;
-    writer.sync_all()?;
+    fs::write_file(&atm_list_path, content, fs::FILE_MODE)?;
 
     // Save /var/lib/atm/state
-    eprintln!("{}", "Saving ATM state file ...".bold().cyan());
-    let writer = File::create(atm_state_path)?;
-    serde_json::to_writer(writer, &topics)?;
-    eprintln!(
-        "{} {} {}",
-        "Saved".bold(),
-        topics.len().bold().cyan(),
-        "topics into the target system.".bold()
+    logging::event(
+        "topics_save_state",
+        json!({}),
+        "Saving ATM state file ...".bold().cyan(),
+    );
+    fs::write_file(&atm_state_path, serde_json::to_vec(&topics)?, fs::FILE_MODE)?;
+    logging::event(
+        "topics_saved",
+        json!({ "count": topics.len() }),
+        format!(
+            "{} {} {}",
+            "Saved".bold(),
+            topics.len().bold().cyan(),
+            "topics into the target system.".bold()
+        ),
     );
     Ok(())
 }
 
 #[test]
 fn test_fetch_topics() -> Result<()> {
-    let topics = fetch_topics()?;
+    let topics = fetch_topics(&crate::network::make_new_client(None, &Default::default())?)?;
     println!("Fetched topics:");
     for topic in topics {
         println!(
@@ -135,7 +155,7 @@ fn test_fetch_topics() -> Result<()> {
 
 #[test]
 fn test_save_topics() -> Result<()> {
-    let topics = fetch_topics()?;
+    let topics = fetch_topics(&crate::network::make_new_client(None, &Default::default())?)?;
     save_topics(&PathBuf::from("/tmp/aoscbootstrap"), topics)
 }
 
@@ -0,0 +1,172 @@
+//! Delta updates of an installed target (`--update`).
+
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{install, solv::PackageMeta};
+
+/// The files removed from the target, and the ones left alone because their parent
+/// directory is a symbolic link pointing outside of the target
+#[derive(Debug, Default)]
+pub struct RemovedFiles {
+    pub removed: usize,
+    pub skipped: Vec<String>,
+}
+
+/// The files of the `previous` packages (the removed ones, and the old versions of the
+/// upgraded ones) which are neither shipped by the `kept` packages nor by the `changed` ones
+pub fn stale_files(
+    target: &Path,
+    previous: &[PackageMeta],
+    kept: &[PackageMeta],
+    changed: &[PackageMeta],
+    archive_path: &Path,
+) -> Result<Vec<String>> {
+    let info_dir = target.join("var/lib/dpkg/info");
+    let mut owned = HashSet::new();
+    for package in kept {
+        owned.extend(install::installed_files(&info_dir, package)?);
+    }
+    for package in changed {
+        owned.extend(install::package_files(package, archive_path)?);
+    }
+    let mut stale = HashSet::new();
+    for package in previous {
+        stale.extend(
+            install::installed_files(&info_dir, package)?
+                .into_iter()
+                .filter(|f| !owned.contains(f)),
+        );
+    }
+    let mut stale = stale.into_iter().collect::<Vec<_>>();
+    // the contents of a directory come before the directory itself
+    stale.sort_unstable_by(|a, b| b.cmp(a));
+
+    Ok(stale)
+}
+
+/// The path of the listed `file` (e.g. `/usr/bin/bash`) in the `target`
+fn target_path(target: &Path, file: &str) -> Option<PathBuf> {
+    let relative = Path::new(file.trim_start_matches('/'));
+    if relative.as_os_str().is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    Some(target.join(relative))
+}
+
+/// Remove the stale `files` (see [stale_files]) from the target, the directories are only
+/// removed when they are empty
+pub fn remove_files(target: &Path, files: &[String]) -> Result<RemovedFiles> {
+    let root = target.canonicalize()?;
+    let mut result = RemovedFiles::default();
+    for file in files {
+        let Some(path) = target_path(target, file) else {
+            continue;
+        };
+        let Ok(metadata) = path.symlink_metadata() else {
+            continue;
+        };
+        // e.g. /lib64 -> /usr/lib64, which would be resolved against the host
+        let parent = path.parent().unwrap_or(target).canonicalize()?;
+        if !parent.starts_with(&root) {
+            result.skipped.push(file.clone());
+            continue;
+        }
+        let path = parent.join(path.file_name().unwrap_or_default());
+        if metadata.is_dir() {
+            match std::fs::remove_dir(&path) {
+                Ok(()) => result.removed += 1,
+                // still used by the files of other packages
+                Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => (),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("when removing {}", path.display()))
+                }
+            }
+        } else {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("when removing {}", path.display()))?;
+            result.removed += 1;
+        }
+    }
+
+    Ok(result)
+}
+
+#[test]
+fn test_remove_stale_files() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    let info_dir = target.path().join("var/lib/dpkg/info");
+    std::fs::create_dir_all(&info_dir)?;
+    std::fs::create_dir_all(target.path().join("usr/share/old"))?;
+    std::fs::create_dir_all(target.path().join("usr/bin"))?;
+    for file in [
+        "usr/share/old/data",
+        "usr/bin/old",
+        "usr/bin/kept",
+        "usr/bin/tool",
+    ] {
+        std::fs::write(target.path().join(file), "")?;
+    }
+    let package = |name: &str| PackageMeta {
+        path: format!("pool/stable/main/{}_1.0_all.deb", name),
+        ..PackageMeta::for_test(name)
+    };
+    std::fs::write(
+        info_dir.join("old.list"),
+        "/.\n/usr\n/usr/bin\n/usr/bin/old\n/usr/bin/tool\n/usr/share\n/usr/share/old\n/usr/share/old/data\n",
+    )?;
+    std::fs::write(
+        info_dir.join("kept.list"),
+        "/.\n/usr\n/usr/bin\n/usr/bin/kept\n",
+    )?;
+    // the tool moves to a new package
+    let archive = tempfile::tempdir()?;
+    std::fs::write(
+        archive.path().join("new_1.0_all.deb"),
+        install::build_test_deb_with("", &[("./usr/bin/tool", b"")])?,
+    )?;
+
+    let stale = stale_files(
+        target.path(),
+        &[package("old")],
+        &[package("kept")],
+        &[package("new")],
+        archive.path(),
+    )?;
+    assert_eq!(
+        stale,
+        [
+            "/usr/share/old/data",
+            "/usr/share/old",
+            "/usr/share",
+            "/usr/bin/old"
+        ]
+    );
+    let removed = remove_files(target.path(), &stale)?;
+    assert_eq!(removed.removed, 4);
+    assert!(target.path().join("usr/bin/kept").exists());
+    assert!(target.path().join("usr/bin/tool").exists());
+    assert!(!target.path().join("usr/share").exists());
+    assert!(!target.path().join("usr/bin/old").exists());
+    // the packages must be installed
+    assert!(stale_files(
+        target.path(),
+        &[package("missing")],
+        &[],
+        &[],
+        archive.path()
+    )
+    .is_err());
+
+    Ok(())
+}
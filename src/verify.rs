@@ -0,0 +1,388 @@
+//! Verification of the finished tree against the files of the packages (`--verify-tree`).
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+use tar::EntryType;
+use walkdir::WalkDir;
+
+use crate::{
+    fs::{self, ChecksumKind},
+    install::{self, PathFilter},
+    solv::PackageMeta,
+};
+
+/// The directories whose contents are created at run time, rather than shipped by the packages
+const RUNTIME_DIRS: &[&str] = &[
+    "dev",
+    "proc",
+    "sys",
+    "run",
+    "tmp",
+    "var/tmp",
+    "var/cache",
+    "var/log",
+    "var/lib/apt",
+    "var/lib/dpkg",
+    "var/lib/aoscbootstrap",
+];
+
+/// How many symbolic links are followed when resolving a path, like `MAXSYMLINKS`
+const MAX_SYMLINKS: usize = 40;
+
+/// A file of a package which does not match the tree
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Mismatch {
+    pub path: String,
+    pub package: String,
+    /// e.g. `missing`, or `size 10, expected 12`
+    pub problem: String,
+}
+
+/// The result of [verify_tree]
+#[derive(Debug, Default, Serialize)]
+pub struct TreeReport {
+    /// Number of the paths shipped by the packages which were checked
+    pub checked: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// The files which no package ships (e.g. created by the maintainer scripts), outside of
+    /// the [RUNTIME_DIRS]
+    pub extra: Vec<String>,
+}
+
+/// What a package expects to find at a path
+struct Expected<'a> {
+    package: &'a str,
+    entry: install::DebEntry,
+    md5: Option<String>,
+    /// Modified by the maintainer scripts or by the users, only checked for existence
+    conffile: bool,
+}
+
+/// The paths recorded in the dpkg `info_dir` file of the package (`md5sums` or `conffiles`),
+/// with the rest of their line
+fn read_info_file(
+    info_dir: &Path,
+    package: &PackageMeta,
+    kind: &str,
+) -> Result<HashMap<String, String>> {
+    let Some(path) = install::package_info_file(info_dir, package, kind) else {
+        return Ok(HashMap::new());
+    };
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("when reading {}", path.display()))?;
+
+    Ok(content
+        .lines()
+        .filter_map(|line| match kind {
+            // `<md5>  usr/bin/bash`
+            "md5sums" => line
+                .split_once(char::is_whitespace)
+                .map(|(md5, path)| (format!("/{}", path.trim()), md5.to_string())),
+            _ => Some((line.trim().to_string(), String::new())),
+        })
+        .collect())
+}
+
+/// The diversions of the dpkg database: the diverted path -> (where it is, the diverting package)
+fn read_diversions(admin_dir: &Path) -> Result<HashMap<String, (String, String)>> {
+    let path = admin_dir.join("diversions");
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("when reading {}", path.display()))?;
+    let lines = content.lines().collect::<Vec<_>>();
+
+    Ok(lines
+        .chunks_exact(3)
+        .map(|c| (c[0].to_string(), (c[1].to_string(), c[2].to_string())))
+        .collect())
+}
+
+/// Resolve the parent directories of `file` (e.g. `/lib/libc.so.6`) in the `target` like
+/// the guest would, the absolute symbolic links pointing into the target. The last
+/// component is not followed, `None` if there are too many symbolic links.
+fn resolve(target: &Path, file: &str) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut pending = Path::new(file)
+        .components()
+        .rev()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect::<Vec<OsString>>();
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match Path::new(&component).components().next() {
+            Some(Component::Normal(_)) => (),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+                continue;
+            }
+            _ => continue,
+        }
+        let candidate = resolved.join(&component);
+        if pending.is_empty() {
+            return Some(candidate);
+        }
+        match std::fs::read_link(target.join(&candidate)) {
+            Ok(link) => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return None;
+                }
+                if link.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                pending.extend(
+                    link.components()
+                        .rev()
+                        .map(|c| c.as_os_str().to_os_string()),
+                );
+            }
+            Err(_) => resolved = candidate,
+        }
+    }
+
+    Some(resolved)
+}
+
+/// Compare the file at `path` in the tree with what the package expects,
+/// `None` if it matches
+fn check(expected: &Expected, path: &Path) -> Result<Option<String>> {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(Some("missing".to_string()));
+    };
+    let entry = &expected.entry;
+    match entry.kind {
+        _ if expected.conffile => (),
+        // e.g. /lib replaced by a symbolic link to /usr/lib
+        EntryType::Directory => (),
+        EntryType::Symlink => {
+            if !metadata.is_symlink() {
+                return Ok(Some("not a symbolic link".to_string()));
+            }
+            let link = std::fs::read_link(path)?;
+            if Some(link.to_string_lossy().as_ref()) != entry.link.as_deref() {
+                return Ok(Some(format!(
+                    "symbolic link to {}, expected {}",
+                    link.display(),
+                    entry.link.as_deref().unwrap_or_default()
+                )));
+            }
+        }
+        EntryType::Regular | EntryType::Continuous | EntryType::Link => {
+            if !metadata.is_file() {
+                return Ok(Some("not a regular file".to_string()));
+            }
+            // the hard links have no size of their own
+            if entry.kind != EntryType::Link && metadata.len() != entry.size {
+                return Ok(Some(format!(
+                    "size {}, expected {}",
+                    metadata.len(),
+                    entry.size
+                )));
+            }
+            if let Some(ref md5) = expected.md5 {
+                let f =
+                    File::open(path).with_context(|| format!("when opening {}", path.display()))?;
+                if !fs::checksum(ChecksumKind::Md5, f)?.eq_ignore_ascii_case(md5) {
+                    return Ok(Some("MD5 checksum mismatch".to_string()));
+                }
+            }
+        }
+        _ => (),
+    }
+
+    Ok(None)
+}
+
+/// Check that the files shipped by the `packages` (whose debs are in the `archive_path`)
+/// are in the `target`, with the sizes of the packages and the MD5 checksums recorded in
+/// the dpkg database. The paths excluded by the `filter` are not expected, and the paths
+/// shipped by several packages may match any of them.
+pub fn verify_tree(
+    target: &Path,
+    packages: &[PackageMeta],
+    archive_path: &Path,
+    filter: &PathFilter,
+) -> Result<TreeReport> {
+    let admin_dir = target.join("var/lib/dpkg");
+    let info_dir = admin_dir.join("info");
+    let diversions = read_diversions(&admin_dir)?;
+    let entries = packages
+        .par_iter()
+        .map(|package| -> Result<Vec<(String, Expected)>> {
+            let md5sums = read_info_file(&info_dir, package, "md5sums")?;
+            let conffiles = read_info_file(&info_dir, package, "conffiles")?;
+            Ok(install::package_entries(package, archive_path)?
+                .into_iter()
+                .filter(|e| e.path != "/" && !filter.excludes(&e.path))
+                .map(|entry| {
+                    // the diverted files of the other packages are installed elsewhere
+                    let path = match diversions.get(&entry.path) {
+                        Some((to, by)) if *by != package.name => to.clone(),
+                        _ => entry.path.clone(),
+                    };
+                    let expected = Expected {
+                        package: &package.name,
+                        md5: md5sums.get(&entry.path).cloned(),
+                        conffile: conffiles.contains_key(&entry.path),
+                        entry,
+                    };
+                    (path, expected)
+                })
+                .collect())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut expected: BTreeMap<String, Vec<Expected>> = BTreeMap::new();
+    for (path, entry) in entries.into_iter().flatten() {
+        expected.entry(path).or_default().push(entry);
+    }
+
+    let checked = expected
+        .par_iter()
+        .map(
+            |(path, candidates)| -> Result<(Option<PathBuf>, Option<Mismatch>)> {
+                let resolved = resolve(target, path);
+                let mut problem = None;
+                if let Some(ref resolved) = resolved {
+                    for candidate in candidates {
+                        match check(candidate, &target.join(resolved))? {
+                            Some(p) => problem = problem.or(Some(p)),
+                            None => return Ok((Some(resolved.clone()), None)),
+                        }
+                    }
+                }
+                let mismatch = Mismatch {
+                    path: path.clone(),
+                    package: candidates[0].package.to_string(),
+                    problem: problem.unwrap_or_else(|| "too many symbolic links".to_string()),
+                };
+
+                Ok((resolved, Some(mismatch)))
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    let mut report = TreeReport {
+        checked: checked.len(),
+        ..Default::default()
+    };
+    let mut owned = HashSet::new();
+    for (resolved, mismatch) in checked {
+        owned.extend(resolved);
+        report.mismatches.extend(mismatch);
+    }
+
+    let walker = WalkDir::new(target)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            let relative = e.path().strip_prefix(target).unwrap_or(e.path());
+            !RUNTIME_DIRS.iter().any(|d| relative == Path::new(d))
+        });
+    for entry in walker {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(target)?;
+        if !entry.file_type().is_dir() && !owned.contains(relative) {
+            report.extra.push(format!("/{}", relative.display()));
+        }
+    }
+
+    Ok(report)
+}
+
+#[test]
+fn test_verify_tree() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let target = tempfile::tempdir()?;
+    let root = target.path();
+    let info_dir = root.join("var/lib/dpkg/info");
+    std::fs::create_dir_all(&info_dir)?;
+    std::fs::create_dir_all(root.join("usr/bin"))?;
+    std::fs::create_dir_all(root.join("usr/lib"))?;
+    std::fs::create_dir_all(root.join("etc"))?;
+    // resolved against the target, not the host
+    symlink("/usr/lib", root.join("lib"))?;
+    std::fs::write(root.join("usr/bin/tool"), "hello")?;
+    std::fs::write(root.join("usr/lib/libfoo.so"), "library")?;
+    std::fs::write(root.join("usr/lib/data"), "truncated")?;
+    std::fs::write(root.join("etc/tool.conf"), "edited by the user")?;
+    std::fs::write(root.join("etc/machine-id"), "created by a script")?;
+    symlink("tool", root.join("usr/bin/alias"))?;
+    std::fs::write(
+        info_dir.join("tool.md5sums"),
+        format!(
+            "{}  usr/bin/tool\n{}  lib/libfoo.so\n",
+            fs::checksum(ChecksumKind::Md5, &b"hello"[..])?,
+            fs::checksum(ChecksumKind::Md5, &b"other"[..])?
+        ),
+    )?;
+    std::fs::write(info_dir.join("tool.conffiles"), "/etc/tool.conf\n")?;
+
+    let mut tar = tar::Builder::new(Vec::new());
+    let files: [(&str, &[u8]); 5] = [
+        ("./usr/bin/tool", b"hello"),
+        ("./lib/libfoo.so", b"library"),
+        ("./usr/lib/data", b"the complete data"),
+        ("./etc/tool.conf", b"default"),
+        ("./usr/share/doc/tool", b"missing"),
+    ];
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, path, content)?;
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(EntryType::Symlink);
+    header.set_size(0);
+    header.set_cksum();
+    tar.append_link(&mut header, "./usr/bin/alias", "tool")?;
+    let archive = tempfile::tempdir()?;
+    std::fs::write(
+        archive.path().join("tool_1.0_all.deb"),
+        install::build_test_deb_with_control("", b"Package: tool\n", tar.into_inner()?)?,
+    )?;
+    let package = PackageMeta {
+        path: "pool/stable/main/tool_1.0_all.deb".to_string(),
+        ..PackageMeta::for_test("tool")
+    };
+
+    let report = verify_tree(
+        root,
+        std::slice::from_ref(&package),
+        archive.path(),
+        &PathFilter::default(),
+    )?;
+    assert_eq!(report.checked, 6);
+    let problems = report
+        .mismatches
+        .iter()
+        .map(|m| (m.path.as_str(), m.problem.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        problems,
+        [
+            ("/lib/libfoo.so", "MD5 checksum mismatch"),
+            ("/usr/lib/data", "size 9, expected 17"),
+            ("/usr/share/doc/tool", "missing"),
+        ]
+    );
+    assert_eq!(report.extra, ["/etc/machine-id", "/lib"]);
+
+    // the excluded paths are not expected
+    let filter = PathFilter::new(&["/usr/share/doc".to_string()], &[])?;
+    let report = verify_tree(root, &[package], archive.path(), &filter)?;
+    assert_eq!(report.mismatches.len(), 2);
+
+    Ok(())
+}